@@ -37,10 +37,32 @@ mod utils {
 
         (static_files, config_dir)
     }
+
+    /// `--run-scenario NAME [CONFIG_DIR]` runs a scenario headless.
+    pub fn get_scenario_arguments() -> Option<(String, String)> {
+        let args: Vec<String> = std::env::args().collect();
+        if args.get(1).map(String::as_str) != Some("--run-scenario") {
+            return None;
+        }
+        let Some(name) = args.get(2).cloned() else {
+            eprintln!("Usage: backend --run-scenario NAME [CONFIG_DIR]");
+            std::process::exit(2);
+        };
+        let config_dir = args
+            .get(3)
+            .cloned()
+            .unwrap_or_else(|| "../test/config".to_string());
+        Some((name, config_dir))
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    if let Some((name, config_dir)) = utils::get_scenario_arguments() {
+        let passed = server::run_scenario(config_dir, name).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
     let (static_files, config_dir) = utils::get_arguments_or_default();
 
     match std::fs::create_dir_all(&config_dir) {
@@ -20,16 +20,34 @@
  * THE SOFTWARE.
  */
 
+mod alerts;
+mod bridges;
 mod broker_peer_bridge;
+mod bundle;
+mod commands;
 mod config;
+mod cron;
+mod delivery;
+mod history;
 mod jsonrpc;
+mod loadgen;
 mod mqtt;
+mod reload;
+mod requests;
+mod retained;
+mod retention;
+mod scenarios;
+mod scheduler;
+mod schema;
+mod services;
+mod waiters;
+mod webhooks;
 mod websocket;
 
 use std::{
     collections::HashMap,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Mutex,
+    sync::{Mutex, RwLock},
 };
 
 use futures_channel::mpsc::channel;
@@ -44,19 +62,92 @@ fn max_ws_publish_message_size() -> usize {
 }
 
 pub fn run_server(static_files: String, config_path: String) -> tokio::task::JoinHandle<()> {
-    let mqtt_map = mqtt::BrokerMap::new(Mutex::new(HashMap::new()));
+    match schema::migrate(&config_path) {
+        Ok(Some(backup)) => println!(
+            "Migrated {config_path} to config version {}, previous brokers.json kept as {backup}",
+            schema::CONFIG_VERSION
+        ),
+        Ok(None) => {}
+        Err(err) => eprintln!("Failed to migrate {config_path}: {err}"),
+    }
+    for err in schema::validate_config_dir(&config_path) {
+        eprintln!("Invalid config: {err}");
+    }
+
+    let mqtt_map = mqtt::BrokerMap::new(RwLock::new(HashMap::new()));
     let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3030);
     let peer_map = websocket::PeerMap::new(Mutex::new(HashMap::new()));
     let notification_buf =
         websocket::NotificationBuf::new(Mutex::new(websocket::NotificationBuffer::default()));
+    let services = services::Services::new(&config_path);
 
-    // Spawn a dedicated thread that flushes batched notifications every 100 ms
+    // Flush batched notifications every 100 ms
     {
         let buf = notification_buf.clone();
         let pm = peer_map.clone();
-        std::thread::spawn(move || loop {
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            websocket::flush_notification_buffer(&buf, &pm);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                websocket::flush_notification_buffer(&buf, &pm);
+            }
+        });
+    }
+
+    // Evaluate time-based alert conditions (disconnects, quiet periods, rates) every second
+    {
+        let services = services.clone();
+        let pm = peer_map.clone();
+        let mm = mqtt_map.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                alerts::process_tick(&services, &pm, &mm);
+            }
+        });
+    }
+
+    // Report publishes that were never acknowledged
+    {
+        let pm = peer_map.clone();
+        let mm = mqtt_map.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                delivery::process_timeouts(&mm, &pm);
+            }
+        });
+    }
+
+    // Run scheduled publishes
+    {
+        let services = services.clone();
+        let pm = peer_map.clone();
+        let mm = mqtt_map.clone();
+        let config_path = config_path.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_millis(scheduler::TICK_MS));
+            loop {
+                interval.tick().await;
+                scheduler::process_tick(&config_path, &services, &pm, &mm);
+            }
+        });
+    }
+
+    // Expire messages by age and subtree size even when no new messages arrive
+    {
+        let retention = services.retention.clone();
+        let mm = mqtt_map.clone();
+        let buf = notification_buf.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                retention::process_sweep(&retention, &mm, &buf);
+            }
         });
     }
 
@@ -66,9 +157,124 @@ pub fn run_server(static_files: String, config_path: String) -> tokio::task::Joi
         &peer_map,
         &mqtt_map,
         &notification_buf,
+        &services,
     );
+
+    // Apply edits made to brokers.json, commands and pipelines on disk
+    {
+        let mut watcher = reload::ConfigWatcher::new(&config_path);
+        let services = services.clone();
+        let pm = peer_map.clone();
+        let mm = mqtt_map.clone();
+        let buf = notification_buf.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs(reload::POLL_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                reload::process_changes(&mut watcher, &pm, &mm, &buf, &services);
+            }
+        });
+    }
+
     println!("Listening for connections on {server_addr} using static files from {static_files} and config {config_path}");
 
+    // Config bundles over HTTP. There is no session to prove knowledge of
+    // broker passwords, so exports never contain credentials and imports
+    // can't change password-protected brokers. Imports also need the API token.
+    let api_token = bundle::api_token();
+    let config_api = {
+        let export_path = config_path.clone();
+        let export = warp::path!("api" / "config" / "export")
+            .and(warp::get())
+            .map(move || {
+                let bundle = bundle::export_bundle(&export_path, |_| false);
+                warp::reply::with_header(
+                    warp::reply::json(&bundle),
+                    "content-disposition",
+                    "attachment; filename=\"mqtt-config-bundle.json\"",
+                )
+            });
+
+        let preview_path = config_path.clone();
+        let preview_token = api_token.clone();
+        let preview = warp::path!("api" / "config" / "import" / "preview")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::content_length_limit(bundle::MAX_BUNDLE_BYTES))
+            .and(warp::body::json())
+            .map(
+                move |authorization: Option<String>, config_bundle: bundle::ConfigBundle| {
+                    if let Err(err) = bundle::authorize_http_import(
+                        authorization.as_deref(),
+                        preview_token.as_deref(),
+                    ) {
+                        return warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({ "error": err })),
+                            warp::http::StatusCode::UNAUTHORIZED,
+                        );
+                    }
+                    match bundle::preview_import(&preview_path, &config_bundle) {
+                        Ok(items) => warp::reply::with_status(
+                            warp::reply::json(&items),
+                            warp::http::StatusCode::OK,
+                        ),
+                        Err(err) => warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({ "error": err })),
+                            warp::http::StatusCode::BAD_REQUEST,
+                        ),
+                    }
+                },
+            );
+
+        let import_path = config_path.clone();
+        let peer_map = peer_map.clone();
+        let mqtt_map = mqtt_map.clone();
+        let notification_buf = notification_buf.clone();
+        let services = services.clone();
+        let import = warp::path!("api" / "config" / "import")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::content_length_limit(bundle::MAX_BUNDLE_BYTES))
+            .and(warp::body::json())
+            .map(
+                move |authorization: Option<String>, request: bundle::ImportRequest| {
+                    if let Err(err) = bundle::authorize_http_import(
+                        authorization.as_deref(),
+                        api_token.as_deref(),
+                    ) {
+                        return warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({ "error": err })),
+                            warp::http::StatusCode::UNAUTHORIZED,
+                        );
+                    }
+                    match bundle::apply_import(&import_path, &request, |broker| {
+                        !broker.requires_auth()
+                    }) {
+                        Ok(report) => {
+                            broker_peer_bridge::apply_config_dir(
+                                &import_path,
+                                &peer_map,
+                                &mqtt_map,
+                                &notification_buf,
+                                &services,
+                            );
+                            warp::reply::with_status(
+                                warp::reply::json(&report),
+                                warp::http::StatusCode::OK,
+                            )
+                        }
+                        Err(err) => warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({ "error": err })),
+                            warp::http::StatusCode::BAD_REQUEST,
+                        ),
+                    }
+                },
+            );
+
+        export.or(preview).or(import)
+    };
+
     let ws =
         {
             let ws_max = max_ws_publish_message_size();
@@ -81,6 +287,7 @@ pub fn run_server(static_files: String, config_path: String) -> tokio::task::Joi
                     let peer_map = std::sync::Arc::clone(&peer_map);
                     let mqtt_map = std::sync::Arc::clone(&mqtt_map);
                     let notification_buf = std::sync::Arc::clone(&notification_buf);
+                    let services = services.clone();
                     let config_path = config_path.clone();
                     async move {
                         Ok::<_, warp::Rejection>(ws.on_upgrade(move |socket| async move {
@@ -107,6 +314,7 @@ pub fn run_server(static_files: String, config_path: String) -> tokio::task::Joi
                                         &config_path,
                                         addr,
                                         &notification_buf,
+                                        &services,
                                     );
                                 }
 
@@ -125,11 +333,105 @@ pub fn run_server(static_files: String, config_path: String) -> tokio::task::Joi
                 })
         };
 
-    let routes = warp::get()
-        .and(ws)
+    let routes = config_api
+        .or(warp::get().and(ws))
         .or(warp::get().and(warp::fs::dir(static_files)));
 
     tokio::spawn(async move {
         warp::serve(routes).run(server_addr).await;
     })
 }
+
+/// Seconds a headless scenario run waits for its brokers to connect.
+const SCENARIO_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Time after connecting for the broker to acknowledge the `#` subscription,
+/// so replies to the first publish aren't missed.
+const SCENARIO_SUBSCRIBE_SETTLE_MS: u64 = 500;
+
+/// Run one scenario without the web server, printing each step. Connects only
+/// to the brokers the scenario uses. Returns whether every step passed.
+pub async fn run_scenario(config_path: String, name: String) -> bool {
+    let mqtt_map = mqtt::BrokerMap::new(RwLock::new(HashMap::new()));
+    let peer_map = websocket::PeerMap::default();
+    let notification_buf = websocket::NotificationBuf::default();
+    let services = services::Services::new(&config_path);
+    let commands_path = format!("{config_path}/commands");
+
+    let Some(scenario) = scenarios::get_scenario(&format!("{config_path}/scenarios"), &name) else {
+        eprintln!("Scenario {name} not found in {config_path}/scenarios");
+        return false;
+    };
+    let brokers = match scenario.brokers(&commands_path) {
+        Ok(brokers) => brokers,
+        Err(err) => {
+            eprintln!("Scenario {name} is invalid: {err}");
+            return false;
+        }
+    };
+    let known = config::get_known_brokers(&format!("{config_path}/brokers.json"));
+    for broker in &brokers {
+        let Some(broker_config) = known.iter().find(|c| c.key() == broker) else {
+            eprintln!("Broker {broker} is not in brokers.json");
+            return false;
+        };
+        broker_peer_bridge::connect_to_broker(
+            broker_config,
+            &peer_map,
+            &mqtt_map,
+            &notification_buf,
+            &services,
+        );
+    }
+
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_secs(SCENARIO_CONNECT_TIMEOUT_SECS);
+    let all_connected = || {
+        brokers
+            .iter()
+            .all(|b| mqtt::get_broker(&mqtt_map, b).is_some_and(|e| e.is_connected()))
+    };
+    while !all_connected() {
+        if std::time::Instant::now() >= deadline {
+            eprintln!("Timed out connecting to {brokers:?}");
+            return false;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(
+        SCENARIO_SUBSCRIBE_SETTLE_MS,
+    ))
+    .await;
+
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let report = scenarios::run(
+        &scenario,
+        &run_id,
+        &commands_path,
+        &services,
+        &mqtt_map,
+        |step| {
+            let status = if step.success { "ok" } else { "FAILED" };
+            let message = step.error.as_deref().or(step.detail.as_deref());
+            println!(
+                "[{}] {} {status} ({} ms){}",
+                step.index,
+                step.kind,
+                step.elapsed_ms,
+                message.map(|m| format!(": {m}")).unwrap_or_default()
+            );
+        },
+    )
+    .await;
+    println!(
+        "Scenario {name} {} after {} of {} steps in {} ms",
+        if report.success { "passed" } else { "failed" },
+        report.steps.len(),
+        scenario.steps.len(),
+        report.elapsed_ms
+    );
+    for broker in mqtt::all_brokers(&mqtt_map) {
+        broker.stop();
+    }
+    report.success
+}
@@ -0,0 +1,863 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::config;
+use super::mqtt;
+use super::services;
+use super::webhooks;
+use super::websocket;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Resolved alerts kept around so late-joining peers can still see them.
+const MAX_RESOLVED_HISTORY: usize = 200;
+/// Payload bytes kept as the observed value of a `topic_match` alert.
+const MAX_ALERT_VALUE_BYTES: usize = 256;
+/// Sliding window over which `rate` conditions are evaluated.
+const RATE_WINDOW_MS: i64 = 10_000;
+
+fn default_resolve_after_secs() -> u64 {
+    60
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// Any message on a topic matching `filter`.
+    TopicMatch { filter: String },
+    /// A JSON payload value at `path` (e.g. `$.sensor.temp`) compared against `value`.
+    PayloadValue {
+        filter: String,
+        path: String,
+        op: CompareOp,
+        value: serde_json::Value,
+    },
+    /// The throughput of a broker over the last 10 seconds compared against
+    /// `bytes_per_second`.
+    Rate {
+        op: CompareOp,
+        bytes_per_second: f64,
+    },
+    /// The broker has been disconnected for at least `seconds`.
+    Disconnected { seconds: u64 },
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct AlertRule {
+    pub name: String,
+    /// Restrict the rule to one broker. Applies to all brokers when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker: Option<String>,
+    pub condition: AlertCondition,
+    /// Seconds without a new match after which a `topic_match` alert resolves.
+    #[serde(default = "default_resolve_after_secs")]
+    pub resolve_after_secs: u64,
+}
+
+impl AlertRule {
+    fn applies_to(&self, broker: &str) -> bool {
+        self.broker.as_deref().is_none_or(|b| b == broker)
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Alert {
+    pub id: String,
+    pub rule: String,
+    pub broker: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    pub state: AlertState,
+    pub acknowledged: bool,
+    /// Number of times the condition matched while this alert was firing.
+    pub occurrences: usize,
+    /// The observed value that (last) triggered the alert.
+    pub value: serde_json::Value,
+    pub fired_at: i64,
+    pub last_seen: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<i64>,
+}
+
+pub enum AlertEvent {
+    Fired(Alert),
+    Resolved(Alert),
+}
+
+#[derive(Default)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    /// Firing alerts keyed by rule, broker and topic so repeated matches deduplicate.
+    active: HashMap<String, Alert>,
+    resolved: VecDeque<Alert>,
+    /// Epoch ms at which a broker was first seen disconnected.
+    disconnected_since: HashMap<String, i64>,
+    /// Received payload bytes per broker, for `rate` conditions.
+    rates: HashMap<String, RateWindow>,
+}
+
+/// Payload bytes received in the last `RATE_WINDOW_MS`, in one-second buckets.
+struct RateWindow {
+    /// Epoch ms at which counting started. No rate is reported before a
+    /// whole window was seen.
+    since_ms: i64,
+    buckets: VecDeque<(i64, usize)>,
+}
+
+impl RateWindow {
+    fn new(now_ms: i64) -> Self {
+        Self {
+            since_ms: now_ms,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, bytes: usize, now_ms: i64) {
+        let second = now_ms - now_ms.rem_euclid(1000);
+        match self.buckets.back_mut() {
+            Some((start, total)) if *start == second => *total += bytes,
+            _ => self.buckets.push_back((second, bytes)),
+        }
+    }
+
+    /// Bytes per second over the window ending at `now_ms`, or `None` if
+    /// the window isn't full yet.
+    fn bytes_per_second(&mut self, now_ms: i64) -> Option<f64> {
+        let start_ms = now_ms - RATE_WINDOW_MS;
+        while self
+            .buckets
+            .front()
+            .is_some_and(|(second, _)| second + 1000 <= start_ms)
+        {
+            self.buckets.pop_front();
+        }
+        if self.since_ms > start_ms {
+            return None;
+        }
+        let total: usize = self.buckets.iter().map(|(_, bytes)| bytes).sum();
+        Some(total as f64 * 1000.0 / RATE_WINDOW_MS as f64)
+    }
+}
+
+pub type AlertMap = Arc<Mutex<AlertEngine>>;
+
+fn dedup_key(rule: &str, broker: &str, topic: Option<&str>) -> String {
+    format!("{rule}\0{broker}\0{}", topic.unwrap_or(""))
+}
+
+fn compare_f64(lhs: f64, op: CompareOp, rhs: f64) -> bool {
+    match op {
+        CompareOp::Eq => lhs == rhs,
+        CompareOp::Ne => lhs != rhs,
+        CompareOp::Gt => lhs > rhs,
+        CompareOp::Gte => lhs >= rhs,
+        CompareOp::Lt => lhs < rhs,
+        CompareOp::Lte => lhs <= rhs,
+    }
+}
+
+pub fn compare_values(lhs: &serde_json::Value, op: CompareOp, rhs: &serde_json::Value) -> bool {
+    match (lhs, rhs) {
+        (serde_json::Value::Number(l), serde_json::Value::Number(r)) => {
+            match (l.as_f64(), r.as_f64()) {
+                (Some(l), Some(r)) => compare_f64(l, op, r),
+                _ => false,
+            }
+        }
+        (serde_json::Value::String(l), serde_json::Value::String(r)) => match op {
+            CompareOp::Eq => l == r,
+            CompareOp::Ne => l != r,
+            CompareOp::Gt => l > r,
+            CompareOp::Gte => l >= r,
+            CompareOp::Lt => l < r,
+            CompareOp::Lte => l <= r,
+        },
+        _ => match op {
+            CompareOp::Eq => lhs == rhs,
+            CompareOp::Ne => lhs != rhs,
+            _ => false,
+        },
+    }
+}
+
+/// Resolve a simple JSON path such as `$.a.b[0]` or `a.b.0` against `value`.
+pub fn json_path_lookup<'a>(
+    value: &'a serde_json::Value,
+    path: &str,
+) -> Option<&'a serde_json::Value> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let mut current = value;
+    for segment in path.split(['.', '[', ']']).filter(|s| !s.is_empty()) {
+        let segment = segment.trim_matches(|c| c == '\'' || c == '"');
+        current = match current {
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            serde_json::Value::Object(map) => map.get(segment)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        Self {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    /// Replace the rule set. Firing alerts whose rule no longer exists are resolved.
+    pub fn set_rules(&mut self, rules: Vec<AlertRule>, now_ms: i64) -> Vec<AlertEvent> {
+        self.rules = rules;
+        let orphaned: Vec<String> = self
+            .active
+            .iter()
+            .filter(|(_, alert)| !self.rules.iter().any(|r| r.name == alert.rule))
+            .map(|(key, _)| key.clone())
+            .collect();
+        orphaned
+            .into_iter()
+            .filter_map(|key| self.resolve(&key, now_ms))
+            .collect()
+    }
+
+    fn fire(
+        &mut self,
+        rule: &str,
+        broker: &str,
+        topic: Option<&str>,
+        value: serde_json::Value,
+        now_ms: i64,
+    ) -> Option<AlertEvent> {
+        let key = dedup_key(rule, broker, topic);
+        if let Some(alert) = self.active.get_mut(&key) {
+            alert.occurrences += 1;
+            alert.last_seen = now_ms;
+            alert.value = value;
+            return None;
+        }
+        let alert = Alert {
+            id: uuid::Uuid::new_v4().to_string(),
+            rule: rule.to_string(),
+            broker: broker.to_string(),
+            topic: topic.map(str::to_string),
+            state: AlertState::Firing,
+            acknowledged: false,
+            occurrences: 1,
+            value,
+            fired_at: now_ms,
+            last_seen: now_ms,
+            resolved_at: None,
+        };
+        self.active.insert(key, alert.clone());
+        Some(AlertEvent::Fired(alert))
+    }
+
+    fn resolve(&mut self, key: &str, now_ms: i64) -> Option<AlertEvent> {
+        let mut alert = self.active.remove(key)?;
+        alert.state = AlertState::Resolved;
+        alert.resolved_at = Some(now_ms);
+        self.resolved.push_back(alert.clone());
+        while self.resolved.len() > MAX_RESOLVED_HISTORY {
+            self.resolved.pop_front();
+        }
+        Some(AlertEvent::Resolved(alert))
+    }
+
+    /// Evaluate topic and payload rules against an incoming message, and
+    /// count it towards the broker's rate.
+    pub fn on_message(
+        &mut self,
+        broker: &str,
+        topic: &str,
+        payload: &[u8],
+        now_ms: i64,
+    ) -> Vec<AlertEvent> {
+        self.rates
+            .entry(broker.to_string())
+            .or_insert_with(|| RateWindow::new(now_ms))
+            .add(payload.len(), now_ms);
+        if self.rules.is_empty() {
+            return Vec::new();
+        }
+        let mut parsed: Option<Option<serde_json::Value>> = None;
+        let mut events = Vec::new();
+        let rules = std::mem::take(&mut self.rules);
+        for rule in &rules {
+            if !rule.applies_to(broker) {
+                continue;
+            }
+            match &rule.condition {
+                AlertCondition::TopicMatch { filter } if mqtt::topic_matches(filter, topic) => {
+                    let excerpt = &payload[..payload.len().min(MAX_ALERT_VALUE_BYTES)];
+                    let value = serde_json::json!(String::from_utf8_lossy(excerpt));
+                    events.extend(self.fire(&rule.name, broker, Some(topic), value, now_ms));
+                }
+                AlertCondition::PayloadValue {
+                    filter,
+                    path,
+                    op,
+                    value,
+                } if mqtt::topic_matches(filter, topic) => {
+                    let json = parsed.get_or_insert_with(|| serde_json::from_slice(payload).ok());
+                    let Some(observed) = json.as_ref().and_then(|j| json_path_lookup(j, path))
+                    else {
+                        continue;
+                    };
+                    if compare_values(observed, *op, value) {
+                        let observed = observed.clone();
+                        events.extend(self.fire(&rule.name, broker, Some(topic), observed, now_ms));
+                    } else {
+                        let key = dedup_key(&rule.name, broker, Some(topic));
+                        events.extend(self.resolve(&key, now_ms));
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.rules = rules;
+        events
+    }
+
+    /// Periodic evaluation of time-based and rate conditions. `brokers` holds the current
+    /// connection state of every broker.
+    pub fn on_tick(&mut self, brokers: &[(String, bool)], now_ms: i64) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+
+        self.disconnected_since
+            .retain(|broker, _| brokers.iter().any(|(b, _)| b == broker));
+        self.rates
+            .retain(|broker, _| brokers.iter().any(|(b, _)| b == broker));
+        for (broker, connected) in brokers {
+            self.rates
+                .entry(broker.clone())
+                .or_insert_with(|| RateWindow::new(now_ms));
+            if *connected {
+                self.disconnected_since.remove(broker);
+            } else {
+                self.disconnected_since
+                    .entry(broker.clone())
+                    .or_insert(now_ms);
+            }
+        }
+
+        let rules = std::mem::take(&mut self.rules);
+        for rule in &rules {
+            match rule.condition {
+                AlertCondition::Disconnected { seconds } => {
+                    for (broker, _) in brokers {
+                        if !rule.applies_to(broker) {
+                            continue;
+                        }
+                        let down_ms = self
+                            .disconnected_since
+                            .get(broker)
+                            .map(|since| now_ms - since);
+                        match down_ms {
+                            Some(down_ms) if down_ms >= seconds as i64 * 1000 => {
+                                let value = serde_json::json!(down_ms / 1000);
+                                events.extend(self.fire(&rule.name, broker, None, value, now_ms));
+                            }
+                            Some(_) => {}
+                            None => {
+                                let key = dedup_key(&rule.name, broker, None);
+                                events.extend(self.resolve(&key, now_ms));
+                            }
+                        }
+                    }
+                }
+                AlertCondition::Rate {
+                    op,
+                    bytes_per_second: threshold,
+                } => {
+                    for (broker, _) in brokers {
+                        if !rule.applies_to(broker) {
+                            continue;
+                        }
+                        let Some(rate) = self
+                            .rates
+                            .get_mut(broker)
+                            .and_then(|window| window.bytes_per_second(now_ms))
+                        else {
+                            continue;
+                        };
+                        if compare_f64(rate, op, threshold) {
+                            let value = serde_json::json!(rate);
+                            events.extend(self.fire(&rule.name, broker, None, value, now_ms));
+                        } else {
+                            let key = dedup_key(&rule.name, broker, None);
+                            events.extend(self.resolve(&key, now_ms));
+                        }
+                    }
+                }
+                AlertCondition::TopicMatch { .. } => {
+                    let expire_ms = rule.resolve_after_secs as i64 * 1000;
+                    let stale: Vec<String> = self
+                        .active
+                        .iter()
+                        .filter(|(_, a)| a.rule == rule.name && now_ms - a.last_seen >= expire_ms)
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    for key in stale {
+                        events.extend(self.resolve(&key, now_ms));
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.rules = rules;
+        events
+    }
+
+    /// The firing alert with `id`.
+    pub fn active_alert(&self, id: &str) -> Option<&Alert> {
+        self.active.values().find(|a| a.id == id)
+    }
+
+    pub fn acknowledge(&mut self, id: &str) -> Option<Alert> {
+        let alert = self.active.values_mut().find(|a| a.id == id)?;
+        alert.acknowledged = true;
+        Some(alert.clone())
+    }
+
+    /// Firing alerts followed by recently resolved ones, limited to `visible` brokers.
+    pub fn alerts(&self, visible: impl Fn(&str) -> bool) -> Vec<Alert> {
+        let mut firing: Vec<&Alert> = self.active.values().collect();
+        firing.sort_by_key(|a| a.fired_at);
+        firing
+            .into_iter()
+            .chain(self.resolved.iter().rev())
+            .filter(|a| visible(&a.broker))
+            .cloned()
+            .collect()
+    }
+}
+
+// ─── Rule persistence ────────────────────────────────────────────────
+
+pub fn get_alert_rules(alerts_path: &str) -> Vec<AlertRule> {
+    config::read_named_files(alerts_path, "alert rule")
+}
+
+pub fn parse_alert_rule(params: serde_json::Value) -> Option<AlertRule> {
+    let rule = match serde_json::from_value::<AlertRule>(params) {
+        Ok(rule) => rule,
+        Err(_) => {
+            println!("Could not deserialize alert rule.");
+            return None;
+        }
+    };
+    if let Err(err) = config::validate_file_name(&rule.name) {
+        println!("Alert rule {} is invalid: {err}", rule.name);
+        return None;
+    }
+    Some(rule)
+}
+
+pub fn add_to_alert_rules(alerts_path: &str, rule: &AlertRule) -> bool {
+    config::write_named_file(alerts_path, "alert rule", &rule.name, rule)
+}
+
+pub fn remove_from_alert_rules(alerts_path: &str, name: &str) -> bool {
+    config::remove_named_file(alerts_path, "alert rule", name)
+}
+
+// ─── Glue between broker loops, the engine and peers ─────────────────
+
+fn publish_events(
+    services: &services::Services,
+    peer_map: &websocket::PeerMap,
+    events: Vec<AlertEvent>,
+) {
+    for event in events {
+        let (method, alert) = match event {
+            AlertEvent::Fired(alert) => ("alert_fired", alert),
+            AlertEvent::Resolved(alert) => ("alert_resolved", alert),
+        };
+        println!(
+            "{method}: \"{}\" for {} {:?}",
+            alert.rule, alert.broker, alert.topic
+        );
+        websocket::send_alert_to_peers(peer_map, method, &alert);
+        webhooks::dispatch_alert(&services.webhooks, method, &alert);
+    }
+}
+
+pub fn process_message(
+    services: &services::Services,
+    peer_map: &websocket::PeerMap,
+    broker: &str,
+    topic: &str,
+    payload: &[u8],
+) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let events = services
+        .alerts
+        .lock()
+        .unwrap()
+        .on_message(broker, topic, payload, now_ms);
+    publish_events(services, peer_map, events);
+}
+
+pub fn process_tick(
+    services: &services::Services,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+) {
+    let brokers: Vec<(String, bool)> = mqtt_map
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, b)| (name.clone(), b.is_connected()))
+        .collect();
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let events = services.alerts.lock().unwrap().on_tick(&brokers, now_ms);
+    publish_events(services, peer_map, events);
+}
+
+/// Reload the rules from disk, e.g. after one was saved or removed.
+pub fn reload_rules(
+    services: &services::Services,
+    peer_map: &websocket::PeerMap,
+    alerts_path: &str,
+) {
+    let rules = get_alert_rules(alerts_path);
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let events = services.alerts.lock().unwrap().set_rules(rules, now_ms);
+    publish_events(services, peer_map, events);
+}
+
+pub fn acknowledge(alert_map: &AlertMap, peer_map: &websocket::PeerMap, id: &str) {
+    let alert = alert_map.lock().unwrap().acknowledge(id);
+    match alert {
+        Some(alert) => websocket::send_alert_to_peers(peer_map, "alert_acknowledged", &alert),
+        None => println!("Alert {id} is not firing. Nothing to acknowledge."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, condition: AlertCondition) -> AlertRule {
+        AlertRule {
+            name: name.to_string(),
+            broker: None,
+            condition,
+            resolve_after_secs: 10,
+        }
+    }
+
+    fn fired(events: &[AlertEvent]) -> usize {
+        events
+            .iter()
+            .filter(|e| matches!(e, AlertEvent::Fired(_)))
+            .count()
+    }
+
+    fn resolved(events: &[AlertEvent]) -> usize {
+        events
+            .iter()
+            .filter(|e| matches!(e, AlertEvent::Resolved(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_json_path_lookup() {
+        let value = serde_json::json!({"a": {"b": [1, {"c": "x"}]}});
+        assert_eq!(
+            json_path_lookup(&value, "$.a.b[0]"),
+            Some(&serde_json::json!(1))
+        );
+        assert_eq!(
+            json_path_lookup(&value, "a.b.1.c"),
+            Some(&serde_json::json!("x"))
+        );
+        assert_eq!(json_path_lookup(&value, "$"), Some(&value));
+        assert_eq!(json_path_lookup(&value, "$.a.missing"), None);
+        assert_eq!(json_path_lookup(&value, "$.a.b[7]"), None);
+    }
+
+    #[test]
+    fn test_compare_values() {
+        let n = |v: f64| serde_json::json!(v);
+        assert!(compare_values(&n(5.0), CompareOp::Gt, &n(4.0)));
+        assert!(!compare_values(&n(5.0), CompareOp::Lt, &n(4.0)));
+        assert!(compare_values(
+            &serde_json::json!("on"),
+            CompareOp::Eq,
+            &serde_json::json!("on")
+        ));
+        assert!(compare_values(
+            &serde_json::json!(true),
+            CompareOp::Ne,
+            &serde_json::json!(false)
+        ));
+        assert!(!compare_values(
+            &serde_json::json!(true),
+            CompareOp::Gt,
+            &serde_json::json!(false)
+        ));
+    }
+
+    #[test]
+    fn test_topic_match_fires_once_and_deduplicates() {
+        let mut engine = AlertEngine::new(vec![rule(
+            "errors",
+            AlertCondition::TopicMatch {
+                filter: "dev/+/error".to_string(),
+            },
+        )]);
+
+        let events = engine.on_message("b:1883", "dev/1/error", b"boom", 0);
+        assert_eq!(fired(&events), 1);
+        let events = engine.on_message("b:1883", "dev/1/error", b"boom", 10);
+        assert!(events.is_empty());
+        let events = engine.on_message("b:1883", "dev/1/status", b"ok", 20);
+        assert!(events.is_empty());
+
+        let alerts = engine.alerts(|_| true);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].occurrences, 2);
+        assert_eq!(alerts[0].state, AlertState::Firing);
+    }
+
+    #[test]
+    fn test_topic_match_resolves_after_quiet_period() {
+        let mut engine = AlertEngine::new(vec![rule(
+            "errors",
+            AlertCondition::TopicMatch {
+                filter: "#".to_string(),
+            },
+        )]);
+        engine.on_message("b:1883", "t", b"", 0);
+
+        let events = engine.on_tick(&[("b:1883".to_string(), true)], 5_000);
+        assert!(events.is_empty());
+        let events = engine.on_tick(&[("b:1883".to_string(), true)], 10_000);
+        assert_eq!(resolved(&events), 1);
+
+        let alerts = engine.alerts(|_| true);
+        assert_eq!(alerts[0].state, AlertState::Resolved);
+        assert_eq!(alerts[0].resolved_at, Some(10_000));
+    }
+
+    #[test]
+    fn test_payload_value_fires_and_resolves() {
+        let mut engine = AlertEngine::new(vec![rule(
+            "hot",
+            AlertCondition::PayloadValue {
+                filter: "sensors/#".to_string(),
+                path: "$.temp".to_string(),
+                op: CompareOp::Gt,
+                value: serde_json::json!(30),
+            },
+        )]);
+
+        assert!(engine
+            .on_message("b:1883", "sensors/a", br#"{"temp":20}"#, 0)
+            .is_empty());
+        let events = engine.on_message("b:1883", "sensors/a", br#"{"temp":35.5}"#, 1);
+        assert_eq!(fired(&events), 1);
+        // Other topics are tracked separately.
+        let events = engine.on_message("b:1883", "sensors/b", br#"{"temp":31}"#, 2);
+        assert_eq!(fired(&events), 1);
+        // Non-JSON payloads neither fire nor resolve.
+        assert!(engine
+            .on_message("b:1883", "sensors/a", b"garbage", 3)
+            .is_empty());
+        let events = engine.on_message("b:1883", "sensors/a", br#"{"temp":25}"#, 4);
+        assert_eq!(resolved(&events), 1);
+        assert_eq!(engine.alerts(|_| true).len(), 2);
+    }
+
+    #[test]
+    fn test_rate_rule_respects_broker_scope() {
+        let mut scoped = rule(
+            "busy",
+            AlertCondition::Rate {
+                op: CompareOp::Gte,
+                bytes_per_second: 1000.0,
+            },
+        );
+        scoped.broker = Some("a:1883".to_string());
+        let mut engine = AlertEngine::new(vec![scoped]);
+        let brokers = [("a:1883".to_string(), true), ("b:1883".to_string(), true)];
+        engine.on_tick(&brokers, 0);
+        for second in 0..10 {
+            engine.on_message("a:1883", "t", &[0; 2000], second * 1000);
+            engine.on_message("b:1883", "t", &[0; 2000], second * 1000);
+        }
+
+        let events = engine.on_tick(&brokers, 10_000);
+        assert_eq!(fired(&events), 1);
+        assert_eq!(engine.alerts(|_| true)[0].broker, "a:1883");
+    }
+
+    #[test]
+    fn test_rate_rule_is_evaluated_without_messages() {
+        let busy = rule(
+            "busy",
+            AlertCondition::Rate {
+                op: CompareOp::Gte,
+                bytes_per_second: 1000.0,
+            },
+        );
+        let quiet = rule(
+            "quiet",
+            AlertCondition::Rate {
+                op: CompareOp::Lt,
+                bytes_per_second: 10.0,
+            },
+        );
+        let mut engine = AlertEngine::new(vec![busy, quiet]);
+        let brokers = [("a:1883".to_string(), true)];
+        engine.on_tick(&brokers, 0);
+        engine.on_message("a:1883", "t", &[0; 20_000], 500);
+        // Nothing is reported before a whole window was seen
+        assert!(engine.on_tick(&brokers, 9_000).is_empty());
+        assert_eq!(fired(&engine.on_tick(&brokers, 10_000)), 1);
+
+        // The traffic stops: once it leaves the window, busy resolves and
+        // quiet fires without another message arriving
+        assert!(engine.on_tick(&brokers, 10_900).is_empty());
+        let events = engine.on_tick(&brokers, 11_000);
+        assert_eq!(resolved(&events), 1);
+        assert_eq!(fired(&events), 1);
+        assert_eq!(engine.alerts(|_| true)[0].rule, "quiet");
+    }
+
+    #[test]
+    fn test_disconnected_rule() {
+        let mut engine = AlertEngine::new(vec![rule(
+            "down",
+            AlertCondition::Disconnected { seconds: 5 },
+        )]);
+        let down = [("b:1883".to_string(), false)];
+        let up = [("b:1883".to_string(), true)];
+
+        assert!(engine.on_tick(&down, 0).is_empty());
+        assert!(engine.on_tick(&down, 4_000).is_empty());
+        assert_eq!(fired(&engine.on_tick(&down, 5_000)), 1);
+        assert!(engine.on_tick(&down, 6_000).is_empty());
+        assert_eq!(resolved(&engine.on_tick(&up, 7_000)), 1);
+    }
+
+    #[test]
+    fn test_acknowledge_and_visibility() {
+        let mut engine = AlertEngine::new(vec![rule(
+            "any",
+            AlertCondition::TopicMatch {
+                filter: "#".to_string(),
+            },
+        )]);
+        engine.on_message("open:1883", "t", b"", 0);
+        engine.on_message("secret:1883", "t", b"", 0);
+
+        let visible = engine.alerts(|b| b == "open:1883");
+        assert_eq!(visible.len(), 1);
+        let id = visible[0].id.clone();
+
+        assert!(engine.acknowledge(&id).unwrap().acknowledged);
+        assert!(engine.acknowledge("unknown").is_none());
+    }
+
+    #[test]
+    fn test_removing_rule_resolves_its_alerts() {
+        let mut engine = AlertEngine::new(vec![rule(
+            "any",
+            AlertCondition::TopicMatch {
+                filter: "#".to_string(),
+            },
+        )]);
+        engine.on_message("b:1883", "t", b"", 0);
+        let events = engine.set_rules(Vec::new(), 1);
+        assert_eq!(resolved(&events), 1);
+        assert!(engine.on_message("b:1883", "t", b"", 2).is_empty());
+    }
+
+    #[test]
+    fn test_alert_rule_persistence() {
+        let alerts_path = format!("/tmp/mqtt_test_{}/alerts", uuid::Uuid::new_v4());
+        let params = serde_json::json!({
+            "name": "hot",
+            "broker": "b:1883",
+            "condition": {
+                "type": "payload_value",
+                "filter": "sensors/#",
+                "path": "$.temp",
+                "op": "gt",
+                "value": 30
+            }
+        });
+        assert!(add_to_alert_rules(
+            &alerts_path,
+            &parse_alert_rule(params).unwrap()
+        ));
+        assert!(parse_alert_rule(
+            serde_json::json!({"name": "broken", "condition": {"type": "unknown"}})
+        )
+        .is_none());
+
+        let rules = get_alert_rules(&alerts_path);
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].name, "hot");
+        assert_eq!(rules[0].resolve_after_secs, 60);
+
+        assert!(parse_alert_rule(serde_json::json!({
+            "name": "../../brokers",
+            "condition": {"type": "disconnected", "seconds": 5}
+        }))
+        .is_none());
+        assert!(!remove_from_alert_rules(&alerts_path, "../hot"));
+
+        assert!(remove_from_alert_rules(&alerts_path, "hot"));
+        assert!(get_alert_rules(&alerts_path).is_empty());
+
+        std::fs::remove_dir_all(std::path::Path::new(&alerts_path).parent().unwrap()).ok();
+    }
+}
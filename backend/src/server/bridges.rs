@@ -0,0 +1,588 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::config;
+use super::mqtt;
+use super::services;
+
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+/// How long a forwarded message is remembered for loop prevention. Its echo
+/// usually arrives within milliseconds; this only bounds the bookkeeping.
+const IN_FLIGHT_TTL_MS: i64 = 30_000;
+
+fn default_filter() -> String {
+    "#".to_string()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetainHandling {
+    /// Forward the retain flag as received.
+    #[default]
+    Keep,
+    /// Never set the retain flag on the destination.
+    Never,
+    /// Always publish retained on the destination.
+    Always,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct BridgeConfig {
+    pub name: String,
+    /// Broker key messages are read from.
+    pub source: String,
+    /// Broker key messages are published to.
+    pub destination: String,
+    #[serde(default = "default_filter")]
+    pub filter: String,
+    /// Prefix removed from the source topic before `destination_prefix` is prepended.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub source_prefix: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub destination_prefix: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: RetainHandling,
+    /// Whether the bridge is running. Toggled by `start_bridge`/`stop_bridge`.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+impl BridgeConfig {
+    pub fn destination_topic(&self, topic: &str) -> String {
+        let rest = topic.strip_prefix(&self.source_prefix).unwrap_or(topic);
+        format!("{}{rest}", self.destination_prefix)
+    }
+
+    fn destination_retain(&self, retain: bool) -> bool {
+        match self.retain {
+            RetainHandling::Keep => retain,
+            RetainHandling::Never => false,
+            RetainHandling::Always => true,
+        }
+    }
+}
+
+struct Bridge {
+    config: BridgeConfig,
+    forwarded: usize,
+    failed: usize,
+    last_error: Option<String>,
+}
+
+pub struct Forward {
+    pub bridge: String,
+    pub destination: String,
+    pub topic: String,
+    pub qos: rumqttc::QoS,
+    pub retain: bool,
+}
+
+#[derive(Default)]
+pub struct BridgeManager {
+    bridges: Vec<Bridge>,
+    /// Messages recently published by a bridge, keyed by destination broker and
+    /// fingerprint, with their count and expiry. When such a message shows up
+    /// on the destination it is consumed here instead of being forwarded again.
+    in_flight: HashMap<(String, u64), (usize, i64)>,
+    last_purge_ms: i64,
+    /// Messages dropped by loop prevention.
+    suppressed: usize,
+}
+
+pub type BridgeMap = Arc<Mutex<BridgeManager>>;
+
+fn fingerprint(topic: &str, payload: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    topic.hash(&mut hasher);
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl BridgeManager {
+    /// Replace the bridge set. Counters of bridges that keep their name survive.
+    pub fn set_bridges(&mut self, configs: Vec<BridgeConfig>) {
+        let mut previous: HashMap<String, Bridge> = self
+            .bridges
+            .drain(..)
+            .map(|b| (b.config.name.clone(), b))
+            .collect();
+        self.bridges = configs
+            .into_iter()
+            .map(|config| match previous.remove(&config.name) {
+                Some(existing) => Bridge { config, ..existing },
+                None => Bridge {
+                    config,
+                    forwarded: 0,
+                    failed: 0,
+                    last_error: None,
+                },
+            })
+            .collect();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BridgeConfig> {
+        self.bridges
+            .iter()
+            .find(|b| b.config.name == name)
+            .map(|b| &b.config)
+    }
+
+    /// Decide where an incoming message has to be forwarded to.
+    pub fn route(
+        &mut self,
+        broker: &str,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+        now_ms: i64,
+    ) -> Vec<Forward> {
+        if self.bridges.is_empty() {
+            return Vec::new();
+        }
+
+        if now_ms - self.last_purge_ms >= 1000 {
+            self.in_flight.retain(|_, (_, expiry)| *expiry > now_ms);
+            self.last_purge_ms = now_ms;
+        }
+        if !self.in_flight.is_empty() {
+            let key = (broker.to_string(), fingerprint(topic, payload));
+            if self
+                .in_flight
+                .get(&key)
+                .is_some_and(|(_, expiry)| *expiry > now_ms)
+            {
+                self.take_in_flight(&key);
+                self.suppressed += 1;
+                return Vec::new();
+            }
+        }
+
+        let mut forwards = Vec::new();
+        for bridge in &self.bridges {
+            let config = &bridge.config;
+            if !config.enabled
+                || config.source != broker
+                || !mqtt::topic_matches(&config.filter, topic)
+            {
+                continue;
+            }
+            let Some(qos) = mqtt::qos_from_u8(config.qos) else {
+                continue;
+            };
+            let destination_topic = config.destination_topic(topic);
+            let key = (
+                config.destination.clone(),
+                fingerprint(&destination_topic, payload),
+            );
+            let entry = self.in_flight.entry(key).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 = now_ms + IN_FLIGHT_TTL_MS;
+            forwards.push(Forward {
+                bridge: config.name.clone(),
+                destination: config.destination.clone(),
+                topic: destination_topic,
+                qos,
+                retain: config.destination_retain(retain),
+            });
+        }
+        forwards
+    }
+
+    fn take_in_flight(&mut self, key: &(String, u64)) {
+        if let Some((count, _)) = self.in_flight.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(key);
+            }
+        }
+    }
+
+    /// Forget a forward that was never published, so the same message
+    /// showing up on its destination isn't mistaken for the echo.
+    pub fn release(&mut self, forward: &Forward, payload: &[u8]) {
+        let key = (
+            forward.destination.clone(),
+            fingerprint(&forward.topic, payload),
+        );
+        self.take_in_flight(&key);
+    }
+
+    pub fn record_result(&mut self, name: &str, result: Result<(), String>) {
+        let Some(bridge) = self.bridges.iter_mut().find(|b| b.config.name == name) else {
+            return;
+        };
+        match result {
+            Ok(()) => bridge.forwarded += 1,
+            Err(err) => {
+                bridge.failed += 1;
+                bridge.last_error = Some(err);
+            }
+        }
+    }
+
+    pub fn summaries(&self) -> serde_json::Value {
+        let bridges: Vec<serde_json::Value> = self
+            .bridges
+            .iter()
+            .map(|b| {
+                serde_json::json!({
+                    "config": b.config,
+                    "forwarded": b.forwarded,
+                    "failed": b.failed,
+                    "last_error": b.last_error,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "bridges": bridges,
+            "suppressed": self.suppressed,
+        })
+    }
+}
+
+// ─── Bridge persistence ──────────────────────────────────────────────
+
+pub fn get_bridges(bridges_path: &str) -> Vec<BridgeConfig> {
+    config::read_named_files(bridges_path, "bridge")
+}
+
+fn write_bridge(bridges_path: &str, bridge: &BridgeConfig) -> bool {
+    config::write_named_file(bridges_path, "bridge", &bridge.name, bridge)
+}
+
+pub fn parse_bridge(params: serde_json::Value) -> Option<BridgeConfig> {
+    let Ok(bridge) = serde_json::from_value::<BridgeConfig>(params) else {
+        println!("Could not deserialize bridge.");
+        return None;
+    };
+    if mqtt::qos_from_u8(bridge.qos).is_none() {
+        println!("Bridge {} has an invalid qos {}", bridge.name, bridge.qos);
+        return None;
+    }
+    if let Err(err) = config::validate_file_name(&bridge.name) {
+        println!("Bridge {} is invalid: {err}", bridge.name);
+        return None;
+    }
+    Some(bridge)
+}
+
+pub fn add_to_bridges(bridges_path: &str, bridge: &BridgeConfig) -> bool {
+    write_bridge(bridges_path, bridge)
+}
+
+pub fn remove_from_bridges(bridges_path: &str, name: &str) -> bool {
+    config::remove_named_file(bridges_path, "bridge", name)
+}
+
+pub fn set_bridge_enabled(bridges_path: &str, name: &str, enabled: bool) -> bool {
+    let Some(mut bridge) = get_bridges(bridges_path)
+        .into_iter()
+        .find(|b| b.name == name)
+    else {
+        println!("Bridge {name} not found in {bridges_path}");
+        return false;
+    };
+    bridge.enabled = enabled;
+    write_bridge(bridges_path, &bridge)
+}
+
+/// Reload the bridges from disk, e.g. after one was saved, removed or toggled.
+pub fn reload_bridges(bridge_map: &BridgeMap, bridges_path: &str) {
+    let bridges = get_bridges(bridges_path);
+    bridge_map.lock().unwrap().set_bridges(bridges);
+}
+
+// ─── Entry point for broker loops ────────────────────────────────────
+
+/// Forward an incoming message over all bridges sourced at `broker`.
+pub fn forward_message(
+    services: &services::Services,
+    mqtt_map: &mqtt::BrokerMap,
+    broker: &str,
+    topic: &str,
+    payload: &bytes::Bytes,
+    retain: bool,
+) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let forwards = services
+        .bridges
+        .lock()
+        .unwrap()
+        .route(broker, topic, payload, retain, now_ms);
+    if forwards.is_empty() {
+        return;
+    }
+
+    let results: Vec<(Forward, Result<(), String>)> = forwards
+        .into_iter()
+        .map(|forward| {
+            let result = mqtt::publish_message(
+                &forward.destination,
+                &forward.topic,
+                payload,
+                forward.qos,
+                forward.retain,
+                None,
+                mqtt_map,
+            );
+            (forward, result)
+        })
+        .collect();
+
+    let mut manager = services.bridges.lock().unwrap();
+    for (forward, result) in results {
+        if result.is_err() {
+            manager.release(&forward, payload);
+        }
+        manager.record_result(&forward.bridge, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bridge(name: &str, source: &str, destination: &str) -> BridgeConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "source": source,
+            "destination": destination,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bridge_defaults() {
+        let config = bridge("b", "a:1883", "b:1883");
+        assert_eq!(config.filter, "#");
+        assert_eq!(config.qos, 1);
+        assert_eq!(config.retain, RetainHandling::Keep);
+        assert!(config.enabled);
+    }
+
+    #[test]
+    fn test_destination_topic_rewrite() {
+        let mut config = bridge("b", "a:1883", "b:1883");
+        assert_eq!(config.destination_topic("x/y"), "x/y");
+        config.destination_prefix = "mirror/".to_string();
+        assert_eq!(config.destination_topic("x/y"), "mirror/x/y");
+        config.source_prefix = "x/".to_string();
+        assert_eq!(config.destination_topic("x/y"), "mirror/y");
+        assert_eq!(config.destination_topic("z/y"), "mirror/z/y");
+    }
+
+    #[test]
+    fn test_retain_handling() {
+        let mut config = bridge("b", "a:1883", "b:1883");
+        assert!(config.destination_retain(true));
+        assert!(!config.destination_retain(false));
+        config.retain = RetainHandling::Never;
+        assert!(!config.destination_retain(true));
+        config.retain = RetainHandling::Always;
+        assert!(config.destination_retain(false));
+    }
+
+    #[test]
+    fn test_route_respects_source_filter_and_enabled() {
+        let mut filtered = bridge("filtered", "a:1883", "b:1883");
+        filtered.filter = "sensors/#".to_string();
+        let mut stopped = bridge("stopped", "a:1883", "c:1883");
+        stopped.enabled = false;
+        let mut manager = BridgeManager::default();
+        manager.set_bridges(vec![filtered, stopped]);
+
+        assert!(manager.route("a:1883", "other", b"x", false, 0).is_empty());
+        assert!(manager
+            .route("b:1883", "sensors/1", b"x", false, 0)
+            .is_empty());
+        let forwards = manager.route("a:1883", "sensors/1", b"x", true, 0);
+        assert_eq!(forwards.len(), 1);
+        assert_eq!(forwards[0].destination, "b:1883");
+        assert_eq!(forwards[0].qos, rumqttc::QoS::AtLeastOnce);
+        assert!(forwards[0].retain);
+    }
+
+    #[test]
+    fn test_route_prevents_loops_between_bidirectional_bridges() {
+        let mut manager = BridgeManager::default();
+        manager.set_bridges(vec![
+            bridge("a_to_b", "a:1883", "b:1883"),
+            bridge("b_to_a", "b:1883", "a:1883"),
+        ]);
+
+        let forwards = manager.route("a:1883", "t", b"x", false, 0);
+        assert_eq!(forwards.len(), 1);
+        // The forwarded copy arrives on b and must not travel back to a.
+        assert!(manager.route("b:1883", "t", b"x", false, 1).is_empty());
+        // A genuinely new message on b is still forwarded.
+        assert_eq!(manager.route("b:1883", "t", b"y", false, 2).len(), 1);
+        assert_eq!(manager.summaries()["suppressed"], 1);
+    }
+
+    #[test]
+    fn test_route_prevents_loops_on_same_broker() {
+        let mut config = bridge("self", "a:1883", "a:1883");
+        config.destination_prefix = "copy/".to_string();
+        let mut manager = BridgeManager::default();
+        manager.set_bridges(vec![config]);
+
+        let forwards = manager.route("a:1883", "t", b"x", false, 0);
+        assert_eq!(forwards[0].topic, "copy/t");
+        assert!(manager.route("a:1883", "copy/t", b"x", false, 1).is_empty());
+    }
+
+    #[test]
+    fn test_in_flight_entries_expire() {
+        let mut manager = BridgeManager::default();
+        manager.set_bridges(vec![
+            bridge("a_to_b", "a:1883", "b:1883"),
+            bridge("b_to_a", "b:1883", "a:1883"),
+        ]);
+        manager.route("a:1883", "t", b"x", false, 0);
+        let later = IN_FLIGHT_TTL_MS + 1;
+        assert_eq!(manager.route("b:1883", "t", b"x", false, later).len(), 1);
+    }
+
+    #[test]
+    fn test_counters_survive_reload() {
+        let mut manager = BridgeManager::default();
+        manager.set_bridges(vec![bridge("b", "a:1883", "b:1883")]);
+        manager.record_result("b", Ok(()));
+        manager.record_result("b", Err("down".to_string()));
+        manager.set_bridges(vec![bridge("b", "a:1883", "c:1883")]);
+
+        let summaries = manager.summaries();
+        let summary = &summaries["bridges"][0];
+        assert_eq!(summary["forwarded"], 1);
+        assert_eq!(summary["failed"], 1);
+        assert_eq!(summary["last_error"], "down");
+        assert_eq!(summary["config"]["destination"], "c:1883");
+    }
+
+    #[test]
+    fn test_forward_message_counts_failures_for_missing_destination() {
+        let services = services::Services::default();
+        let mqtt_map = mqtt::BrokerMap::new(std::sync::RwLock::new(HashMap::new()));
+        services.bridges.lock().unwrap().set_bridges(vec![
+            bridge("b", "a:1883", "missing:1883"),
+            bridge("back", "missing:1883", "a:1883"),
+        ]);
+        let payload = bytes::Bytes::from_static(b"x");
+
+        forward_message(&services, &mqtt_map, "a:1883", "t", &payload, false);
+
+        let summaries = services.bridges.lock().unwrap().summaries();
+        assert_eq!(summaries["bridges"][0]["failed"], 1);
+        assert_eq!(summaries["bridges"][0]["forwarded"], 0);
+
+        // Nothing was published, so the same message on the destination is
+        // a real one and goes over the reverse bridge
+        forward_message(&services, &mqtt_map, "missing:1883", "t", &payload, false);
+        let summaries = services.bridges.lock().unwrap().summaries();
+        assert_eq!(summaries["suppressed"], 0);
+        assert_eq!(summaries["bridges"][1]["failed"], 1);
+    }
+
+    #[test]
+    fn test_forward_message_queues_for_disconnected_destination() {
+        let services = services::Services::default();
+        let mqtt_map = mqtt::BrokerMap::new(std::sync::RwLock::new(HashMap::new()));
+        // Keep the event loop so the client's request queue stays open
+        let (client, _eventloop) =
+            mqtt::connect_to_mqtt_host(&config::BrokerConfig::from_host("b:1883")).unwrap();
+        let destination = mqtt::SharedBroker::new(mqtt::BrokerEntry::new(
+            client,
+            false,
+            mqtt::MqttBroker::new("b:1883"),
+        ));
+        mqtt_map
+            .write()
+            .unwrap()
+            .insert("b:1883".to_string(), destination.clone());
+        let mut config = bridge("b", "a:1883", "b:1883");
+        config.qos = 2;
+        services.bridges.lock().unwrap().set_bridges(vec![config]);
+
+        forward_message(
+            &services,
+            &mqtt_map,
+            "a:1883",
+            "t",
+            &bytes::Bytes::from_static(b"x"),
+            true,
+        );
+
+        // Queued like any other publish until the destination reconnects
+        assert!(!destination.is_connected());
+        let summaries = services.bridges.lock().unwrap().summaries();
+        assert_eq!(summaries["bridges"][0]["forwarded"], 1);
+    }
+
+    #[test]
+    fn test_bridge_persistence_and_toggle() {
+        let bridges_path = format!("/tmp/mqtt_test_{}/bridges", uuid::Uuid::new_v4());
+        let config = parse_bridge(serde_json::json!({
+            "name": "b",
+            "source": "a:1883",
+            "destination": "b:1883",
+        }))
+        .unwrap();
+        assert!(parse_bridge(serde_json::json!({
+            "name": "bad",
+            "source": "a:1883",
+            "destination": "b:1883",
+            "qos": 3,
+        }))
+        .is_none());
+        assert!(parse_bridge(serde_json::json!({
+            "name": "../alerts/x",
+            "source": "a:1883",
+            "destination": "b:1883",
+        }))
+        .is_none());
+        assert!(!remove_from_bridges(&bridges_path, "../bridges/b"));
+
+        assert!(add_to_bridges(&bridges_path, &config));
+        assert!(set_bridge_enabled(&bridges_path, "b", false));
+        assert!(!get_bridges(&bridges_path)[0].enabled);
+        assert!(!set_bridge_enabled(&bridges_path, "missing", true));
+        assert!(remove_from_bridges(&bridges_path, "b"));
+        assert!(get_bridges(&bridges_path).is_empty());
+
+        std::fs::remove_dir_all(std::path::Path::new(&bridges_path).parent().unwrap()).ok();
+    }
+}
@@ -20,12 +20,30 @@
  * THE SOFTWARE.
  */
 
+use super::alerts;
+use super::bridges;
+use super::bundle;
+use super::commands;
 use super::config;
+use super::delivery;
+use super::history;
 use super::jsonrpc;
+use super::loadgen;
 use super::mqtt;
+use super::requests;
+use super::retained;
+use super::retention;
+use super::scenarios;
+use super::scheduler;
+use super::schema;
+use super::services;
+use super::waiters;
+use super::webhooks;
 use super::websocket;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+use tokio_util::sync::CancellationToken;
 
 const RECONNECT_BACKOFF_MS: u64 = 1000;
 const DISCONNECT_NOTIFY_GRACE_MS: u64 = 1500;
@@ -45,7 +63,120 @@ fn constant_time_eq(a: &str, b: &str) -> bool {
 }
 
 fn broker_exists(mqtt_map: &mqtt::BrokerMap, hostname: &str) -> bool {
-    mqtt_map.lock().unwrap().contains_key(hostname)
+    mqtt_map.read().unwrap().contains_key(hostname)
+}
+
+fn peer_is_authenticated(
+    peer_map: &websocket::PeerMap,
+    addr: Option<std::net::SocketAddr>,
+    broker: &str,
+) -> bool {
+    let Some(addr) = addr else {
+        return false;
+    };
+    peer_map
+        .lock()
+        .unwrap()
+        .get(&addr)
+        .is_some_and(|peer| peer.authenticated_brokers.contains(broker))
+}
+
+/// Whether `addr` may manage an item scoped to `broker`. Items without a
+/// broker apply to all of them, so they need access to every protected one.
+fn peer_may_manage(
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    addr: Option<std::net::SocketAddr>,
+    broker: Option<&str>,
+) -> bool {
+    if let Some(broker) = broker {
+        return peer_is_authenticated(peer_map, addr, broker);
+    }
+    let protected: Vec<String> = mqtt_map
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, entry)| entry.requires_auth())
+        .map(|(key, _)| key.clone())
+        .collect();
+    protected
+        .iter()
+        .all(|broker| peer_is_authenticated(peer_map, addr, broker))
+}
+
+/// Whether `addr` may manage `scenario`. One whose brokers can't be told,
+/// e.g. because a command it publishes was removed, needs access to all.
+fn peer_may_manage_scenario(
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    addr: Option<std::net::SocketAddr>,
+    scenario: &scenarios::ScenarioConfig,
+    commands_path: &str,
+) -> bool {
+    match scenario.brokers(commands_path) {
+        Ok(brokers) => brokers
+            .iter()
+            .all(|broker| peer_is_authenticated(peer_map, addr, broker)),
+        Err(_) => peer_may_manage(peer_map, mqtt_map, addr, None),
+    }
+}
+
+/// The broker an RPC refers to. Older clients send the host under
+/// `legacy_name`, which is also the id of brokers configured without one.
+fn broker_id_param(params: &serde_json::Value, legacy_name: &str) -> Option<String> {
+    ["id", legacy_name]
+        .iter()
+        .find_map(|name| params.get(*name).and_then(|v| v.as_str()))
+        .map(|id| id.trim_matches('"').to_string())
+}
+
+/// `None` if `name` is absent, `Some(None)` if it is empty or not a string.
+fn string_param_update(params: &serde_json::Value, name: &str) -> Option<Option<String>> {
+    params
+        .get(name)
+        .map(|v| v.as_str().filter(|s| !s.is_empty()).map(|s| s.to_string()))
+}
+
+fn tags_param(params: &serde_json::Value) -> Option<Vec<String>> {
+    let tags = params.get("tags")?.as_array()?;
+    Some(
+        tags.iter()
+            .filter_map(|t| t.as_str())
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string())
+            .collect(),
+    )
+}
+
+/// Authenticate the peer that added or edited a password-protected broker,
+/// or all peers for a broker without password.
+fn grant_broker_access(
+    peer_map: &websocket::PeerMap,
+    addr: Option<std::net::SocketAddr>,
+    broker_config: &config::BrokerConfig,
+) {
+    let broker_key = broker_config.key().to_string();
+    if !broker_config.requires_auth() {
+        websocket::auto_authenticate_all_peers_for_broker(peer_map, &broker_key);
+        return;
+    }
+    let Some(peer_addr) = addr else {
+        return;
+    };
+    let mut peers = peer_map.lock().unwrap();
+    if let Some(peer) = peers.get_mut(&peer_addr) {
+        peer.authenticated_brokers.insert(broker_key.clone());
+    }
+    drop(peers);
+    // Notify the frontend so it marks the broker as authenticated
+    let result = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "broker_auth_result",
+        params: serde_json::json!({ "broker": broker_key, "success": true }),
+    };
+    if let Ok(serialized) = serde_json::to_string(&result) {
+        websocket::send_to_specific_peer(peer_map, peer_addr, &serialized);
+    }
 }
 
 fn truncate_payload(payload: bytes::Bytes, max_len: usize) -> (bytes::Bytes, usize) {
@@ -77,36 +208,28 @@ fn evict_while_preserving_topic_latest(
         let mut evicted_one = false;
 
         for _ in 0..queue_len {
-            let Some((topic_key, payload_len)) = broker.eviction_order.pop_front() else {
+            let Some((topic_id, seq)) = broker.eviction_order.pop_front() else {
                 break;
             };
 
-            let topic_len = broker
-                .topics
-                .get(&topic_key)
-                .map(std::collections::VecDeque::len);
-            match topic_len {
-                Some(len) if len > 1 => {
-                    if let Some(topic_vec) = broker.topics.get_mut(&topic_key) {
-                        topic_vec.pop_front();
-                        broker.total_bytes = broker.total_bytes.saturating_sub(payload_len);
-                        broker.total_messages = broker.total_messages.saturating_sub(1);
-                        *eviction_counts.entry(topic_key.clone()).or_insert(0) += 1;
-                        if topic_vec.is_empty() {
-                            broker.topics.remove(&topic_key);
-                        }
-                        evicted_one = true;
-                        break;
-                    }
-                }
-                Some(_) => {
-                    // Keep newest/only message for this topic.
-                    broker.eviction_order.push_back((topic_key, payload_len));
-                }
-                None => {
-                    // Stale eviction entry: topic was already removed.
-                }
+            let Some(history) = broker.topics.history_by_id(topic_id) else {
+                // Stale eviction entry: topic was already removed.
+                continue;
+            };
+            let front_seq = history.messages.front().map_or(u64::MAX, |m| m.seq);
+            if front_seq > seq {
+                // Stale eviction entry: message was already removed.
+                continue;
+            }
+            if history.messages.len() > 1 {
+                let topic_key = history.name.to_string();
+                broker.remove_oldest_by_id(topic_id);
+                *eviction_counts.entry(topic_key).or_insert(0) += 1;
+                evicted_one = true;
+                break;
             }
+            // Keep newest/only message for this topic.
+            broker.eviction_order.push_back((topic_id, seq));
         }
 
         if !evicted_one {
@@ -123,60 +246,92 @@ fn evict_while_preserving_topic_latest(
         .collect()
 }
 
-fn loop_forever(
-    mut connection: rumqttc::Connection,
+/// Wait before the next reconnect attempt. Returns `false` if the broker was
+/// removed in the meantime.
+async fn reconnect_backoff(cancel: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = cancel.cancelled() => false,
+        _ = tokio::time::sleep(std::time::Duration::from_millis(RECONNECT_BACKOFF_MS)) => true,
+    }
+}
+
+fn send_publish_results(peer_map: &websocket::PeerMap, reports: Vec<delivery::Report>) {
+    for (addr, result) in reports {
+        websocket::send_publish_result(peer_map, addr, &result);
+    }
+}
+
+async fn loop_forever(
+    broker_id: &str,
+    mut eventloop: rumqttc::EventLoop,
+    cancel: &CancellationToken,
     peer_map: &websocket::PeerMap,
     mqtt_map: &mqtt::BrokerMap,
     notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
 ) {
-    let (ip, port) = connection.eventloop.mqtt_options.broker_address();
-    let hostname = format!("{ip}:{port}");
+    let hostname = broker_id.to_string();
     let mut disconnect_candidate_since: Option<std::time::Instant> = None;
     let mut disconnect_notified = false;
 
-    for notification in connection.iter() {
-        if !broker_exists(mqtt_map, &hostname) {
-            println!("Broker {hostname} no longer exists in map. Stopping connection loop.");
-            break;
-        }
+    loop {
+        let notification = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => {
+                println!("Broker {hostname} was removed or reconfigured. Stopping connection loop.");
+                break;
+            }
+            notification = eventloop.poll() => notification,
+        };
 
         match notification {
             Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(p))) => {
                 disconnect_candidate_since = None;
                 let retain = p.retain;
+                bridges::forward_message(
+                    services, mqtt_map, &hostname, &p.topic, &p.payload, retain,
+                );
+                waiters::dispatch(&services.waiters, &hostname, &p.topic, &p.payload);
                 let (payload, original_payload_len) =
                     truncate_payload(p.payload, mqtt::max_message_size());
-                let timestamp = chrono::Utc::now().to_rfc3339();
-                let (total_bytes, new_sample, topic_message_count, evictions, _rate_history_len) = {
-                    let mut mqtt_lock = mqtt_map.lock().unwrap();
-                    let broker = match mqtt_lock.get_mut(&hostname) {
-                        Some(b) => b,
-                        None => {
-                            println!("Broker {hostname} not found in map. Exiting loop.");
-                            break;
-                        }
+                let timestamp_ns = mqtt::now_ns();
+                let timestamp = mqtt::format_timestamp_ns(timestamp_ns);
+                let limits = services
+                    .retention
+                    .lock()
+                    .unwrap()
+                    .topic_limits(&hostname, &p.topic);
+                let stored = !limits.never_store;
+                let (total_bytes, new_sample, topic_message_count, evictions, paused) = {
+                    let Some(entry) = mqtt::get_broker(mqtt_map, &hostname) else {
+                        println!("Broker {hostname} not found in map. Exiting loop.");
+                        break;
                     };
-                    broker.connected = true;
+                    entry.set_connected(true);
+                    let mut broker_lock = entry.state();
+                    let broker = &mut *broker_lock;
                     let msg_bytes = payload.len();
-                    let new_msg = mqtt::MqttMessage {
-                        timestamp: timestamp.clone(),
-                        payload: payload.clone(),
-                        original_payload_size: original_payload_len,
-                        retain,
-                    };
-                    let topic_name = p.topic.clone();
-                    if let Some(topic_vec) = broker.topics.get_mut(&topic_name) {
-                        topic_vec.push_back(new_msg);
-                    } else {
-                        let mut vd = std::collections::VecDeque::new();
-                        vd.push_back(new_msg);
-                        broker.topics.insert(topic_name.clone(), vd);
-                    }
-                    broker.total_bytes += msg_bytes;
-                    broker.total_messages += 1;
-                    broker.eviction_order.push_back((topic_name, msg_bytes));
+                    let paused = broker.paused;
+                    let mut evictions = Vec::new();
+                    if paused {
+                        broker.skipped_messages += 1;
+                        broker.skipped_bytes += original_payload_len;
+                    } else if stored {
+                        broker.store_message(
+                            &p.topic,
+                            timestamp_ns,
+                            payload.clone(),
+                            original_payload_len,
+                            retain,
+                        );
 
-                    let evictions = evict_while_preserving_topic_latest(broker);
+                        let trimmed = retention::apply_topic_limits(broker, &p.topic, &limits);
+                        evictions = evict_while_preserving_topic_latest(broker);
+                        if trimmed > 0 {
+                            let remaining = broker.topics.get(&p.topic).map_or(0, |v| v.len());
+                            evictions.push((p.topic.clone(), trimmed, remaining));
+                        }
+                    }
 
                     let topic_message_count =
                         broker.topics.get(&p.topic).map(|v| v.len()).unwrap_or(0);
@@ -204,23 +359,33 @@ fn loop_forever(
                         None
                     };
 
-                    let rate_history_len = broker.rate_history.len();
-
                     (
                         broker.total_bytes,
                         new_sample,
                         topic_message_count,
                         evictions,
-                        rate_history_len,
+                        paused,
                     )
-                }; // mqtt_lock dropped here
+                }; // broker lock dropped here
                 if let Some(ref sample) = new_sample {
                     websocket::send_rate_sample_to_peers(peer_map, &hostname, sample);
                 }
+                alerts::process_message(services, peer_map, &hostname, &p.topic, &payload);
+                webhooks::dispatch_message(
+                    &services.webhooks,
+                    &hostname,
+                    &p.topic,
+                    &timestamp,
+                    &payload,
+                    retain,
+                );
                 // Buffer eviction notifications (will be flushed in batch)
                 if !evictions.is_empty() {
                     websocket::buffer_evictions(notification_buf, &hostname, &evictions);
                 }
+                if !stored || paused {
+                    continue;
+                }
                 // Buffer lightweight meta (will be flushed in batch)
                 websocket::buffer_message_meta(
                     notification_buf,
@@ -249,23 +414,38 @@ fn loop_forever(
             Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(a))) => {
                 disconnect_candidate_since = None;
                 disconnect_notified = false;
-                let client = {
-                    let mut mqtt_lock = mqtt_map.lock().unwrap();
-                    if let Some(broker) = mqtt_lock.get_mut(&hostname) {
-                        broker.connected = true;
-                        Some(broker.client.clone())
-                    } else {
-                        None
-                    }
-                };
-                if let Some(mut client) = client {
-                    if let Err(err) = client.subscribe("#", rumqttc::QoS::AtMostOnce) {
+                let client = mqtt::get_broker(mqtt_map, &hostname).map(|broker| {
+                    broker.set_connected(true);
+                    broker.client()
+                });
+                if let Some(client) = client {
+                    if let Err(err) = client.try_subscribe("#", rumqttc::QoS::AtMostOnce) {
                         println!("Failed to re-subscribe after ConnAck for {hostname}: {err}");
                     }
                 }
                 websocket::send_broker_status_to_peers(peer_map, &hostname, true);
                 println!("Connection event: {:?} for {:?}", a.code, hostname);
             }
+            Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Publish(pkid))) => {
+                let reports = mqtt::get_broker(mqtt_map, &hostname)
+                    .map(|broker| broker.deliveries().on_sent(pkid))
+                    .unwrap_or_default();
+                send_publish_results(peer_map, reports);
+            }
+            Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::AwaitAck(pkid))) => {
+                if let Some(broker) = mqtt::get_broker(mqtt_map, &hostname) {
+                    broker.deliveries().on_collision(pkid);
+                }
+            }
+            Ok(rumqttc::Event::Incoming(
+                rumqttc::Packet::PubAck(rumqttc::PubAck { pkid })
+                | rumqttc::Packet::PubComp(rumqttc::PubComp { pkid }),
+            )) => {
+                let reports = mqtt::get_broker(mqtt_map, &hostname)
+                    .map(|broker| broker.deliveries().on_acked(pkid))
+                    .unwrap_or_default();
+                send_publish_results(peer_map, reports);
+            }
             Ok(rumqttc::Event::Incoming(rumqttc::Packet::Disconnect)) => {
                 println!("Disconnect event for {hostname:?}");
                 break;
@@ -277,11 +457,8 @@ fn loop_forever(
                 rumqttc::mqttbytes::Error::PayloadSizeLimitExceeded(_),
             ))) => {}
             Err(rumqttc::ConnectionError::MqttState(err)) => {
-                {
-                    let mut mqtt_lock = mqtt_map.lock().unwrap();
-                    if let Some(broker) = mqtt_lock.get_mut(&hostname) {
-                        broker.connected = false;
-                    }
+                if let Some(broker) = mqtt::get_broker(mqtt_map, &hostname) {
+                    broker.set_connected(false);
                 }
                 println!("MqttState error for {hostname:?}: {err}. Will retry.");
                 let now = std::time::Instant::now();
@@ -293,14 +470,13 @@ fn loop_forever(
                     websocket::send_broker_status_to_peers(peer_map, &hostname, false);
                     disconnect_notified = true;
                 }
-                std::thread::sleep(std::time::Duration::from_millis(RECONNECT_BACKOFF_MS));
+                if !reconnect_backoff(cancel).await {
+                    break;
+                }
             }
             Err(err) => {
-                {
-                    let mut mqtt_lock = mqtt_map.lock().unwrap();
-                    if let Some(broker) = mqtt_lock.get_mut(&hostname) {
-                        broker.connected = false;
-                    }
+                if let Some(broker) = mqtt::get_broker(mqtt_map, &hostname) {
+                    broker.set_connected(false);
                 }
                 println!("Connection error for {hostname:?}: {err}. Will retry.");
                 let now = std::time::Instant::now();
@@ -312,17 +488,20 @@ fn loop_forever(
                     websocket::send_broker_status_to_peers(peer_map, &hostname, false);
                     disconnect_notified = true;
                 }
-                std::thread::sleep(std::time::Duration::from_millis(RECONNECT_BACKOFF_MS));
+                if !reconnect_backoff(cancel).await {
+                    break;
+                }
             }
         }
     }
-    // Connection iterator ended — broker disconnected or was removed
+    if cancel.is_cancelled() {
+        // Removed, or restarted with new options; the new task reports its state.
+        return;
+    }
+    // Event loop ended — broker disconnected
     println!("Connection loop for {hostname:?} ended. Marking broker as disconnected.");
-    {
-        let mut mqtt_lock = mqtt_map.lock().unwrap();
-        if let Some(broker) = mqtt_lock.get_mut(&hostname) {
-            broker.connected = false;
-        }
+    if let Some(broker) = mqtt::get_broker(mqtt_map, &hostname) {
+        broker.set_connected(false);
     }
     if !disconnect_notified {
         websocket::send_broker_status_to_peers(peer_map, &hostname, false);
@@ -334,14 +513,123 @@ pub fn connect_to_known_brokers(
     peer_map: &websocket::PeerMap,
     mqtt_map: &mqtt::BrokerMap,
     notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
 ) {
     config::get_known_brokers(broker_path)
         .iter()
         .for_each(|broker_config| {
-            connect_to_broker(broker_config, peer_map, mqtt_map, notification_buf);
+            connect_to_broker(
+                broker_config,
+                peer_map,
+                mqtt_map,
+                notification_buf,
+                services,
+            );
         });
 }
 
+/// Connect, reconnect or remove brokers so the running ones match `configs`,
+/// e.g. after brokers.json was edited on disk. Brokers whose connection
+/// options are unchanged keep running. Returns whether anything changed.
+pub fn sync_brokers(
+    configs: &[config::BrokerConfig],
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) -> bool {
+    let mut changed = false;
+    for broker_config in configs {
+        let Some(broker) = mqtt::get_broker(mqtt_map, broker_config.key()) else {
+            changed |= connect_to_broker(
+                broker_config,
+                peer_map,
+                mqtt_map,
+                notification_buf,
+                services,
+            );
+            continue;
+        };
+        let running = broker.config();
+        if running == *broker_config {
+            continue;
+        }
+        changed = true;
+        if running.same_connection(broker_config) {
+            broker.set_config(broker_config);
+            continue;
+        }
+        reconnect_broker(
+            broker_config,
+            peer_map,
+            mqtt_map,
+            notification_buf,
+            services,
+        );
+        if running.password != broker_config.password {
+            websocket::revoke_broker_access(peer_map, broker_config.key(), None);
+        }
+    }
+
+    let removed: Vec<String> = mqtt_map
+        .read()
+        .unwrap()
+        .keys()
+        .filter(|id| !configs.iter().any(|c| c.key() == id.as_str()))
+        .cloned()
+        .collect();
+    for id in &removed {
+        remove_broker(id, peer_map, mqtt_map);
+    }
+    changed |= !removed.is_empty();
+
+    if changed {
+        websocket::broadcast_brokers(peer_map, mqtt_map);
+    }
+    changed
+}
+
+/// Bring brokers, commands and pipelines of connected peers up to date after
+/// files in the config directory were replaced, e.g. by an import.
+pub fn apply_config_dir(
+    config_path: &str,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) {
+    if let Some(configs) = config::read_broker_configs(&format!("{config_path}/brokers.json")) {
+        sync_brokers(&configs, peer_map, mqtt_map, notification_buf, services);
+    }
+    websocket::broadcast_commands(peer_map, config_path);
+    websocket::broadcast_pipelines(peer_map, config_path);
+}
+
+fn item_name_param(params: &serde_json::Value) -> String {
+    params
+        .get("name")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Log a failed save or removal and tell the requesting peer how it went.
+fn report_save(
+    peer_map: &websocket::PeerMap,
+    addr: Option<std::net::SocketAddr>,
+    method: &str,
+    kind: &str,
+    name: &str,
+    result: &Result<u64, config::SaveError>,
+) {
+    if let Err(err) = result {
+        println!("{method} {name} failed: {err}");
+    }
+    if let Some(addr) = addr {
+        websocket::send_save_result(peer_map, addr, kind, name, result);
+    }
+}
+
 pub fn deserialize_json_rpc_and_process(
     json_rpc: &str,
     peer_map: &websocket::PeerMap,
@@ -349,6 +637,7 @@ pub fn deserialize_json_rpc_and_process(
     config_path: &str,
     addr: Option<std::net::SocketAddr>,
     notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
 ) {
     let message = match jsonrpc::deserialize_json_rpc(json_rpc) {
         Ok(msg) => msg,
@@ -370,6 +659,11 @@ pub fn deserialize_json_rpc_and_process(
                     return;
                 }
             };
+            if let Err(err) = schema::check_broker_host(&hostname) {
+                let result = Err(config::SaveError::Failed(err));
+                report_save(peer_map, addr, message.method, "broker", &hostname, &result);
+                return;
+            }
             let use_tls = message
                 .params
                 .get("use_tls")
@@ -387,79 +681,160 @@ pub fn deserialize_json_rpc_and_process(
                 .and_then(|v| v.as_str())
                 .filter(|s| !s.is_empty())
                 .map(|s| s.to_string());
+            // Without an id, connecting to a known host refers to that broker
+            let id = message
+                .params
+                .get("id")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .unwrap_or(&hostname)
+                .to_string();
             let broker_config = config::BrokerConfig {
+                id,
                 host: hostname,
                 use_tls,
                 username,
                 password,
+                name: string_param_update(&message.params, "name").flatten(),
+                description: string_param_update(&message.params, "description").flatten(),
+                tags: tags_param(&message.params).unwrap_or_default(),
+                revision: 0,
             };
-            let has_password = broker_config
-                .password
-                .as_ref()
-                .is_some_and(|p| !p.is_empty());
-            connect_to_broker(&broker_config, peer_map, mqtt_map, notification_buf);
-            let broker_path = std::format!("{}/brokers.json", &config_path);
-            config::add_to_brokers(&broker_path, &broker_config);
-            websocket::broadcast_brokers(peer_map, mqtt_map);
-            // Auto-authenticate the peer that added a password-protected broker,
-            // and auto-authenticate ALL peers for non-password brokers.
-            let broker_key = broker_config.key().to_string();
-            if has_password {
-                if let Some(peer_addr) = addr {
-                    let mut peers = peer_map.lock().unwrap();
-                    if let Some(peer) = peers.get_mut(&peer_addr) {
-                        peer.authenticated_brokers.insert(broker_key.clone());
-                    }
-                    drop(peers);
-                    // Notify the frontend so it marks the broker as authenticated
-                    let result = jsonrpc::JsonRpcNotification {
-                        jsonrpc: "2.0",
-                        method: "broker_auth_result",
-                        params: serde_json::json!({ "broker": broker_key, "success": true }),
-                    };
-                    if let Ok(serialized) = serde_json::to_string(&result) {
-                        websocket::send_to_specific_peer(peer_map, peer_addr, &serialized);
+            let created = connect_to_broker(
+                &broker_config,
+                peer_map,
+                mqtt_map,
+                notification_buf,
+                services,
+            );
+            // An existing broker keeps its stored options; use update_broker to change them
+            if created {
+                let broker_path = std::format!("{}/brokers.json", &config_path);
+                match config::add_to_brokers(&broker_path, &broker_config) {
+                    Ok(revision) => {
+                        if let Some(broker) = mqtt::get_broker(mqtt_map, broker_config.key()) {
+                            broker.set_config(&config::BrokerConfig {
+                                revision,
+                                ..broker_config.clone()
+                            });
+                        }
                     }
+                    Err(err) => println!("Can't store broker {}: {err}", broker_config.key()),
                 }
-            } else {
-                websocket::auto_authenticate_all_peers_for_broker(peer_map, &broker_key);
+                websocket::broadcast_brokers(peer_map, mqtt_map);
+                grant_broker_access(peer_map, addr, &broker_config);
+            }
+        }
+        "update_broker" => {
+            let Some(id) = broker_id_param(&message.params, "hostname") else {
+                println!("Missing or invalid 'id' param for update_broker");
+                return;
+            };
+            if !peer_is_authenticated(peer_map, addr, &id) {
+                println!("Peer not authenticated for broker {id}, update_broker denied");
+                return;
+            }
+            let broker_path = std::format!("{config_path}/brokers.json");
+            let params = &message.params;
+            let host = params
+                .get("host")
+                .and_then(|v| v.as_str())
+                .filter(|host| !host.is_empty());
+            if let Some(Err(err)) = host.map(schema::check_broker_host) {
+                let result = Err(config::SaveError::Failed(err));
+                report_save(peer_map, addr, message.method, "broker", &id, &result);
+                return;
+            }
+            let result = config::update_in_brokers(
+                &broker_path,
+                &id,
+                config::revision_param(params),
+                |broker_config| {
+                    // Pin the id so it survives a host change
+                    broker_config.id = id.clone();
+                    // Omitted fields keep their value; empty strings clear optional ones
+                    if let Some(host) = host {
+                        broker_config.host = host.to_string();
+                    }
+                    if let Some(use_tls) = params.get("use_tls").and_then(|v| v.as_bool()) {
+                        broker_config.use_tls = use_tls;
+                    }
+                    if let Some(username) = string_param_update(params, "username") {
+                        broker_config.username = username;
+                    }
+                    if let Some(password) = string_param_update(params, "password") {
+                        broker_config.password = password;
+                    }
+                    if let Some(name) = string_param_update(params, "name") {
+                        broker_config.name = name;
+                    }
+                    if let Some(description) = string_param_update(params, "description") {
+                        broker_config.description = description;
+                    }
+                    if let Some(tags) = tags_param(params) {
+                        broker_config.tags = tags;
+                    }
+                },
+            );
+            let revision = result
+                .as_ref()
+                .map(|(_, updated)| updated.revision)
+                .map_err(Clone::clone);
+            report_save(peer_map, addr, message.method, "broker", &id, &revision);
+            let Ok((previous, broker_config)) = result else {
+                return;
+            };
+            if !broker_config.same_connection(&previous) {
+                reconnect_broker(
+                    &broker_config,
+                    peer_map,
+                    mqtt_map,
+                    notification_buf,
+                    services,
+                );
+            } else if let Some(broker) = mqtt::get_broker(mqtt_map, &id) {
+                broker.set_config(&broker_config);
             }
+            if broker_config.password != previous.password {
+                websocket::revoke_broker_access(peer_map, &id, addr);
+            }
+            websocket::broadcast_brokers(peer_map, mqtt_map);
+            grant_broker_access(peer_map, addr, &broker_config);
         }
         "remove" => {
-            let hostname = match message.params.get("hostname").and_then(|v| v.as_str()) {
-                Some(h) => h.trim_matches('"').to_string(),
-                None => {
-                    println!("Missing or invalid 'hostname' param for remove");
-                    return;
-                }
+            let Some(id) = broker_id_param(&message.params, "hostname") else {
+                println!("Missing or invalid 'id' param for remove");
+                return;
             };
-            remove_broker(&hostname, peer_map, mqtt_map);
             let broker_path = std::format!("{}/brokers.json", &config_path);
-            config::remove_from_brokers(&broker_path, &hostname);
+            let result = config::remove_from_brokers(
+                &broker_path,
+                &id,
+                config::revision_param(&message.params),
+            );
+            report_save(
+                peer_map,
+                addr,
+                message.method,
+                "broker",
+                &id,
+                &result.clone().map(|()| 0),
+            );
+            // Brokers connected without being stored are removed all the same
+            if let Err(config::SaveError::Conflict { .. }) = result {
+                return;
+            }
+            remove_broker(&id, peer_map, mqtt_map);
             websocket::broadcast_brokers(peer_map, mqtt_map)
         }
         "publish" => {
-            let host = match message.params.get("host").and_then(|v| v.as_str()) {
-                Some(h) => h.trim_matches('"').to_string(),
-                None => {
-                    println!("Missing or invalid 'host' param for publish");
-                    return;
-                }
+            let Some(host) = broker_id_param(&message.params, "host") else {
+                println!("Missing or invalid 'id' param for publish");
+                return;
             };
             // Check authentication before allowing publish
-            if let Some(peer_addr) = addr {
-                let peers = peer_map.lock().unwrap();
-                if let Some(peer) = peers.get(&peer_addr) {
-                    if !peer.authenticated_brokers.contains(&host) {
-                        println!(
-                            "Peer {peer_addr} not authenticated for broker {host}, publish denied"
-                        );
-                        return;
-                    }
-                } else {
-                    return;
-                }
-            } else {
+            if !peer_is_authenticated(peer_map, addr, &host) {
+                println!("Peer {addr:?} not authenticated for broker {host}, publish denied");
                 return;
             }
             let topic = match message.params.get("topic").and_then(|v| v.as_str()) {
@@ -481,313 +856,2243 @@ pub fn deserialize_json_rpc_and_process(
                 .get("retain")
                 .and_then(|v| v.as_bool())
                 .unwrap_or(false);
-            mqtt::publish_message(&host, topic, payload, retain, mqtt_map);
+            let qos = message
+                .params
+                .get("qos")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(1);
+            let Some(qos) = u8::try_from(qos).ok().and_then(mqtt::qos_from_u8) else {
+                println!("Invalid 'qos' param {qos} for publish");
+                return;
+            };
+            let encoding = match message.params.get("encoding").cloned() {
+                Some(v) => match serde_json::from_value::<mqtt::PayloadEncoding>(v) {
+                    Ok(encoding) => encoding,
+                    Err(_) => {
+                        println!("Invalid 'encoding' param for publish");
+                        return;
+                    }
+                },
+                None => mqtt::PayloadEncoding::Utf8,
+            };
+            let payload = match encoding.decode(payload) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    println!("Can't publish to {host} topic {topic}: {err}");
+                    return;
+                }
+            };
+            // The requesting peer learns whether the broker acknowledged the publish
+            let requester = addr.map(|addr| delivery::Requester {
+                addr,
+                id: message
+                    .params
+                    .get("request_id")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string),
+            });
+            let tracked = requester
+                .clone()
+                .map(|requester| delivery::Tracked::new(requester, &host, topic, qos));
+            let published =
+                mqtt::publish_message(&host, topic, &payload, qos, retain, requester, mqtt_map);
+            if let Some(tracked) = tracked {
+                let (peer_addr, result) = match published {
+                    Ok(()) => tracked.report(delivery::DeliveryState::Queued, None),
+                    Err(err) => tracked.report(delivery::DeliveryState::Failed, Some(err)),
+                };
+                websocket::send_publish_result(peer_map, peer_addr, &result);
+            }
+        }
+        "request" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let params = match serde_json::from_value::<requests::RequestParams>(message.params) {
+                Ok(params) => params,
+                Err(err) => {
+                    println!("Invalid params for request: {err}");
+                    return;
+                }
+            };
+            if !peer_is_authenticated(peer_map, addr, &params.broker) {
+                println!(
+                    "Peer {addr:?} not authenticated for broker {}, request denied",
+                    params.broker
+                );
+                return;
+            }
+            if tokio::runtime::Handle::try_current().is_err() {
+                eprintln!("No async runtime available. Can't send request.");
+                return;
+            }
+            let peer_map = peer_map.clone();
+            let mqtt_map = mqtt_map.clone();
+            let waiters = services.waiters.clone();
+            tokio::spawn(async move {
+                let result = requests::send_request(&params, &waiters, &mqtt_map).await;
+                websocket::send_request_result(&peer_map, peer_addr, &result);
+            });
+        }
+        "start_load" => {
+            let config = match serde_json::from_value::<loadgen::LoadConfig>(message.params) {
+                Ok(config) => config,
+                Err(err) => {
+                    println!("Invalid params for start_load: {err}");
+                    return;
+                }
+            };
+            if let Err(err) = config.validate() {
+                println!("Load test on {} is invalid: {err}", config.broker);
+                return;
+            }
+            if !broker_exists(mqtt_map, &config.broker) {
+                println!("Load test refers to unknown broker {}", config.broker);
+                return;
+            }
+            if !peer_is_authenticated(peer_map, addr, &config.broker) {
+                println!(
+                    "Peer {addr:?} not authenticated for broker {}, start_load denied",
+                    config.broker
+                );
+                return;
+            }
+            if tokio::runtime::Handle::try_current().is_err() {
+                eprintln!("No async runtime available. Can't start load test.");
+                return;
+            }
+            loadgen::start(config, &services.load_tests, peer_map, mqtt_map);
+        }
+        "stop_load" => {
+            let Some(run_id) = message.params.get("run_id").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'run_id' param for stop_load");
+                return;
+            };
+            let Some(broker) = loadgen::broker_of(&services.load_tests, run_id) else {
+                println!("Load test {run_id} is not running");
+                return;
+            };
+            if !peer_is_authenticated(peer_map, addr, &broker) {
+                println!("Peer {addr:?} not authenticated for broker {broker}, stop_load denied");
+                return;
+            }
+            loadgen::stop(&services.load_tests, run_id);
         }
         "save_command" => {
             let command_path: String = std::format!("{config_path}/commands");
-            config::add_to_commands(&command_path, message.params);
+            let name = item_name_param(&message.params);
+            let result = config::add_to_commands(&command_path, message.params);
+            report_save(peer_map, addr, message.method, "command", &name, &result);
             websocket::broadcast_commands(peer_map, config_path);
         }
         "remove_command" => {
             let command_path: String = std::format!("{config_path}/commands");
-            config::remove_from_commands(&command_path, message.params);
+            let name = item_name_param(&message.params);
+            let result = config::remove_from_commands(&command_path, message.params).map(|()| 0);
+            report_save(peer_map, addr, message.method, "command", &name, &result);
             websocket::broadcast_commands(peer_map, config_path);
         }
+        "execute_command" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for execute_command");
+                return;
+            };
+            let commands_path = format!("{config_path}/commands");
+            let Some(command) = config::get_command(&commands_path, name) else {
+                let error = format!("Command {name} not found");
+                let result = commands::CommandResult::failed(name, "", error);
+                websocket::send_command_result(peer_map, peer_addr, &result);
+                return;
+            };
+            // The caller's choice wins over the broker bound to the command
+            let Some(broker) = message
+                .params
+                .get("broker")
+                .and_then(|v| v.as_str())
+                .or(command.broker.as_deref())
+            else {
+                let error = "No broker given and none bound to the command".to_string();
+                let result = commands::CommandResult::failed(name, "", error);
+                websocket::send_command_result(peer_map, peer_addr, &result);
+                return;
+            };
+            if !peer_is_authenticated(peer_map, addr, broker) {
+                println!(
+                    "Peer {addr:?} not authenticated for broker {broker}, execute_command denied"
+                );
+                return;
+            }
+            let overrides: BTreeMap<String, String> = message
+                .params
+                .get("variables")
+                .and_then(|v| v.as_object())
+                .map(|vars| {
+                    vars.iter()
+                        .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let result = commands::execute_command(
+                &command,
+                broker,
+                &overrides,
+                &services.command_counters,
+                mqtt_map,
+            );
+            websocket::send_command_result(peer_map, peer_addr, &result);
+        }
         "save_pipeline" => {
             let pipelines_path = std::format!("{config_path}/pipelines");
-            config::add_to_pipelines(&pipelines_path, message.params);
+            let name = item_name_param(&message.params);
+            let result = config::add_to_pipelines(&pipelines_path, message.params);
+            report_save(peer_map, addr, message.method, "pipeline", &name, &result);
             websocket::broadcast_pipelines(peer_map, config_path);
         }
         "remove_pipeline" => {
             let pipelines_path = std::format!("{config_path}/pipelines");
-            config::remove_from_pipelines(&pipelines_path, message.params);
+            let name = item_name_param(&message.params);
+            let result = config::remove_from_pipelines(&pipelines_path, message.params).map(|()| 0);
+            report_save(peer_map, addr, message.method, "pipeline", &name, &result);
             websocket::broadcast_pipelines(peer_map, config_path);
         }
-        "subscribe_topic" => {
-            if let Some(peer_addr) = addr {
-                if let (Some(broker), Some(topic)) = (
-                    message.params["broker"].as_str(),
-                    message.params["topic"].as_str(),
-                ) {
-                    let since_timestamp = message.params["since_timestamp"].as_str();
-                    websocket::handle_subscribe_topic(
-                        peer_map,
-                        mqtt_map,
-                        peer_addr,
-                        broker,
-                        topic,
-                        since_timestamp,
+        "move_command" | "move_pipeline" => {
+            let (Some(from), Some(to)) = (
+                message.params.get("name").and_then(|v| v.as_str()),
+                message.params.get("to").and_then(|v| v.as_str()),
+            ) else {
+                println!(
+                    "Missing or invalid 'name' or 'to' param for {}",
+                    message.method
+                );
+                return;
+            };
+            let commands = message.method == "move_command";
+            let dir = if commands { "commands" } else { "pipelines" };
+            if let Err(err) = config::move_item(&format!("{config_path}/{dir}"), from, to) {
+                println!("Can't move {from} to {to}: {err}");
+                return;
+            }
+            if commands {
+                websocket::broadcast_commands(peer_map, config_path);
+            } else {
+                websocket::broadcast_pipelines(peer_map, config_path);
+            }
+        }
+        "move_folder" | "remove_folder" => {
+            let Some(path) = message.params.get("path").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'path' param for {}", message.method);
+                return;
+            };
+            let commands = match message.params.get("kind").and_then(|v| v.as_str()) {
+                Some("commands") => true,
+                Some("pipelines") => false,
+                _ => {
+                    println!(
+                        "'kind' param for {} must be commands or pipelines",
+                        message.method
                     );
+                    return;
                 }
+            };
+            let dir = if commands { "commands" } else { "pipelines" };
+            let dir = format!("{config_path}/{dir}");
+            let result = match message.method {
+                "move_folder" => match message.params.get("to").and_then(|v| v.as_str()) {
+                    Some(to) => config::move_folder(&dir, path, to),
+                    None => Err("Missing or invalid 'to' param".to_string()),
+                },
+                _ => config::remove_folder(&dir, path),
+            };
+            if let Err(err) = result {
+                println!("{} {path} failed: {err}", message.method);
+                return;
+            }
+            if commands {
+                websocket::broadcast_commands(peer_map, config_path);
+            } else {
+                websocket::broadcast_pipelines(peer_map, config_path);
             }
         }
-        "unsubscribe_topic" => {
+        "list_revisions" | "list_removed" | "diff_revisions" | "restore_revision" => {
+            let commands = match message.params.get("kind").and_then(|v| v.as_str()) {
+                Some("commands") => true,
+                Some("pipelines") => false,
+                _ => {
+                    println!(
+                        "'kind' param for {} must be commands or pipelines",
+                        message.method
+                    );
+                    return;
+                }
+            };
+            let kind = if commands { "commands" } else { "pipelines" };
+            let dir = format!("{config_path}/{kind}");
+            if message.method == "list_removed" {
+                if let Some(peer_addr) = addr {
+                    let removed = history::removed_items(&dir);
+                    websocket::send_removed_items(peer_map, peer_addr, kind, &removed);
+                }
+                return;
+            }
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for {}", message.method);
+                return;
+            };
+            if let Err(err) = config::validate_item_name(name) {
+                println!("Invalid 'name' param for {}: {err}", message.method);
+                match (message.method, addr) {
+                    ("diff_revisions", Some(peer_addr)) => {
+                        websocket::send_revision_diff(peer_map, peer_addr, kind, name, &Err(err));
+                    }
+                    ("restore_revision", _) => {
+                        let item = if commands { "command" } else { "pipeline" };
+                        let result = Err(config::SaveError::Failed(err));
+                        report_save(peer_map, addr, message.method, item, name, &result);
+                    }
+                    _ => {}
+                }
+                return;
+            }
+            let revision_param = |key: &str| message.params.get(key).and_then(|v| v.as_u64());
+            match message.method {
+                "list_revisions" => {
+                    if let Some(peer_addr) = addr {
+                        let revisions = history::list(&dir, name);
+                        websocket::send_revisions(peer_map, peer_addr, kind, name, &revisions);
+                    }
+                }
+                "diff_revisions" => {
+                    let Some(peer_addr) = addr else {
+                        return;
+                    };
+                    let result = match (revision_param("from"), revision_param("to")) {
+                        (Some(from), Some(to)) => history::diff_revisions(&dir, name, from, to),
+                        _ => Err("Missing or invalid 'from' or 'to' param".to_string()),
+                    };
+                    websocket::send_revision_diff(peer_map, peer_addr, kind, name, &result);
+                }
+                _ => {
+                    let Some(revision) = revision_param("revision") else {
+                        println!("Missing or invalid 'revision' param for restore_revision");
+                        return;
+                    };
+                    // Like `revision` for saves: fail if the item changed since
+                    let expected = revision_param("current_revision");
+                    let result = if commands {
+                        config::restore_command(&dir, name, revision, expected)
+                    } else {
+                        config::restore_pipeline(&dir, name, revision, expected)
+                    };
+                    let item = if commands { "command" } else { "pipeline" };
+                    report_save(peer_map, addr, message.method, item, name, &result);
+                    if commands {
+                        websocket::broadcast_commands(peer_map, config_path);
+                    } else {
+                        websocket::broadcast_pipelines(peer_map, config_path);
+                    }
+                }
+            }
+        }
+        "validate_config" => {
             if let Some(peer_addr) = addr {
-                if let (Some(broker), Some(topic)) = (
-                    message.params["broker"].as_str(),
-                    message.params["topic"].as_str(),
-                ) {
-                    websocket::handle_unsubscribe_topic(peer_map, peer_addr, broker, topic);
+                let errors = schema::validate_config_dir(config_path);
+                websocket::send_config_validation(peer_map, peer_addr, &errors);
+            }
+        }
+        "export_config" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let strip_secrets = message
+                .params
+                .get("strip_secrets")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            // Only passwords the peer has proven to know are exported
+            let bundle = bundle::export_bundle(config_path, |broker| {
+                !strip_secrets && peer_is_authenticated(peer_map, addr, broker.key())
+            });
+            websocket::send_config_bundle(peer_map, peer_addr, &bundle);
+        }
+        "preview_import" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let preview = message
+                .params
+                .get("bundle")
+                .cloned()
+                .ok_or_else(|| "Missing 'bundle' param".to_string())
+                .and_then(|v| {
+                    serde_json::from_value::<bundle::ConfigBundle>(v)
+                        .map_err(|err| format!("Invalid bundle: {err}"))
+                })
+                .and_then(|b| bundle::preview_import(config_path, &b));
+            websocket::send_config_import_preview(peer_map, peer_addr, &preview);
+        }
+        "import_config" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let result = serde_json::from_value::<bundle::ImportRequest>(message.params)
+                .map_err(|err| format!("Invalid import request: {err}"))
+                .and_then(|request| {
+                    bundle::apply_import(config_path, &request, |broker| {
+                        !broker.requires_auth()
+                            || peer_is_authenticated(peer_map, addr, broker.key())
+                    })
+                });
+            if result.is_ok() {
+                apply_config_dir(config_path, peer_map, mqtt_map, notification_buf, services);
+            }
+            websocket::send_config_import_result(peer_map, peer_addr, &result);
+        }
+        "list_alerts" => {
+            if let Some(peer_addr) = addr {
+                websocket::send_alerts(peer_map, &services.alerts, peer_addr);
+            }
+        }
+        "save_alert_rule" => {
+            let Some(rule) = alerts::parse_alert_rule(message.params) else {
+                return;
+            };
+            let alerts_path = std::format!("{config_path}/alerts");
+            // Replacing a rule needs access to the broker of the old one too
+            let previous = alerts::get_alert_rules(&alerts_path)
+                .into_iter()
+                .find(|r| r.name == rule.name);
+            if !previous
+                .iter()
+                .chain([&rule])
+                .all(|r| peer_may_manage(peer_map, mqtt_map, addr, r.broker.as_deref()))
+            {
+                println!(
+                    "Peer not authenticated for alert rule {}, save_alert_rule denied",
+                    rule.name
+                );
+                return;
+            }
+            if alerts::add_to_alert_rules(&alerts_path, &rule) {
+                alerts::reload_rules(services, peer_map, &alerts_path);
+                websocket::broadcast_alerts(peer_map, &services.alerts);
+            }
+        }
+        "remove_alert_rule" => {
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for remove_alert_rule");
+                return;
+            };
+            let alerts_path = std::format!("{config_path}/alerts");
+            let Some(rule) = alerts::get_alert_rules(&alerts_path)
+                .into_iter()
+                .find(|r| r.name == name)
+            else {
+                println!("Alert rule {name} not found");
+                return;
+            };
+            if !peer_may_manage(peer_map, mqtt_map, addr, rule.broker.as_deref()) {
+                println!("Peer not authenticated for alert rule {name}, remove_alert_rule denied");
+                return;
+            }
+            if alerts::remove_from_alert_rules(&alerts_path, name) {
+                alerts::reload_rules(services, peer_map, &alerts_path);
+                websocket::broadcast_alerts(peer_map, &services.alerts);
+            }
+        }
+        "acknowledge_alert" => {
+            let id = match message.params.get("id").and_then(|v| v.as_str()) {
+                Some(id) => id,
+                None => {
+                    println!("Missing or invalid 'id' param for acknowledge_alert");
+                    return;
+                }
+            };
+            let broker = services
+                .alerts
+                .lock()
+                .unwrap()
+                .active_alert(id)
+                .map(|alert| alert.broker.clone());
+            let Some(broker) = broker else {
+                println!("Alert {id} is not firing. Nothing to acknowledge.");
+                return;
+            };
+            if !peer_is_authenticated(peer_map, addr, &broker) {
+                println!("Peer not authenticated for broker {broker}, acknowledge_alert denied");
+                return;
+            }
+            alerts::acknowledge(&services.alerts, peer_map, id);
+        }
+        "list_webhooks" => {
+            if let Some(peer_addr) = addr {
+                websocket::send_webhooks(peer_map, &services.webhooks, peer_addr);
+            }
+        }
+        "save_webhook" => {
+            let Some(webhook) = webhooks::parse_webhook(message.params) else {
+                return;
+            };
+            let webhooks_path = std::format!("{config_path}/webhooks");
+            // The replaced webhook's dead letters stay with the name, so
+            // taking it over needs access to the old broker as well
+            let previous = webhooks::get_webhooks(&webhooks_path)
+                .into_iter()
+                .find(|w| w.name == webhook.name);
+            if !previous
+                .iter()
+                .chain([&webhook])
+                .all(|w| peer_may_manage(peer_map, mqtt_map, addr, w.broker.as_deref()))
+            {
+                println!(
+                    "Peer not authenticated for webhook {}, save_webhook denied",
+                    webhook.name
+                );
+                return;
+            }
+            if webhooks::add_to_webhooks(&webhooks_path, &webhook) {
+                webhooks::reload_webhooks(&services.webhooks, &webhooks_path);
+                websocket::broadcast_webhooks(peer_map, &services.webhooks);
+            }
+        }
+        "remove_webhook" => {
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for remove_webhook");
+                return;
+            };
+            let webhooks_path = std::format!("{config_path}/webhooks");
+            let Some(webhook) = webhooks::get_webhooks(&webhooks_path)
+                .into_iter()
+                .find(|w| w.name == name)
+            else {
+                println!("Webhook {name} not found");
+                return;
+            };
+            if !peer_may_manage(peer_map, mqtt_map, addr, webhook.broker.as_deref()) {
+                println!("Peer not authenticated for webhook {name}, remove_webhook denied");
+                return;
+            }
+            if webhooks::remove_from_webhooks(&webhooks_path, name) {
+                webhooks::reload_webhooks(&services.webhooks, &webhooks_path);
+                websocket::broadcast_webhooks(peer_map, &services.webhooks);
+            }
+        }
+        "list_bridges" => {
+            if let Some(peer_addr) = addr {
+                websocket::send_bridges(peer_map, &services.bridges, peer_addr);
+            }
+        }
+        "save_bridge" => {
+            let Some(bridge) = bridges::parse_bridge(message.params) else {
+                return;
+            };
+            for broker in [&bridge.source, &bridge.destination] {
+                if !broker_exists(mqtt_map, broker) {
+                    println!("Bridge {} refers to unknown broker {broker}", bridge.name);
+                    return;
+                }
+            }
+            let bridges_path = std::format!("{config_path}/bridges");
+            // Replacing a bridge needs access to the brokers of the old one too
+            let previous = bridges::get_bridges(&bridges_path)
+                .into_iter()
+                .find(|b| b.name == bridge.name);
+            if let Some(broker) = previous
+                .iter()
+                .chain([&bridge])
+                .flat_map(|b| [&b.source, &b.destination])
+                .find(|broker| !peer_is_authenticated(peer_map, addr, broker))
+            {
+                println!("Peer not authenticated for broker {broker}, save_bridge denied");
+                return;
+            }
+            if bridges::add_to_bridges(&bridges_path, &bridge) {
+                bridges::reload_bridges(&services.bridges, &bridges_path);
+                websocket::broadcast_bridges(peer_map, &services.bridges);
+            }
+        }
+        "remove_bridge" | "start_bridge" | "stop_bridge" => {
+            let name = match message.params.get("name").and_then(|v| v.as_str()) {
+                Some(n) => n,
+                None => {
+                    println!("Missing or invalid 'name' param for {}", message.method);
+                    return;
+                }
+            };
+            let brokers = services
+                .bridges
+                .lock()
+                .unwrap()
+                .get(name)
+                .map(|b| [b.source.clone(), b.destination.clone()]);
+            let Some(brokers) = brokers else {
+                println!("Bridge {name} not found");
+                return;
+            };
+            if !brokers
+                .iter()
+                .all(|broker| peer_is_authenticated(peer_map, addr, broker))
+            {
+                println!(
+                    "Peer not authenticated for bridge {name}, {} denied",
+                    message.method
+                );
+                return;
+            }
+            let bridges_path = std::format!("{config_path}/bridges");
+            let changed = match message.method {
+                "remove_bridge" => bridges::remove_from_bridges(&bridges_path, name),
+                method => {
+                    bridges::set_bridge_enabled(&bridges_path, name, method == "start_bridge")
+                }
+            };
+            if changed {
+                bridges::reload_bridges(&services.bridges, &bridges_path);
+                websocket::broadcast_bridges(peer_map, &services.bridges);
+            }
+        }
+        "list_schedules" => {
+            if let Some(peer_addr) = addr {
+                websocket::send_schedules(peer_map, &services.scheduler, peer_addr);
+            }
+        }
+        "save_schedule" => {
+            let Some(job) = scheduler::parse_job(message.params) else {
+                return;
+            };
+            let commands_path = std::format!("{config_path}/commands");
+            let Some(broker) = job.target_broker(&commands_path) else {
+                println!("Schedule {} has no broker to publish to", job.name);
+                return;
+            };
+            if !broker_exists(mqtt_map, &broker) {
+                println!("Schedule {} refers to unknown broker {broker}", job.name);
+                return;
+            }
+            let schedules_path = std::format!("{config_path}/schedules");
+            // Replacing a schedule needs access to the broker of the old one
+            // too. One whose command is gone may have published anywhere.
+            let previous = scheduler::get_jobs(&schedules_path)
+                .into_iter()
+                .find(|j| j.name == job.name);
+            let previous_broker = previous.map(|j| j.target_broker(&commands_path));
+            if !previous_broker
+                .iter()
+                .chain([&Some(broker)])
+                .all(|b| peer_may_manage(peer_map, mqtt_map, addr, b.as_deref()))
+            {
+                println!(
+                    "Peer not authenticated for schedule {}, save_schedule denied",
+                    job.name
+                );
+                return;
+            }
+            if scheduler::add_to_jobs(&schedules_path, &job) {
+                scheduler::reload_jobs(&services.scheduler, &schedules_path);
+                websocket::broadcast_schedules(peer_map, &services.scheduler);
+            }
+        }
+        "remove_schedule" | "start_schedule" | "stop_schedule" => {
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for {}", message.method);
+                return;
+            };
+            let schedules_path = std::format!("{config_path}/schedules");
+            let Some(job) = scheduler::get_jobs(&schedules_path)
+                .into_iter()
+                .find(|j| j.name == name)
+            else {
+                println!("Schedule {name} not found");
+                return;
+            };
+            let commands_path = std::format!("{config_path}/commands");
+            let broker = job.target_broker(&commands_path);
+            if !peer_may_manage(peer_map, mqtt_map, addr, broker.as_deref()) {
+                println!(
+                    "Peer not authenticated for schedule {name}, {} denied",
+                    message.method
+                );
+                return;
+            }
+            let changed = match message.method {
+                "remove_schedule" => scheduler::remove_from_jobs(&schedules_path, name),
+                method => {
+                    scheduler::set_job_enabled(&schedules_path, name, method == "start_schedule")
+                }
+            };
+            if changed {
+                scheduler::reload_jobs(&services.scheduler, &schedules_path);
+                websocket::broadcast_schedules(peer_map, &services.scheduler);
+            }
+        }
+        "list_scenarios" => {
+            if let Some(peer_addr) = addr {
+                websocket::send_scenarios(peer_map, config_path, peer_addr);
+            }
+        }
+        "save_scenario" => {
+            let Some(scenario) = scenarios::parse_scenario(message.params) else {
+                return;
+            };
+            let commands_path = std::format!("{config_path}/commands");
+            if let Err(err) = scenario.brokers(&commands_path) {
+                println!("Scenario {} is invalid: {err}", scenario.name);
+                return;
+            }
+            let scenarios_path = std::format!("{config_path}/scenarios");
+            // Replacing a scenario needs access to the brokers of the old one too
+            let previous = scenarios::get_scenario(&scenarios_path, &scenario.name);
+            if !previous
+                .iter()
+                .chain([&scenario])
+                .all(|s| peer_may_manage_scenario(peer_map, mqtt_map, addr, s, &commands_path))
+            {
+                println!(
+                    "Peer not authenticated for scenario {}, save_scenario denied",
+                    scenario.name
+                );
+                return;
+            }
+            if scenarios::add_to_scenarios(&scenarios_path, &scenario) {
+                websocket::broadcast_scenarios(peer_map, config_path);
+            }
+        }
+        "remove_scenario" => {
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for remove_scenario");
+                return;
+            };
+            let scenarios_path = std::format!("{config_path}/scenarios");
+            let Some(scenario) = scenarios::get_scenario(&scenarios_path, name) else {
+                println!("Scenario {name} not found");
+                return;
+            };
+            let commands_path = std::format!("{config_path}/commands");
+            if !peer_may_manage_scenario(peer_map, mqtt_map, addr, &scenario, &commands_path) {
+                println!("Peer not authenticated for scenario {name}, remove_scenario denied");
+                return;
+            }
+            if scenarios::remove_from_scenarios(&scenarios_path, name) {
+                websocket::broadcast_scenarios(peer_map, config_path);
+            }
+        }
+        "run_scenario" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for run_scenario");
+                return;
+            };
+            let run_id = uuid::Uuid::new_v4().to_string();
+            let scenarios_path = std::format!("{config_path}/scenarios");
+            let commands_path = std::format!("{config_path}/commands");
+            let brokers = scenarios::get_scenario(&scenarios_path, name)
+                .ok_or_else(|| format!("Scenario {name} not found"))
+                .and_then(|scenario| Ok((scenario.brokers(&commands_path)?, scenario)));
+            let scenario = match brokers {
+                Ok((brokers, scenario)) => {
+                    if let Some(broker) = brokers
+                        .iter()
+                        .find(|b| !peer_is_authenticated(peer_map, addr, b))
+                    {
+                        println!("Peer not authenticated for broker {broker}, run_scenario denied");
+                        return;
+                    }
+                    scenario
+                }
+                Err(err) => {
+                    let report = scenarios::ScenarioReport::failed(&run_id, name, err);
+                    websocket::send_scenario_result(peer_map, peer_addr, &report);
+                    return;
+                }
+            };
+            if tokio::runtime::Handle::try_current().is_err() {
+                eprintln!("No async runtime available. Can't run scenario {name}.");
+                return;
+            }
+            let peer_map = peer_map.clone();
+            let mqtt_map = mqtt_map.clone();
+            let services = services.clone();
+            tokio::spawn(async move {
+                let report = scenarios::run(
+                    &scenario,
+                    &run_id,
+                    &commands_path,
+                    &services,
+                    &mqtt_map,
+                    |step| {
+                        websocket::send_scenario_step(
+                            &peer_map,
+                            peer_addr,
+                            &run_id,
+                            &scenario.name,
+                            step,
+                        )
+                    },
+                )
+                .await;
+                websocket::send_scenario_result(&peer_map, peer_addr, &report);
+            });
+        }
+        "list_retention_rules" => {
+            if let Some(peer_addr) = addr {
+                websocket::send_retention_rules(peer_map, &services.retention, peer_addr);
+            }
+        }
+        "save_retention_rule" => {
+            let Some(rule) = retention::parse_retention_rule(message.params) else {
+                return;
+            };
+            let retention_path = std::format!("{config_path}/retention");
+            // Replacing a rule needs access to the broker of the old one too
+            let previous = retention::get_retention_rules(&retention_path)
+                .into_iter()
+                .find(|r| r.name == rule.name);
+            if !previous
+                .iter()
+                .chain([&rule])
+                .all(|r| peer_may_manage(peer_map, mqtt_map, addr, r.broker.as_deref()))
+            {
+                println!(
+                    "Peer not authenticated for retention rule {}, save_retention_rule denied",
+                    rule.name
+                );
+                return;
+            }
+            if retention::add_to_retention_rules(&retention_path, &rule) {
+                retention::reload_retention_rules(&services.retention, &retention_path);
+                websocket::broadcast_retention_rules(peer_map, &services.retention);
+            }
+        }
+        "remove_retention_rule" => {
+            let Some(name) = message.params.get("name").and_then(|v| v.as_str()) else {
+                println!("Missing or invalid 'name' param for remove_retention_rule");
+                return;
+            };
+            let retention_path = std::format!("{config_path}/retention");
+            let Some(rule) = retention::get_retention_rules(&retention_path)
+                .into_iter()
+                .find(|r| r.name == name)
+            else {
+                println!("Retention rule {name} not found");
+                return;
+            };
+            if !peer_may_manage(peer_map, mqtt_map, addr, rule.broker.as_deref()) {
+                println!(
+                    "Peer not authenticated for retention rule {name}, remove_retention_rule denied"
+                );
+                return;
+            }
+            if retention::remove_from_retention_rules(&retention_path, name) {
+                retention::reload_retention_rules(&services.retention, &retention_path);
+                websocket::broadcast_retention_rules(peer_map, &services.retention);
+            }
+        }
+        "purge_broker" | "purge_subtree" | "purge_topic" => {
+            let broker = match message.params.get("broker").and_then(|v| v.as_str()) {
+                Some(b) => b,
+                None => {
+                    println!("Missing or invalid 'broker' param for {}", message.method);
+                    return;
+                }
+            };
+            if !peer_is_authenticated(peer_map, addr, broker) {
+                println!(
+                    "Peer not authenticated for broker {broker}, {} denied",
+                    message.method
+                );
+                return;
+            }
+            // Topics are taken literally, not as filters: a topic named
+            // `a/+` purges just that one
+            let topic = message.params.get("topic").and_then(|v| v.as_str());
+            let (prefix, subtree) = match (message.method, topic) {
+                ("purge_broker", _) => ("", true),
+                ("purge_subtree", Some(topic)) => (topic, true),
+                ("purge_topic", Some(topic)) => (topic, false),
+                _ => {
+                    println!("Missing or invalid 'topic' param for {}", message.method);
+                    return;
+                }
+            };
+            let Some(entry) = mqtt::get_broker(mqtt_map, broker) else {
+                println!("Broker {broker} not found for {}", message.method);
+                return;
+            };
+            let purged = entry.state().purge_topics(|name| {
+                prefix.is_empty()
+                    || name
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.is_empty() || (subtree && rest.starts_with('/')))
+            });
+            println!(
+                "Purged {} topics for {} {prefix} on {broker}",
+                purged.len(),
+                message.method
+            );
+            let evictions: Vec<_> = purged
+                .into_iter()
+                .map(|(topic, count)| (topic, count, 0))
+                .collect();
+            websocket::buffer_evictions(notification_buf, broker, &evictions);
+        }
+        "pause_capture" | "resume_capture" => {
+            let broker = match message.params.get("broker").and_then(|v| v.as_str()) {
+                Some(b) => b,
+                None => {
+                    println!("Missing or invalid 'broker' param for {}", message.method);
+                    return;
+                }
+            };
+            if !peer_is_authenticated(peer_map, addr, broker) {
+                println!(
+                    "Peer not authenticated for broker {broker}, {} denied",
+                    message.method
+                );
+                return;
+            }
+            let Some(entry) = mqtt::get_broker(mqtt_map, broker) else {
+                println!("Broker {broker} not found for {}", message.method);
+                return;
+            };
+            let paused = message.method == "pause_capture";
+            let changed = entry.state().set_paused(paused);
+            if changed {
+                println!("Capture for {broker} paused: {paused}");
+                websocket::broadcast_brokers(peer_map, mqtt_map);
+            }
+        }
+        "retained_topics" | "clear_retained" => {
+            let Some(peer_addr) = addr else {
+                return;
+            };
+            let broker = match message.params.get("broker").and_then(|v| v.as_str()) {
+                Some(b) => b,
+                None => {
+                    println!("Missing or invalid 'broker' param for {}", message.method);
+                    return;
+                }
+            };
+            if !peer_is_authenticated(peer_map, addr, broker) {
+                println!(
+                    "Peer not authenticated for broker {broker}, {} denied",
+                    message.method
+                );
+                return;
+            }
+            let now_ms = chrono::Utc::now().timestamp_millis();
+
+            // A confirmed clear only touches the topics from its preview
+            if let Some(token) = message.params.get("token").and_then(|v| v.as_str()) {
+                let topics = services
+                    .retained_clears
+                    .lock()
+                    .unwrap()
+                    .take(token, broker, now_ms);
+                match topics {
+                    Some(topics) => {
+                        let (cleared, failed) = retained::clear_topics(mqtt_map, broker, &topics);
+                        println!(
+                            "Cleared {} retained topics on {broker}, {} failed",
+                            cleared.len(),
+                            failed.len()
+                        );
+                        websocket::send_retained_clear_result(
+                            peer_map, peer_addr, broker, &cleared, &failed, None,
+                        );
+                    }
+                    None => websocket::send_retained_clear_result(
+                        peer_map,
+                        peer_addr,
+                        broker,
+                        &[],
+                        &[],
+                        Some("Invalid or expired confirmation token"),
+                    ),
+                }
+                return;
+            }
+
+            let Some(entry) = mqtt::get_broker(mqtt_map, broker) else {
+                println!("Broker {broker} not found for {}", message.method);
+                return;
+            };
+            let prefix = message.params.get("prefix").and_then(|v| v.as_str());
+            let retained = retained::retained_topics(&entry.state(), prefix, mqtt::now_ns());
+            if message.method == "retained_topics" {
+                websocket::send_retained_topics(peer_map, peer_addr, broker, &retained);
+                return;
+            }
+
+            // Dry run: optionally narrow to the requested topics and hand out a token
+            let requested: Option<Vec<&str>> = message
+                .params
+                .get("topics")
+                .and_then(|v| v.as_array())
+                .map(|topics| topics.iter().filter_map(|t| t.as_str()).collect());
+            let topics: Vec<String> = retained
+                .into_iter()
+                .map(|t| t.topic)
+                .filter(|t| requested.as_ref().is_none_or(|r| r.contains(&t.as_str())))
+                .collect();
+            let token =
+                services
+                    .retained_clears
+                    .lock()
+                    .unwrap()
+                    .issue(broker, topics.clone(), now_ms);
+            websocket::send_retained_clear_preview(peer_map, peer_addr, broker, &topics, &token);
+        }
+        "subscribe_topic" => {
+            if let Some(peer_addr) = addr {
+                if let (Some(broker), Some(topic)) = (
+                    message.params["broker"].as_str(),
+                    message.params["topic"].as_str(),
+                ) {
+                    let since_timestamp = message.params["since_timestamp"].as_str();
+                    websocket::handle_subscribe_topic(
+                        peer_map,
+                        mqtt_map,
+                        peer_addr,
+                        broker,
+                        topic,
+                        since_timestamp,
+                    );
+                }
+            }
+        }
+        "unsubscribe_topic" => {
+            if let Some(peer_addr) = addr {
+                if let (Some(broker), Some(topic)) = (
+                    message.params["broker"].as_str(),
+                    message.params["topic"].as_str(),
+                ) {
+                    websocket::handle_unsubscribe_topic(peer_map, peer_addr, broker, topic);
+                }
+            }
+        }
+        "authenticate_broker" => {
+            let Some(hostname) = broker_id_param(&message.params, "hostname") else {
+                println!("Missing 'id' param for authenticate_broker");
+                return;
+            };
+            let supplied_password = message
+                .params
+                .get("password")
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+
+            // Look up the broker's configured password
+            let brokers_path = std::format!("{config_path}/brokers.json");
+            let broker_configs = config::get_known_brokers(&brokers_path);
+            let broker_cfg = broker_configs.iter().find(|b| b.key() == hostname);
+
+            let success = match broker_cfg {
+                Some(cfg) => match &cfg.password {
+                    Some(p) if !p.is_empty() => constant_time_eq(supplied_password, p),
+                    _ => true, // No password required
+                },
+                None => false, // Broker not found in config
+            };
+
+            if success {
+                if let Some(peer_addr) = addr {
+                    {
+                        let mut peers = peer_map.lock().unwrap();
+                        if let Some(peer) = peers.get_mut(&peer_addr) {
+                            peer.authenticated_brokers.insert(hostname.clone());
+                        }
+                    }
+                    // Send topic summaries for this broker now that the peer is authenticated
+                    websocket::send_broker_topic_summaries(
+                        peer_map, mqtt_map, peer_addr, &hostname,
+                    );
+                }
+            }
+
+            // Send result back to the requesting peer
+            if let Some(peer_addr) = addr {
+                let result = jsonrpc::JsonRpcNotification {
+                    jsonrpc: "2.0",
+                    method: "broker_auth_result",
+                    params: serde_json::json!({
+                        "broker": hostname,
+                        "success": success,
+                    }),
+                };
+                if let Ok(serialized) = serde_json::to_string(&result) {
+                    websocket::send_to_specific_peer(peer_map, peer_addr, &serialized);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Create the broker and start its connection task. Returns `false` if a
+/// broker with the same key already exists.
+pub fn connect_to_broker(
+    broker_config: &config::BrokerConfig,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) -> bool {
+    let mqtt_host = broker_config.key();
+    if tokio::runtime::Handle::try_current().is_err() {
+        eprintln!("No async runtime available. Can't connect to {mqtt_host}.");
+        return false;
+    }
+    if mqtt::get_broker(mqtt_map, mqtt_host).is_some() {
+        println!("MQTT-Client for {mqtt_host} already exists.");
+        return false;
+    }
+    println!("MQTT-Client for {mqtt_host} does not exist. Creating new client.");
+    let (client, eventloop) = match mqtt::connect_to_mqtt_host(broker_config) {
+        Ok(connection) => connection,
+        Err(err) => {
+            eprintln!("Can't connect to {mqtt_host}: {err}");
+            return false;
+        }
+    };
+    let mut mqtt_lock = mqtt_map.write().unwrap();
+    // Another connect may have won the race while the client was built
+    if mqtt_lock.contains_key(mqtt_host) {
+        println!("MQTT-Client for {mqtt_host} already exists.");
+        return false;
+    }
+    let broker = mqtt::BrokerEntry::new(
+        client,
+        broker_config.requires_auth(),
+        mqtt::MqttBroker::new(mqtt_host),
+    );
+    broker.set_config(broker_config);
+    let cancel = broker.cancellation();
+    mqtt_lock.insert(mqtt_host.to_string(), mqtt::SharedBroker::new(broker));
+    drop(mqtt_lock);
+
+    spawn_connection(
+        broker_config,
+        eventloop,
+        cancel,
+        peer_map,
+        mqtt_map,
+        notification_buf,
+        services,
+    );
+    true
+}
+
+/// Reconnect an existing broker with new options. Stored messages are kept.
+fn reconnect_broker(
+    broker_config: &config::BrokerConfig,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) -> bool {
+    let mqtt_host = broker_config.key();
+    if tokio::runtime::Handle::try_current().is_err() {
+        eprintln!("No async runtime available. Can't reconnect to {mqtt_host}.");
+        return false;
+    }
+    let Some(broker) = mqtt::get_broker(mqtt_map, mqtt_host) else {
+        println!("No MQTT-Client for {mqtt_host} found.");
+        return false;
+    };
+    println!("Reconnecting MQTT-Client for {mqtt_host} with updated options.");
+    let (client, eventloop) = match mqtt::connect_to_mqtt_host(broker_config) {
+        Ok(connection) => connection,
+        Err(err) => {
+            eprintln!("Can't reconnect to {mqtt_host}: {err}");
+            return false;
+        }
+    };
+    broker.set_requires_auth(broker_config.requires_auth());
+    broker.set_config(broker_config);
+    let cancel = broker.restart(client);
+    websocket::send_broker_status_to_peers(peer_map, mqtt_host, false);
+
+    spawn_connection(
+        broker_config,
+        eventloop,
+        cancel,
+        peer_map,
+        mqtt_map,
+        notification_buf,
+        services,
+    );
+    true
+}
+
+/// Must be called from within the tokio runtime.
+fn spawn_connection(
+    broker_config: &config::BrokerConfig,
+    eventloop: rumqttc::EventLoop,
+    cancel: CancellationToken,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) {
+    let config_clone = broker_config.clone();
+    let mqtt_map_clone = mqtt_map.clone();
+    let peer_map_clone = peer_map.clone();
+    let buf_clone = notification_buf.clone();
+    let services_clone = services.clone();
+
+    tokio::spawn(async move {
+        connect_to_mqtt_client_and_loop_forever(
+            &config_clone,
+            eventloop,
+            &cancel,
+            &mqtt_map_clone,
+            &peer_map_clone,
+            &buf_clone,
+            &services_clone,
+        )
+        .await;
+    });
+}
+
+async fn connect_to_mqtt_client_and_loop_forever(
+    broker_config: &config::BrokerConfig,
+    mut eventloop: rumqttc::EventLoop,
+    cancel: &CancellationToken,
+    mqtt_map: &mqtt::BrokerMap,
+    peer_map: &websocket::PeerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) {
+    let mqtt_host = broker_config.key();
+    loop {
+        loop_forever(
+            mqtt_host,
+            eventloop,
+            cancel,
+            peer_map,
+            mqtt_map,
+            notification_buf,
+            services,
+        )
+        .await;
+
+        if cancel.is_cancelled() {
+            println!("Broker {mqtt_host} was removed or reconfigured. Stopping reconnect loop.");
+            break;
+        }
+
+        println!("Connection loop ended for {mqtt_host}. Recreating MQTT client and reconnecting.");
+        if !reconnect_backoff(cancel).await {
+            break;
+        }
+
+        let (new_client, new_eventloop) = match mqtt::connect_to_mqtt_host(broker_config) {
+            Ok(connection) => connection,
+            Err(err) => {
+                eprintln!("Can't recreate MQTT client for {mqtt_host}: {err}");
+                break;
+            }
+        };
+        let Some(broker) = mqtt::get_broker(mqtt_map, mqtt_host) else {
+            break;
+        };
+        if !broker.set_client(cancel, new_client) {
+            break;
+        }
+        broker.set_connected(false);
+        eventloop = new_eventloop;
+    }
+}
+
+fn remove_broker(mqtt_host: &str, peer_map: &websocket::PeerMap, mqtt_map: &mqtt::BrokerMap) {
+    let removed = mqtt_map.write().unwrap().remove(mqtt_host);
+
+    if let Some(broker) = removed {
+        println!("Removing MQTT-Client for {mqtt_host}");
+        broker.stop();
+
+        peer_map
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|(_addr, peer)| {
+                if peer.selected_broker.as_deref() == Some(mqtt_host) {
+                    peer.selected_broker = None;
+                    peer.subscribed_topics.clear();
+                }
+                peer.authenticated_brokers.remove(mqtt_host);
+
+                let message = jsonrpc::JsonRpcNotification {
+                    jsonrpc: "2.0",
+                    method: "broker_removal",
+                    params: serde_json::json!(mqtt_host),
+                };
+
+                if let Ok(serialized) = serde_json::to_string(&message) {
+                    match peer
+                        .tx
+                        .try_send(warp::filters::ws::Message::text(serialized))
+                    {
+                        Ok(_) => {}
+                        Err(err) if err.is_disconnected() => {
+                            println!("Error sending message: {err:?}")
+                        }
+                        Err(_) => {}
+                    }
+                } else {
+                    println!("Failed to serialize brokers.");
+                }
+            });
+    } else {
+        println!("No MQTT-Client for {mqtt_host} found.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_channel::mpsc::channel;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Mutex;
+    use websocket::PEER_CHANNEL_CAPACITY;
+
+    fn make_peer_map() -> websocket::PeerMap {
+        websocket::PeerMap::new(Mutex::new(HashMap::new()))
+    }
+
+    fn make_mqtt_map() -> mqtt::BrokerMap {
+        mqtt::BrokerMap::new(std::sync::RwLock::new(HashMap::new()))
+    }
+
+    fn make_notification_buf() -> websocket::NotificationBuf {
+        websocket::NotificationBuf::new(Mutex::new(websocket::NotificationBuffer::default()))
+    }
+
+    fn make_addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    fn insert_peer(
+        peer_map: &websocket::PeerMap,
+        port: u16,
+    ) -> (
+        SocketAddr,
+        futures_channel::mpsc::Receiver<warp::filters::ws::Message>,
+    ) {
+        let addr = make_addr(port);
+        let (tx, rx) = channel(PEER_CHANNEL_CAPACITY);
+        peer_map
+            .lock()
+            .unwrap()
+            .insert(addr, websocket::PeerConnection::new(tx));
+        (addr, rx)
+    }
+
+    // --- deserialize_json_rpc_and_process ---
+
+    #[test]
+    fn test_process_invalid_json() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        // Should not panic
+        deserialize_json_rpc_and_process(
+            "not json",
+            &peer_map,
+            &mqtt_map,
+            "/tmp",
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+    }
+
+    #[test]
+    fn test_process_unknown_method() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let json = r#"{"jsonrpc":"2.0","method":"unknown_method","params":{}}"#;
+        // Should not panic
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            "/tmp",
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+    }
+
+    #[test]
+    fn test_process_publish() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let json = r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"nonexistent:1883","topic":"test","payload":"hello"}}"#;
+        // Should not panic (broker doesn't exist, but it's handled gracefully)
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            "/tmp",
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+    }
+
+    #[test]
+    fn test_process_publish_reports_queued_then_delivery() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        // Keep the event loop so the client's request queue stays open
+        let (client, _eventloop) =
+            mqtt::connect_to_mqtt_host(&config::BrokerConfig::from_host("a:1883")).unwrap();
+        let broker = mqtt::SharedBroker::new(mqtt::BrokerEntry::new(
+            client,
+            false,
+            mqtt::MqttBroker::new("a:1883"),
+        ));
+        mqtt_map
+            .write()
+            .unwrap()
+            .insert("a:1883".to_string(), broker.clone());
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        let process = |json: &str| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                "/tmp",
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"a:1883","topic":"t","payload":"p","request_id":"p1"}}"#,
+        );
+        let queued = received(&mut rx, "publish_result").unwrap();
+        assert_eq!(queued["id"], "p1");
+        assert_eq!(queued["state"], "queued");
+        assert_eq!(queued["qos"], 1);
+
+        // What the connection loop does when the publish is written and acknowledged
+        assert!(broker.deliveries().on_sent(1).is_empty());
+        send_publish_results(&peer_map, broker.deliveries().on_acked(1));
+        let acked = received(&mut rx, "publish_result").unwrap();
+        assert_eq!(acked["state"], "acked");
+        assert!(acked["latency_ms"].is_number());
+    }
+
+    #[test]
+    fn test_process_connect_spawns_task() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+        let json =
+            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19999"}}"#;
+
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        // Give the spawned task a moment to start
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // The broker should appear in the mqtt_map
+        let map = mqtt_map.read().unwrap();
+        assert!(map.contains_key("127.0.0.1:19999"));
+        drop(map);
+
+        // Cleanup
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_process_connect_duplicate_broker() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+
+        let json =
+            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19998"}}"#;
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // Connect again — should not duplicate
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(mqtt_map.read().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_process_remove_nonexistent_broker() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+
+        let json =
+            r#"{"jsonrpc":"2.0","method":"remove","params":{"hostname":"nonexistent:1883"}}"#;
+        // Should not panic
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_process_save_and_remove_command() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let commands_path = format!("{}/commands", config_path);
+        std::fs::create_dir_all(&commands_path).ok();
+
+        // Save a command
+        let save_json = r#"{"jsonrpc":"2.0","method":"save_command","params":{"name":"test_cmd","topic":"t","payload":"p"}}"#;
+        deserialize_json_rpc_and_process(
+            save_json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        let cmd_file = format!("{}/test_cmd.json", commands_path);
+        assert!(std::path::Path::new(&cmd_file).exists());
+
+        // Remove the command
+        let remove_json =
+            r#"{"jsonrpc":"2.0","method":"remove_command","params":{"name":"test_cmd"}}"#;
+        deserialize_json_rpc_and_process(
+            remove_json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        assert!(!std::path::Path::new(&cmd_file).exists());
+
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_process_save_and_remove_pipeline() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let pipelines_path = format!("{}/pipelines", config_path);
+        std::fs::create_dir_all(&pipelines_path).ok();
+
+        let save_json = r#"{"jsonrpc":"2.0","method":"save_pipeline","params":{"name":"test_pipe","pipeline":[{"topic":"t1"}]}}"#;
+        deserialize_json_rpc_and_process(
+            save_json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        let pipe_file = format!("{}/test_pipe.json", pipelines_path);
+        assert!(std::path::Path::new(&pipe_file).exists());
+
+        let remove_json =
+            r#"{"jsonrpc":"2.0","method":"remove_pipeline","params":{"name":"test_pipe"}}"#;
+        deserialize_json_rpc_and_process(
+            remove_json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        assert!(!std::path::Path::new(&pipe_file).exists());
+
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_connect_broadcasts_brokers_to_peers() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (_addr, mut rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+
+        let json =
+            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19997"}}"#;
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        // Should have received a broadcast_brokers message, even if a status
+        // notification arrived first.
+        let mut methods = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            let parsed: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+            if let Some(method) = parsed["method"].as_str() {
+                methods.push(method.to_string());
+            }
+        }
+        assert!(methods.contains(&"mqtt_brokers".to_string()));
+
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_connect_same_host_under_two_ids() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (_addr, mut rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+
+        for (id, name) in [("reader", "Reader"), ("writer", "Writer")] {
+            let json = format!(
+                r#"{{"jsonrpc":"2.0","method":"connect","params":{{"id":"{id}","hostname":"127.0.0.1:19990","name":"{name}","tags":["lab"]}}}}"#
+            );
+            deserialize_json_rpc_and_process(
+                &json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                None,
+                &make_notification_buf(),
+                &services::Services::default(),
+            );
+        }
+
+        let mut ids: Vec<_> = mqtt_map.read().unwrap().keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, vec!["reader", "writer"]);
+
+        let mut brokers = None;
+        while let Some(params) = received(&mut rx, "mqtt_brokers") {
+            brokers = Some(params);
+        }
+        let brokers = brokers.unwrap();
+        let writer = brokers
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|b| b["id"] == "writer")
+            .unwrap();
+        assert_eq!(writer["host"], "127.0.0.1:19990");
+        assert_eq!(writer["name"], "Writer");
+        assert_eq!(writer["tags"], serde_json::json!(["lab"]));
+
+        let stored = config::get_known_brokers(&format!("{config_path}/brokers.json"));
+        assert_eq!(stored.len(), 2);
+
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_sync_brokers_only_reconnects_changed_options() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let buf = make_notification_buf();
+        let services = services::Services::default();
+        let renamed = config::BrokerConfig::from_host("127.0.0.1:19981");
+        let moved = config::BrokerConfig {
+            id: "moved".to_string(),
+            ..config::BrokerConfig::from_host("127.0.0.1:19982")
+        };
+        let dropped = config::BrokerConfig::from_host("127.0.0.1:19983");
+        let configs = [renamed.clone(), moved.clone(), dropped];
+        assert!(sync_brokers(
+            &configs, &peer_map, &mqtt_map, &buf, &services
+        ));
+        assert_eq!(mqtt_map.read().unwrap().len(), 3);
+        assert!(!sync_brokers(
+            &configs, &peer_map, &mqtt_map, &buf, &services
+        ));
+
+        let renamed_cancel = mqtt::get_broker(&mqtt_map, renamed.key())
+            .unwrap()
+            .cancellation();
+        let moved_cancel = mqtt::get_broker(&mqtt_map, "moved").unwrap().cancellation();
+        let configs = [
+            config::BrokerConfig {
+                name: Some("Renamed".to_string()),
+                ..renamed.clone()
+            },
+            config::BrokerConfig {
+                host: "127.0.0.1:19984".to_string(),
+                ..moved
+            },
+        ];
+        assert!(sync_brokers(
+            &configs, &peer_map, &mqtt_map, &buf, &services
+        ));
+
+        let renamed_entry = mqtt::get_broker(&mqtt_map, renamed.key()).unwrap();
+        assert!(!renamed_cancel.is_cancelled());
+        assert_eq!(renamed_entry.info().name.as_deref(), Some("Renamed"));
+        assert!(moved_cancel.is_cancelled());
+        assert_eq!(
+            mqtt::get_broker(&mqtt_map, "moved").unwrap().info().host,
+            "127.0.0.1:19984"
+        );
+        assert!(mqtt::get_broker(&mqtt_map, "127.0.0.1:19983").is_none());
+
+        for entry in mqtt::all_brokers(&mqtt_map) {
+            entry.stop();
+        }
+    }
+
+    #[test]
+    fn test_remove_broker_sends_removal_notification() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (_addr, mut rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+
+        // First connect
+        let connect_json =
+            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19996"}}"#;
+        deserialize_json_rpc_and_process(
+            connect_json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // Drain the connect broadcast
+        while rx.try_recv().is_ok() {}
+
+        // Now remove
+        let remove_json =
+            r#"{"jsonrpc":"2.0","method":"remove","params":{"hostname":"127.0.0.1:19996"}}"#;
+        deserialize_json_rpc_and_process(
+            remove_json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+
+        // Should receive broker_removal and broadcast_brokers messages
+        let mut methods = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            if let Ok(text) = msg.to_str() {
+                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(text) {
+                    if let Some(method) = parsed["method"].as_str() {
+                        methods.push(method.to_string());
+                    }
                 }
             }
         }
-        "authenticate_broker" => {
-            let hostname = match message.params.get("hostname").and_then(|v| v.as_str()) {
-                Some(h) => h.trim_matches('"').to_string(),
-                None => {
-                    println!("Missing 'hostname' param for authenticate_broker");
-                    return;
-                }
-            };
-            let supplied_password = message
-                .params
-                .get("password")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
+        assert!(methods.contains(&"broker_removal".to_string()));
 
-            // Look up the broker's configured password
-            let brokers_path = std::format!("{config_path}/brokers.json");
-            let broker_configs = config::get_known_brokers(&brokers_path);
-            let broker_cfg = broker_configs.iter().find(|b| b.key() == hostname);
+        assert!(!mqtt_map.read().unwrap().contains_key("127.0.0.1:19996"));
 
-            let success = match broker_cfg {
-                Some(cfg) => match &cfg.password {
-                    Some(p) if !p.is_empty() => constant_time_eq(supplied_password, p),
-                    _ => true, // No password required
-                },
-                None => false, // Broker not found in config
-            };
+        std::fs::remove_dir_all(&config_path).ok();
+    }
 
-            if success {
-                if let Some(peer_addr) = addr {
-                    {
-                        let mut peers = peer_map.lock().unwrap();
-                        if let Some(peer) = peers.get_mut(&peer_addr) {
-                            peer.authenticated_brokers.insert(hostname.clone());
-                        }
-                    }
-                    // Send topic summaries for this broker now that the peer is authenticated
-                    websocket::send_broker_topic_summaries(
-                        peer_map, mqtt_map, peer_addr, &hostname,
-                    );
-                }
-            }
+    #[test]
+    fn test_process_update_broker_keeps_history() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (requester, _rx_a) = insert_peer(&peer_map, 9001);
+        let (other, _rx_b) = insert_peer(&peer_map, 9002);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+        let process = |json: &str, addr: SocketAddr| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
 
-            // Send result back to the requesting peer
-            if let Some(peer_addr) = addr {
-                let result = jsonrpc::JsonRpcNotification {
-                    jsonrpc: "2.0",
-                    method: "broker_auth_result",
-                    params: serde_json::json!({
-                        "broker": hostname,
-                        "success": success,
-                    }),
-                };
-                if let Ok(serialized) = serde_json::to_string(&result) {
-                    websocket::send_to_specific_peer(peer_map, peer_addr, &serialized);
-                }
-            }
-        }
-        _ => {}
+        process(
+            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19992"}}"#,
+            requester,
+        );
+        let entry = mqtt::get_broker(&mqtt_map, "127.0.0.1:19992").unwrap();
+        entry
+            .state()
+            .store_message("kept", 1, bytes::Bytes::from("x"), 1, false);
+        let old_cancel = entry.cancellation();
+        assert!(peer_is_authenticated(
+            &peer_map,
+            Some(other),
+            "127.0.0.1:19992"
+        ));
+
+        // Connecting again must not overwrite the stored options
+        process(
+            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19992","password":"guess"}}"#,
+            other,
+        );
+        let brokers_path = format!("{config_path}/brokers.json");
+        assert_eq!(config::get_known_brokers(&brokers_path)[0].password, None);
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"update_broker","params":{"hostname":"127.0.0.1:19992","password":"secret"}}"#,
+            requester,
+        );
+
+        let brokers = config::get_known_brokers(&brokers_path);
+        assert_eq!(brokers.len(), 1);
+        assert_eq!(brokers[0].password.as_deref(), Some("secret"));
+        assert!(old_cancel.is_cancelled());
+        assert!(!entry.cancellation().is_cancelled());
+        assert!(entry.requires_auth());
+        assert!(entry.state().topics.contains_key("kept"));
+        assert!(peer_is_authenticated(
+            &peer_map,
+            Some(requester),
+            "127.0.0.1:19992"
+        ));
+        assert!(!peer_is_authenticated(
+            &peer_map,
+            Some(other),
+            "127.0.0.1:19992"
+        ));
+
+        remove_broker("127.0.0.1:19992", &peer_map, &mqtt_map);
+        std::fs::remove_dir_all(&config_path).ok();
     }
-}
 
-fn connect_to_broker(
-    broker_config: &config::BrokerConfig,
-    peer_map: &websocket::PeerMap,
-    mqtt_map: &mqtt::BrokerMap,
-    notification_buf: &websocket::NotificationBuf,
-) {
-    let config_clone = broker_config.clone();
-    let mqtt_map_clone = mqtt_map.clone();
-    let peer_map_clone = peer_map.clone();
-    let buf_clone = notification_buf.clone();
+    #[test]
+    fn test_process_update_broker_rejects_invalid_host() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+        let brokers_path = format!("{config_path}/brokers.json");
+        config::add_to_brokers(
+            &brokers_path,
+            &config::BrokerConfig::from_host("127.0.0.1:19990"),
+        )
+        .unwrap();
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("127.0.0.1:19990".to_string());
 
-    std::thread::spawn(move || {
-        connect_to_mqtt_client_and_loop_forever(
-            &config_clone,
-            &mqtt_map_clone,
-            &peer_map_clone,
-            &buf_clone,
+        let json = r#"{"jsonrpc":"2.0","method":"update_broker","params":{"hostname":"127.0.0.1:19990","host":"nohost"}}"#;
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            Some(addr),
+            &make_notification_buf(),
+            &services::Services::default(),
         );
-    });
-}
 
-fn connect_to_mqtt_client_and_loop_forever(
-    broker_config: &config::BrokerConfig,
-    mqtt_map: &mqtt::BrokerMap,
-    peer_map: &websocket::PeerMap,
-    notification_buf: &websocket::NotificationBuf,
-) {
-    let mqtt_host = broker_config.key();
-    let mut mqtt_lock = mqtt_map.lock().unwrap();
+        let result = received(&mut rx, "save_result").unwrap();
+        assert_eq!(result["success"], false);
+        assert_eq!(
+            result["error"],
+            "invalid host \"nohost\", expected host:port"
+        );
+        // brokers.json stays valid and unchanged
+        let brokers = config::load_brokers(&brokers_path).unwrap();
+        assert_eq!(brokers.len(), 1);
+        assert_eq!(brokers[0].host, "127.0.0.1:19990");
+        std::fs::remove_dir_all(&config_path).ok();
+    }
 
-    if mqtt_lock.contains_key(mqtt_host) {
-        println!("MQTT-Client for {mqtt_host} already exists.");
-    } else {
-        println!("MQTT-Client for {mqtt_host} does not exist. Creating new client.");
-        let (client, mut connection) = mqtt::connect_to_mqtt_host(broker_config);
-        let requires_auth = broker_config
-            .password
-            .as_ref()
-            .is_some_and(|p| !p.is_empty());
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        let broker = mqtt::MqttBroker {
-            client,
-            broker: mqtt_host.to_string(),
-            connected: false,
-            topics: HashMap::new(),
-            total_bytes: 0,
-            total_messages: 0,
-            eviction_order: std::collections::VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: now_ms,
-            requires_auth,
-        };
+    #[test]
+    fn test_process_update_broker_requires_authentication() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+        let brokers_path = format!("{config_path}/brokers.json");
+        let mut broker_config = config::BrokerConfig::from_host("127.0.0.1:19991");
+        broker_config.password = Some("secret".to_string());
+        config::add_to_brokers(&brokers_path, &broker_config).unwrap();
 
-        mqtt_lock.insert(mqtt_host.to_string(), broker);
-        drop(mqtt_lock);
+        let json = r#"{"jsonrpc":"2.0","method":"update_broker","params":{"hostname":"127.0.0.1:19991","password":""}}"#;
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            &config_path,
+            Some(addr),
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
 
-        loop {
-            loop_forever(connection, peer_map, mqtt_map, notification_buf);
+        let brokers = config::get_known_brokers(&brokers_path);
+        assert_eq!(brokers[0].password.as_deref(), Some("secret"));
+        std::fs::remove_dir_all(&config_path).ok();
+    }
 
-            if !broker_exists(mqtt_map, mqtt_host) {
-                println!("Broker {mqtt_host} was removed. Stopping reconnect loop.");
-                break;
-            }
+    #[test]
+    fn test_remove_broker_stops_connection_task() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let broker_config = config::BrokerConfig::from_host("127.0.0.1:19993");
 
-            println!(
-                "Connection loop ended for {mqtt_host}. Recreating MQTT client and reconnecting."
-            );
-            std::thread::sleep(std::time::Duration::from_millis(RECONNECT_BACKOFF_MS));
+        // Nothing listens on this port, so the task keeps backing off and retrying
+        connect_to_broker(
+            &broker_config,
+            &peer_map,
+            &mqtt_map,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+        let cancel = mqtt::get_broker(&mqtt_map, "127.0.0.1:19993")
+            .unwrap()
+            .cancellation();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(runtime.metrics().num_alive_tasks(), 1);
 
-            let (new_client, new_connection) = mqtt::connect_to_mqtt_host(broker_config);
-            {
-                let mut mqtt_lock = mqtt_map.lock().unwrap();
-                let Some(broker) = mqtt_lock.get_mut(mqtt_host) else {
-                    break;
-                };
-                broker.client = new_client;
-                broker.connected = false;
-            }
-            connection = new_connection;
+        remove_broker("127.0.0.1:19993", &peer_map, &mqtt_map);
+        assert!(cancel.is_cancelled());
+
+        // The task must end well before the next reconnect attempt
+        let start = std::time::Instant::now();
+        while runtime.metrics().num_alive_tasks() > 0
+            && start.elapsed() < std::time::Duration::from_millis(RECONNECT_BACKOFF_MS / 2)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
         }
+        assert_eq!(runtime.metrics().num_alive_tasks(), 0);
     }
-}
 
-fn remove_broker(mqtt_host: &str, peer_map: &websocket::PeerMap, mqtt_map: &mqtt::BrokerMap) {
-    let mut mqtt_lock = mqtt_map.lock().unwrap();
+    // --- connect_to_known_brokers ---
 
-    if let Some(broker) = mqtt_lock.get(mqtt_host) {
-        println!("Removing MQTT-Client for {mqtt_host}");
+    #[test]
+    fn test_connect_to_known_brokers_empty_file() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        // Nonexistent file should result in 0 brokers being connected
+        connect_to_known_brokers(
+            "/nonexistent/brokers.json",
+            &peer_map,
+            &mqtt_map,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+        // Give threads a moment
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(mqtt_map.read().unwrap().len(), 0);
+    }
 
-        if let Err(err) = broker.client.clone().disconnect() {
-            println!("Error disconnecting MQTT client: {err:?}");
-        }
+    // --- Concurrency stress test ---
 
-        mqtt_lock.remove(mqtt_host);
-        drop(mqtt_lock);
-        peer_map
-            .lock()
-            .unwrap()
-            .iter_mut()
-            .for_each(|(_addr, peer)| {
-                if peer.selected_broker.as_deref() == Some(mqtt_host) {
-                    peer.selected_broker = None;
-                    peer.subscribed_topics.clear();
-                }
-                peer.authenticated_brokers.remove(mqtt_host);
+    #[test]
+    fn test_export_and_import_config_rpcs() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&config_path).ok();
+        let mut protected = config::BrokerConfig::from_host("secure:8883");
+        protected.password = Some("secret".to_string());
+        config::add_to_brokers(&format!("{config_path}/brokers.json"), &protected).unwrap();
+        let process = |json: &str| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
 
-                let message = jsonrpc::JsonRpcNotification {
-                    jsonrpc: "2.0",
-                    method: "broker_removal",
-                    params: serde_json::json!(mqtt_host),
-                };
+        process(r#"{"jsonrpc":"2.0","method":"export_config","params":{}}"#);
+        let exported = received(&mut rx, "config_bundle").unwrap();
+        assert_eq!(exported["secrets_stripped"], true);
+        assert!(exported["brokers"][0].get("password").is_none());
+
+        let import = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "import_config",
+            "params": {
+                "bundle": {
+                    "version": bundle::BUNDLE_VERSION,
+                    "commands": [{ "name": "imported", "topic": "t", "payload": "p" }],
+                },
+            },
+        });
+        process(&import.to_string());
+        let commands = received(&mut rx, "commands").unwrap();
+        assert_eq!(commands[0]["name"], "imported");
+        let result = received(&mut rx, "config_import_result").unwrap();
+        assert_eq!(result["success"], true);
+        assert_eq!(result["report"]["imported"][0], "command/imported");
 
-                if let Ok(serialized) = serde_json::to_string(&message) {
-                    match peer
-                        .tx
-                        .try_send(warp::filters::ws::Message::text(serialized))
-                    {
-                        Ok(_) => {}
-                        Err(err) if err.is_disconnected() => {
-                            println!("Error sending message: {err:?}")
-                        }
-                        Err(_) => {}
-                    }
-                } else {
-                    println!("Failed to serialize brokers.");
-                }
-            });
-    } else {
-        println!("No MQTT-Client for {mqtt_host} found.");
+        std::fs::remove_dir_all(&config_path).ok();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures_channel::mpsc::channel;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-    use std::sync::Mutex;
-    use websocket::PEER_CHANNEL_CAPACITY;
+    #[test]
+    fn test_process_execute_command_requires_auth() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("127.0.0.1:18843"));
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        config::add_to_commands(
+            &format!("{config_path}/commands"),
+            serde_json::json!({ "name": "ping", "topic": "ping/{{counter}}", "payload": "{{who}}", "template": true, "variables": { "who": "me" } }),
+        ).unwrap();
+        let services = services::Services::default();
+        let json = r#"{"jsonrpc":"2.0","method":"execute_command","params":{"name":"ping","broker":"127.0.0.1:18843","variables":{"who":"you"}}}"#;
+        let process = || {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
+
+        process();
+        assert!(received(&mut rx, "command_executed").is_none());
+
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("127.0.0.1:18843".to_string());
+        process();
+        let result = received(&mut rx, "command_executed").unwrap();
+        assert_eq!(result["topic"], "ping/1");
+        assert_eq!(result["payload"], "you");
+        assert_eq!(result["success"], false);
 
-    fn make_peer_map() -> websocket::PeerMap {
-        websocket::PeerMap::new(Mutex::new(HashMap::new()))
+        std::fs::remove_dir_all(&config_path).ok();
     }
 
-    fn make_mqtt_map() -> mqtt::BrokerMap {
-        mqtt::BrokerMap::new(Mutex::new(HashMap::new()))
-    }
+    #[test]
+    fn test_concurrent_process_commands_no_deadlock() {
+        use std::sync::Arc;
+        use std::thread;
 
-    fn make_notification_buf() -> websocket::NotificationBuf {
-        websocket::NotificationBuf::new(Mutex::new(websocket::NotificationBuffer::default()))
-    }
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let commands_path = format!("{}/commands", config_path);
+        std::fs::create_dir_all(&commands_path).ok();
 
-    fn make_addr(port: u16) -> SocketAddr {
-        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+        // Insert some peers to receive broadcasts
+        let mut _receivers = Vec::new();
+        for port in 9001..9004 {
+            let (_addr, rx) = insert_peer(&peer_map, port);
+            _receivers.push(rx);
+        }
+
+        let pm = Arc::clone(&peer_map);
+        let mm = Arc::clone(&mqtt_map);
+        let cp = config_path.clone();
+
+        let handles: Vec<_> = (0..5)
+            .map(|i| {
+                let pm = Arc::clone(&pm);
+                let mm = Arc::clone(&mm);
+                let cp = cp.clone();
+                thread::spawn(move || {
+                    let json = format!(
+                        r#"{{"jsonrpc":"2.0","method":"save_command","params":{{"name":"cmd_{}","topic":"t","payload":"p"}}}}"#,
+                        i
+                    );
+                    deserialize_json_rpc_and_process(
+                        &json,
+                        &pm,
+                        &mm,
+                        &cp,
+                        None,
+                        &make_notification_buf(),
+                        &services::Services::default(),
+                    );
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        std::fs::remove_dir_all(&config_path).ok();
     }
 
-    fn insert_peer(
-        peer_map: &websocket::PeerMap,
-        port: u16,
-    ) -> (
-        SocketAddr,
-        futures_channel::mpsc::Receiver<warp::filters::ws::Message>,
-    ) {
-        let addr = make_addr(port);
-        let (tx, rx) = channel(PEER_CHANNEL_CAPACITY);
-        peer_map
-            .lock()
-            .unwrap()
-            .insert(addr, websocket::PeerConnection::new(tx));
-        (addr, rx)
+    // --- Missing/invalid params edge cases ---
+
+    #[test]
+    fn test_process_connect_missing_hostname() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        // "hostname" key is absent
+        let json = r#"{"jsonrpc":"2.0","method":"connect","params":{}}"#;
+        deserialize_json_rpc_and_process(
+            json,
+            &peer_map,
+            &mqtt_map,
+            "/tmp",
+            None,
+            &make_notification_buf(),
+            &services::Services::default(),
+        );
+        // Should not panic, no broker added
+        assert_eq!(mqtt_map.read().unwrap().len(), 0);
     }
 
-    // --- deserialize_json_rpc_and_process ---
+    #[test]
+    fn test_process_connect_invalid_hostname_reports_error() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        for hostname in ["nohost", "a:port", "a:0"] {
+            let json = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "connect",
+                "params": { "hostname": hostname },
+            })
+            .to_string();
+            deserialize_json_rpc_and_process(
+                &json,
+                &peer_map,
+                &mqtt_map,
+                "/tmp",
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            );
+            let result = received(&mut rx, "save_result").unwrap();
+            assert_eq!(result["success"], false);
+            assert_eq!(
+                result["error"],
+                format!("invalid host {hostname:?}, expected host:port")
+            );
+        }
+        assert_eq!(mqtt_map.read().unwrap().len(), 0);
+        // The map lock is not poisoned
+        assert!(mqtt_map.write().is_ok());
+    }
 
     #[test]
-    fn test_process_invalid_json() {
+    fn test_process_connect_hostname_not_string() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        // Should not panic
+        let json = r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname": 42}}"#;
         deserialize_json_rpc_and_process(
-            "not json",
+            json,
             &peer_map,
             &mqtt_map,
             "/tmp",
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
+        assert_eq!(mqtt_map.read().unwrap().len(), 0);
     }
 
     #[test]
-    fn test_process_unknown_method() {
+    fn test_process_remove_missing_hostname() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let json = r#"{"jsonrpc":"2.0","method":"unknown_method","params":{}}"#;
-        // Should not panic
+        let json = r#"{"jsonrpc":"2.0","method":"remove","params":{}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
@@ -795,15 +3100,16 @@ mod tests {
             "/tmp",
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
+        // No panic
     }
 
     #[test]
-    fn test_process_publish() {
+    fn test_process_publish_missing_host() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let json = r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"nonexistent:1883","topic":"test","payload":"hello"}}"#;
-        // Should not panic (broker doesn't exist, but it's handled gracefully)
+        let json = r#"{"jsonrpc":"2.0","method":"publish","params":{"topic":"t","payload":"p"}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
@@ -811,59 +3117,52 @@ mod tests {
             "/tmp",
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
     }
 
     #[test]
-    fn test_process_connect_spawns_thread() {
+    fn test_process_publish_missing_topic() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        std::fs::create_dir_all(&config_path).ok();
         let json =
-            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19999"}}"#;
-
+            r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"h:1883","payload":"p"}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
             &mqtt_map,
-            &config_path,
+            "/tmp",
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
-
-        // Give the spawned thread a moment to take the lock
-        std::thread::sleep(std::time::Duration::from_millis(100));
-
-        // The broker should appear in the mqtt_map
-        let map = mqtt_map.lock().unwrap();
-        assert!(map.contains_key("127.0.0.1:19999"));
-        drop(map);
-
-        // Cleanup
-        std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_connect_duplicate_broker() {
+    fn test_process_publish_missing_payload() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        std::fs::create_dir_all(&config_path).ok();
-
-        let json =
-            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19998"}}"#;
+        let json = r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"h:1883","topic":"t"}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
             &mqtt_map,
-            &config_path,
+            "/tmp",
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
 
-        // Connect again — should not duplicate
+    #[test]
+    fn test_process_remove_command_missing_name() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let commands_path = format!("{}/commands", config_path);
+        std::fs::create_dir_all(&commands_path).ok();
+
+        let json = r#"{"jsonrpc":"2.0","method":"remove_command","params":{}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
@@ -871,24 +3170,22 @@ mod tests {
             &config_path,
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
-        std::thread::sleep(std::time::Duration::from_millis(100));
-
-        assert_eq!(mqtt_map.lock().unwrap().len(), 1);
+        // Should not panic
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_remove_nonexistent_broker() {
+    fn test_process_remove_pipeline_missing_name() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        std::fs::create_dir_all(&config_path).ok();
+        let pipelines_path = format!("{}/pipelines", config_path);
+        std::fs::create_dir_all(&pipelines_path).ok();
 
-        let json =
-            r#"{"jsonrpc":"2.0","method":"remove","params":{"hostname":"nonexistent:1883"}}"#;
-        // Should not panic
+        let json = r#"{"jsonrpc":"2.0","method":"remove_pipeline","params":{}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
@@ -896,417 +3193,828 @@ mod tests {
             &config_path,
             None,
             &make_notification_buf(),
+            &services::Services::default(),
         );
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_save_and_remove_command() {
+    fn test_process_subscribe_topic_updates_peer() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        let commands_path = format!("{}/commands", config_path);
-        std::fs::create_dir_all(&commands_path).ok();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
 
-        // Save a command
-        let save_json = r#"{"jsonrpc":"2.0","method":"save_command","params":{"name":"test_cmd","topic":"t","payload":"p"}}"#;
+        let json = r#"{"jsonrpc":"2.0","method":"subscribe_topic","params":{"broker":"b:1883","topic":"t/1"}}"#;
         deserialize_json_rpc_and_process(
-            save_json,
+            json,
             &peer_map,
             &mqtt_map,
-            &config_path,
-            None,
+            "/tmp",
+            Some(addr),
             &make_notification_buf(),
+            &services::Services::default(),
         );
 
-        let cmd_file = format!("{}/test_cmd.json", commands_path);
-        assert!(std::path::Path::new(&cmd_file).exists());
+        // Should receive topic_sync_complete (no clear anymore)
+        let msg1 = rx.try_recv().unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(msg1.to_str().unwrap()).unwrap();
+        assert_eq!(parsed["method"], "topic_sync_complete");
+        assert_eq!(parsed["params"]["topic"], "t/1");
 
-        // Remove the command
-        let remove_json =
-            r#"{"jsonrpc":"2.0","method":"remove_command","params":{"name":"test_cmd"}}"#;
+        // Peer should have the topic subscribed
+        let peers = peer_map.lock().unwrap();
+        let peer = peers.get(&addr).unwrap();
+        assert_eq!(peer.selected_broker.as_deref(), Some("b:1883"));
+        assert!(peer.subscribed_topics.contains("t/1"));
+    }
+
+    #[test]
+    fn test_process_unsubscribe_topic() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+
+        for method in ["subscribe_topic", "unsubscribe_topic"] {
+            let json = format!(
+                r#"{{"jsonrpc":"2.0","method":"{method}","params":{{"broker":"b:1883","topic":"t/1"}}}}"#
+            );
+            deserialize_json_rpc_and_process(
+                &json,
+                &peer_map,
+                &mqtt_map,
+                "/tmp",
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            );
+        }
+
+        let peers = peer_map.lock().unwrap();
+        let peer = peers.get(&addr).unwrap();
+        assert!(!peer.subscribed_topics.contains("t/1"));
+    }
+
+    #[test]
+    fn test_process_subscribe_topic_without_addr_is_noop() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+
+        // addr = None → should skip subscribe_topic entirely
+        let json = r#"{"jsonrpc":"2.0","method":"subscribe_topic","params":{"broker":"b:1883","topic":"t/1"}}"#;
         deserialize_json_rpc_and_process(
-            remove_json,
+            json,
             &peer_map,
             &mqtt_map,
-            &config_path,
+            "/tmp",
             None,
             &make_notification_buf(),
+            &services::Services::default(),
+        );
+        // No panic, no state change
+    }
+
+    #[test]
+    fn test_process_save_schedule_requires_auth() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"));
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let services = services::Services::default();
+        let json = r#"{"jsonrpc":"2.0","method":"save_schedule","params":{"name":"hb","action":{"type":"publish","broker":"a:1883","topic":"hb"},"schedule":{"type":"interval","every_secs":5}}}"#;
+        let process = || {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
+
+        process();
+        assert!(scheduler::get_jobs(&format!("{config_path}/schedules")).is_empty());
+
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        process();
+        assert_eq!(
+            services.scheduler.lock().unwrap().summaries()[0]["name"],
+            "hb"
         );
-
-        assert!(!std::path::Path::new(&cmd_file).exists());
+        let schedules = received(&mut rx, "schedules").unwrap();
+        assert_eq!(schedules[0]["name"], "hb");
+        assert!(schedules[0]["next_run"].is_string());
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_save_and_remove_pipeline() {
+    fn test_process_schedules_authorize_against_stored_schedule() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"))
+            .set_requires_auth(true);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("b:1883"));
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        let pipelines_path = format!("{}/pipelines", config_path);
-        std::fs::create_dir_all(&pipelines_path).ok();
-
-        let save_json = r#"{"jsonrpc":"2.0","method":"save_pipeline","params":{"name":"test_pipe","pipeline":[{"topic":"t1"}]}}"#;
-        deserialize_json_rpc_and_process(
-            save_json,
-            &peer_map,
-            &mqtt_map,
-            &config_path,
-            None,
-            &make_notification_buf(),
-        );
-
-        let pipe_file = format!("{}/test_pipe.json", pipelines_path);
-        assert!(std::path::Path::new(&pipe_file).exists());
+        let schedules_path = format!("{config_path}/schedules");
+        let services = services::Services::default();
+        // Runs a command that was removed since, so its broker is unknown
+        let stored = scheduler::parse_job(serde_json::json!({
+            "name": "hb",
+            "action": {"type": "command", "command": "gone"},
+            "schedule": {"type": "interval", "every_secs": 5},
+        }))
+        .unwrap();
+        assert!(scheduler::add_to_jobs(&schedules_path, &stored));
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("b:1883".to_string());
+        let process = |method: &str, params: serde_json::Value| {
+            let json = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params});
+            deserialize_json_rpc_and_process(
+                &json.to_string(),
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
 
-        let remove_json =
-            r#"{"jsonrpc":"2.0","method":"remove_pipeline","params":{"name":"test_pipe"}}"#;
-        deserialize_json_rpc_and_process(
-            remove_json,
-            &peer_map,
-            &mqtt_map,
-            &config_path,
-            None,
-            &make_notification_buf(),
+        process(
+            "save_schedule",
+            serde_json::json!({
+                "name": "hb",
+                "action": {"type": "publish", "broker": "b:1883", "topic": "hb"},
+                "schedule": {"type": "interval", "every_secs": 5},
+            }),
         );
+        process("stop_schedule", serde_json::json!({"name": "hb"}));
+        process("remove_schedule", serde_json::json!({"name": "hb"}));
+        assert_eq!(scheduler::get_jobs(&schedules_path), vec![stored]);
 
-        assert!(!std::path::Path::new(&pipe_file).exists());
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        process("remove_schedule", serde_json::json!({"name": "hb"}));
+        assert!(scheduler::get_jobs(&schedules_path).is_empty());
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_connect_broadcasts_brokers_to_peers() {
+    fn test_process_save_and_run_scenario() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let (_addr, mut rx) = insert_peer(&peer_map, 9001);
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"));
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        std::fs::create_dir_all(&config_path).ok();
+        let services = services::Services::default();
+        let process = |json: &str| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
+        let save = r#"{"jsonrpc":"2.0","method":"save_scenario","params":{"name":"door","broker":"a:1883","steps":[{"type":"wait_for","filter":"door/state"}]}}"#;
 
-        let json =
-            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19997"}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            &config_path,
-            None,
-            &make_notification_buf(),
-        );
+        process(save);
+        assert!(scenarios::get_scenarios(&format!("{config_path}/scenarios")).is_empty());
 
-        // Should have received a broadcast_brokers message, even if a status
-        // notification arrived first.
-        let mut methods = Vec::new();
-        while let Ok(msg) = rx.try_recv() {
-            let parsed: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
-            if let Some(method) = parsed["method"].as_str() {
-                methods.push(method.to_string());
-            }
-        }
-        assert!(methods.contains(&"mqtt_brokers".to_string()));
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        process(save);
+        let saved = received(&mut rx, "scenarios").unwrap();
+        assert_eq!(saved[0]["name"], "door");
+        assert_eq!(saved[0]["steps"][0]["timeout_ms"], 5000);
+
+        process(r#"{"jsonrpc":"2.0","method":"run_scenario","params":{"name":"missing"}}"#);
+        let result = received(&mut rx, "scenario_result").unwrap();
+        assert_eq!(result["success"], false);
+        assert_eq!(result["error"], "Scenario missing not found");
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_remove_broker_sends_removal_notification() {
+    fn test_process_scenarios_authorize_against_stored_scenario() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let (_addr, mut rx) = insert_peer(&peer_map, 9001);
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"));
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("b:1883"));
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        std::fs::create_dir_all(&config_path).ok();
+        let scenarios_path = format!("{config_path}/scenarios");
+        let services = services::Services::default();
+        let stored = scenarios::parse_scenario(serde_json::json!({
+            "name": "door",
+            "broker": "a:1883",
+            "steps": [{"type": "wait_for", "filter": "door/state"}],
+        }))
+        .unwrap();
+        assert!(scenarios::add_to_scenarios(&scenarios_path, &stored));
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("b:1883".to_string());
+        let process = |method: &str, params: serde_json::Value| {
+            let json = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params});
+            deserialize_json_rpc_and_process(
+                &json.to_string(),
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
 
-        // First connect
-        let connect_json =
-            r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname":"127.0.0.1:19996"}}"#;
-        deserialize_json_rpc_and_process(
-            connect_json,
-            &peer_map,
-            &mqtt_map,
-            &config_path,
-            None,
-            &make_notification_buf(),
+        process(
+            "save_scenario",
+            serde_json::json!({
+                "name": "door",
+                "broker": "b:1883",
+                "steps": [{"type": "wait_for", "filter": "door/state"}],
+            }),
         );
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        process("remove_scenario", serde_json::json!({"name": "door"}));
+        let kept = scenarios::get_scenario(&scenarios_path, "door").unwrap();
+        assert_eq!(kept.broker.as_deref(), Some("a:1883"));
 
-        // Drain the connect broadcast
-        while rx.try_recv().is_ok() {}
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        process("remove_scenario", serde_json::json!({"name": "door"}));
+        assert!(scenarios::get_scenarios(&scenarios_path).is_empty());
 
-        // Now remove
-        let remove_json =
-            r#"{"jsonrpc":"2.0","method":"remove","params":{"hostname":"127.0.0.1:19996"}}"#;
+        std::fs::remove_dir_all(&config_path).ok();
+    }
+
+    #[test]
+    fn test_process_save_bridge_requires_known_brokers() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let services = services::Services::default();
+
+        let json = r#"{"jsonrpc":"2.0","method":"save_bridge","params":{"name":"b","source":"a:1883","destination":"b:1883"}}"#;
         deserialize_json_rpc_and_process(
-            remove_json,
+            json,
             &peer_map,
             &mqtt_map,
             &config_path,
-            None,
+            Some(addr),
             &make_notification_buf(),
+            &services,
         );
 
-        // Should receive broker_removal and broadcast_brokers messages
-        let mut methods = Vec::new();
-        while let Ok(msg) = rx.try_recv() {
-            if let Ok(text) = msg.to_str() {
-                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(text) {
-                    if let Some(method) = parsed["method"].as_str() {
-                        methods.push(method.to_string());
-                    }
-                }
-            }
-        }
-        assert!(methods.contains(&"broker_removal".to_string()));
-
-        assert!(!mqtt_map.lock().unwrap().contains_key("127.0.0.1:19996"));
-
-        std::fs::remove_dir_all(&config_path).ok();
+        assert!(!std::path::Path::new(&format!("{config_path}/bridges/b.json")).exists());
+        assert!(services.bridges.lock().unwrap().get("b").is_none());
     }
 
-    // --- connect_to_known_brokers ---
-
     #[test]
-    fn test_connect_to_known_brokers_empty_file() {
+    fn test_process_save_bridge_requires_auth_for_replaced_bridge() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        // Nonexistent file should result in 0 brokers being connected
-        connect_to_known_brokers(
-            "/nonexistent/brokers.json",
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        for host in ["a:1883", "b:1883", "c:1883"] {
+            mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new(host));
+        }
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let bridges_path = format!("{config_path}/bridges");
+        let services = services::Services::default();
+        let existing = bridges::parse_bridge(
+            serde_json::json!({"name": "b", "source": "a:1883", "destination": "b:1883"}),
+        )
+        .unwrap();
+        assert!(bridges::add_to_bridges(&bridges_path, &existing));
+        for host in ["b:1883", "c:1883"] {
+            peer_map
+                .lock()
+                .unwrap()
+                .get_mut(&addr)
+                .unwrap()
+                .authenticated_brokers
+                .insert(host.to_string());
+        }
+
+        // Authenticated for the new brokers but not the old source
+        let json = r#"{"jsonrpc":"2.0","method":"save_bridge","params":{"name":"b","source":"c:1883","destination":"b:1883"}}"#;
+        deserialize_json_rpc_and_process(
+            json,
             &peer_map,
             &mqtt_map,
+            &config_path,
+            Some(addr),
             &make_notification_buf(),
+            &services,
         );
-        // Give threads a moment
-        std::thread::sleep(std::time::Duration::from_millis(50));
-        assert_eq!(mqtt_map.lock().unwrap().len(), 0);
-    }
 
-    // --- Concurrency stress test ---
+        assert_eq!(bridges::get_bridges(&bridges_path)[0].source, "a:1883");
+        std::fs::remove_dir_all(&config_path).ok();
+    }
 
     #[test]
-    fn test_concurrent_process_commands_no_deadlock() {
-        use std::sync::Arc;
-        use std::thread;
-
+    fn test_process_alert_rules_require_auth() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"))
+            .set_requires_auth(true);
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        let commands_path = format!("{}/commands", config_path);
-        std::fs::create_dir_all(&commands_path).ok();
-
-        // Insert some peers to receive broadcasts
-        let mut _receivers = Vec::new();
-        for port in 9001..9004 {
-            let (_addr, rx) = insert_peer(&peer_map, port);
-            _receivers.push(rx);
-        }
-
-        let pm = Arc::clone(&peer_map);
-        let mm = Arc::clone(&mqtt_map);
-        let cp = config_path.clone();
-
-        let handles: Vec<_> = (0..5)
-            .map(|i| {
-                let pm = Arc::clone(&pm);
-                let mm = Arc::clone(&mm);
-                let cp = cp.clone();
-                thread::spawn(move || {
-                    let json = format!(
-                        r#"{{"jsonrpc":"2.0","method":"save_command","params":{{"name":"cmd_{}","topic":"t","payload":"p"}}}}"#,
-                        i
-                    );
-                    deserialize_json_rpc_and_process(&json, &pm, &mm, &cp, None, &make_notification_buf());
-                })
+        let rule_path = format!("{config_path}/alerts/r.json");
+        let services = services::Services::default();
+        let process = |method: &str, params: serde_json::Value| {
+            let json = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params});
+            deserialize_json_rpc_and_process(
+                &json.to_string(),
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
+        let rule = |broker: Option<&str>| {
+            serde_json::json!({
+                "name": "r",
+                "broker": broker,
+                "condition": {"type": "topic_match", "filter": "alarm/#"},
             })
-            .collect();
+        };
+        let set_authenticated = |authenticated: bool| {
+            let mut peers = peer_map.lock().unwrap();
+            let brokers = &mut peers.get_mut(&addr).unwrap().authenticated_brokers;
+            if authenticated {
+                brokers.insert("a:1883".to_string());
+            } else {
+                brokers.remove("a:1883");
+            }
+        };
 
-        for h in handles {
-            h.join().unwrap();
-        }
+        // Scoped to the protected broker, or to all brokers
+        process("save_alert_rule", rule(Some("a:1883")));
+        process("save_alert_rule", rule(None));
+        assert!(!std::path::Path::new(&rule_path).exists());
+
+        set_authenticated(true);
+        process("save_alert_rule", rule(Some("a:1883")));
+        assert!(std::path::Path::new(&rule_path).exists());
 
+        // A firing alert of the protected broker
+        services
+            .alerts
+            .lock()
+            .unwrap()
+            .on_message("a:1883", "alarm/1", b"", 1);
+        let id = services.alerts.lock().unwrap().alerts(|_| true)[0]
+            .id
+            .clone();
+
+        set_authenticated(false);
+        process("acknowledge_alert", serde_json::json!({"id": id}));
+        assert!(!services.alerts.lock().unwrap().alerts(|_| true)[0].acknowledged);
+        process("remove_alert_rule", serde_json::json!({"name": "r"}));
+        assert!(std::path::Path::new(&rule_path).exists());
+
+        set_authenticated(true);
+        process("acknowledge_alert", serde_json::json!({"id": id}));
+        assert!(services.alerts.lock().unwrap().alerts(|_| true)[0].acknowledged);
+        process("remove_alert_rule", serde_json::json!({"name": "r"}));
+        assert!(!std::path::Path::new(&rule_path).exists());
         std::fs::remove_dir_all(&config_path).ok();
     }
 
-    // --- Missing/invalid params edge cases ---
-
     #[test]
-    fn test_process_connect_missing_hostname() {
+    fn test_process_webhooks_require_auth() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        // "hostname" key is absent
-        let json = r#"{"jsonrpc":"2.0","method":"connect","params":{}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
-        );
-        // Should not panic, no broker added
-        assert_eq!(mqtt_map.lock().unwrap().len(), 0);
-    }
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("open:1883"));
+        let protected = mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"));
+        protected.set_requires_auth(true);
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("open:1883".to_string());
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let webhook_path = format!("{config_path}/webhooks/w.json");
+        let process = |method: &str, params: serde_json::Value| {
+            let json = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params});
+            deserialize_json_rpc_and_process(
+                &json.to_string(),
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
+        let webhook = |broker: Option<&str>| serde_json::json!({"name": "w", "url": "http://localhost:9/hook", "broker": broker});
 
-    #[test]
-    fn test_process_connect_hostname_not_string() {
-        let peer_map = make_peer_map();
-        let mqtt_map = make_mqtt_map();
-        let json = r#"{"jsonrpc":"2.0","method":"connect","params":{"hostname": 42}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
+        // Unscoped webhooks forward the protected broker's messages too
+        process("save_webhook", webhook(None));
+        process("save_webhook", webhook(Some("a:1883")));
+        assert!(!std::path::Path::new(&webhook_path).exists());
+
+        process("save_webhook", webhook(Some("open:1883")));
+        assert!(std::path::Path::new(&webhook_path).exists());
+
+        // Authenticated for every protected broker
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        process("save_webhook", webhook(None));
+        assert!(
+            webhooks::get_webhooks(&format!("{config_path}/webhooks"))[0]
+                .broker
+                .is_none()
         );
-        assert_eq!(mqtt_map.lock().unwrap().len(), 0);
-    }
 
-    #[test]
-    fn test_process_remove_missing_hostname() {
-        let peer_map = make_peer_map();
-        let mqtt_map = make_mqtt_map();
-        let json = r#"{"jsonrpc":"2.0","method":"remove","params":{}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
+        // Taking over or removing it needs that access
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .remove("a:1883");
+        process("save_webhook", webhook(Some("open:1883")));
+        process("remove_webhook", serde_json::json!({"name": "w"}));
+        assert!(
+            webhooks::get_webhooks(&format!("{config_path}/webhooks"))[0]
+                .broker
+                .is_none()
         );
-        // No panic
+        std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_publish_missing_host() {
+    fn test_process_retention_rules_require_auth() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let json = r#"{"jsonrpc":"2.0","method":"publish","params":{"topic":"t","payload":"p"}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
-        );
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"))
+            .set_requires_auth(true);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let rule_path = format!("{config_path}/retention/r.json");
+        let services = services::Services::default();
+        let process = |method: &str, params: serde_json::Value| {
+            let json = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params});
+            deserialize_json_rpc_and_process(
+                &json.to_string(),
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
+        let rule = serde_json::json!({"name": "r", "broker": "a:1883", "never_store": true});
+
+        process("save_retention_rule", rule.clone());
+        assert!(!std::path::Path::new(&rule_path).exists());
+        assert!(services.retention.lock().unwrap().rules().is_empty());
+
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("a:1883".to_string());
+        process("save_retention_rule", rule);
+        assert_eq!(services.retention.lock().unwrap().rules().len(), 1);
+
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .remove("a:1883");
+        process("remove_retention_rule", serde_json::json!({"name": "r"}));
+        assert!(std::path::Path::new(&rule_path).exists());
+        std::fs::remove_dir_all(&config_path).ok();
     }
 
-    #[test]
-    fn test_process_publish_missing_topic() {
-        let peer_map = make_peer_map();
-        let mqtt_map = make_mqtt_map();
-        let json =
-            r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"h:1883","payload":"p"}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
-        );
+    fn received(
+        rx: &mut futures_channel::mpsc::Receiver<warp::filters::ws::Message>,
+        method: &str,
+    ) -> Option<serde_json::Value> {
+        while let Ok(msg) = rx.try_recv() {
+            let parsed: serde_json::Value = serde_json::from_str(msg.to_str().ok()?).ok()?;
+            if parsed["method"] == method {
+                return Some(parsed["params"].clone());
+            }
+        }
+        None
     }
 
     #[test]
-    fn test_process_publish_missing_payload() {
+    fn test_process_save_command_with_stale_revision_reports_conflict() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let json = r#"{"jsonrpc":"2.0","method":"publish","params":{"host":"h:1883","topic":"t"}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+        let process = |json: &str| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"save_command","params":{"name":"cmd","topic":"t","payload":"first","revision":0}}"#,
+        );
+        let result = received(&mut rx, "save_result").unwrap();
+        assert_eq!(result["success"], true);
+        assert_eq!(result["revision"], 1);
+
+        // A second browser saves on top of revision 1, the first one is then outdated
+        process(
+            r#"{"jsonrpc":"2.0","method":"save_command","params":{"name":"cmd","topic":"t","payload":"second","revision":1}}"#,
+        );
+        assert_eq!(received(&mut rx, "save_result").unwrap()["revision"], 2);
+        process(
+            r#"{"jsonrpc":"2.0","method":"save_command","params":{"name":"cmd","topic":"t","payload":"stale","revision":1}}"#,
         );
+        let result = received(&mut rx, "save_result").unwrap();
+        assert_eq!(result["kind"], "command");
+        assert_eq!(result["name"], "cmd");
+        assert_eq!(result["success"], false);
+        assert_eq!(result["conflict"], true);
+        assert_eq!(result["current_revision"], 2);
+        let stored = config::get_command(&format!("{config_path}/commands"), "cmd").unwrap();
+        assert_eq!(stored.payload, "second");
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"remove_command","params":{"name":"cmd","revision":1}}"#,
+        );
+        assert_eq!(received(&mut rx, "save_result").unwrap()["conflict"], true);
+        process(
+            r#"{"jsonrpc":"2.0","method":"remove_command","params":{"name":"cmd","revision":2}}"#,
+        );
+        assert_eq!(received(&mut rx, "save_result").unwrap()["success"], true);
+
+        std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_remove_command_missing_name() {
+    fn test_process_restore_removed_pipeline() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        let commands_path = format!("{}/commands", config_path);
-        std::fs::create_dir_all(&commands_path).ok();
+        let process = |json: &str| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                &config_path,
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
+        process(
+            r#"{"jsonrpc":"2.0","method":"save_pipeline","params":{"name":"p","pipeline":[{"topic":"a"}]}}"#,
+        );
+        process(
+            r#"{"jsonrpc":"2.0","method":"save_pipeline","params":{"name":"p","pipeline":[{"topic":"b"}]}}"#,
+        );
+        process(r#"{"jsonrpc":"2.0","method":"remove_pipeline","params":{"name":"p"}}"#);
 
-        let json = r#"{"jsonrpc":"2.0","method":"remove_command","params":{}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            &config_path,
-            None,
-            &make_notification_buf(),
+        process(r#"{"jsonrpc":"2.0","method":"list_removed","params":{"kind":"pipelines"}}"#);
+        let removed = received(&mut rx, "removed_items").unwrap();
+        assert_eq!(removed["kind"], "pipelines");
+        assert_eq!(removed["items"][0]["name"], "p");
+        assert_eq!(removed["items"][0]["revision"], 2);
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"diff_revisions","params":{"kind":"pipelines","name":"p","from":1,"to":2}}"#,
         );
-        // Should not panic
+        let diff = received(&mut rx, "revision_diff").unwrap();
+        assert_eq!(diff["error"], serde_json::Value::Null);
+        assert_eq!(diff["changes"][0]["path"], "$.pipeline[0].topic");
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"restore_revision","params":{"kind":"pipelines","name":"p","revision":1}}"#,
+        );
+        let result = received(&mut rx, "save_result").unwrap();
+        assert_eq!(result["success"], true);
+        assert_eq!(result["revision"], 4);
+        let pipelines = received(&mut rx, "pipelines").unwrap();
+        assert!(pipelines.to_string().contains(r#""topic":"a""#));
+
+        process(
+            r#"{"jsonrpc":"2.0","method":"list_revisions","params":{"kind":"pipelines","name":"p"}}"#,
+        );
+        let revisions = received(&mut rx, "revisions").unwrap();
+        assert_eq!(revisions["revisions"].as_array().unwrap().len(), 4);
+        assert_eq!(revisions["revisions"][2]["removed"], true);
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_remove_pipeline_missing_name() {
+    fn test_process_validate_config_reports_errors() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
         let config_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
-        let pipelines_path = format!("{}/pipelines", config_path);
-        std::fs::create_dir_all(&pipelines_path).ok();
+        std::fs::create_dir_all(&config_path).unwrap();
+        std::fs::write(
+            format!("{config_path}/brokers.json"),
+            "{\"version\": 1,\n \"brokers\": [{\"hots\": \"a:1883\"}]}",
+        )
+        .unwrap();
 
-        let json = r#"{"jsonrpc":"2.0","method":"remove_pipeline","params":{}}"#;
         deserialize_json_rpc_and_process(
-            json,
+            r#"{"jsonrpc":"2.0","method":"validate_config","params":{}}"#,
             &peer_map,
             &mqtt_map,
             &config_path,
-            None,
+            Some(addr),
             &make_notification_buf(),
+            &services::Services::default(),
         );
+        let validation = received(&mut rx, "config_validation").unwrap();
+        assert_eq!(validation["version"], schema::CONFIG_VERSION);
+        let error = &validation["errors"][0];
+        assert_eq!(error["file"], "brokers.json");
+        assert_eq!(error["line"], 2);
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("unknown field `hots`"));
 
         std::fs::remove_dir_all(&config_path).ok();
     }
 
     #[test]
-    fn test_process_subscribe_topic_updates_peer() {
+    fn test_process_purge_subtree_buffers_evictions() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
-        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        let (addr, _rx) = insert_peer(&peer_map, 9001);
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("127.0.0.1:18842".to_string());
+        let mut broker = mqtt::MqttBroker::new("127.0.0.1:18842");
+        for topic in ["a", "a/1", "a/1", "a/2/x", "b"] {
+            broker.store_message(topic, 1, bytes::Bytes::from("x"), 1, false);
+        }
+        let entry = mqtt::insert_offline_broker(&mqtt_map, broker);
+        let buf = make_notification_buf();
 
-        let json = r#"{"jsonrpc":"2.0","method":"subscribe_topic","params":{"broker":"b:1883","topic":"t/1"}}"#;
+        let json = r#"{"jsonrpc":"2.0","method":"purge_subtree","params":{"broker":"127.0.0.1:18842","topic":"a"}}"#;
         deserialize_json_rpc_and_process(
             json,
             &peer_map,
             &mqtt_map,
             "/tmp",
             Some(addr),
-            &make_notification_buf(),
+            &buf,
+            &services::Services::default(),
         );
 
-        // Should receive topic_sync_complete (no clear anymore)
-        let msg1 = rx.try_recv().unwrap();
-        let parsed: serde_json::Value = serde_json::from_str(msg1.to_str().unwrap()).unwrap();
-        assert_eq!(parsed["method"], "topic_sync_complete");
-        assert_eq!(parsed["params"]["topic"], "t/1");
-
-        // Peer should have the topic subscribed
-        let peers = peer_map.lock().unwrap();
-        let peer = peers.get(&addr).unwrap();
-        assert_eq!(peer.selected_broker.as_deref(), Some("b:1883"));
-        assert!(peer.subscribed_topics.contains("t/1"));
+        let names: Vec<_> = entry.state().topics.names().map(str::to_string).collect();
+        assert_eq!(names, vec!["b"]);
+        assert_eq!(entry.state().total_messages, 1);
+        let mut evictions: Vec<_> = buf
+            .lock()
+            .unwrap()
+            .evictions
+            .iter()
+            .map(|e| (e.topic.clone(), e.count, e.topic_message_count))
+            .collect();
+        evictions.sort();
+        assert_eq!(
+            evictions,
+            vec![
+                ("a".to_string(), 1, 0),
+                ("a/1".to_string(), 2, 0),
+                ("a/2/x".to_string(), 1, 0)
+            ]
+        );
     }
 
     #[test]
-    fn test_process_unsubscribe_topic() {
+    fn test_process_purge_topic_takes_wildcards_literally() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
         let (addr, _rx) = insert_peer(&peer_map, 9001);
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("127.0.0.1:18842".to_string());
+        let mut broker = mqtt::MqttBroker::new("127.0.0.1:18842");
+        for topic in ["a/+", "a/1", "a/2", "b/#", "b/c", "ab"] {
+            broker.store_message(topic, 1, bytes::Bytes::from("x"), 1, false);
+        }
+        let entry = mqtt::insert_offline_broker(&mqtt_map, broker);
+        let process = |method: &str, topic: &str| {
+            let json = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": {"broker": "127.0.0.1:18842", "topic": topic},
+            });
+            deserialize_json_rpc_and_process(
+                &json.to_string(),
+                &peer_map,
+                &mqtt_map,
+                "/tmp",
+                Some(addr),
+                &make_notification_buf(),
+                &services::Services::default(),
+            )
+        };
 
-        for method in ["subscribe_topic", "unsubscribe_topic"] {
+        process("purge_topic", "a/+");
+        process("purge_topic", "b/#");
+        process("purge_subtree", "a/1");
+        let mut names: Vec<_> = entry.state().topics.names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, vec!["a/2", "ab", "b/c"]);
+    }
+
+    #[test]
+    fn test_process_pause_and_resume_capture() {
+        let peer_map = make_peer_map();
+        let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("127.0.0.1:18843".to_string());
+        let entry =
+            mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("127.0.0.1:18843"));
+        let process = |method: &str| {
             let json = format!(
-                r#"{{"jsonrpc":"2.0","method":"{method}","params":{{"broker":"b:1883","topic":"t/1"}}}}"#
+                r#"{{"jsonrpc":"2.0","method":"{method}","params":{{"broker":"127.0.0.1:18843"}}}}"#
             );
             deserialize_json_rpc_and_process(
                 &json,
@@ -1315,85 +4023,116 @@ mod tests {
                 "/tmp",
                 Some(addr),
                 &make_notification_buf(),
-            );
-        }
+                &services::Services::default(),
+            )
+        };
 
-        let peers = peer_map.lock().unwrap();
-        let peer = peers.get(&addr).unwrap();
-        assert!(!peer.subscribed_topics.contains("t/1"));
+        process("pause_capture");
+        assert!(entry.state().paused);
+        let brokers = received(&mut rx, "mqtt_brokers").unwrap();
+        assert_eq!(brokers[0]["paused"], true);
+        assert_eq!(brokers[0]["skipped_messages"], 0);
+
+        process("resume_capture");
+        assert!(!entry.state().paused);
+        let brokers = received(&mut rx, "mqtt_brokers").unwrap();
+        assert_eq!(brokers[0]["paused"], false);
     }
 
     #[test]
-    fn test_process_subscribe_topic_without_addr_is_noop() {
+    fn test_process_clear_retained_requires_confirmation() {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
+        let (addr, mut rx) = insert_peer(&peer_map, 9001);
+        let services = services::Services::default();
+        let mut broker = mqtt::MqttBroker::new("127.0.0.1:18841");
+        for topic in ["a/1", "a/2", "b/1"] {
+            broker.store_message(topic, 1, bytes::Bytes::from("x"), 1, true);
+        }
+        mqtt::insert_offline_broker(&mqtt_map, broker);
+        let process = |json: &str| {
+            deserialize_json_rpc_and_process(
+                json,
+                &peer_map,
+                &mqtt_map,
+                "/tmp",
+                Some(addr),
+                &make_notification_buf(),
+                &services,
+            )
+        };
 
-        // addr = None → should skip subscribe_topic entirely
-        let json = r#"{"jsonrpc":"2.0","method":"subscribe_topic","params":{"broker":"b:1883","topic":"t/1"}}"#;
-        deserialize_json_rpc_and_process(
-            json,
-            &peer_map,
-            &mqtt_map,
-            "/tmp",
-            None,
-            &make_notification_buf(),
+        // Unauthenticated peers get nothing
+        process(
+            r#"{"jsonrpc":"2.0","method":"retained_topics","params":{"broker":"127.0.0.1:18841"}}"#,
         );
-        // No panic, no state change
-    }
+        assert!(received(&mut rx, "retained_topics").is_none());
 
-    #[test]
-    fn test_eviction_keeps_newest_message_per_topic() {
-        let cfg = config::BrokerConfig::from_host("127.0.0.1:18839");
-        let (client, _conn) = mqtt::connect_to_mqtt_host(&cfg);
+        peer_map
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .authenticated_brokers
+            .insert("127.0.0.1:18841".to_string());
+        process(
+            r#"{"jsonrpc":"2.0","method":"retained_topics","params":{"broker":"127.0.0.1:18841","prefix":"a/"}}"#,
+        );
+        let listed = received(&mut rx, "retained_topics").unwrap();
+        assert_eq!(listed["topics"].as_array().unwrap().len(), 2);
 
-        let mut broker = mqtt::MqttBroker {
-            client,
-            broker: "127.0.0.1:18839".to_string(),
-            connected: true,
-            topics: HashMap::new(),
-            total_bytes: mqtt::max_broker_bytes() + 3,
-            total_messages: 3,
-            eviction_order: std::collections::VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: 0,
-            requires_auth: false,
-        };
+        process(
+            r#"{"jsonrpc":"2.0","method":"clear_retained","params":{"broker":"127.0.0.1:18841","topics":["a/2","b/1","c/9"]}}"#,
+        );
+        let preview = received(&mut rx, "retained_clear_preview").unwrap();
+        assert_eq!(preview["topics"], serde_json::json!(["a/2", "b/1"]));
+        let token = preview["token"].as_str().unwrap().to_string();
 
-        let mut topic_a = std::collections::VecDeque::new();
-        topic_a.push_back(mqtt::MqttMessage {
-            timestamp: "2026-01-01T00:00:00Z".to_string(),
-            payload: bytes::Bytes::from_static(b"a-old"),
-            original_payload_size: 5,
-            retain: false,
-        });
-        topic_a.push_back(mqtt::MqttMessage {
-            timestamp: "2026-01-01T00:00:01Z".to_string(),
-            payload: bytes::Bytes::from_static(b"a-new"),
-            original_payload_size: 5,
-            retain: false,
-        });
+        // The broker is offline, so the confirmed topics are reported as failed
+        let confirm = format!(
+            r#"{{"jsonrpc":"2.0","method":"clear_retained","params":{{"broker":"127.0.0.1:18841","token":"{token}"}}}}"#
+        );
+        process(&confirm);
+        let result = received(&mut rx, "retained_clear_result").unwrap();
+        assert_eq!(result["success"], false);
+        assert_eq!(result["failed"].as_array().unwrap().len(), 2);
+        assert!(result["error"].is_null());
+
+        // Tokens can only be used once
+        process(&confirm);
+        let result = received(&mut rx, "retained_clear_result").unwrap();
+        assert_eq!(result["error"], "Invalid or expired confirmation token");
+    }
 
-        let mut topic_b = std::collections::VecDeque::new();
-        topic_b.push_back(mqtt::MqttMessage {
-            timestamp: "2026-01-01T00:00:02Z".to_string(),
-            payload: bytes::Bytes::from_static(b"b-only"),
-            original_payload_size: 6,
-            retain: false,
-        });
+    fn make_test_broker() -> mqtt::MqttBroker {
+        mqtt::MqttBroker::new("127.0.0.1:18839")
+    }
+
+    fn store(broker: &mut mqtt::MqttBroker, topic: &str, timestamp: &str, payload: &'static [u8]) {
+        broker.store_message(
+            topic,
+            mqtt::parse_timestamp_ns(timestamp).unwrap(),
+            bytes::Bytes::from_static(payload),
+            payload.len(),
+            false,
+        );
+    }
 
-        broker.topics.insert("t/a".to_string(), topic_a);
-        broker.topics.insert("t/b".to_string(), topic_b);
-        broker.eviction_order.push_back(("t/a".to_string(), 1));
-        broker.eviction_order.push_back(("t/b".to_string(), 1));
-        broker.eviction_order.push_back(("t/a".to_string(), 1));
+    #[test]
+    fn test_eviction_keeps_newest_message_per_topic() {
+        let mut broker = make_test_broker();
+        store(&mut broker, "t/a", "2026-01-01T00:00:00Z", b"a-old");
+        store(&mut broker, "t/b", "2026-01-01T00:00:02Z", b"b-only");
+        store(&mut broker, "t/a", "2026-01-01T00:00:01Z", b"a-new");
+        // Pretend the broker is over its limit.
+        broker.total_bytes += mqtt::max_broker_bytes();
 
         let evictions = evict_while_preserving_topic_latest(&mut broker);
 
         assert_eq!(broker.topics["t/a"].len(), 1);
         assert_eq!(
-            broker.topics["t/a"].back().unwrap().timestamp,
-            "2026-01-01T00:00:01Z"
+            mqtt::format_timestamp_ns(broker.topics["t/a"].back().unwrap().timestamp_ns),
+            "2026-01-01T00:00:01+00:00"
         );
         assert_eq!(broker.topics["t/b"].len(), 1);
         assert_eq!(broker.total_messages, 2);
@@ -1402,6 +4141,75 @@ mod tests {
             .any(|(topic, count, new_count)| topic == "t/a" && *count == 1 && *new_count == 1));
     }
 
+    #[test]
+    fn test_eviction_skips_entries_removed_by_retention() {
+        let mut broker = make_test_broker();
+        store(&mut broker, "t/a", "2026-01-01T00:00:00Z", b"a1");
+        store(&mut broker, "t/a", "2026-01-01T00:00:01Z", b"a2");
+        store(&mut broker, "t/b", "2026-01-01T00:00:02Z", b"b1");
+        store(&mut broker, "t/b", "2026-01-01T00:00:03Z", b"b2");
+        // Retention already dropped the older t/a message.
+        assert!(broker.remove_oldest("t/a"));
+        broker.total_bytes += mqtt::max_broker_bytes();
+
+        let evictions = evict_while_preserving_topic_latest(&mut broker);
+
+        assert_eq!(evictions, vec![("t/b".to_string(), 1, 1)]);
+        assert_eq!(broker.topics["t/a"][0].payload, &b"a2"[..]);
+        assert_eq!(broker.topics["t/b"][0].payload, &b"b2"[..]);
+        assert_eq!(broker.total_messages, 2);
+    }
+
+    #[test]
+    fn test_eviction_skips_entries_of_recreated_topic() {
+        let mut broker = make_test_broker();
+        store(&mut broker, "t/x", "2026-01-01T00:00:00Z", b"x");
+        store(&mut broker, "t/a", "2026-01-01T00:00:00Z", b"old");
+        assert!(broker.remove_oldest("t/a"));
+        // The topic id is reused; the old queue entry must not evict these.
+        store(&mut broker, "t/a", "2026-01-01T00:00:01Z", b"new1");
+        store(&mut broker, "t/a", "2026-01-01T00:00:02Z", b"new2");
+        assert_eq!(broker.eviction_order.len(), 4);
+        broker.total_bytes += mqtt::max_broker_bytes();
+
+        let evictions = evict_while_preserving_topic_latest(&mut broker);
+
+        assert_eq!(evictions, vec![("t/a".to_string(), 1, 1)]);
+        assert_eq!(broker.topics["t/a"][0].payload, &b"new2"[..]);
+        assert_eq!(broker.eviction_order.len(), 2);
+    }
+
+    #[test]
+    fn test_eviction_after_many_per_topic_removals() {
+        let mut broker = make_test_broker();
+        store(&mut broker, "t/old", "2026-01-01T00:00:00Z", b"o1");
+        store(&mut broker, "t/old", "2026-01-01T00:00:01Z", b"o2");
+        // A per-topic limit keeps t/busy at two messages
+        for i in 0..500 {
+            let payload = bytes::Bytes::from(format!("b{i}"));
+            let len = payload.len();
+            broker.store_message("t/busy", 1_800_000_000_000_000_000 + i, payload, len, false);
+            if broker.topics["t/busy"].len() > 2 {
+                assert!(broker.remove_oldest("t/busy"));
+            }
+        }
+        assert_eq!(broker.total_messages, 4);
+        assert!(broker.eviction_order.len() <= 2 * broker.total_messages);
+        broker.total_bytes += mqtt::max_broker_bytes();
+
+        let mut evictions = evict_while_preserving_topic_latest(&mut broker);
+
+        // Both topics lose their older message and keep the newest one
+        evictions.sort();
+        assert_eq!(
+            evictions,
+            vec![("t/busy".to_string(), 1, 1), ("t/old".to_string(), 1, 1),]
+        );
+        assert_eq!(broker.topics["t/old"][0].payload, &b"o2"[..]);
+        assert_eq!(broker.topics["t/busy"][0].payload, &b"b499"[..]);
+        assert_eq!(broker.eviction_order.len(), 2);
+    }
+
     // ─── Integration: reconnect after broker restart ─────────────────
 
     /// Helper: find a free TCP port for mosquitto.
@@ -1462,22 +4270,24 @@ mod tests {
         let peer_map = make_peer_map();
         let mqtt_map = make_mqtt_map();
         let notification_buf = make_notification_buf();
-        let broker_config = config::BrokerConfig {
-            host: hostname.clone(),
-            use_tls: false,
-            username: None,
-            password: None,
-        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let broker_config = config::BrokerConfig::from_host(&hostname);
 
-        connect_to_broker(&broker_config, &peer_map, &mqtt_map, &notification_buf);
+        connect_to_broker(
+            &broker_config,
+            &peer_map,
+            &mqtt_map,
+            &notification_buf,
+            &services::Services::default(),
+        );
 
         // Wait for the backend to connect and subscribe
         let connected = (|| {
             for _ in 0..50 {
                 std::thread::sleep(std::time::Duration::from_millis(100));
-                let lock = mqtt_map.lock().unwrap();
-                if let Some(b) = lock.get(&hostname) {
-                    if b.connected {
+                if let Some(b) = mqtt::get_broker(&mqtt_map, &hostname) {
+                    if b.is_connected() {
                         return true;
                     }
                 }
@@ -1510,11 +4320,8 @@ mod tests {
                 .unwrap();
             std::thread::sleep(std::time::Duration::from_millis(500));
 
-            let has_before = mqtt_map
-                .lock()
-                .unwrap()
-                .get(&hostname)
-                .map(|b| b.topics.contains_key("test/before"))
+            let has_before = mqtt::get_broker(&mqtt_map, &hostname)
+                .map(|b| b.state().topics.contains_key("test/before"))
                 .unwrap_or(false);
             assert!(has_before, "backend should have received test/before");
             let _ = pub_client.disconnect();
@@ -1528,9 +4335,8 @@ mod tests {
         let disconnected = (|| {
             for _ in 0..100 {
                 std::thread::sleep(std::time::Duration::from_millis(100));
-                let lock = mqtt_map.lock().unwrap();
-                if let Some(b) = lock.get(&hostname) {
-                    if !b.connected {
+                if let Some(b) = mqtt::get_broker(&mqtt_map, &hostname) {
+                    if !b.is_connected() {
                         return true;
                     }
                 }
@@ -1547,9 +4353,8 @@ mod tests {
         let reconnected = (|| {
             for _ in 0..100 {
                 std::thread::sleep(std::time::Duration::from_millis(100));
-                let lock = mqtt_map.lock().unwrap();
-                if let Some(b) = lock.get(&hostname) {
-                    if b.connected {
+                if let Some(b) = mqtt::get_broker(&mqtt_map, &hostname) {
+                    if b.is_connected() {
                         return true;
                     }
                 }
@@ -1585,9 +4390,8 @@ mod tests {
             let received_after = (|| {
                 for _ in 0..50 {
                     std::thread::sleep(std::time::Duration::from_millis(100));
-                    let lock = mqtt_map.lock().unwrap();
-                    if let Some(b) = lock.get(&hostname) {
-                        if b.topics.contains_key("test/after") {
+                    if let Some(b) = mqtt::get_broker(&mqtt_map, &hostname) {
+                        if b.state().topics.contains_key("test/after") {
                             return true;
                         }
                     }
@@ -0,0 +1,644 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::config::{self, BrokerConfig, CommandMessage, PipelineMessage, Revisioned};
+
+use std::collections::HashMap;
+
+/// Format version written to exported bundles. Bundles with a newer version
+/// are rejected instead of being imported partially.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Upper limit for bundles uploaded over HTTP.
+pub const MAX_BUNDLE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Token HTTP imports have to send as `Authorization: Bearer <token>`.
+/// Set via MQTT_INSPECTOR_API_TOKEN. Without it, HTTP imports are disabled.
+pub fn api_token() -> Option<String> {
+    std::env::var("MQTT_INSPECTOR_API_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Check the `Authorization` header of an HTTP import against `token`.
+pub fn authorize_http_import(
+    authorization: Option<&str>,
+    token: Option<&str>,
+) -> Result<(), String> {
+    let Some(token) = token else {
+        return Err(
+            "Imports over HTTP are disabled. Set MQTT_INSPECTOR_API_TOKEN or import over the WebSocket"
+                .to_string(),
+        );
+    };
+    let given = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Compare in constant time so the token can't be guessed byte by byte
+    let matches = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if matches {
+        Ok(())
+    } else {
+        Err("Missing or invalid API token".to_string())
+    }
+}
+
+/// Brokers, commands and pipelines of a config directory in one file.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ConfigBundle {
+    pub version: u32,
+    #[serde(default)]
+    pub exported_at: String,
+    /// Whether broker passwords were left out on export.
+    #[serde(default)]
+    pub secrets_stripped: bool,
+    #[serde(default)]
+    pub brokers: Vec<BrokerConfig>,
+    #[serde(default)]
+    pub commands: Vec<CommandMessage>,
+    #[serde(default)]
+    pub pipelines: Vec<PipelineMessage>,
+}
+
+impl ConfigBundle {
+    fn check_version(&self) -> Result<(), String> {
+        if self.version == 0 || self.version > BUNDLE_VERSION {
+            return Err(format!(
+                "Unsupported bundle version {}, expected at most {BUNDLE_VERSION}",
+                self.version
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    Broker,
+    Command,
+    Pipeline,
+}
+
+impl ItemKind {
+    /// Key of an item in `ImportRequest::choices`, e.g. `command/lights_on`.
+    fn label(self, name: &str) -> String {
+        let kind = match self {
+            ItemKind::Broker => "broker",
+            ItemKind::Command => "command",
+            ItemKind::Pipeline => "pipeline",
+        };
+        format!("{kind}/{name}")
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemStatus {
+    New,
+    Identical,
+    Conflict,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct ImportItem {
+    pub kind: ItemKind,
+    pub name: String,
+    pub status: ItemStatus,
+}
+
+/// What to do with an item that exists with different content.
+///
+/// `Merge` keeps stored values the bundle leaves out (e.g. stripped
+/// passwords) for brokers, and imports commands and pipelines next to the
+/// existing ones under a new name.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportAction {
+    Merge,
+    Overwrite,
+    #[default]
+    Skip,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ImportRequest {
+    pub bundle: ConfigBundle,
+    /// Action per conflicting item, keyed by `kind/name`.
+    #[serde(default)]
+    pub choices: HashMap<String, ImportAction>,
+    /// Action for conflicts without a choice.
+    #[serde(default)]
+    pub default: ImportAction,
+}
+
+/// Outcome per item, as `kind/name` labels.
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub skipped: Vec<String>,
+    /// Password-protected brokers the importer is not authenticated for.
+    pub denied: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Export the config at `config_path`. Broker credentials are only included
+/// where `keep_password` allows it.
+pub fn export_bundle(
+    config_path: &str,
+    keep_password: impl Fn(&BrokerConfig) -> bool,
+) -> ConfigBundle {
+    let mut secrets_stripped = false;
+    let brokers = config::get_known_brokers(&format!("{config_path}/brokers.json"))
+        .into_iter()
+        .map(|mut broker| {
+            if broker.password.is_some() && !keep_password(&broker) {
+                broker.username = None;
+                broker.password = None;
+                secrets_stripped = true;
+            }
+            without_revision(&broker)
+        })
+        .collect();
+    let commands = config::get_commands(&format!("{config_path}/commands")).unwrap_or_default();
+    let pipelines = config::get_pipelines(&format!("{config_path}/pipelines")).unwrap_or_default();
+    ConfigBundle {
+        version: BUNDLE_VERSION,
+        exported_at: chrono::Utc::now().to_rfc3339(),
+        secrets_stripped,
+        brokers,
+        commands: commands.iter().map(without_revision).collect(),
+        pipelines: pipelines.iter().map(without_revision).collect(),
+    }
+}
+
+/// Revisions are local to a config directory, so bundles leave them out and
+/// comparisons ignore them.
+fn without_revision<T: Revisioned + Clone>(item: &T) -> T {
+    let mut item = item.clone();
+    item.set_revision(0);
+    item
+}
+
+fn same_content<T: Revisioned + Clone + PartialEq>(existing: &T, incoming: &T) -> bool {
+    without_revision(existing) == without_revision(incoming)
+}
+
+fn status<T: Revisioned + Clone + PartialEq>(existing: Option<&T>, incoming: &T) -> ItemStatus {
+    match existing {
+        None => ItemStatus::New,
+        Some(existing) if same_content(existing, incoming) => ItemStatus::Identical,
+        Some(_) => ItemStatus::Conflict,
+    }
+}
+
+/// Compare each item of `bundle` with the config at `config_path`.
+pub fn preview_import(config_path: &str, bundle: &ConfigBundle) -> Result<Vec<ImportItem>, String> {
+    bundle.check_version()?;
+    let brokers = config::get_known_brokers(&format!("{config_path}/brokers.json"));
+    let commands = config::get_commands(&format!("{config_path}/commands")).unwrap_or_default();
+    let pipelines = config::get_pipelines(&format!("{config_path}/pipelines")).unwrap_or_default();
+
+    let mut items = Vec::new();
+    for broker in &bundle.brokers {
+        let existing = brokers.iter().find(|b| b.key() == broker.key());
+        items.push(ImportItem {
+            kind: ItemKind::Broker,
+            name: broker.key().to_string(),
+            status: status(existing, broker),
+        });
+    }
+    for command in &bundle.commands {
+        let existing = commands.iter().find(|c| c.name == command.name);
+        items.push(ImportItem {
+            kind: ItemKind::Command,
+            name: command.name.clone(),
+            status: status(existing, command),
+        });
+    }
+    for pipeline in &bundle.pipelines {
+        let existing = pipelines.iter().find(|p| p.name == pipeline.name);
+        items.push(ImportItem {
+            kind: ItemKind::Pipeline,
+            name: pipeline.name.clone(),
+            status: status(existing, pipeline),
+        });
+    }
+    Ok(items)
+}
+
+/// `incoming`, with stored values kept where the bundle has none.
+fn merge_broker(existing: &BrokerConfig, incoming: &BrokerConfig) -> BrokerConfig {
+    let mut merged = incoming.clone();
+    merged.username = incoming.username.clone().or(existing.username.clone());
+    merged.password = incoming.password.clone().or(existing.password.clone());
+    merged.name = incoming.name.clone().or(existing.name.clone());
+    merged.description = incoming
+        .description
+        .clone()
+        .or(existing.description.clone());
+    merged.tags = existing.tags.clone();
+    for tag in &incoming.tags {
+        if !merged.tags.contains(tag) {
+            merged.tags.push(tag.clone());
+        }
+    }
+    merged
+}
+
+/// First of `name_imported`, `name_imported_2`, ... not in `taken`.
+fn free_name(name: &str, taken: &[String]) -> String {
+    let mut candidate = format!("{name}_imported");
+    let mut n = 2;
+    while taken.contains(&candidate) {
+        candidate = format!("{name}_imported_{n}");
+        n += 1;
+    }
+    candidate
+}
+
+/// Import `request.bundle` into the config at `config_path`. New items are
+/// always added; items that differ follow the chosen action. Existing brokers
+/// are only changed where `may_modify_broker` allows it.
+pub fn apply_import(
+    config_path: &str,
+    request: &ImportRequest,
+    may_modify_broker: impl Fn(&BrokerConfig) -> bool,
+) -> Result<ImportReport, String> {
+    let bundle = &request.bundle;
+    bundle.check_version()?;
+    let action = |label: &str| {
+        request
+            .choices
+            .get(label)
+            .copied()
+            .unwrap_or(request.default)
+    };
+    let mut report = ImportReport::default();
+
+    let brokers_path = format!("{config_path}/brokers.json");
+    let lock = config::lock_config();
+    // Never replace an invalid brokers file with just the imported brokers
+    let mut brokers = config::load_brokers(&brokers_path).map_err(|err| err.to_string())?;
+    let mut brokers_changed = false;
+    for incoming in &bundle.brokers {
+        let label = ItemKind::Broker.label(incoming.key());
+        let Some(existing) = brokers.iter_mut().find(|b| b.key() == incoming.key()) else {
+            let mut added = incoming.clone();
+            added.revision = 1;
+            brokers.push(added);
+            brokers_changed = true;
+            report.imported.push(label);
+            continue;
+        };
+        if same_content(existing, incoming) {
+            report.unchanged.push(label);
+            continue;
+        }
+        let replacement = match action(&label) {
+            ImportAction::Skip => {
+                report.skipped.push(label);
+                continue;
+            }
+            _ if !may_modify_broker(existing) => {
+                report.denied.push(label);
+                continue;
+            }
+            ImportAction::Overwrite => incoming.clone(),
+            ImportAction::Merge => merge_broker(existing, incoming),
+        };
+        *existing = BrokerConfig {
+            revision: existing.revision + 1,
+            ..replacement
+        };
+        brokers_changed = true;
+        report.imported.push(label);
+    }
+    if brokers_changed && !config::write_broker_configs(&brokers_path, &brokers) {
+        report.failed.append(&mut report.imported);
+    }
+    drop(lock);
+
+    let commands_path = format!("{config_path}/commands");
+    let existing_commands = config::get_commands(&commands_path).unwrap_or_default();
+    let mut command_names: Vec<String> = existing_commands.iter().map(|c| c.name.clone()).collect();
+    for incoming in &bundle.commands {
+        let label = ItemKind::Command.label(&incoming.name);
+        if let Err(err) = incoming.validate() {
+            println!("Not importing {label}: {err}");
+            report.failed.push(label);
+            continue;
+        }
+        let mut command = incoming.clone();
+        match existing_commands.iter().find(|c| c.name == incoming.name) {
+            Some(existing) if same_content(existing, incoming) => {
+                report.unchanged.push(label);
+                continue;
+            }
+            Some(_) => match action(&label) {
+                ImportAction::Skip => {
+                    report.skipped.push(label);
+                    continue;
+                }
+                ImportAction::Overwrite => {}
+                ImportAction::Merge => command.name = free_name(&command.name, &command_names),
+            },
+            None => {}
+        }
+        let label = ItemKind::Command.label(&command.name);
+        if config::save_command(&commands_path, &command, None).is_ok() {
+            command_names.push(command.name);
+            report.imported.push(label);
+        } else {
+            report.failed.push(label);
+        }
+    }
+
+    let pipelines_path = format!("{config_path}/pipelines");
+    let existing_pipelines = config::get_pipelines(&pipelines_path).unwrap_or_default();
+    let mut pipeline_names: Vec<String> =
+        existing_pipelines.iter().map(|p| p.name.clone()).collect();
+    for incoming in &bundle.pipelines {
+        let label = ItemKind::Pipeline.label(&incoming.name);
+        let mut pipeline = incoming.clone();
+        match existing_pipelines.iter().find(|p| p.name == incoming.name) {
+            Some(existing) if same_content(existing, incoming) => {
+                report.unchanged.push(label);
+                continue;
+            }
+            Some(_) => match action(&label) {
+                ImportAction::Skip => {
+                    report.skipped.push(label);
+                    continue;
+                }
+                ImportAction::Overwrite => {}
+                ImportAction::Merge => pipeline.name = free_name(&pipeline.name, &pipeline_names),
+            },
+            None => {}
+        }
+        let label = ItemKind::Pipeline.label(&pipeline.name);
+        if config::save_pipeline(&pipelines_path, &pipeline, None).is_ok() {
+            pipeline_names.push(pipeline.name);
+            report.imported.push(label);
+        } else {
+            report.failed.push(label);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestConfig {
+        path: String,
+    }
+
+    impl TestConfig {
+        fn new() -> Self {
+            let path = format!("../test/config_bundle_{}", uuid::Uuid::new_v4());
+            copy_dir::copy_dir("../test/config_source", &path).unwrap();
+            Self { path }
+        }
+
+        fn brokers(&self) -> Vec<BrokerConfig> {
+            config::get_known_brokers(&format!("{}/brokers.json", self.path))
+        }
+
+        fn command(&self, name: &str) -> Option<CommandMessage> {
+            config::get_commands(&format!("{}/commands", self.path))
+                .unwrap()
+                .into_iter()
+                .find(|c| c.name == name)
+        }
+    }
+
+    impl Drop for TestConfig {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.path).ok();
+        }
+    }
+
+    fn command(name: &str, payload: &str) -> CommandMessage {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "topic": "test",
+            "payload": payload,
+        }))
+        .unwrap()
+    }
+
+    fn protected_broker(host: &str, password: &str) -> BrokerConfig {
+        BrokerConfig {
+            username: Some("user".to_string()),
+            password: Some(password.to_string()),
+            ..BrokerConfig::from_host(host)
+        }
+    }
+
+    fn request(bundle: ConfigBundle, default: ImportAction) -> ImportRequest {
+        ImportRequest {
+            bundle,
+            choices: HashMap::new(),
+            default,
+        }
+    }
+
+    #[test]
+    fn test_export_strips_passwords_unless_allowed() {
+        let config = TestConfig::new();
+        let brokers_path = format!("{}/brokers.json", config.path);
+        config::add_to_brokers(&brokers_path, &protected_broker("secure:8883", "secret")).unwrap();
+
+        let bundle = export_bundle(&config.path, |_| false);
+        assert!(bundle.brokers.iter().all(|b| b.revision == 0));
+        assert_eq!(bundle.version, BUNDLE_VERSION);
+        assert!(bundle.secrets_stripped);
+        assert_eq!(bundle.brokers.len(), 3);
+        assert!(bundle.brokers.iter().all(|b| b.password.is_none()));
+        assert_eq!(bundle.brokers[2].username, None);
+        assert_eq!(bundle.commands.len(), 2);
+        assert_eq!(bundle.pipelines.len(), 2);
+
+        let bundle = export_bundle(&config.path, |b| b.key() == "secure:8883");
+        assert!(!bundle.secrets_stripped);
+        assert_eq!(bundle.brokers[2].password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_authorize_http_import() {
+        assert!(authorize_http_import(Some("Bearer t0ken"), None).is_err());
+        assert!(authorize_http_import(None, Some("t0ken")).is_err());
+        assert!(authorize_http_import(Some("t0ken"), Some("t0ken")).is_err());
+        assert!(authorize_http_import(Some("Bearer t0kem"), Some("t0ken")).is_err());
+        assert!(authorize_http_import(Some("Bearer t0ken!"), Some("t0ken")).is_err());
+        assert!(authorize_http_import(Some("Bearer t0ken"), Some("t0ken")).is_ok());
+    }
+
+    #[test]
+    fn test_preview_import_reports_conflicts() {
+        let config = TestConfig::new();
+        let mut bundle = export_bundle(&config.path, |_| true);
+        bundle.brokers[0].use_tls = true;
+        bundle.brokers.push(BrokerConfig::from_host("new:1883"));
+        bundle.commands = vec![command("first_command", "I am a test")];
+        bundle.pipelines.clear();
+
+        let items = preview_import(&config.path, &bundle).unwrap();
+        let statuses: Vec<_> = items.iter().map(|i| (i.name.as_str(), i.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("localhost:1883", ItemStatus::Conflict),
+                ("127.0.0.1:1234", ItemStatus::Identical),
+                ("new:1883", ItemStatus::New),
+                ("first_command", ItemStatus::Identical),
+            ]
+        );
+
+        bundle.version = BUNDLE_VERSION + 1;
+        assert!(preview_import(&config.path, &bundle).is_err());
+    }
+
+    #[test]
+    fn test_import_skips_conflicts_by_default() {
+        let config = TestConfig::new();
+        let mut bundle = export_bundle(&config.path, |_| true);
+        bundle.commands = vec![command("first_command", "changed"), command("added", "x")];
+
+        let report =
+            apply_import(&config.path, &request(bundle, ImportAction::Skip), |_| true).unwrap();
+        assert_eq!(report.skipped, vec!["command/first_command"]);
+        assert_eq!(report.imported, vec!["command/added"]);
+        assert_eq!(report.unchanged.len(), 4);
+        assert_eq!(
+            config.command("first_command").unwrap(),
+            command("first_command", "I am a test")
+        );
+        assert!(config.command("added").is_some());
+    }
+
+    #[test]
+    fn test_import_reports_invalid_commands_as_failed() {
+        let config = TestConfig::new();
+        let mut bundle = export_bundle(&config.path, |_| true);
+        let mut bad_qos = command("bad_qos", "x");
+        bad_qos.qos = 3;
+        let bad_payload: CommandMessage = serde_json::from_value(serde_json::json!({
+            "name": "bad_payload",
+            "topic": "test",
+            "payload": "not hex",
+            "encoding": "hex",
+        }))
+        .unwrap();
+        bundle.commands = vec![bad_qos, bad_payload, command("added", "x")];
+
+        let report =
+            apply_import(&config.path, &request(bundle, ImportAction::Skip), |_| true).unwrap();
+        assert_eq!(
+            report.failed,
+            vec!["command/bad_qos", "command/bad_payload"]
+        );
+        assert_eq!(report.imported, vec!["command/added"]);
+        assert!(config.command("bad_qos").is_none());
+        assert!(config.command("bad_payload").is_none());
+    }
+
+    #[test]
+    fn test_import_choices_per_item() {
+        let config = TestConfig::new();
+        let mut bundle = export_bundle(&config.path, |_| true);
+        bundle.pipelines.clear();
+        bundle.commands = vec![
+            command("first_command", "overwritten"),
+            command("i_am_a_test", "merged"),
+        ];
+        let mut request = request(bundle, ImportAction::Skip);
+        request
+            .choices
+            .insert("command/first_command".to_string(), ImportAction::Overwrite);
+        request
+            .choices
+            .insert("command/i_am_a_test".to_string(), ImportAction::Merge);
+
+        let report = apply_import(&config.path, &request, |_| true).unwrap();
+        assert_eq!(
+            report.imported,
+            vec!["command/first_command", "command/i_am_a_test_imported"]
+        );
+        assert_eq!(
+            config.command("first_command").unwrap(),
+            CommandMessage {
+                revision: 1,
+                ..command("first_command", "overwritten")
+            }
+        );
+        assert_eq!(
+            config.command("i_am_a_test").unwrap(),
+            command("i_am_a_test", "I am a payload")
+        );
+        assert_eq!(
+            config.command("i_am_a_test_imported").unwrap(),
+            CommandMessage {
+                revision: 1,
+                ..command("i_am_a_test_imported", "merged")
+            }
+        );
+    }
+
+    #[test]
+    fn test_import_merge_keeps_stripped_password() {
+        let config = TestConfig::new();
+        let brokers_path = format!("{}/brokers.json", config.path);
+        config::add_to_brokers(&brokers_path, &protected_broker("secure:8883", "secret")).unwrap();
+        let mut bundle = export_bundle(&config.path, |_| false);
+        bundle.brokers[2].name = Some("Secure".to_string());
+        bundle.commands.clear();
+        bundle.pipelines.clear();
+
+        let report = apply_import(
+            &config.path,
+            &request(bundle.clone(), ImportAction::Merge),
+            |_| false,
+        )
+        .unwrap();
+        assert_eq!(report.denied, vec!["broker/secure:8883"]);
+        assert!(config.brokers()[2].name.is_none());
+
+        let report = apply_import(&config.path, &request(bundle, ImportAction::Merge), |_| {
+            true
+        })
+        .unwrap();
+        assert_eq!(report.imported, vec!["broker/secure:8883"]);
+        let merged = &config.brokers()[2];
+        assert_eq!(merged.name.as_deref(), Some("Secure"));
+        assert_eq!(merged.password.as_deref(), Some("secret"));
+    }
+}
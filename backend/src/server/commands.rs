@@ -0,0 +1,391 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::alerts;
+use super::config;
+use super::mqtt;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+/// Executions per command since startup, for `{{counter}}`.
+pub type CounterMap = Arc<Mutex<HashMap<String, u64>>>;
+
+/// Values available to a template besides the built-in placeholders.
+pub struct TemplateContext<'a> {
+    pub variables: &'a BTreeMap<String, String>,
+    pub counter: u64,
+    /// Payload of the latest stored message on a topic.
+    pub latest_payload: &'a dyn Fn(&str) -> Option<String>,
+}
+
+/// Render the `{{...}}` placeholders in `template`:
+///
+/// - `{{now}}`: current time, RFC 3339 in UTC
+/// - `{{uuid}}`: a random UUID
+/// - `{{counter}}`: how often the command has been executed, starting at 1
+/// - `{{random_int MIN MAX}}`: a random integer, both bounds inclusive
+/// - `{{topic TOPIC [PATH]}}`: latest stored payload on `TOPIC`, or the value
+///   at the JSON path `PATH` in it
+/// - `{{NAME}}`: a user-defined variable
+pub fn render(template: &str, context: &TemplateContext) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            return Err(format!("Unclosed placeholder in '{template}'"));
+        };
+        rendered.push_str(&render_placeholder(after[..end].trim(), context)?);
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn render_placeholder(placeholder: &str, context: &TemplateContext) -> Result<String, String> {
+    let mut parts = placeholder.split_whitespace();
+    let name = parts.next().unwrap_or_default();
+    let args: Vec<&str> = parts.collect();
+    match (name, args.as_slice()) {
+        ("now", []) => Ok(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        ("uuid", []) => Ok(uuid::Uuid::new_v4().to_string()),
+        ("counter", []) => Ok(context.counter.to_string()),
+        ("random_int", [min, max]) => {
+            let (Ok(min), Ok(max)) = (min.parse::<i64>(), max.parse::<i64>()) else {
+                return Err(format!("Invalid bounds in {{{{{placeholder}}}}}"));
+            };
+            if min > max {
+                return Err(format!("Empty range in {{{{{placeholder}}}}}"));
+            }
+            let span = (max as i128 - min as i128 + 1) as u128;
+            let offset = uuid::Uuid::new_v4().as_u128() % span;
+            Ok((min as i128 + offset as i128).to_string())
+        }
+        ("topic", [topic, path @ ..]) if path.len() <= 1 => {
+            let Some(payload) = (context.latest_payload)(topic) else {
+                return Err(format!("No stored message on {topic}"));
+            };
+            let Some(path) = path.first() else {
+                return Ok(payload);
+            };
+            let json: serde_json::Value = serde_json::from_str(&payload)
+                .map_err(|_| format!("Latest message on {topic} is not JSON"))?;
+            match alerts::json_path_lookup(&json, path) {
+                Some(serde_json::Value::String(value)) => Ok(value.clone()),
+                Some(value) => Ok(value.to_string()),
+                None => Err(format!("{path} not found in latest message on {topic}")),
+            }
+        }
+        (name, []) => context
+            .variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Unknown placeholder {{{{{placeholder}}}}}")),
+        _ => Err(format!("Unknown placeholder {{{{{placeholder}}}}}")),
+    }
+}
+
+/// Result of `execute_command`, echoed to the caller.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct CommandResult {
+    pub name: String,
+    pub broker: String,
+    pub topic: Option<String>,
+    pub payload: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+impl CommandResult {
+    pub fn failed(name: &str, broker: &str, error: String) -> Self {
+        Self {
+            name: name.to_string(),
+            broker: broker.to_string(),
+            topic: None,
+            payload: None,
+            success: false,
+            error: Some(error),
+        }
+    }
+}
+
+/// Render `command` and publish it to `broker`. `overrides` replace the
+/// defaults of the command's variables.
+pub fn execute_command(
+    command: &config::CommandMessage,
+    broker: &str,
+    overrides: &BTreeMap<String, String>,
+    counters: &CounterMap,
+    mqtt_map: &mqtt::BrokerMap,
+) -> CommandResult {
+    let name = command.name.as_str();
+    if let Some(unknown) = overrides
+        .keys()
+        .find(|k| !command.variables.contains_key(*k))
+    {
+        return CommandResult::failed(name, broker, format!("Unknown variable {unknown}"));
+    }
+    let Some(qos) = mqtt::qos_from_u8(command.qos) else {
+        return CommandResult::failed(name, broker, format!("Invalid QoS {}", command.qos));
+    };
+    let mut variables = command.variables.clone();
+    variables.extend(overrides.clone());
+
+    let counter = {
+        let mut counters = counters.lock().unwrap();
+        let counter = counters.entry(name.to_string()).or_default();
+        *counter += 1;
+        *counter
+    };
+    let latest_payload = |topic: &str| {
+        let entry = mqtt::get_broker(mqtt_map, broker)?;
+        let state = entry.state();
+        let message = state.topics.get(topic)?.back()?;
+        Some(String::from_utf8_lossy(&message.payload).into_owned())
+    };
+    let context = TemplateContext {
+        variables: &variables,
+        counter,
+        latest_payload: &latest_payload,
+    };
+    let rendered = if command.template {
+        render(&command.topic, &context)
+            .and_then(|topic| Ok((topic, render(&command.payload, &context)?)))
+    } else {
+        Ok((command.topic.clone(), command.payload.clone()))
+    };
+    let (topic, payload) = match rendered {
+        Ok(rendered) => rendered,
+        Err(err) => return CommandResult::failed(name, broker, err),
+    };
+
+    let bytes = match command.encoding.decode(&payload) {
+        Ok(bytes) => bytes,
+        Err(err) => return CommandResult::failed(name, broker, err),
+    };
+
+    // Queued like publishes from the UI, so a command run during a
+    // reconnect is sent once the broker is back
+    let published =
+        mqtt::publish_message(broker, &topic, &bytes, qos, command.retain, None, mqtt_map);
+    CommandResult {
+        name: name.to_string(),
+        broker: broker.to_string(),
+        topic: Some(topic),
+        payload: Some(payload),
+        success: published.is_ok(),
+        error: published.err(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_with(template: &str, variables: &[(&str, &str)]) -> Result<String, String> {
+        let variables = variables
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let latest_payload = |topic: &str| match topic {
+            "sensors/a" => Some(r#"{"temperature":21.5,"unit":"C"}"#.to_string()),
+            "plain" => Some("on".to_string()),
+            _ => None,
+        };
+        render(
+            template,
+            &TemplateContext {
+                variables: &variables,
+                counter: 7,
+                latest_payload: &latest_payload,
+            },
+        )
+    }
+
+    #[test]
+    fn test_render_builtins_and_variables() {
+        assert_eq!(
+            render_with("{{room}}/set #{{ counter }}", &[("room", "kitchen")]),
+            Ok("kitchen/set #7".to_string())
+        );
+        assert_eq!(
+            render_with("no placeholders", &[]),
+            Ok("no placeholders".to_string())
+        );
+
+        let id = render_with("{{uuid}}", &[]).unwrap();
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+        let now = render_with("{{now}}", &[]).unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(&now).is_ok());
+
+        for _ in 0..50 {
+            let value: i64 = render_with("{{random_int -2 2}}", &[])
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((-2..=2).contains(&value));
+        }
+        assert_eq!(render_with("{{random_int 5 5}}", &[]), Ok("5".to_string()));
+    }
+
+    #[test]
+    fn test_render_latest_topic_values() {
+        assert_eq!(render_with("{{topic plain}}", &[]), Ok("on".to_string()));
+        assert_eq!(
+            render_with(
+                "{{topic sensors/a $.temperature}}{{topic sensors/a unit}}",
+                &[]
+            ),
+            Ok("21.5C".to_string())
+        );
+        assert!(render_with("{{topic missing}}", &[]).is_err());
+        assert!(render_with("{{topic plain $.x}}", &[]).is_err());
+        assert!(render_with("{{topic sensors/a $.missing}}", &[]).is_err());
+    }
+
+    #[test]
+    fn test_render_errors() {
+        assert!(render_with("{{unknown}}", &[]).is_err());
+        assert!(render_with("{{now", &[]).is_err());
+        assert!(render_with("{{random_int 10 1}}", &[]).is_err());
+        assert!(render_with("{{random_int a 1}}", &[]).is_err());
+        assert!(render_with("{{uuid extra}}", &[]).is_err());
+    }
+
+    fn command(json: serde_json::Value) -> config::CommandMessage {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_execute_command_renders_and_counts() {
+        let set = command(serde_json::json!({
+            "name": "set",
+            "topic": "rooms/{{room}}/set",
+            "payload": "{{counter}}:{{topic rooms/kitchen/state}}",
+            "template": true,
+            "variables": { "room": "hall" },
+        }));
+        let mqtt_map = mqtt::BrokerMap::default();
+        let mut broker = mqtt::MqttBroker::new("offline:1883");
+        broker.store_message(
+            "rooms/kitchen/state",
+            1,
+            bytes::Bytes::from_static(b"warm"),
+            4,
+            false,
+        );
+        mqtt::insert_offline_broker(&mqtt_map, broker);
+        let counters = CounterMap::default();
+        let overrides = BTreeMap::from([("room".to_string(), "kitchen".to_string())]);
+
+        execute_command(&set, "offline:1883", &overrides, &counters, &mqtt_map);
+        let result = execute_command(&set, "offline:1883", &overrides, &counters, &mqtt_map);
+        assert_eq!(result.topic.as_deref(), Some("rooms/kitchen/set"));
+        assert_eq!(result.payload.as_deref(), Some("2:warm"));
+        // Rendered, but the broker's event loop is gone
+        assert!(!result.success);
+        assert!(result.error.is_some());
+
+        let unknown = BTreeMap::from([("floor".to_string(), "1".to_string())]);
+        let result = execute_command(&set, "offline:1883", &unknown, &counters, &mqtt_map);
+        assert_eq!(result.error.as_deref(), Some("Unknown variable floor"));
+        assert!(result.payload.is_none());
+    }
+
+    #[test]
+    fn test_execute_command_publishes_literal_payloads_as_they_are() {
+        // Saved before templates existed; `{{` is part of the payload
+        let literal = command(serde_json::json!({
+            "name": "literal",
+            "topic": "ui/{{widget}}",
+            "payload": "<p>{{ user.name }}</p>",
+        }));
+        assert!(literal.validate().is_ok());
+        let mqtt_map = mqtt::BrokerMap::default();
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("offline:1883"));
+        let result = execute_command(
+            &literal,
+            "offline:1883",
+            &BTreeMap::new(),
+            &CounterMap::default(),
+            &mqtt_map,
+        );
+        assert_eq!(result.topic.as_deref(), Some("ui/{{widget}}"));
+        assert_eq!(result.payload.as_deref(), Some("<p>{{ user.name }}</p>"));
+    }
+
+    #[test]
+    fn test_execute_command_queues_while_disconnected() {
+        let plain = command(serde_json::json!({
+            "name": "plain",
+            "topic": "t",
+            "payload": "on",
+        }));
+        let mqtt_map = mqtt::BrokerMap::default();
+        // Reconnecting: not connected, but the event loop is still there
+        let (client, _eventloop) =
+            mqtt::connect_to_mqtt_host(&config::BrokerConfig::from_host("down:1883")).unwrap();
+        let entry = mqtt::BrokerEntry::new(client, false, mqtt::MqttBroker::new("down:1883"));
+        assert!(!entry.is_connected());
+        mqtt_map
+            .write()
+            .unwrap()
+            .insert("down:1883".to_string(), std::sync::Arc::new(entry));
+
+        let result = execute_command(
+            &plain,
+            "down:1883",
+            &BTreeMap::new(),
+            &CounterMap::default(),
+            &mqtt_map,
+        );
+        assert!(result.success, "{:?}", result.error);
+    }
+
+    #[test]
+    fn test_execute_command_checks_encoding_and_qos() {
+        let mqtt_map = mqtt::BrokerMap::default();
+        let counters = CounterMap::default();
+        let bad_hex = command(serde_json::json!({
+            "name": "raw",
+            "topic": "raw",
+            "payload": "zz{{counter}}0",
+            "template": true,
+            "encoding": "hex",
+        }));
+        let result = execute_command(&bad_hex, "b:1883", &BTreeMap::new(), &counters, &mqtt_map);
+        assert_eq!(result.error.as_deref(), Some("Invalid hex payload"));
+
+        let bad_qos = command(serde_json::json!({
+            "name": "q",
+            "topic": "q",
+            "payload": "",
+            "qos": 3,
+        }));
+        let result = execute_command(&bad_qos, "b:1883", &BTreeMap::new(), &counters, &mqtt_map);
+        assert_eq!(result.error.as_deref(), Some("Invalid QoS 3"));
+    }
+}
@@ -20,10 +20,121 @@
  * THE SOFTWARE.
  */
 
-use std::collections::VecDeque;
+use super::history;
+use super::mqtt;
+use super::schema;
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::sync::{Mutex, MutexGuard};
+
+/// Serializes read-modify-write cycles on the config directory, e.g. saves
+/// from two browsers at once.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Hold while reading and writing config files that must not change in
+/// between.
+pub fn lock_config() -> MutexGuard<'static, ()> {
+    CONFIG_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Config items carrying a revision that is bumped on every save, so saves
+/// based on an outdated copy can be rejected.
+pub trait Revisioned {
+    fn revision(&self) -> u64;
+    fn set_revision(&mut self, revision: u64);
+}
+
+fn is_zero(revision: &u64) -> bool {
+    *revision == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Why saving or removing a config item failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SaveError {
+    /// The item was changed since the caller read it; `current` is the
+    /// stored revision, 0 if the item no longer exists.
+    Conflict {
+        current: u64,
+    },
+    Failed(String),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Conflict { current: 0 } => write!(f, "It was removed in the meantime"),
+            SaveError::Conflict { current } => {
+                write!(f, "It was changed in the meantime (now revision {current})")
+            }
+            SaveError::Failed(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// Reject a save based on `expected` if the item is at `current` by now.
+/// Callers that don't send a revision overwrite unconditionally.
+fn check_revision(current: u64, expected: Option<u64>) -> Result<(), SaveError> {
+    match expected {
+        Some(expected) if expected != current => Err(SaveError::Conflict { current }),
+        _ => Ok(()),
+    }
+}
+
+/// The `revision` param of a save or remove RPC.
+pub fn revision_param(params: &serde_json::Value) -> Option<u64> {
+    params.get("revision").and_then(|v| v.as_u64())
+}
+
+/// Replace `path` via a temporary file and rename, so a crash never leaves a
+/// truncated file behind. Both the file and the rename are flushed to disk
+/// before returning, so a power loss can't bring back the old content either.
+pub fn write_atomic(path: &std::path::Path, content: &str) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let write_tmp = || {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    };
+    let result = write_tmp().and_then(|()| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        std::fs::remove_file(&tmp_path).ok();
+        return result;
+    }
+    sync_parent_dir(path)
+}
+
+/// Flush the directory entry of `path`, e.g. after renaming onto it.
+#[cfg(unix)]
+fn sync_parent_dir(path: &std::path::Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing here. NTFS journals the rename.
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct BrokerConfig {
+    /// Stable identifier, so one host can be connected several times, e.g.
+    /// with different credentials. Entries without one are keyed by `host`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    #[serde(deserialize_with = "schema::broker_host")]
     pub host: String,
     #[serde(default)]
     pub use_tls: bool,
@@ -31,143 +142,759 @@ pub struct BrokerConfig {
     pub username: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub password: Option<String>,
+    /// Display name shown instead of the id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub revision: u64,
 }
 
 impl BrokerConfig {
-    /// The display key used to identify this broker throughout the app (host:port).
+    /// The key used to identify this broker throughout the app: its id, or
+    /// host:port for entries without one.
     pub fn key(&self) -> &str {
-        &self.host
+        if self.id.is_empty() {
+            &self.host
+        } else {
+            &self.id
+        }
     }
 
-    #[cfg(test)]
+    pub fn requires_auth(&self) -> bool {
+        self.password.as_ref().is_some_and(|p| !p.is_empty())
+    }
+
+    /// Whether a connection made with `other` is also valid for `self`.
+    pub fn same_connection(&self, other: &BrokerConfig) -> bool {
+        self.host == other.host
+            && self.use_tls == other.use_tls
+            && self.username == other.username
+            && self.password == other.password
+    }
+
+    /// Config for `host` with default options, as for a broker connected
+    /// without one.
     pub fn from_host(host: &str) -> Self {
         Self {
+            id: String::new(),
             host: host.to_string(),
             use_tls: false,
             username: None,
             password: None,
+            name: None,
+            description: None,
+            tags: Vec::new(),
+            revision: 0,
         }
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+impl Revisioned for BrokerConfig {
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn set_revision(&mut self, revision: u64) {
+        self.revision = revision;
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct CommandMessage {
-    name: String,
-    topic: String,
-    payload: String,
+    pub name: String,
+    /// With `template`, topic and payload may contain `{{...}}` placeholders,
+    /// rendered by `execute_command`.
+    pub topic: String,
+    pub payload: String,
+    /// Whether topic and payload are templates. Commands without it are
+    /// published as they are, also if they contain `{{`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub template: bool,
+    /// User-defined template variables and their default values.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// QoS 0, 1 or 2.
+    #[serde(default = "default_command_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    /// Broker the command is published to when the caller doesn't pick one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker: Option<String>,
+    /// How `payload` is turned into bytes, after rendering placeholders.
+    #[serde(default, skip_serializing_if = "mqtt::PayloadEncoding::is_utf8")]
+    pub encoding: mqtt::PayloadEncoding,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub revision: u64,
+}
+
+fn default_command_qos() -> u8 {
+    1
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+impl CommandMessage {
+    /// Reject QoS values MQTT doesn't know and payloads that don't match their
+    /// encoding. Template payloads are checked once rendered.
+    pub fn validate(&self) -> Result<(), String> {
+        if mqtt::qos_from_u8(self.qos).is_none() {
+            return Err(format!(
+                "Invalid QoS {} for command {}",
+                self.qos, self.name
+            ));
+        }
+        if !self.template {
+            self.encoding.decode(&self.payload)?;
+        }
+        Ok(())
+    }
+}
+
+impl Revisioned for CommandMessage {
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn set_revision(&mut self, revision: u64) {
+        self.revision = revision;
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 struct PipelineEntry {
     topic: String,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct PipelineMessage {
-    name: String,
+    pub name: String,
     pipeline: VecDeque<PipelineEntry>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub revision: u64,
 }
 
+impl Revisioned for PipelineMessage {
+    fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn set_revision(&mut self, revision: u64) {
+        self.revision = revision;
+    }
+}
+
+/// Brokers stored at `brokers_path`, in either layout. A missing file holds
+/// no brokers; an invalid one is an error with its position.
+pub fn load_brokers(brokers_path: &str) -> Result<Vec<BrokerConfig>, schema::ConfigError> {
+    match std::fs::read_to_string(brokers_path) {
+        Ok(content) => schema::parse_brokers(brokers_path, &content).map(|(brokers, _)| brokers),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(schema::ConfigError::new(brokers_path, err.to_string())),
+    }
+}
+
+/// Like `load_brokers`, but an invalid file is logged and counts as empty.
+/// Use `load_brokers` before writing the list back.
 pub fn get_known_brokers(brokers_path: &str) -> Vec<BrokerConfig> {
-    if let Ok(file_content) = std::fs::read_to_string(brokers_path) {
-        serde_json::from_str(&file_content).unwrap_or_default()
-    } else {
-        eprintln!("Failed to read file {}", brokers_path);
+    load_brokers(brokers_path).unwrap_or_else(|err| {
+        eprintln!("Invalid config: {err}");
         Vec::new()
+    })
+}
+
+/// Like `get_known_brokers`, but tells a missing or invalid file apart from
+/// an empty list.
+pub fn read_broker_configs(brokers_path: &str) -> Option<Vec<BrokerConfig>> {
+    let file_content = std::fs::read_to_string(brokers_path).ok()?;
+    match schema::parse_brokers(brokers_path, &file_content) {
+        Ok((configs, _)) => Some(configs),
+        Err(err) => {
+            eprintln!("Invalid config: {err}");
+            None
+        }
     }
 }
 
-fn write_broker_configs(brokers_path: &str, configs: &[BrokerConfig]) {
-    match serde_json::to_string_pretty(configs) {
-        Ok(content) => {
-            if std::fs::write(brokers_path, content).is_err() {
-                eprintln!("Failed to save brokers file to {brokers_path}");
-            }
+/// Write `configs` to the brokers file. Callers modifying the stored list
+/// hold `lock_config` from reading it until this returns.
+pub fn write_broker_configs(brokers_path: &str, configs: &[BrokerConfig]) -> bool {
+    let content = match schema::brokers_json(configs) {
+        Ok(content) => content,
+        Err(_) => {
+            eprintln!("Failed to serialize broker configs.");
+            return false;
         }
-        Err(_) => eprintln!("Failed to serialize broker configs."),
+    };
+    if let Err(err) = write_atomic(std::path::Path::new(brokers_path), &content) {
+        eprintln!("Failed to save brokers file to {brokers_path}: {err}");
+        return false;
+    }
+    true
+}
+
+/// Read, change and write the brokers file under the config lock. A missing
+/// file counts as empty; an invalid one is left alone rather than replaced.
+fn modify_brokers<R>(
+    brokers_path: &str,
+    modify: impl FnOnce(&mut Vec<BrokerConfig>) -> Result<R, SaveError>,
+) -> Result<R, SaveError> {
+    let _lock = lock_config();
+    let mut brokers = load_brokers(brokers_path)
+        .map_err(|err| SaveError::Failed(format!("Invalid config: {err}")))?;
+    let result = modify(&mut brokers)?;
+    if !write_broker_configs(brokers_path, &brokers) {
+        return Err(SaveError::Failed(format!("Failed to save {brokers_path}")));
     }
+    Ok(result)
+}
+
+/// Insert `config`, or replace the entry with the same key. Returns the new
+/// revision.
+pub fn add_to_brokers(brokers_path: &str, config: &BrokerConfig) -> Result<u64, SaveError> {
+    modify_brokers(brokers_path, |brokers| {
+        let mut config = config.clone();
+        match brokers.iter_mut().find(|b| b.key() == config.key()) {
+            Some(existing) => {
+                config.revision = existing.revision + 1;
+                *existing = config.clone();
+            }
+            None => {
+                config.revision = 1;
+                brokers.push(config.clone());
+            }
+        }
+        Ok(config.revision)
+    })
 }
 
-pub fn add_to_brokers(brokers_path: &str, config: &BrokerConfig) {
-    let mut brokers = get_known_brokers(brokers_path);
-    brokers.push(config.clone());
-    write_broker_configs(brokers_path, &brokers);
+/// Apply `update` to the stored entry with key `broker`, unless it is no
+/// longer at the `expected` revision. Returns the entry before and after.
+pub fn update_in_brokers(
+    brokers_path: &str,
+    broker: &str,
+    expected: Option<u64>,
+    update: impl FnOnce(&mut BrokerConfig),
+) -> Result<(BrokerConfig, BrokerConfig), SaveError> {
+    modify_brokers(brokers_path, |brokers| {
+        let Some(existing) = brokers.iter_mut().find(|b| b.key() == broker) else {
+            return match expected {
+                Some(_) => Err(SaveError::Conflict { current: 0 }),
+                None => Err(SaveError::Failed(format!("Broker {broker} not found"))),
+            };
+        };
+        check_revision(existing.revision, expected)?;
+        let previous = existing.clone();
+        update(existing);
+        existing.revision = previous.revision + 1;
+        Ok((previous, existing.clone()))
+    })
 }
 
-pub fn remove_from_brokers(brokers_path: &str, broker: &str) {
-    let mut brokers = get_known_brokers(brokers_path);
-    if let Some(index) = brokers.iter().position(|b| b.host == broker) {
+/// Remove the entry with key `broker`, unless it is no longer at the
+/// `expected` revision.
+pub fn remove_from_brokers(
+    brokers_path: &str,
+    broker: &str,
+    expected: Option<u64>,
+) -> Result<(), SaveError> {
+    modify_brokers(brokers_path, |brokers| {
+        let Some(index) = brokers.iter().position(|b| b.key() == broker) else {
+            return match expected {
+                Some(_) => Err(SaveError::Conflict { current: 0 }),
+                None => Err(SaveError::Failed(format!("Broker {broker} not found"))),
+            };
+        };
+        check_revision(brokers[index].revision, expected)?;
         brokers.remove(index);
-        write_broker_configs(brokers_path, &brokers);
-    } else {
-        eprintln!("Broker {broker} not found in {brokers_path}");
-    }
+        Ok(())
+    })
 }
 
-pub fn add_to_commands(commands_path: &str, params: serde_json::Value) {
-    if let Ok(new_command) = serde_json::from_value::<CommandMessage>(params) {
-        let new_command_path = std::format!("{commands_path}/{}.json", new_command.name);
-        if let Some(parent_dir) = std::path::Path::new(&new_command_path).parent() {
-            std::fs::create_dir_all(parent_dir).expect("Failed to create directory path");
+/// Deepest folder nesting of commands and pipelines.
+const MAX_NAME_SEGMENTS: usize = 8;
+
+const MAX_NAME_SEGMENT_LEN: usize = 128;
+
+/// Check the name of a command or pipeline, or of a folder of them, such as
+/// `lab/door/open`: `/`-separated segments that can't leave their directory.
+pub fn validate_item_name(name: &str) -> Result<(), String> {
+    let segments: Vec<&str> = name.split('/').collect();
+    if segments.len() > MAX_NAME_SEGMENTS {
+        return Err(format!(
+            "Name {name} is nested deeper than {MAX_NAME_SEGMENTS} levels"
+        ));
+    }
+    for segment in segments {
+        if segment.is_empty() {
+            return Err(format!("Name {name:?} has an empty segment"));
         }
-        if let Ok(content) = serde_json::to_string(&new_command) {
-            if std::fs::write(&new_command_path, content).is_err() {
-                eprintln!("Failed to save new commands file to {new_command_path}");
-            }
-        } else {
-            eprintln!("Failed to serialize updated saved commands.");
+        if segment.starts_with('.') {
+            return Err(format!("Name {name} has a segment starting with '.'"));
+        }
+        if segment.len() > MAX_NAME_SEGMENT_LEN {
+            return Err(format!(
+                "Name {name} has a segment longer than {MAX_NAME_SEGMENT_LEN} bytes"
+            ));
+        }
+        if let Some(c) = segment
+            .chars()
+            .find(|c| c.is_control() || matches!(c, '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        {
+            return Err(format!("Name {name:?} contains {c:?}"));
         }
-    } else {
-        println!("Could not deserialize new command.");
     }
+    Ok(())
 }
 
-pub fn remove_from_commands(commands_path: &str, params: serde_json::Value) {
-    let command = match params.get("name").and_then(|v| v.as_str()) {
-        Some(c) => c,
-        None => {
-            eprintln!("Missing or invalid 'name' param for remove_command");
-            return;
-        }
+/// File of the item `name` in `dir`.
+fn item_path(dir: &str, name: &str) -> Result<std::path::PathBuf, String> {
+    validate_item_name(name)?;
+    Ok(std::path::Path::new(dir).join(format!("{name}.json")))
+}
+
+/// Check the name of an item kept as `<name>.json` in a flat directory,
+/// such as an alert rule or a webhook: a single segment of an item name.
+pub fn validate_file_name(name: &str) -> Result<(), String> {
+    if name.contains('/') {
+        return Err(format!("Name {name} contains '/'"));
+    }
+    validate_item_name(name)
+}
+
+/// The `kind` items kept as `<name>.json` in `dir`, sorted by name. Files
+/// that don't parse or have an invalid name are logged and skipped.
+pub fn read_named_files<T: serde::de::DeserializeOwned>(dir: &str, kind: &str) -> Vec<T> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
     };
-    let command_path = std::format!("{commands_path}/{command}.json");
-    if std::fs::remove_file(&command_path).is_err() {
-        eprintln!("Failed to remove command file from {command_path}");
+    let mut items: Vec<(String, T)> = entries
+        .filter_map(|dir_entry| {
+            let path = dir_entry.ok()?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                return None;
+            }
+            let content = std::fs::read_to_string(&path).ok()?;
+            let parsed = serde_json::from_str::<serde_json::Value>(&content)
+                .map_err(|err| err.to_string())
+                .and_then(|value| {
+                    let name = value.get("name").and_then(|v| v.as_str()).unwrap_or("");
+                    validate_file_name(name)?;
+                    let name = name.to_string();
+                    let item = serde_json::from_value(value).map_err(|err| err.to_string())?;
+                    Ok((name, item))
+                });
+            match parsed {
+                Ok(item) => Some(item),
+                Err(err) => {
+                    eprintln!("Invalid {kind} {path:?}: {err}");
+                    None
+                }
+            }
+        })
+        .collect();
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Store the `kind` item `name` as `<dir>/<name>.json`.
+pub fn write_named_file<T: serde::Serialize>(dir: &str, kind: &str, name: &str, item: &T) -> bool {
+    if let Err(err) = validate_file_name(name) {
+        eprintln!("Can't save {kind}: {err}");
+        return false;
+    }
+    let _lock = lock_config();
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        eprintln!("Failed to create {kind} directory {dir}: {err}");
+        return false;
+    }
+    let path = std::path::Path::new(dir).join(format!("{name}.json"));
+    let Ok(content) = serde_json::to_string_pretty(item) else {
+        eprintln!("Failed to serialize {kind} {name}.");
+        return false;
+    };
+    if let Err(err) = write_atomic(&path, &content) {
+        eprintln!("Failed to save {kind} to {path:?}: {err}");
+        return false;
     }
+    true
 }
 
-pub fn add_to_pipelines(pipelines_path: &str, params: serde_json::Value) {
-    if let Ok(new_pipeline) = serde_json::from_value::<PipelineMessage>(params) {
-        let new_pipeline_path = std::format!("{pipelines_path}/{}.json", new_pipeline.name);
-        if let Some(parent_dir) = std::path::Path::new(&new_pipeline_path).parent() {
-            std::fs::create_dir_all(parent_dir).expect("Failed to create directory path");
-        }
-        if let Ok(content) = serde_json::to_string(&new_pipeline) {
-            if std::fs::write(&new_pipeline_path, content).is_err() {
-                eprintln!("Failed to save pipeline file to {new_pipeline_path}");
+/// Remove the `kind` item `name` from `dir`.
+pub fn remove_named_file(dir: &str, kind: &str, name: &str) -> bool {
+    if let Err(err) = validate_file_name(name) {
+        eprintln!("Can't remove {kind}: {err}");
+        return false;
+    }
+    let _lock = lock_config();
+    let path = std::path::Path::new(dir).join(format!("{name}.json"));
+    if let Err(err) = std::fs::remove_file(&path) {
+        eprintln!("Failed to remove {kind} from {path:?}: {err}");
+        return false;
+    }
+    true
+}
+
+/// A folder of commands or pipelines with its direct contents.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct FolderInfo {
+    pub path: String,
+    pub items: usize,
+    pub folders: usize,
+}
+
+/// Item files by name, paired with their path on disk.
+type ItemFiles = Vec<(String, std::path::PathBuf)>;
+
+/// `.json` files below `dir` by item name, and the folders holding them.
+/// `None` if `dir` can't be read.
+fn walk_item_dir(dir: &str) -> Option<(ItemFiles, Vec<FolderInfo>)> {
+    let root = std::path::Path::new(dir);
+    std::fs::read_dir(root).ok()?;
+    let mut files = Vec::new();
+    let mut folders = Vec::new();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((path, prefix)) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&path) else {
+            continue;
+        };
+        let mut folder = FolderInfo {
+            path: prefix.trim_end_matches('/').to_string(),
+            items: 0,
+            folders: 0,
+        };
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if entry_path.is_dir() {
+                let name = format!("{prefix}{file_name}");
+                if validate_item_name(&name).is_ok() {
+                    folder.folders += 1;
+                    pending.push((entry_path, format!("{name}/")));
+                }
+            } else if let Some(stem) = file_name.strip_suffix(".json") {
+                let name = format!("{prefix}{stem}");
+                if validate_item_name(&name).is_ok() {
+                    folder.items += 1;
+                    files.push((name, entry_path));
+                }
             }
-        } else {
-            eprintln!("Failed to serialize pipeline.");
         }
-    } else {
-        println!("Could not deserialize new pipeline.");
+        if !folder.path.is_empty() {
+            folders.push(folder);
+        }
     }
+    files.sort();
+    folders.sort_by(|a, b| a.path.cmp(&b.path));
+    Some((files, folders))
+}
+
+/// Files of the items below `dir` by item name.
+pub fn item_files(dir: &str) -> ItemFiles {
+    walk_item_dir(dir)
+        .map(|(files, _)| files)
+        .unwrap_or_default()
+}
+
+/// A stored item and the fields `T` doesn't know.
+#[derive(serde::Deserialize)]
+struct StoredItem<T> {
+    #[serde(flatten)]
+    item: T,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+/// Parse the item `name` stored in `file`. Fields this build doesn't know,
+/// e.g. in files of older versions, are ignored with a warning;
+/// `schema::validate_config_dir` reports them as well.
+fn parse_stored_item<T: serde::de::DeserializeOwned>(
+    file: &std::path::Path,
+    name: &str,
+    content: &str,
+) -> Result<T, String> {
+    let mut value: serde_json::Value =
+        serde_json::from_str(content).map_err(|err| err.to_string())?;
+    value
+        .as_object_mut()
+        .ok_or("expected an object")?
+        .insert("name".to_string(), name.into());
+    let stored: StoredItem<T> = serde_json::from_value(value).map_err(|err| err.to_string())?;
+    if !stored.unknown.is_empty() {
+        let fields: Vec<_> = stored.unknown.keys().collect();
+        eprintln!("Ignoring unknown fields {fields:?} in {}", file.display());
+    }
+    Ok(stored.item)
+}
+
+/// Items below `dir` that parse as `T`. The name of an item is its path
+/// relative to `dir`. Files that don't parse are logged and left out.
+/// `None` if the directory can't be read.
+fn read_json_files<T: serde::de::DeserializeOwned>(dir: &str) -> Option<Vec<T>> {
+    let (files, _) = walk_item_dir(dir)?;
+    Some(
+        files
+            .into_iter()
+            .filter_map(|(name, path)| {
+                let parsed = std::fs::read_to_string(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|content| parse_stored_item(&path, &name, &content));
+                parsed
+                    .map_err(|err| eprintln!("Skipping {}: {err}", path.display()))
+                    .ok()
+            })
+            .collect(),
+    )
 }
 
-pub fn remove_from_pipelines(pipelines_path: &str, params: serde_json::Value) {
-    let pipeline = match params.get("name").and_then(|v| v.as_str()) {
-        Some(p) => p,
-        None => {
-            eprintln!("Missing or invalid 'name' param for remove_pipeline");
-            return;
+/// Folders below `dir`, including empty ones.
+pub fn get_folders(dir: &str) -> Vec<FolderInfo> {
+    walk_item_dir(dir)
+        .map(|(_, folders)| folders)
+        .unwrap_or_default()
+}
+
+/// Revision of the item stored at `path`, 0 if there is none.
+fn stored_revision(path: &std::path::Path) -> u64 {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        .and_then(|value| value.get("revision")?.as_u64())
+        .unwrap_or(0)
+}
+
+/// Write `item` as `name` below `dir`, creating its folders, unless the
+/// stored item is no longer at the `expected` revision, and add it to the
+/// history. Returns the new revision.
+fn write_item<T: serde::Serialize + Revisioned + Clone>(
+    dir: &str,
+    name: &str,
+    item: &T,
+    expected: Option<u64>,
+) -> Result<u64, SaveError> {
+    let item_path = item_path(dir, name)
+        .map_err(|err| SaveError::Failed(format!("Can't save {name}: {err}")))?;
+    let _lock = lock_config();
+    let current = stored_revision(&item_path);
+    check_revision(current, expected)?;
+    let mut item = item.clone();
+    item.set_revision(current.max(history::last_revision(dir, name)) + 1);
+    if let Some(parent_dir) = item_path.parent() {
+        std::fs::create_dir_all(parent_dir).map_err(|err| {
+            SaveError::Failed(format!("Failed to create directory {parent_dir:?}: {err}"))
+        })?;
+    }
+    let content = serde_json::to_string(&item)
+        .map_err(|_| SaveError::Failed(format!("Failed to serialize {name}.")))?;
+    write_atomic(&item_path, &content).map_err(|err| {
+        SaveError::Failed(format!("Failed to save {name} to {item_path:?}: {err}"))
+    })?;
+    history::record(dir, name, serde_json::to_value(&item).ok(), item.revision());
+    Ok(item.revision())
+}
+
+/// Remove `name` below `dir`, unless it is no longer at the `expected`
+/// revision. The removal is kept in the history, so it can be undone.
+fn remove_item(dir: &str, name: &str, expected: Option<u64>) -> Result<(), SaveError> {
+    let item_path = item_path(dir, name)
+        .map_err(|err| SaveError::Failed(format!("Can't remove {name}: {err}")))?;
+    let _lock = lock_config();
+    if !item_path.is_file() {
+        return match expected {
+            Some(_) => Err(SaveError::Conflict { current: 0 }),
+            None => Err(SaveError::Failed(format!("{name} not found"))),
+        };
+    }
+    let current = stored_revision(&item_path);
+    check_revision(current, expected)?;
+    std::fs::remove_file(&item_path)
+        .map_err(|err| SaveError::Failed(format!("Failed to remove {item_path:?}: {err}")))?;
+    let revision = current.max(history::last_revision(dir, name)) + 1;
+    history::record(dir, name, None, revision);
+    Ok(())
+}
+
+/// Rename the item `from` below `dir` to `to`, which may be in another folder.
+pub fn move_item(dir: &str, from: &str, to: &str) -> Result<(), String> {
+    let source = item_path(dir, from)?;
+    let target = item_path(dir, to)?;
+    let _lock = lock_config();
+    if !source.is_file() {
+        return Err(format!("{from} not found"));
+    }
+    if target.exists() {
+        return Err(format!("{to} already exists"));
+    }
+    history::check_rename_target(dir, to, false)?;
+    if let Some(parent_dir) = target.parent() {
+        std::fs::create_dir_all(parent_dir).map_err(|err| format!("Can't create folder: {err}"))?;
+    }
+    std::fs::rename(&source, &target).map_err(|err| format!("Can't move {from}: {err}"))?;
+    rewrite_name(&target, to);
+    history::rename(dir, from, to, false);
+    Ok(())
+}
+
+/// Rename the folder `from` below `dir`, with everything in it, to `to`.
+pub fn move_folder(dir: &str, from: &str, to: &str) -> Result<(), String> {
+    validate_item_name(from)?;
+    validate_item_name(to)?;
+    if to.starts_with(&format!("{from}/")) {
+        return Err(format!("Can't move {from} into itself"));
+    }
+    let source = std::path::Path::new(dir).join(from);
+    let target = std::path::Path::new(dir).join(to);
+    let _lock = lock_config();
+    if !source.is_dir() {
+        return Err(format!("Folder {from} not found"));
+    }
+    if target.exists() {
+        return Err(format!("{to} already exists"));
+    }
+    history::check_rename_target(dir, to, true)?;
+    if let Some(parent_dir) = target.parent() {
+        std::fs::create_dir_all(parent_dir).map_err(|err| format!("Can't create folder: {err}"))?;
+    }
+    std::fs::rename(&source, &target).map_err(|err| format!("Can't move {from}: {err}"))?;
+    if let Some((files, _)) = walk_item_dir(&target.to_string_lossy()) {
+        for (name, path) in files {
+            rewrite_name(&path, &format!("{to}/{name}"));
         }
+    }
+    history::rename(dir, from, to, true);
+    Ok(())
+}
+
+/// Remove the folder `path` below `dir` if it is empty.
+pub fn remove_folder(dir: &str, path: &str) -> Result<(), String> {
+    validate_item_name(path)?;
+    let _lock = lock_config();
+    std::fs::remove_dir(std::path::Path::new(dir).join(path))
+        .map_err(|err| format!("Can't remove folder {path}: {err}"))
+}
+
+/// Keep the `name` stored in a moved file in line with its location.
+fn rewrite_name(path: &std::path::Path, name: &str) {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return;
     };
-    let pipeline_path = std::format!("{pipelines_path}/{pipeline}.json");
-    if std::fs::remove_file(&pipeline_path).is_err() {
-        eprintln!("Failed to remove pipeline file from {pipeline_path}");
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&content) else {
+        return;
+    };
+    if let Some(object) = value.as_object_mut() {
+        object.insert("name".to_string(), name.into());
+        if write_atomic(path, &value.to_string()).is_err() {
+            eprintln!("Failed to update the name in {path:?}");
+        }
     }
 }
 
+pub fn get_commands(commands_path: &str) -> Option<Vec<CommandMessage>> {
+    read_json_files(commands_path)
+}
+
+pub fn get_command(commands_path: &str, name: &str) -> Option<CommandMessage> {
+    get_commands(commands_path)?
+        .into_iter()
+        .find(|c| c.name == name)
+}
+
+pub fn get_pipelines(pipelines_path: &str) -> Option<Vec<PipelineMessage>> {
+    read_json_files(pipelines_path)
+}
+
+pub fn save_command(
+    commands_path: &str,
+    command: &CommandMessage,
+    expected: Option<u64>,
+) -> Result<u64, SaveError> {
+    write_item(commands_path, &command.name, command, expected)
+}
+
+/// Save the command in `params`. A `revision` param makes the save fail if
+/// the stored command changed since.
+pub fn add_to_commands(commands_path: &str, params: serde_json::Value) -> Result<u64, SaveError> {
+    let expected = revision_param(&params);
+    let new_command = serde_json::from_value::<CommandMessage>(params)
+        .map_err(|err| SaveError::Failed(format!("Could not deserialize new command: {err}")))?;
+    new_command
+        .validate()
+        .map_err(|err| SaveError::Failed(format!("Could not save command: {err}")))?;
+    save_command(commands_path, &new_command, expected)
+}
+
+pub fn remove_from_commands(
+    commands_path: &str,
+    params: serde_json::Value,
+) -> Result<(), SaveError> {
+    let Some(command) = params.get("name").and_then(|v| v.as_str()) else {
+        return Err(SaveError::Failed(
+            "Missing or invalid 'name' param for remove_command".to_string(),
+        ));
+    };
+    remove_item(commands_path, command, revision_param(&params))
+}
+
+/// Save revision `revision` of the command `name` again, also if it was
+/// removed since. Returns the new revision.
+pub fn restore_command(
+    commands_path: &str,
+    name: &str,
+    revision: u64,
+    expected: Option<u64>,
+) -> Result<u64, SaveError> {
+    let command: CommandMessage =
+        history::item_at(commands_path, name, revision).map_err(SaveError::Failed)?;
+    command.validate().map_err(SaveError::Failed)?;
+    save_command(commands_path, &command, expected)
+}
+
+pub fn save_pipeline(
+    pipelines_path: &str,
+    pipeline: &PipelineMessage,
+    expected: Option<u64>,
+) -> Result<u64, SaveError> {
+    write_item(pipelines_path, &pipeline.name, pipeline, expected)
+}
+
+/// Save the pipeline in `params`, like `add_to_commands`.
+pub fn add_to_pipelines(pipelines_path: &str, params: serde_json::Value) -> Result<u64, SaveError> {
+    let expected = revision_param(&params);
+    let new_pipeline = serde_json::from_value::<PipelineMessage>(params)
+        .map_err(|err| SaveError::Failed(format!("Could not deserialize new pipeline: {err}")))?;
+    save_pipeline(pipelines_path, &new_pipeline, expected)
+}
+
+pub fn remove_from_pipelines(
+    pipelines_path: &str,
+    params: serde_json::Value,
+) -> Result<(), SaveError> {
+    let Some(pipeline) = params.get("name").and_then(|v| v.as_str()) else {
+        return Err(SaveError::Failed(
+            "Missing or invalid 'name' param for remove_pipeline".to_string(),
+        ));
+    };
+    remove_item(pipelines_path, pipeline, revision_param(&params))
+}
+
+/// Save revision `revision` of the pipeline `name` again, like
+/// `restore_command`.
+pub fn restore_pipeline(
+    pipelines_path: &str,
+    name: &str,
+    revision: u64,
+    expected: Option<u64>,
+) -> Result<u64, SaveError> {
+    let pipeline: PipelineMessage =
+        history::item_at(pipelines_path, name, revision).map_err(SaveError::Failed)?;
+    save_pipeline(pipelines_path, &pipeline, expected)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,9 +911,8 @@ mod tests {
 
     impl TestResource {
         fn new() -> Self {
-            let config_path =
-                std::format!("{}_{}", CONFIG_PATH, uuid::Uuid::new_v4().to_string()).to_string();
-            copy_dir::copy_dir(CONFIG_SOURCE_PATH, config_path.to_string()).unwrap();
+            let config_path = std::format!("{}_{}", CONFIG_PATH, uuid::Uuid::new_v4());
+            copy_dir::copy_dir(CONFIG_SOURCE_PATH, &config_path).unwrap();
             let brokers_path = std::format!("{}/brokers.json", config_path);
             let commands_path = std::format!("{}/commands", config_path);
             let pipelines_path = std::format!("{}/pipelines", config_path);
@@ -234,11 +960,93 @@ mod tests {
         let brokers = get_known_brokers(&resource.brokers_path);
         let len_before = brokers.len();
 
-        add_to_brokers(&resource.brokers_path, &broker);
+        assert_eq!(add_to_brokers(&resource.brokers_path, &broker), Ok(1));
         let brokers = get_known_brokers(&resource.brokers_path);
 
         assert_eq!(brokers.len(), len_before + 1);
         assert_eq!(brokers.last().unwrap().host, "test.mosquitto.org:1883");
+        // Saving moves an unversioned file to the current layout
+        let stored = std::fs::read_to_string(&resource.brokers_path).unwrap();
+        assert_eq!(schema::parse_brokers("brokers.json", &stored).unwrap().1, 1);
+    }
+
+    #[test]
+    fn test_add_to_brokers_replaces_existing_entry() {
+        let resource = TestResource::new();
+        let len_before = get_known_brokers(&resource.brokers_path).len();
+        let mut broker = BrokerConfig::from_host("localhost:1883");
+        broker.use_tls = true;
+
+        add_to_brokers(&resource.brokers_path, &broker).unwrap();
+        assert_eq!(add_to_brokers(&resource.brokers_path, &broker), Ok(2));
+        let brokers = get_known_brokers(&resource.brokers_path);
+
+        assert_eq!(brokers.len(), len_before);
+        assert_eq!(brokers[0].host, "localhost:1883");
+        assert!(brokers[0].use_tls);
+    }
+
+    #[test]
+    fn test_broker_key_prefers_id() {
+        let mut broker = BrokerConfig::from_host("10.3.4.5:1883");
+        assert_eq!(broker.key(), "10.3.4.5:1883");
+        broker.id = "plant-a".to_string();
+        assert_eq!(broker.key(), "plant-a");
+
+        let parsed: Vec<BrokerConfig> = serde_json::from_str(
+            r#"[{"host":"h:1883"},{"id":"x","host":"h:1883","name":"X","tags":["prod"]}]"#,
+        )
+        .unwrap();
+        assert_eq!(parsed[0].key(), "h:1883");
+        assert_eq!(parsed[1].key(), "x");
+        assert_eq!(parsed[1].name.as_deref(), Some("X"));
+        assert_eq!(parsed[1].tags, vec!["prod"]);
+    }
+
+    #[test]
+    fn test_add_to_brokers_same_host_with_different_ids() {
+        let resource = TestResource::new();
+        let len_before = get_known_brokers(&resource.brokers_path).len();
+        for id in ["reader", "writer"] {
+            let mut broker = BrokerConfig::from_host("localhost:1883");
+            broker.id = id.to_string();
+            add_to_brokers(&resource.brokers_path, &broker).unwrap();
+        }
+        assert_eq!(
+            get_known_brokers(&resource.brokers_path).len(),
+            len_before + 2
+        );
+
+        remove_from_brokers(&resource.brokers_path, "reader", None).unwrap();
+        let keys: Vec<_> = get_known_brokers(&resource.brokers_path)
+            .iter()
+            .map(|b| b.key().to_string())
+            .collect();
+        assert_eq!(keys, vec!["localhost:1883", "127.0.0.1:1234", "writer"]);
+    }
+
+    #[test]
+    fn test_update_in_brokers() {
+        let resource = TestResource::new();
+        let set_password = |broker: &mut BrokerConfig| broker.password = Some("secret".to_string());
+
+        let (previous, updated) =
+            update_in_brokers(&resource.brokers_path, "127.0.0.1:1234", None, set_password)
+                .unwrap();
+        assert_eq!(previous.password, None);
+        assert_eq!(updated.revision, 1);
+        let brokers = get_known_brokers(&resource.brokers_path);
+        assert_eq!(brokers[1].password.as_deref(), Some("secret"));
+        assert!(!std::path::Path::new(&format!("{}.tmp", resource.brokers_path)).exists());
+
+        let unknown = update_in_brokers(&resource.brokers_path, "unknown:1883", None, set_password);
+        assert_eq!(
+            unknown,
+            Err(SaveError::Failed(
+                "Broker unknown:1883 not found".to_string()
+            ))
+        );
+        assert_eq!(get_known_brokers(&resource.brokers_path).len(), 2);
     }
 
     #[test]
@@ -249,7 +1057,7 @@ mod tests {
         let len_before = brokers.len();
 
         let broker_host = brokers.first().unwrap().host.clone();
-        remove_from_brokers(brokers_path.as_str(), &broker_host);
+        remove_from_brokers(brokers_path.as_str(), &broker_host, None).unwrap();
         let brokers = get_known_brokers(brokers_path.as_str());
 
         assert_eq!(brokers.len(), len_before - 1);
@@ -264,7 +1072,7 @@ mod tests {
         let brokers_before = get_known_brokers(brokers_path.as_str());
         let len_before = brokers_before.len();
 
-        remove_from_brokers(brokers_path.as_str(), broker);
+        assert!(remove_from_brokers(brokers_path.as_str(), broker, None).is_err());
         let brokers_after = get_known_brokers(brokers_path.as_str());
         let len_after = brokers_after.len();
 
@@ -274,7 +1082,7 @@ mod tests {
     #[test]
     fn test_remove_from_brokers_failure_no_file() {
         let broker = "test.mosquitto.org:1883";
-        remove_from_brokers("not_a_real_path.json", broker);
+        assert!(remove_from_brokers("not_a_real_path.json", broker, None).is_err());
     }
 
     #[test]
@@ -286,9 +1094,9 @@ mod tests {
             "payload": "test"
         });
         let command_path = std::format!("{}/new_command.json", resource.commands_path);
-        assert_eq!(std::path::Path::new(&command_path).exists(), false);
-        add_to_commands(&resource.commands_path, params);
-        assert_eq!(std::path::Path::new(&resource.commands_path).exists(), true);
+        assert!(!std::path::Path::new(&command_path).exists());
+        add_to_commands(&resource.commands_path, params).unwrap();
+        assert!(std::path::Path::new(&resource.commands_path).exists());
         let command = std::fs::read_to_string(command_path).unwrap();
         let command: CommandMessage = serde_json::from_str(&command).unwrap();
         assert_eq!(command.name, "new_command");
@@ -296,6 +1104,81 @@ mod tests {
         assert_eq!(command.payload, "test");
     }
 
+    #[test]
+    fn test_command_defaults_for_old_files() {
+        let resource = TestResource::new();
+        let command = get_command(&resource.commands_path, "first_command").unwrap();
+        assert_eq!(command.qos, 1);
+        assert!(!command.retain);
+        assert_eq!(command.broker, None);
+        assert_eq!(command.encoding, mqtt::PayloadEncoding::Utf8);
+    }
+
+    #[test]
+    fn test_legacy_files_with_unknown_fields_are_loaded() {
+        let resource = TestResource::new();
+        std::fs::write(
+            std::format!("{}/legacy.json", resource.commands_path),
+            r#"{"name": "legacy", "topic": "t", "payload": "p", "description": "old"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            std::format!("{}/broken.json", resource.commands_path),
+            r#"{"name": "broken", "topic": "t""#,
+        )
+        .unwrap();
+        std::fs::write(
+            std::format!("{}/legacy.json", resource.pipelines_path),
+            r#"{"name": "legacy", "pipeline": [{"topic": "a"}], "color": "red"}"#,
+        )
+        .unwrap();
+
+        let command = get_command(&resource.commands_path, "legacy").unwrap();
+        assert_eq!(command.payload, "p");
+        assert!(get_command(&resource.commands_path, "broken").is_none());
+        let pipelines = get_pipelines(&resource.pipelines_path).unwrap();
+        assert!(pipelines.iter().any(|p| p.name == "legacy"));
+        // New saves stay strict
+        let params = serde_json::json!({"name": "new", "topic": "t", "payload": "p", "x": 1});
+        assert!(add_to_commands(&resource.commands_path, params).is_err());
+    }
+
+    #[test]
+    fn test_add_to_commands_rejects_invalid_options() {
+        let resource = TestResource::new();
+        for (name, qos, encoding, payload) in [
+            ("bad_qos", 3, "utf8", "x"),
+            ("bad_hex", 1, "hex", "xyz"),
+            ("bad_base64", 0, "base64", "%%"),
+        ] {
+            let params = serde_json::json!({
+                "name": name,
+                "topic": "test",
+                "payload": payload,
+                "qos": qos,
+                "encoding": encoding,
+            });
+            assert!(add_to_commands(&resource.commands_path, params).is_err());
+            assert!(get_command(&resource.commands_path, name).is_none());
+        }
+
+        let params = serde_json::json!({
+            "name": "binary",
+            "topic": "test",
+            "payload": "AP8=",
+            "qos": 2,
+            "retain": true,
+            "broker": "plant-a",
+            "encoding": "base64",
+        });
+        add_to_commands(&resource.commands_path, params).unwrap();
+        let command = get_command(&resource.commands_path, "binary").unwrap();
+        assert_eq!(command.qos, 2);
+        assert!(command.retain);
+        assert_eq!(command.broker.as_deref(), Some("plant-a"));
+        assert_eq!(command.encoding, mqtt::PayloadEncoding::Base64);
+    }
+
     #[test]
     fn test_add_to_commands_already_exists() {
         let resource = TestResource::new();
@@ -313,7 +1196,7 @@ mod tests {
             "payload": "I replaced the test"
         });
 
-        add_to_commands(&resource.commands_path, params);
+        add_to_commands(&resource.commands_path, params).unwrap();
         let new_command = std::fs::read_to_string(&command_path).unwrap();
         let new_command: CommandMessage = serde_json::from_str(&new_command).unwrap();
         assert_eq!(new_command.name, "first_command");
@@ -329,7 +1212,7 @@ mod tests {
         let params = serde_json::json!({
             "name": "first_command"
         });
-        remove_from_commands(&resource.commands_path, params);
+        remove_from_commands(&resource.commands_path, params).unwrap();
         assert!(std::fs::metadata(command_path).is_err());
     }
 
@@ -338,7 +1221,7 @@ mod tests {
         let params = serde_json::json!({
             "name": "does_not_exist"
         });
-        remove_from_commands("whatever", params);
+        assert!(remove_from_commands("whatever", params).is_err());
     }
 
     #[test]
@@ -354,7 +1237,7 @@ mod tests {
                 }
             ]
         });
-        add_to_pipelines(&resource.pipelines_path, params);
+        add_to_pipelines(&resource.pipelines_path, params).unwrap();
         let pipeline = std::fs::read_to_string(&pipeline_path).unwrap();
         let pipeline: PipelineMessage = serde_json::from_str(&pipeline).unwrap();
         assert_eq!(pipeline.name, "new_pipeline");
@@ -373,7 +1256,7 @@ mod tests {
                 }
             ]
         });
-        add_to_pipelines(&resource.pipelines_path, params);
+        assert!(add_to_pipelines(&resource.pipelines_path, params).is_err());
         let pipeline_path = std::format!("{}/failure_mode.json", resource.pipelines_path);
         assert!(std::fs::metadata(pipeline_path).is_err());
     }
@@ -386,7 +1269,7 @@ mod tests {
         let params = serde_json::json!({
             "name": "empty_pipeline"
         });
-        remove_from_pipelines(&resource.pipelines_path, params);
+        remove_from_pipelines(&resource.pipelines_path, params).unwrap();
         assert!(!std::path::Path::new(&pipeline_path).exists());
     }
 
@@ -397,7 +1280,7 @@ mod tests {
         let params = serde_json::json!({
             "name": "sould_not_exist"
         });
-        remove_from_pipelines(&pipelines_path, params);
+        assert!(remove_from_pipelines(&pipelines_path, params).is_err());
         // Verify existing pipelines are untouched
         let pipeline_path = std::format!("{}/empty_pipeline.json", pipelines_path);
         assert!(std::path::Path::new(&pipeline_path).exists());
@@ -410,7 +1293,7 @@ mod tests {
         let resource = TestResource::new();
         // No "name" key at all — should not panic, just early return
         let params = serde_json::json!({});
-        remove_from_commands(&resource.commands_path, params);
+        assert!(remove_from_commands(&resource.commands_path, params).is_err());
         // Verify no files were deleted (first_command still exists)
         let command_path = std::format!("{}/first_command.json", resource.commands_path);
         assert!(std::path::Path::new(&command_path).exists());
@@ -421,7 +1304,7 @@ mod tests {
         let resource = TestResource::new();
         // Has a key, but not "name"
         let params = serde_json::json!({"wrong_key": "first_command"});
-        remove_from_commands(&resource.commands_path, params);
+        assert!(remove_from_commands(&resource.commands_path, params).is_err());
         let command_path = std::format!("{}/first_command.json", resource.commands_path);
         assert!(std::path::Path::new(&command_path).exists());
     }
@@ -431,7 +1314,7 @@ mod tests {
         let resource = TestResource::new();
         // "name" exists but is a number, not a string
         let params = serde_json::json!({"name": 42});
-        remove_from_commands(&resource.commands_path, params);
+        assert!(remove_from_commands(&resource.commands_path, params).is_err());
         let command_path = std::format!("{}/first_command.json", resource.commands_path);
         assert!(std::path::Path::new(&command_path).exists());
     }
@@ -440,7 +1323,7 @@ mod tests {
     fn test_remove_from_commands_name_is_null() {
         let resource = TestResource::new();
         let params = serde_json::json!({"name": null});
-        remove_from_commands(&resource.commands_path, params);
+        assert!(remove_from_commands(&resource.commands_path, params).is_err());
         let command_path = std::format!("{}/first_command.json", resource.commands_path);
         assert!(std::path::Path::new(&command_path).exists());
     }
@@ -449,7 +1332,7 @@ mod tests {
     fn test_remove_from_pipelines_missing_name_param() {
         let resource = TestResource::new();
         let params = serde_json::json!({});
-        remove_from_pipelines(&resource.pipelines_path, params);
+        assert!(remove_from_pipelines(&resource.pipelines_path, params).is_err());
         let pipeline_path = std::format!("{}/empty_pipeline.json", resource.pipelines_path);
         assert!(std::path::Path::new(&pipeline_path).exists());
     }
@@ -458,7 +1341,7 @@ mod tests {
     fn test_remove_from_pipelines_wrong_key() {
         let resource = TestResource::new();
         let params = serde_json::json!({"wrong_key": "empty_pipeline"});
-        remove_from_pipelines(&resource.pipelines_path, params);
+        assert!(remove_from_pipelines(&resource.pipelines_path, params).is_err());
         let pipeline_path = std::format!("{}/empty_pipeline.json", resource.pipelines_path);
         assert!(std::path::Path::new(&pipeline_path).exists());
     }
@@ -467,7 +1350,7 @@ mod tests {
     fn test_remove_from_pipelines_name_is_not_string() {
         let resource = TestResource::new();
         let params = serde_json::json!({"name": 123});
-        remove_from_pipelines(&resource.pipelines_path, params);
+        assert!(remove_from_pipelines(&resource.pipelines_path, params).is_err());
         let pipeline_path = std::format!("{}/empty_pipeline.json", resource.pipelines_path);
         assert!(std::path::Path::new(&pipeline_path).exists());
     }
@@ -476,7 +1359,7 @@ mod tests {
     fn test_remove_from_pipelines_name_is_null() {
         let resource = TestResource::new();
         let params = serde_json::json!({"name": null});
-        remove_from_pipelines(&resource.pipelines_path, params);
+        assert!(remove_from_pipelines(&resource.pipelines_path, params).is_err());
         let pipeline_path = std::format!("{}/empty_pipeline.json", resource.pipelines_path);
         assert!(std::path::Path::new(&pipeline_path).exists());
     }
@@ -488,9 +1371,307 @@ mod tests {
         let resource = TestResource::new();
         // Missing required fields — should not panic
         let params = serde_json::json!({"name": "broken"});
-        add_to_commands(&resource.commands_path, params);
+        assert!(add_to_commands(&resource.commands_path, params).is_err());
         // File should not be created since deserialization fails
         let command_path = std::format!("{}/broken.json", resource.commands_path);
         assert!(!std::path::Path::new(&command_path).exists());
     }
+
+    // --- folders ---
+
+    #[test]
+    fn test_validate_item_name() {
+        for valid in ["lights_on", "lab/door/open", "Küche 2/an"] {
+            assert!(validate_item_name(valid).is_ok(), "{valid}");
+        }
+        for invalid in [
+            "",
+            "../x",
+            "../../etc/passwd",
+            "a/../b",
+            "/abs",
+            "a//b",
+            "a/",
+            ".hidden",
+            "a\\b",
+            "c:x",
+            "a/b/c/d/e/f/g/h/i",
+        ] {
+            assert!(validate_item_name(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_write_atomic_replaces_file_without_leftovers() {
+        let dir = format!("/tmp/mqtt_atomic_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = std::path::Path::new(&dir).join("a.json");
+        write_atomic(&path, "old").unwrap();
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A directory in the way fails without leaving the temp file behind
+        std::fs::create_dir(std::path::Path::new(&dir).join("b.json")).unwrap();
+        assert!(write_atomic(&std::path::Path::new(&dir).join("b.json"), "x").is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_named_files_stay_in_their_directory() {
+        let resource = TestResource::new();
+        let dir = std::format!("{}/rules", resource.config_path);
+        let item = serde_json::json!({"name": "../brokers"});
+        for name in ["../brokers", "a/b", "..", ".hidden", ""] {
+            assert!(!write_named_file(&dir, "rule", name, &item), "{name}");
+            assert!(!remove_named_file(&dir, "rule", name), "{name}");
+        }
+        assert!(std::path::Path::new(&resource.brokers_path).exists());
+
+        assert!(write_named_file(
+            &dir,
+            "rule",
+            "b",
+            &serde_json::json!({"name": "b"})
+        ));
+        assert!(write_named_file(
+            &dir,
+            "rule",
+            "a",
+            &serde_json::json!({"name": "a"})
+        ));
+        // Written by hand with a name that would escape
+        std::fs::write(std::format!("{dir}/c.json"), item.to_string()).unwrap();
+        std::fs::write(std::format!("{dir}/d.json.tmp"), "{\"name\": \"d\"}").unwrap();
+        let names: Vec<String> = read_named_files::<serde_json::Value>(&dir, "rule")
+            .into_iter()
+            .map(|v| v["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+
+        assert!(remove_named_file(&dir, "rule", "a"));
+        assert!(!remove_named_file(&dir, "rule", "a"));
+    }
+
+    #[test]
+    fn test_add_to_commands_rejects_escaping_names() {
+        let resource = TestResource::new();
+        let params = serde_json::json!({"name": "../escaped", "topic": "t", "payload": "p"});
+        assert!(add_to_commands(&resource.commands_path, params).is_err());
+        let escaped = std::format!("{}/escaped.json", resource.config_path);
+        assert!(!std::path::Path::new(&escaped).exists());
+
+        let params = serde_json::json!({"name": "../brokers"});
+        assert!(remove_from_commands(&resource.commands_path, params).is_err());
+        assert!(std::path::Path::new(&resource.brokers_path).exists());
+    }
+
+    #[test]
+    fn test_nested_commands_are_listed_with_folders() {
+        let resource = TestResource::new();
+        let command = |name: &str| serde_json::json!({"name": name, "topic": "t", "payload": "p"});
+        add_to_commands(&resource.commands_path, command("lab/door/open")).unwrap();
+        add_to_commands(&resource.commands_path, command("lab/lamp")).unwrap();
+        std::fs::create_dir_all(std::format!("{}/empty", resource.commands_path)).unwrap();
+
+        let names: Vec<String> = get_commands(&resource.commands_path)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(
+            names,
+            ["first_command", "i_am_a_test", "lab/door/open", "lab/lamp"]
+        );
+        assert_eq!(
+            get_folders(&resource.commands_path),
+            vec![
+                FolderInfo {
+                    path: "empty".to_string(),
+                    items: 0,
+                    folders: 0
+                },
+                FolderInfo {
+                    path: "lab".to_string(),
+                    items: 1,
+                    folders: 1
+                },
+                FolderInfo {
+                    path: "lab/door".to_string(),
+                    items: 1,
+                    folders: 0
+                },
+            ]
+        );
+        assert!(get_command(&resource.commands_path, "lab/door/open").is_some());
+    }
+
+    #[test]
+    fn test_move_item_and_folder() {
+        let resource = TestResource::new();
+        let dir = &resource.commands_path;
+        move_item(dir, "first_command", "lab/first").unwrap();
+        assert!(get_command(dir, "first_command").is_none());
+        let moved = std::fs::read_to_string(std::format!("{dir}/lab/first.json")).unwrap();
+        assert!(moved.contains(r#""name":"lab/first""#));
+
+        assert_eq!(
+            move_item(dir, "i_am_a_test", "lab/first"),
+            Err("lab/first already exists".to_string())
+        );
+        assert!(move_item(dir, "i_am_a_test", "../outside").is_err());
+        assert_eq!(
+            move_folder(dir, "lab", "lab/inner"),
+            Err("Can't move lab into itself".to_string())
+        );
+
+        move_folder(dir, "lab", "archive/lab").unwrap();
+        let command = get_command(dir, "archive/lab/first").unwrap();
+        assert_eq!(command.topic, get_commands(dir).unwrap()[0].topic);
+        let stored = std::fs::read_to_string(std::format!("{dir}/archive/lab/first.json")).unwrap();
+        assert!(stored.contains(r#""name":"archive/lab/first""#));
+
+        assert!(remove_folder(dir, "archive").is_err());
+        remove_from_commands(dir, serde_json::json!({"name": "archive/lab/first"})).unwrap();
+        remove_folder(dir, "archive/lab").unwrap();
+        remove_folder(dir, "archive").unwrap();
+        assert!(get_folders(dir).is_empty());
+    }
+
+    // --- revisions ---
+
+    #[test]
+    fn test_saves_with_stale_revision_are_rejected() {
+        let resource = TestResource::new();
+        let dir = &resource.commands_path;
+        let command = |payload: &str, revision: Option<u64>| {
+            let mut params = serde_json::json!({"name": "cmd", "topic": "t", "payload": payload});
+            if let Some(revision) = revision {
+                params["revision"] = revision.into();
+            }
+            params
+        };
+        assert_eq!(add_to_commands(dir, command("a", Some(0))), Ok(1));
+        assert_eq!(add_to_commands(dir, command("b", Some(1))), Ok(2));
+        assert_eq!(
+            add_to_commands(dir, command("c", Some(1))),
+            Err(SaveError::Conflict { current: 2 })
+        );
+        assert_eq!(get_command(dir, "cmd").unwrap().payload, "b");
+        // Without a revision the caller overwrites whatever is stored
+        assert_eq!(add_to_commands(dir, command("d", None)), Ok(3));
+
+        let remove = |revision: u64| serde_json::json!({"name": "cmd", "revision": revision});
+        assert_eq!(
+            remove_from_commands(dir, remove(2)),
+            Err(SaveError::Conflict { current: 3 })
+        );
+        assert_eq!(remove_from_commands(dir, remove(3)), Ok(()));
+        assert_eq!(
+            add_to_commands(dir, command("e", Some(3))),
+            Err(SaveError::Conflict { current: 0 })
+        );
+        assert!(std::fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .all(|entry| !entry.file_name().to_string_lossy().ends_with(".tmp")));
+    }
+
+    #[test]
+    fn test_update_in_brokers_rejects_stale_revision() {
+        let resource = TestResource::new();
+        let path = &resource.brokers_path;
+        let rename = |name: &'static str| {
+            move |broker: &mut BrokerConfig| broker.name = Some(name.to_string())
+        };
+        update_in_brokers(path, "localhost:1883", Some(0), rename("first")).unwrap();
+        assert_eq!(
+            update_in_brokers(path, "localhost:1883", Some(0), rename("stale")),
+            Err(SaveError::Conflict { current: 1 })
+        );
+        assert_eq!(
+            remove_from_brokers(path, "localhost:1883", Some(0)),
+            Err(SaveError::Conflict { current: 1 })
+        );
+        let brokers = get_known_brokers(path);
+        assert_eq!(brokers[0].name.as_deref(), Some("first"));
+        assert_eq!(brokers[0].revision, 1);
+        remove_from_brokers(path, "localhost:1883", Some(1)).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_broker_saves_keep_every_entry() {
+        let resource = TestResource::new();
+        let len_before = get_known_brokers(&resource.brokers_path).len();
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let path = resource.brokers_path.clone();
+                std::thread::spawn(move || {
+                    let mut broker = BrokerConfig::from_host("localhost:1883");
+                    broker.id = format!("client-{n}");
+                    add_to_brokers(&path, &broker).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            get_known_brokers(&resource.brokers_path).len(),
+            len_before + 8
+        );
+    }
+
+    #[test]
+    fn test_invalid_brokers_file_is_not_overwritten() {
+        let resource = TestResource::new();
+        std::fs::write(&resource.brokers_path, "[{\"host\": ").unwrap();
+        let broker = BrokerConfig::from_host("new:1883");
+        assert!(matches!(
+            add_to_brokers(&resource.brokers_path, &broker),
+            Err(SaveError::Failed(_))
+        ));
+        assert_eq!(
+            std::fs::read_to_string(&resource.brokers_path).unwrap(),
+            "[{\"host\": "
+        );
+    }
+
+    // --- history ---
+
+    #[test]
+    fn test_removed_command_can_be_restored() {
+        let resource = TestResource::new();
+        let dir = &resource.commands_path;
+        let params =
+            |payload: &str| serde_json::json!({"name": "cmd", "topic": "t", "payload": payload});
+        add_to_commands(dir, params("a")).unwrap();
+        add_to_commands(dir, params("b")).unwrap();
+        remove_from_commands(dir, serde_json::json!({"name": "cmd"})).unwrap();
+        assert!(get_command(dir, "cmd").is_none());
+        // The history is not listed as a folder or item
+        assert!(get_folders(dir).is_empty());
+        assert_eq!(get_commands(dir).unwrap().len(), 2);
+
+        assert_eq!(
+            restore_command(dir, "cmd", 1, Some(3)),
+            Err(SaveError::Conflict { current: 0 })
+        );
+        // Numbering continues after the removal, which was revision 3
+        assert_eq!(restore_command(dir, "cmd", 1, Some(0)), Ok(4));
+        let restored = get_command(dir, "cmd").unwrap();
+        assert_eq!(restored.payload, "a");
+        assert_eq!(restored.revision, 4);
+        let revisions: Vec<(u64, bool)> = history::list(dir, "cmd")
+            .iter()
+            .map(|r| (r.revision, r.removed))
+            .collect();
+        assert_eq!(revisions, [(1, false), (2, false), (3, true), (4, false)]);
+        assert!(restore_command(dir, "cmd", 3, None).is_err());
+
+        move_item(dir, "cmd", "lab/cmd").unwrap();
+        assert_eq!(history::last_revision(dir, "lab/cmd"), 4);
+        assert_eq!(history::last_revision(dir, "cmd"), 0);
+    }
 }
@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc};
+
+/// How far ahead `next_after` looks before giving up, e.g. for `0 0 31 2 *`.
+const SEARCH_DAYS: i64 = 366 * 5;
+
+/// A standard five-field cron expression (`minute hour day-of-month month
+/// day-of-week`), evaluated in UTC. Fields accept `*`, numbers, ranges
+/// (`1-5`), steps (`*/15`, `0-30/10`) and lists (`1,15`). Day-of-week is
+/// 0-6 starting on Sunday; 7 is also Sunday.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronSchedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    /// Standard cron matches either day field when both are restricted.
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("Invalid step in '{part}'"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, part)?, parse_value(end, part)?)
+        } else {
+            let value = parse_value(range, part)?;
+            // `5/10` means every 10th value starting at 5
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("'{part}' is outside {min}-{max}"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+fn parse_value(value: &str, part: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid value in '{part}'"))
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(format!(
+                "Expected 5 fields in cron expression '{expression}', found {}",
+                fields.len()
+            ));
+        };
+        let mut days_of_week_allowed = parse_field(days_of_week, 0, 7)?;
+        if days_of_week_allowed[7] {
+            days_of_week_allowed[0] = true;
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days_of_month: parse_field(days_of_month, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            days_of_week: days_of_week_allowed,
+            days_of_month_restricted: days_of_month != "*",
+            days_of_week_restricted: days_of_week != "*",
+        })
+    }
+
+    fn matches_day(&self, time: &DateTime<Utc>) -> bool {
+        if !self.months[time.month() as usize] {
+            return false;
+        }
+        let day_of_month = self.days_of_month[time.day() as usize];
+        let day_of_week = self.days_of_week[time.weekday().num_days_from_sunday() as usize];
+        match (self.days_of_month_restricted, self.days_of_week_restricted) {
+            (true, true) => day_of_month || day_of_week,
+            (true, false) => day_of_month,
+            (false, true) => day_of_week,
+            (false, false) => true,
+        }
+    }
+
+    /// The first matching minute strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = after.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        let limit = after + Duration::days(SEARCH_DAYS);
+        while time <= limit {
+            if !self.matches_day(&time) {
+                time = time.duration_trunc(Duration::days(1)).ok()? + Duration::days(1);
+            } else if !self.hours[time.hour() as usize] {
+                time = time.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+            } else if !self.minutes[time.minute() as usize] {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(timestamp)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn next(expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        CronSchedule::parse(expression)
+            .unwrap()
+            .next_after(at(after))
+    }
+
+    #[test]
+    fn test_next_after() {
+        assert_eq!(
+            next("0 3 * * *", "2026-03-01T03:00:00Z"),
+            Some(at("2026-03-02T03:00:00Z"))
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2026-03-01T10:07:30Z"),
+            Some(at("2026-03-01T10:15:00Z"))
+        );
+        assert_eq!(
+            next("30 8 * * 1-5", "2026-03-06T09:00:00Z"),
+            Some(at("2026-03-09T08:30:00Z"))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2026-03-01T00:00:00Z"),
+            Some(at("2028-02-29T00:00:00Z"))
+        );
+        assert_eq!(
+            next("0 12 1,15 * 7", "2026-03-02T00:00:00Z"),
+            Some(at("2026-03-08T12:00:00Z"))
+        );
+        assert_eq!(next("0 0 31 2 *", "2026-01-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(CronSchedule::parse(expression).is_err(), "{expression}");
+        }
+    }
+}
@@ -0,0 +1,335 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::mqtt;
+use super::websocket;
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Publishes without an acknowledgement after this long are reported as timed out.
+pub const PUBLISH_TIMEOUT_SECS: u64 = 10;
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Handed to the connection, waiting to be written.
+    Queued,
+    /// Written to the connection. Final for QoS 0, which has no acknowledgement.
+    Sent,
+    /// PUBACK (QoS 1) or PUBCOMP (QoS 2) received.
+    Acked,
+    Failed,
+    TimedOut,
+}
+
+/// Peer that asked for a publish, and its optional id for the publish.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Requester {
+    pub addr: SocketAddr,
+    pub id: Option<String>,
+}
+
+/// `publish_result` sent to the requesting peer.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct PublishResult {
+    pub id: Option<String>,
+    pub broker: String,
+    pub topic: String,
+    pub qos: u8,
+    pub state: DeliveryState,
+    /// Milliseconds from queueing to the state change.
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+pub type Report = (SocketAddr, PublishResult);
+
+/// A publish whose outcome is reported to its requester.
+#[derive(Debug)]
+pub struct Tracked {
+    requester: Requester,
+    broker: String,
+    topic: String,
+    qos: u8,
+    queued_at: Instant,
+}
+
+impl Tracked {
+    pub fn new(requester: Requester, broker: &str, topic: &str, qos: rumqttc::QoS) -> Self {
+        Self {
+            requester,
+            broker: broker.to_string(),
+            topic: topic.to_string(),
+            qos: qos as u8,
+            queued_at: Instant::now(),
+        }
+    }
+
+    pub fn report(&self, state: DeliveryState, error: Option<String>) -> Report {
+        let latency_ms = match state {
+            DeliveryState::Queued | DeliveryState::Failed => None,
+            _ => Some(self.queued_at.elapsed().as_secs_f64() * 1000.0),
+        };
+        let result = PublishResult {
+            id: self.requester.id.clone(),
+            broker: self.broker.clone(),
+            topic: self.topic.clone(),
+            qos: self.qos,
+            state,
+            latency_ms,
+            error,
+        };
+        (self.requester.addr, result)
+    }
+}
+
+/// Outgoing publishes of one connection, matched to the packet ids the
+/// connection loop reports. The event loop writes publishes in the order
+/// they were queued, so every publish on the client is queued here, tracked
+/// or not (`None`).
+#[derive(Default, Debug)]
+pub struct DeliveryTracker {
+    queued: VecDeque<Option<Tracked>>,
+    /// QoS 1/2 publishes waiting for PUBACK/PUBCOMP, by packet id.
+    inflight: HashMap<u16, Option<Tracked>>,
+    /// A publish held back because its packet id was still in flight.
+    collided: Option<(u16, Option<Tracked>)>,
+    /// Packet ids whose next acknowledgement is for the publish a collision
+    /// replaced, already reported.
+    acked_early: HashSet<u16>,
+    /// Publishes of a connection that was replaced, reported as failed next.
+    abandoned: Vec<Tracked>,
+}
+
+impl DeliveryTracker {
+    pub fn push(&mut self, tracked: Option<Tracked>) {
+        self.queued.push_back(tracked);
+    }
+
+    /// A publish with `pkid` (0 for QoS 0) was written to the connection.
+    pub fn on_sent(&mut self, pkid: u16) -> Vec<Report> {
+        if let Some((collided_pkid, _)) = &self.collided {
+            if *collided_pkid == pkid {
+                // The event loop sends the held-back publish right after the
+                // acknowledgement for the previous one with this id
+                let (_, entry) = self.collided.take().unwrap();
+                let previous = self.inflight.insert(pkid, entry);
+                self.acked_early.insert(pkid);
+                let report = previous
+                    .flatten()
+                    .map(|t| t.report(DeliveryState::Acked, None));
+                return report.into_iter().collect();
+            }
+        }
+        if pkid != 0 && self.inflight.contains_key(&pkid) {
+            // Resent after a reconnect
+            return Vec::new();
+        }
+        let Some(entry) = self.queued.pop_front() else {
+            return Vec::new();
+        };
+        if pkid == 0 {
+            return entry
+                .map(|t| t.report(DeliveryState::Sent, None))
+                .into_iter()
+                .collect();
+        }
+        self.inflight.insert(pkid, entry);
+        Vec::new()
+    }
+
+    /// The next queued publish got `pkid`, which is still in flight.
+    pub fn on_collision(&mut self, pkid: u16) {
+        if self.collided.is_none() {
+            if let Some(entry) = self.queued.pop_front() {
+                self.collided = Some((pkid, entry));
+            }
+        }
+    }
+
+    /// PUBACK or PUBCOMP for `pkid` arrived.
+    pub fn on_acked(&mut self, pkid: u16) -> Vec<Report> {
+        if self.acked_early.remove(&pkid) {
+            return Vec::new();
+        }
+        self.inflight
+            .remove(&pkid)
+            .flatten()
+            .map(|t| t.report(DeliveryState::Acked, None))
+            .into_iter()
+            .collect()
+    }
+
+    /// The connection was replaced. Its publishes won't be acknowledged.
+    pub fn reset(&mut self) {
+        let entries = self
+            .queued
+            .drain(..)
+            .chain(self.inflight.drain().map(|(_, e)| e))
+            .chain(self.collided.take().map(|(_, e)| e));
+        self.abandoned.extend(entries.flatten());
+        self.acked_early.clear();
+    }
+
+    /// Failed publishes of replaced connections and tracked publishes older
+    /// than `timeout`. Untracked slots stay so later publishes still match.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<Report> {
+        let mut reports: Vec<Report> = self
+            .abandoned
+            .drain(..)
+            .map(|t| {
+                t.report(
+                    DeliveryState::Failed,
+                    Some("Connection was reset".to_string()),
+                )
+            })
+            .collect();
+        let expired = |entry: &mut Option<Tracked>| {
+            if entry.as_ref()?.queued_at.elapsed() < timeout {
+                return None;
+            }
+            let tracked = entry.take()?;
+            Some(tracked.report(DeliveryState::TimedOut, None))
+        };
+        reports.extend(self.queued.iter_mut().filter_map(expired));
+        reports.extend(self.inflight.values_mut().filter_map(expired));
+        if let Some((_, entry)) = &mut self.collided {
+            reports.extend(expired(entry));
+        }
+        reports
+    }
+}
+
+/// Report publishes that timed out or were lost to a reconnect.
+pub fn process_timeouts(mqtt_map: &mqtt::BrokerMap, peer_map: &websocket::PeerMap) {
+    let timeout = Duration::from_secs(PUBLISH_TIMEOUT_SECS);
+    for broker in mqtt::all_brokers(mqtt_map) {
+        let reports = broker.deliveries().expire(timeout);
+        for (addr, result) in reports {
+            websocket::send_publish_result(peer_map, addr, &result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracked(id: &str) -> Option<Tracked> {
+        let requester = Requester {
+            addr: "127.0.0.1:9001".parse().unwrap(),
+            id: Some(id.to_string()),
+        };
+        Some(Tracked::new(requester, "b", "t", rumqttc::QoS::AtLeastOnce))
+    }
+
+    fn states(reports: Vec<Report>) -> Vec<(Option<String>, DeliveryState)> {
+        reports.into_iter().map(|(_, r)| (r.id, r.state)).collect()
+    }
+
+    fn done(id: &str, state: DeliveryState) -> Vec<(Option<String>, DeliveryState)> {
+        vec![(Some(id.to_string()), state)]
+    }
+
+    #[test]
+    fn test_matches_packet_ids_in_queue_order() {
+        let mut tracker = DeliveryTracker::default();
+        tracker.push(tracked("a"));
+        tracker.push(None);
+        tracker.push(tracked("qos0"));
+        tracker.push(tracked("b"));
+
+        assert!(tracker.on_sent(1).is_empty());
+        assert!(tracker.on_sent(2).is_empty());
+        assert_eq!(
+            states(tracker.on_sent(0)),
+            done("qos0", DeliveryState::Sent)
+        );
+        assert!(tracker.on_sent(3).is_empty());
+        // Resent after a reconnect: still waiting for the same ack
+        assert!(tracker.on_sent(1).is_empty());
+
+        assert_eq!(states(tracker.on_acked(3)), done("b", DeliveryState::Acked));
+        assert!(tracker.on_acked(2).is_empty());
+        let (_, result) = tracker.on_acked(1).pop().unwrap();
+        assert_eq!(result.state, DeliveryState::Acked);
+        assert!(result.latency_ms.is_some());
+        assert!(tracker.on_acked(1).is_empty());
+    }
+
+    #[test]
+    fn test_collision_waits_for_previous_ack() {
+        let mut tracker = DeliveryTracker::default();
+        tracker.push(tracked("first"));
+        tracker.push(tracked("second"));
+        assert!(tracker.on_sent(1).is_empty());
+        tracker.on_collision(1);
+        // The event loop resends after the PUBACK for 1, then reports that PUBACK
+        assert_eq!(
+            states(tracker.on_sent(1)),
+            done("first", DeliveryState::Acked)
+        );
+        assert!(tracker.on_acked(1).is_empty());
+        assert_eq!(
+            states(tracker.on_acked(1)),
+            done("second", DeliveryState::Acked)
+        );
+    }
+
+    #[test]
+    fn test_reset_and_timeouts() {
+        let mut tracker = DeliveryTracker::default();
+        tracker.push(tracked("inflight"));
+        tracker.push(tracked("queued"));
+        tracker.on_sent(1);
+        tracker.reset();
+        assert_eq!(
+            states(tracker.expire(Duration::from_secs(60))),
+            vec![
+                (Some("queued".to_string()), DeliveryState::Failed),
+                (Some("inflight".to_string()), DeliveryState::Failed),
+            ]
+        );
+
+        tracker.push(tracked("slow"));
+        tracker.push(None);
+        assert!(tracker.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(
+            states(tracker.expire(Duration::ZERO)),
+            done("slow", DeliveryState::TimedOut)
+        );
+        // Slots are kept so the next packet ids still line up
+        assert!(tracker.on_sent(1).is_empty());
+        assert!(tracker.on_acked(1).is_empty());
+        tracker.push(tracked("next"));
+        tracker.on_sent(2);
+        tracker.on_sent(3);
+        assert_eq!(
+            states(tracker.on_acked(3)),
+            done("next", DeliveryState::Acked)
+        );
+    }
+}
@@ -0,0 +1,431 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::config;
+
+use std::path::{Path, PathBuf};
+
+/// Revisions kept per command or pipeline; older ones are dropped.
+pub const MAX_REVISIONS: usize = 20;
+
+/// Folder below the commands or pipelines dir holding their history. Item
+/// names can't start with `.`, so it never clashes with a folder of items.
+const HISTORY_DIR: &str = ".history";
+
+/// A saved or removed state of an item.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct Revision {
+    pub revision: u64,
+    /// RFC 3339, UTC.
+    pub saved_at: String,
+    /// Set for the revision that removed the item, which has no content.
+    #[serde(default)]
+    pub removed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item: Option<serde_json::Value>,
+}
+
+/// A revision without its content, as listed to clients.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct RevisionSummary {
+    pub revision: u64,
+    pub saved_at: String,
+    pub removed: bool,
+}
+
+/// An item whose latest revision removed it.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct RemovedItem {
+    pub name: String,
+    /// Last revision with content, the one to restore.
+    pub revision: u64,
+    pub removed_at: String,
+}
+
+/// A value that differs between two revisions. `path` is a JSON path like
+/// `$.pipeline[1].topic`; `before` or `after` is `None` where the value is
+/// missing on that side.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+/// History file of the item `name`, or of the folder `name` if `folder`.
+/// Names that would leave the history dir are rejected.
+fn history_path(dir: &str, name: &str, folder: bool) -> Result<PathBuf, String> {
+    config::validate_item_name(name)?;
+    let root = Path::new(dir).join(HISTORY_DIR);
+    if folder {
+        Ok(root.join(name))
+    } else {
+        Ok(root.join(format!("{name}.json")))
+    }
+}
+
+/// Revisions of `name` below `dir`, oldest first.
+pub fn revisions(dir: &str, name: &str) -> Vec<Revision> {
+    let Ok(path) = history_path(dir, name, false) else {
+        return Vec::new();
+    };
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn list(dir: &str, name: &str) -> Vec<RevisionSummary> {
+    revisions(dir, name)
+        .into_iter()
+        .map(|r| RevisionSummary {
+            revision: r.revision,
+            saved_at: r.saved_at,
+            removed: r.removed,
+        })
+        .collect()
+}
+
+/// Highest revision `name` ever had, 0 without history. Items saved again
+/// after a removal continue from there.
+pub fn last_revision(dir: &str, name: &str) -> u64 {
+    revisions(dir, name).last().map_or(0, |r| r.revision)
+}
+
+/// Append a revision of `name`, dropping the oldest beyond `MAX_REVISIONS`.
+/// The caller holds `config::lock_config`.
+pub fn record(dir: &str, name: &str, item: Option<serde_json::Value>, revision: u64) {
+    let mut history = revisions(dir, name);
+    history.push(Revision {
+        revision,
+        saved_at: chrono::Utc::now().to_rfc3339(),
+        removed: item.is_none(),
+        item,
+    });
+    let excess = history.len().saturating_sub(MAX_REVISIONS);
+    history.drain(..excess);
+
+    let path = match history_path(dir, name, false) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("Can't record the history of {name}: {err}");
+            return;
+        }
+    };
+    if let Some(parent_dir) = path.parent() {
+        if let Err(err) = std::fs::create_dir_all(parent_dir) {
+            eprintln!("Failed to create directory {parent_dir:?}: {err}");
+            return;
+        }
+    }
+    let Ok(content) = serde_json::to_string(&history) else {
+        eprintln!("Failed to serialize the history of {name}.");
+        return;
+    };
+    if let Err(err) = config::write_atomic(&path, &content) {
+        eprintln!("Failed to save the history of {name} to {path:?}: {err}");
+    }
+}
+
+/// Fail if `to` has history already, e.g. of a removed item with that name.
+/// Renaming an item or folder to `to` would replace it.
+pub fn check_rename_target(dir: &str, to: &str, folder: bool) -> Result<(), String> {
+    if history_path(dir, to, folder)?.exists() {
+        return Err(format!(
+            "{to} still has the history of a removed item, pick another name"
+        ));
+    }
+    Ok(())
+}
+
+/// Keep the history with an item or folder renamed from `from` to `to`.
+/// Callers check the target with `check_rename_target` first.
+pub fn rename(dir: &str, from: &str, to: &str, folder: bool) {
+    let (Ok(source), Ok(target)) = (
+        history_path(dir, from, folder),
+        history_path(dir, to, folder),
+    ) else {
+        return;
+    };
+    if !source.exists() || target.exists() {
+        return;
+    }
+    if let Some(parent_dir) = target.parent() {
+        std::fs::create_dir_all(parent_dir).ok();
+    }
+    if let Err(err) = std::fs::rename(&source, &target) {
+        eprintln!("Failed to move the history of {from} to {to}: {err}");
+    }
+}
+
+/// Items below `dir` that were removed and still have history.
+pub fn removed_items(dir: &str) -> Vec<RemovedItem> {
+    let root = Path::new(dir).join(HISTORY_DIR);
+    let mut items = Vec::new();
+    let mut pending = vec![root.clone()];
+    while let Some(path) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&path) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            if entry_path.is_dir() {
+                pending.push(entry_path);
+                continue;
+            }
+            let Some(name) = entry_path
+                .strip_prefix(&root)
+                .ok()
+                .and_then(|relative| relative.to_str()?.strip_suffix(".json"))
+            else {
+                continue;
+            };
+            let history = revisions(dir, name);
+            let (Some(last), Some(restorable)) = (
+                history.last(),
+                history.iter().rev().find(|r| r.item.is_some()),
+            ) else {
+                continue;
+            };
+            if last.removed {
+                items.push(RemovedItem {
+                    name: name.to_string(),
+                    revision: restorable.revision,
+                    removed_at: last.saved_at.clone(),
+                });
+            }
+        }
+    }
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    items
+}
+
+/// Content of revision `revision` of `name`, with its name as it is now.
+pub fn item_at<T: serde::de::DeserializeOwned>(
+    dir: &str,
+    name: &str,
+    revision: u64,
+) -> Result<T, String> {
+    let history = revisions(dir, name);
+    let entry = history
+        .iter()
+        .find(|r| r.revision == revision)
+        .ok_or_else(|| format!("Revision {revision} of {name} not found"))?;
+    let mut item = entry
+        .item
+        .clone()
+        .ok_or_else(|| format!("Revision {revision} of {name} removed it"))?;
+    item["name"] = name.into();
+    serde_json::from_value(item).map_err(|err| format!("Revision {revision} is invalid: {err}"))
+}
+
+/// Values that differ from revision `from` to revision `to` of `name`.
+pub fn diff_revisions(dir: &str, name: &str, from: u64, to: u64) -> Result<Vec<Change>, String> {
+    let history = revisions(dir, name);
+    let content = |revision: u64| {
+        history
+            .iter()
+            .find(|r| r.revision == revision)
+            .map(|r| r.item.clone())
+            .ok_or_else(|| format!("Revision {revision} of {name} not found"))
+    };
+    let (before, after) = (content(from)?, content(to)?);
+    let mut changes = Vec::new();
+    diff_values("$", before.as_ref(), after.as_ref(), &mut changes);
+    Ok(changes)
+}
+
+/// Collect the differences between `before` and `after`, descending into
+/// objects and arrays. The revision counter itself is left out.
+fn diff_values(
+    path: &str,
+    before: Option<&serde_json::Value>,
+    after: Option<&serde_json::Value>,
+    changes: &mut Vec<Change>,
+) {
+    use serde_json::Value;
+    match (before, after) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                if path == "$" && key == "revision" {
+                    continue;
+                }
+                diff_values(&format!("{path}.{key}"), a.get(key), b.get(key), changes);
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) => {
+            for index in 0..a.len().max(b.len()) {
+                diff_values(
+                    &format!("{path}[{index}]"),
+                    a.get(index),
+                    b.get(index),
+                    changes,
+                );
+            }
+        }
+        (before, after) if before != after => changes.push(Change {
+            path: path.to_string(),
+            before: before.cloned(),
+            after: after.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> String {
+        let path = format!("/tmp/mqtt_history_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn command(payload: &str) -> Option<serde_json::Value> {
+        Some(serde_json::json!({ "name": "lab/on", "topic": "t", "payload": payload }))
+    }
+
+    #[test]
+    fn test_record_keeps_the_latest_revisions() {
+        let dir = temp_dir();
+        for revision in 1..=(MAX_REVISIONS as u64 + 5) {
+            record(&dir, "lab/on", command(&revision.to_string()), revision);
+        }
+        let listed = list(&dir, "lab/on");
+        assert_eq!(listed.len(), MAX_REVISIONS);
+        assert_eq!(listed[0].revision, 6);
+        assert_eq!(last_revision(&dir, "lab/on"), MAX_REVISIONS as u64 + 5);
+        assert!(std::path::Path::new(&format!("{dir}/.history/lab/on.json")).is_file());
+        assert_eq!(last_revision(&dir, "other"), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_removed_items_and_restorable_content() {
+        let dir = temp_dir();
+        record(&dir, "lab/on", command("a"), 1);
+        record(&dir, "lab/on", None, 2);
+        record(&dir, "kept", command("b"), 1);
+
+        let removed = removed_items(&dir);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].name, "lab/on");
+        assert_eq!(removed[0].revision, 1);
+
+        let restored: config::CommandMessage = item_at(&dir, "lab/on", 1).unwrap();
+        assert_eq!(restored.payload, "a");
+        assert_eq!(
+            item_at::<config::CommandMessage>(&dir, "lab/on", 2).unwrap_err(),
+            "Revision 2 of lab/on removed it"
+        );
+        assert!(item_at::<config::CommandMessage>(&dir, "lab/on", 7).is_err());
+
+        rename(&dir, "lab", "archive/lab", true);
+        assert_eq!(removed_items(&dir)[0].name, "archive/lab/on");
+        let renamed: config::CommandMessage = item_at(&dir, "archive/lab/on", 1).unwrap();
+        assert_eq!(renamed.name, "archive/lab/on");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_names_outside_the_history_dir_are_rejected() {
+        let dir = temp_dir();
+        let outside = format!("{dir}/outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(
+            format!("{outside}/secret.json"),
+            r#"[{"revision": 1, "saved_at": "", "item": {"password": "x"}}]"#,
+        )
+        .unwrap();
+        let absolute = format!("{outside}/secret");
+        for name in ["../outside/secret", absolute.as_str()] {
+            assert!(list(&dir, name).is_empty(), "{name}");
+            assert!(item_at::<serde_json::Value>(&dir, name, 1).is_err());
+            assert!(diff_revisions(&dir, name, 1, 1).is_err());
+        }
+        record(&dir, "../escaped", command("a"), 1);
+        assert!(!std::path::Path::new(&format!("{dir}/escaped.json")).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rename_keeps_history_of_removed_target() {
+        let dir = temp_dir();
+        record(&dir, "old", command("removed"), 1);
+        record(&dir, "old", None, 2);
+        record(&dir, "new", command("moved"), 1);
+
+        assert!(check_rename_target(&dir, "old", false).is_err());
+        rename(&dir, "new", "old", false);
+        assert_eq!(list(&dir, "old").len(), 2);
+        assert_eq!(list(&dir, "new").len(), 1);
+
+        assert!(check_rename_target(&dir, "other", false).is_ok());
+        assert!(check_rename_target(&dir, "../x", false).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_diff_revisions() {
+        let dir = temp_dir();
+        let pipeline = |topics: &[&str], revision: u64| {
+            let steps: Vec<_> = topics
+                .iter()
+                .map(|topic| serde_json::json!({ "topic": topic }))
+                .collect();
+            Some(serde_json::json!({ "name": "p", "pipeline": steps, "revision": revision }))
+        };
+        record(&dir, "p", pipeline(&["a", "b"], 1), 1);
+        record(&dir, "p", pipeline(&["a", "c", "d"], 2), 2);
+        record(&dir, "p", None, 3);
+
+        assert_eq!(
+            diff_revisions(&dir, "p", 1, 2).unwrap(),
+            vec![
+                Change {
+                    path: "$.pipeline[1].topic".to_string(),
+                    before: Some("b".into()),
+                    after: Some("c".into()),
+                },
+                Change {
+                    path: "$.pipeline[2]".to_string(),
+                    before: None,
+                    after: Some(serde_json::json!({ "topic": "d" })),
+                },
+            ]
+        );
+        let removal = diff_revisions(&dir, "p", 2, 3).unwrap();
+        assert_eq!(removal.len(), 1);
+        assert_eq!(removal[0].path, "$");
+        assert_eq!(removal[0].after, None);
+        assert!(diff_revisions(&dir, "p", 1, 1).unwrap().is_empty());
+        assert_eq!(
+            diff_revisions(&dir, "p", 1, 9),
+            Err("Revision 9 of p not found".to_string())
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,457 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::commands;
+use super::mqtt;
+use super::websocket;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use tokio_util::sync::CancellationToken;
+
+/// How often due messages are published.
+const TICK_MS: u64 = 50;
+
+/// How often a running load test reports its progress.
+const PROGRESS_INTERVAL_MS: u64 = 1000;
+
+/// After a stall, at most this many ticks' worth of messages are sent at once.
+const MAX_CATCH_UP_TICKS: u64 = 4;
+
+const MAX_TOPICS: u32 = 10_000;
+const MAX_RATE: u32 = 100_000;
+const MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+const MAX_DURATION_SECS: u64 = 24 * 60 * 60;
+
+fn default_topic_prefix() -> String {
+    "loadtest".to_string()
+}
+
+fn default_topics() -> u32 {
+    10
+}
+
+fn default_payload_size() -> usize {
+    64
+}
+
+/// Params of `start_load`. Messages go round-robin to `{topic_prefix}/0` ..
+/// `{topic_prefix}/{topics - 1}`.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct LoadConfig {
+    pub broker: String,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_topics")]
+    pub topics: u32,
+    /// Messages per second across all topics.
+    pub rate: u32,
+    /// Size of the generated payload when there is no template.
+    #[serde(default = "default_payload_size")]
+    pub payload_size: usize,
+    /// Command template rendered for every message. `{{topic}}` and
+    /// `{{index}}` are the topic and its number; `{{counter}}` counts messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default)]
+    pub qos: u8,
+    pub duration_secs: u64,
+}
+
+impl LoadConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.topics == 0 || self.topics > MAX_TOPICS {
+            return Err(format!("Topics must be between 1 and {MAX_TOPICS}"));
+        }
+        if self.rate == 0 || self.rate > MAX_RATE {
+            return Err(format!("Rate must be between 1 and {MAX_RATE} per second"));
+        }
+        if self.payload_size > MAX_PAYLOAD_BYTES {
+            return Err(format!("Payload size must be at most {MAX_PAYLOAD_BYTES}"));
+        }
+        if self.duration_secs == 0 || self.duration_secs > MAX_DURATION_SECS {
+            return Err(format!(
+                "Duration must be between 1 and {MAX_DURATION_SECS} seconds"
+            ));
+        }
+        if mqtt::qos_from_u8(self.qos).is_none() {
+            return Err(format!("Invalid QoS {}", self.qos));
+        }
+        if self.topic_prefix.is_empty() || self.topic_prefix.contains(['+', '#']) {
+            return Err(format!("Invalid topic prefix {}", self.topic_prefix));
+        }
+        // Catch template errors before the first tick
+        self.message(0).map(|_| ())
+    }
+
+    /// Topic and payload of the `seq`th message.
+    fn message(&self, seq: u64) -> Result<(String, Vec<u8>), String> {
+        let index = seq % self.topics as u64;
+        let topic = format!("{}/{index}", self.topic_prefix);
+        let Some(template) = &self.payload else {
+            return Ok((topic, vec![b'x'; self.payload_size]));
+        };
+        let variables = BTreeMap::from([
+            ("topic".to_string(), topic.clone()),
+            ("index".to_string(), index.to_string()),
+        ]);
+        let payload = commands::render(
+            template,
+            &commands::TemplateContext {
+                variables: &variables,
+                counter: seq + 1,
+                latest_payload: &|_| None,
+            },
+        )?;
+        Ok((topic, payload.into_bytes()))
+    }
+}
+
+/// Messages to publish now so that `attempted` catches up with `rate` after
+/// `elapsed_ms`, limited so a stalled task doesn't send one large burst.
+fn messages_due(rate: u32, elapsed_ms: u64, attempted: u64) -> u64 {
+    let target = rate as u64 * elapsed_ms / 1000;
+    let max_burst = (rate as u64 * TICK_MS * MAX_CATCH_UP_TICKS / 1000).max(1);
+    target.saturating_sub(attempted).min(max_burst)
+}
+
+/// Why a message was not queued.
+#[derive(Debug, PartialEq)]
+enum Unsent {
+    /// The client's request queue is full: the broker can't keep up.
+    Dropped,
+    Failed,
+}
+
+/// Queue the `seq`th message without waiting for room in the request queue,
+/// so a slow broker lowers the achieved rate instead of stalling the run.
+fn publish(
+    config: &LoadConfig,
+    seq: u64,
+    qos: rumqttc::QoS,
+    mqtt_map: &mqtt::BrokerMap,
+) -> Result<usize, Unsent> {
+    let broker = match mqtt::get_broker(mqtt_map, &config.broker) {
+        Some(broker) if broker.is_connected() => broker,
+        _ => return Err(Unsent::Failed),
+    };
+    let (topic, payload) = config.message(seq).map_err(|_| Unsent::Failed)?;
+    match broker.publish(&topic, &payload, qos, false, None) {
+        Ok(()) => Ok(payload.len()),
+        Err(rumqttc::ClientError::TryRequest(_)) => Err(Unsent::Dropped),
+        Err(_) => Err(Unsent::Failed),
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct LoadStats {
+    sent: u64,
+    dropped: u64,
+    failed: u64,
+    bytes: u64,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadState {
+    Running,
+    Finished,
+    Stopped,
+}
+
+pub struct ActiveLoad {
+    broker: String,
+    cancel: CancellationToken,
+}
+
+/// Running load tests by run id.
+pub type LoadMap = Arc<Mutex<HashMap<String, ActiveLoad>>>;
+
+/// Broker of a running load test.
+pub fn broker_of(load_map: &LoadMap, run_id: &str) -> Option<String> {
+    load_map
+        .lock()
+        .unwrap()
+        .get(run_id)
+        .map(|load| load.broker.clone())
+}
+
+/// Stop a running load test. Returns `false` if there is none with `run_id`.
+pub fn stop(load_map: &LoadMap, run_id: &str) -> bool {
+    match load_map.lock().unwrap().get(run_id) {
+        Some(load) => {
+            load.cancel.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+fn progress(
+    run_id: &str,
+    config: &LoadConfig,
+    stats: &LoadStats,
+    elapsed_ms: u64,
+    recent: (u64, u64),
+    state: LoadState,
+) -> serde_json::Value {
+    let per_second = |count: u64, ms: u64| {
+        if ms == 0 {
+            0.0
+        } else {
+            count as f64 * 1000.0 / ms as f64
+        }
+    };
+    let (recent_sent, recent_ms) = recent;
+    serde_json::json!({
+        "run_id": run_id,
+        "broker": config.broker,
+        "state": state,
+        "sent": stats.sent,
+        "dropped": stats.dropped,
+        "failed": stats.failed,
+        "bytes": stats.bytes,
+        "elapsed_secs": elapsed_ms as f64 / 1000.0,
+        "duration_secs": config.duration_secs,
+        "target_rate": config.rate,
+        "average_rate": per_second(stats.sent, elapsed_ms),
+        "current_rate": per_second(recent_sent, recent_ms),
+    })
+}
+
+/// Start publishing `config` in the background, reporting `load_progress`
+/// to peers authenticated for the broker. Must be called from within the
+/// tokio runtime.
+pub fn start(
+    config: LoadConfig,
+    load_map: &LoadMap,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+) -> String {
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let cancel = CancellationToken::new();
+    load_map.lock().unwrap().insert(
+        run_id.clone(),
+        ActiveLoad {
+            broker: config.broker.clone(),
+            cancel: cancel.clone(),
+        },
+    );
+    let load_map = load_map.clone();
+    let peer_map = peer_map.clone();
+    let mqtt_map = mqtt_map.clone();
+    let id = run_id.clone();
+    tokio::spawn(async move {
+        run(&id, &config, &cancel, &peer_map, &mqtt_map).await;
+        load_map.lock().unwrap().remove(&id);
+    });
+    run_id
+}
+
+async fn run(
+    run_id: &str,
+    config: &LoadConfig,
+    cancel: &CancellationToken,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+) {
+    let qos = mqtt::qos_from_u8(config.qos).unwrap_or(rumqttc::QoS::AtMostOnce);
+    let duration_ms = config.duration_secs * 1000;
+    let started = std::time::Instant::now();
+    let mut stats = LoadStats::default();
+    let mut last_report = (0, 0);
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(TICK_MS));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    println!(
+        "Load test {run_id} started on {}: {} msg/s to {} topics for {} s",
+        config.broker, config.rate, config.topics, config.duration_secs
+    );
+    // Tells peers the run id before the first report
+    let status = progress(run_id, config, &stats, 0, (0, 0), LoadState::Running);
+    websocket::broadcast_load_progress(peer_map, &config.broker, status);
+    let state = loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break LoadState::Stopped,
+            _ = interval.tick() => {}
+        }
+        let elapsed_ms = (started.elapsed().as_millis() as u64).min(duration_ms);
+        let attempted = stats.sent + stats.dropped + stats.failed;
+        for seq in attempted..attempted + messages_due(config.rate, elapsed_ms, attempted) {
+            match publish(config, seq, qos, mqtt_map) {
+                Ok(len) => {
+                    stats.sent += 1;
+                    stats.bytes += len as u64;
+                }
+                Err(Unsent::Dropped) => stats.dropped += 1,
+                Err(Unsent::Failed) => stats.failed += 1,
+            }
+        }
+        if elapsed_ms >= duration_ms {
+            break LoadState::Finished;
+        }
+        let (last_sent, last_ms) = last_report;
+        if elapsed_ms - last_ms >= PROGRESS_INTERVAL_MS {
+            let recent = (stats.sent - last_sent, elapsed_ms - last_ms);
+            let status = progress(
+                run_id,
+                config,
+                &stats,
+                elapsed_ms,
+                recent,
+                LoadState::Running,
+            );
+            websocket::broadcast_load_progress(peer_map, &config.broker, status);
+            last_report = (stats.sent, elapsed_ms);
+        }
+    };
+
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    let (last_sent, last_ms) = last_report;
+    let recent = (stats.sent - last_sent, elapsed_ms.saturating_sub(last_ms));
+    let status = progress(run_id, config, &stats, elapsed_ms, recent, state);
+    println!(
+        "Load test {run_id} on {} ended: {} sent, {} dropped, {} failed",
+        config.broker, stats.sent, stats.dropped, stats.failed
+    );
+    websocket::broadcast_load_progress(peer_map, &config.broker, status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(json: serde_json::Value) -> LoadConfig {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_messages_due_follows_rate_with_bounded_catch_up() {
+        assert_eq!(messages_due(100, 0, 0), 0);
+        assert_eq!(messages_due(100, 50, 0), 5);
+        assert_eq!(messages_due(100, 100, 5), 5);
+        assert_eq!(messages_due(100, 1000, 100), 0);
+        // Behind by a whole second: limited to a few ticks' worth
+        assert_eq!(messages_due(100, 2000, 100), 20);
+        // Low rates still send one message per tick once it's due
+        assert_eq!(messages_due(1, 999, 0), 0);
+        assert_eq!(messages_due(1, 1000, 0), 1);
+    }
+
+    #[test]
+    fn test_messages_round_robin_with_template() {
+        let config = load(serde_json::json!({
+            "broker": "b:1883",
+            "topics": 3,
+            "rate": 10,
+            "duration_secs": 1,
+            "payload": "{\"topic\": \"{{topic}}\", \"seq\": {{counter}}}",
+        }));
+        assert!(config.validate().is_ok());
+        let (topic, payload) = config.message(4).unwrap();
+        assert_eq!(topic, "loadtest/1");
+        assert_eq!(payload, br#"{"topic": "loadtest/1", "seq": 5}"#);
+
+        let filler = load(serde_json::json!({
+            "broker": "b:1883", "rate": 10, "duration_secs": 1, "payload_size": 3,
+        }));
+        assert_eq!(
+            filler.message(0).unwrap(),
+            ("loadtest/0".to_string(), b"xxx".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_out_of_range_configs() {
+        let valid = serde_json::json!({ "broker": "b:1883", "rate": 10, "duration_secs": 5 });
+        assert!(load(valid.clone()).validate().is_ok());
+        for (field, value) in [
+            ("rate", serde_json::json!(0)),
+            ("topics", serde_json::json!(MAX_TOPICS + 1)),
+            ("duration_secs", serde_json::json!(0)),
+            ("qos", serde_json::json!(3)),
+            ("topic_prefix", serde_json::json!("a/#")),
+            ("payload", serde_json::json!("{{nope}}")),
+        ] {
+            let mut config = valid.clone();
+            config[field] = value;
+            assert!(load(config).validate().is_err(), "{field}");
+        }
+    }
+
+    #[test]
+    fn test_publish_reports_full_queue_as_dropped() {
+        let mqtt_map = mqtt::BrokerMap::default();
+        let config = load(serde_json::json!({
+            "broker": "b:1883", "rate": 10, "duration_secs": 1,
+        }));
+        let qos = rumqttc::QoS::AtMostOnce;
+        assert_eq!(publish(&config, 0, qos, &mqtt_map), Err(Unsent::Failed));
+
+        // A request queue with room for one publish; the event loop keeps it open
+        let options = rumqttc::MqttOptions::new("load", "b", 1883);
+        let (client, _eventloop) = rumqttc::AsyncClient::new(options, 1);
+        let broker = mqtt::SharedBroker::new(mqtt::BrokerEntry::new(
+            client,
+            false,
+            mqtt::MqttBroker::new("b:1883"),
+        ));
+        mqtt_map
+            .write()
+            .unwrap()
+            .insert("b:1883".to_string(), broker.clone());
+        assert_eq!(publish(&config, 0, qos, &mqtt_map), Err(Unsent::Failed));
+
+        broker.set_connected(true);
+        assert_eq!(publish(&config, 0, qos, &mqtt_map), Ok(64));
+        assert_eq!(publish(&config, 1, qos, &mqtt_map), Err(Unsent::Dropped));
+    }
+
+    #[tokio::test]
+    async fn test_run_counts_failures_and_can_be_stopped() {
+        let load_map = LoadMap::default();
+        let peer_map = websocket::PeerMap::default();
+        let mqtt_map = mqtt::BrokerMap::default();
+        let config = load(serde_json::json!({
+            "broker": "missing:1883", "rate": 1000, "duration_secs": 60,
+        }));
+        let run_id = start(config, &load_map, &peer_map, &mqtt_map);
+        assert_eq!(
+            broker_of(&load_map, &run_id).as_deref(),
+            Some("missing:1883")
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(120)).await;
+        assert!(stop(&load_map, &run_id));
+        for _ in 0..50 {
+            if load_map.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(load_map.lock().unwrap().is_empty());
+        assert!(!stop(&load_map, &run_id));
+    }
+}
@@ -22,21 +22,210 @@
 
 use std::{
     collections::{HashMap, VecDeque},
-    sync::{Arc, Mutex, OnceLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, RwLock,
+    },
 };
 
 use rumqttc::{MqttOptions, QoS, Transport};
+use tokio_util::sync::CancellationToken;
 
 use super::config::BrokerConfig;
+use super::delivery;
+use super::schema;
+
+/// Interned topic name, unique within one broker's `TopicStore`.
+pub type TopicId = u32;
 
 #[derive(serde::Serialize)]
 pub struct MqttMessage {
-    pub timestamp: String,
+    /// Receive time in nanoseconds since the Unix epoch.
+    #[serde(rename = "timestamp", serialize_with = "serialize_timestamp_ns")]
+    pub timestamp_ns: i64,
+    /// Per-broker sequence number, increasing with every stored message.
+    pub seq: u64,
     pub payload: bytes::Bytes,
     pub original_payload_size: usize,
     pub retain: bool,
 }
 
+fn serialize_timestamp_ns<S: serde::Serializer>(
+    ns: &i64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_timestamp_ns(*ns))
+}
+
+/// Current time in nanoseconds since the Unix epoch.
+pub fn now_ns() -> i64 {
+    chrono::Utc::now().timestamp_nanos_opt().unwrap_or(i64::MAX)
+}
+
+/// RFC 3339 representation used on the wire, e.g. in `mqtt_message` frames.
+pub fn format_timestamp_ns(ns: i64) -> String {
+    chrono::DateTime::from_timestamp_nanos(ns).to_rfc3339()
+}
+
+pub fn parse_timestamp_ns(timestamp: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(timestamp)
+        .ok()?
+        .timestamp_nanos_opt()
+}
+
+/// Bytes charged per stored message on top of its payload: the message
+/// itself, its slot in the topic history and its eviction queue entry.
+const MESSAGE_OVERHEAD_BYTES: usize =
+    std::mem::size_of::<MqttMessage>() + std::mem::size_of::<(TopicId, u64)>();
+
+/// Bytes charged per stored topic on top of its name: the history slot, the
+/// id map entry and the reference counts of the interned name.
+const TOPIC_OVERHEAD_BYTES: usize = std::mem::size_of::<Option<TopicHistory>>()
+    + std::mem::size_of::<(Arc<str>, TopicId)>()
+    + 2 * std::mem::size_of::<usize>();
+
+pub fn message_cost(payload_len: usize) -> usize {
+    payload_len + MESSAGE_OVERHEAD_BYTES
+}
+
+pub fn topic_cost(name: &str) -> usize {
+    name.len() + TOPIC_OVERHEAD_BYTES
+}
+
+pub struct TopicHistory {
+    pub name: Arc<str>,
+    pub messages: VecDeque<MqttMessage>,
+    /// Sum of the payload sizes in `messages`.
+    pub payload_bytes: usize,
+}
+
+/// Stored messages by topic. Topic names are interned once and referred to
+/// by id elsewhere; ids of topics without messages are reused.
+#[derive(Default)]
+pub struct TopicStore {
+    ids: HashMap<Arc<str>, TopicId>,
+    slots: Vec<Option<TopicHistory>>,
+    free_ids: Vec<TopicId>,
+}
+
+impl TopicStore {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn id(&self, name: &str) -> Option<TopicId> {
+        self.ids.get(name).copied()
+    }
+
+    #[cfg(test)]
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.ids.contains_key(name)
+    }
+
+    pub fn history(&self, name: &str) -> Option<&TopicHistory> {
+        self.history_by_id(self.id(name)?)
+    }
+
+    pub fn history_by_id(&self, id: TopicId) -> Option<&TopicHistory> {
+        self.slots.get(id as usize)?.as_ref()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&VecDeque<MqttMessage>> {
+        self.history(name).map(|h| &h.messages)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &VecDeque<MqttMessage>)> {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|h| (h.name.as_ref(), &h.messages))
+    }
+
+    pub fn histories(&self) -> impl Iterator<Item = &TopicHistory> {
+        self.slots.iter().flatten()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.slots.iter().flatten().map(|h| h.name.as_ref())
+    }
+
+    /// Append a message, creating the topic if needed. Returns the topic id
+    /// and whether the topic was created.
+    fn push(&mut self, name: &str, message: MqttMessage) -> (TopicId, bool) {
+        let (id, created) = match self.ids.get(name) {
+            Some(id) => (*id, false),
+            None => {
+                let name: Arc<str> = Arc::from(name);
+                let history = TopicHistory {
+                    name: name.clone(),
+                    messages: VecDeque::new(),
+                    payload_bytes: 0,
+                };
+                let id = match self.free_ids.pop() {
+                    Some(id) => {
+                        self.slots[id as usize] = Some(history);
+                        id
+                    }
+                    None => {
+                        self.slots.push(Some(history));
+                        (self.slots.len() - 1) as TopicId
+                    }
+                };
+                self.ids.insert(name, id);
+                (id, true)
+            }
+        };
+        if let Some(history) = self.slots[id as usize].as_mut() {
+            history.payload_bytes += message.payload.len();
+            history.messages.push_back(message);
+        }
+        (id, created)
+    }
+
+    /// Remove the oldest message of a topic. The topic is dropped with its
+    /// last message; its name is returned alongside in that case.
+    fn pop_front(&mut self, id: TopicId) -> Option<(MqttMessage, Option<Arc<str>>)> {
+        let history = self.slots.get_mut(id as usize)?.as_mut()?;
+        let message = history.messages.pop_front()?;
+        history.payload_bytes -= message.payload.len();
+        if !history.messages.is_empty() {
+            return Some((message, None));
+        }
+        let history = self.slots[id as usize].take()?;
+        self.ids.remove(&history.name);
+        self.free_ids.push(id);
+        Some((message, Some(history.name)))
+    }
+
+    /// Drop a topic with all its messages.
+    fn remove(&mut self, id: TopicId) -> Option<TopicHistory> {
+        let history = self.slots.get_mut(id as usize)?.take()?;
+        self.ids.remove(&history.name);
+        self.free_ids.push(id);
+        Some(history)
+    }
+}
+
+impl std::ops::Index<&str> for TopicStore {
+    type Output = VecDeque<MqttMessage>;
+
+    fn index(&self, name: &str) -> &Self::Output {
+        self.get(name).expect("topic not stored")
+    }
+}
+
+impl serde::Serialize for TopicStore {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
 #[derive(serde::Serialize, Clone)]
 pub struct RateHistoryEntry {
     pub timestamp: i64,
@@ -44,18 +233,23 @@ pub struct RateHistoryEntry {
     pub total_bytes: usize,
 }
 
+/// Messages stored for one broker, plus throughput history.
 #[derive(serde::Serialize)]
 pub struct MqttBroker {
-    #[serde(skip)]
-    pub client: rumqttc::Client,
     pub broker: String,
-    pub connected: bool,
-    pub topics: HashMap<String, VecDeque<MqttMessage>>,
+    pub topics: TopicStore,
+    /// Memory used by stored messages and topics, checked against
+    /// `max_broker_bytes`. Includes bookkeeping, not just payloads.
     pub total_bytes: usize,
     pub total_messages: usize,
-    /// Tracks insertion order for O(1) eviction: (topic_name, payload_len).
+    /// Insertion order for O(1) eviction: (topic id, message sequence number).
+    /// Entries whose message is gone are skipped when they come up, and
+    /// compacted away once they outnumber the stored messages.
     #[serde(skip)]
-    pub eviction_order: VecDeque<(String, usize)>,
+    pub eviction_order: VecDeque<(TopicId, u64)>,
+    /// Sequence number of the next stored message.
+    #[serde(skip)]
+    pub next_seq: u64,
     /// Throughput history samples (timestamp_ms, bytes_per_second, total_bytes).
     pub rate_history: Vec<RateHistoryEntry>,
     /// Bytes received since last rate sample.
@@ -64,8 +258,174 @@ pub struct MqttBroker {
     /// Timestamp of last rate sample (epoch ms).
     #[serde(skip)]
     pub rate_last_sample_ms: i64,
+    /// While paused, received messages are counted but neither stored nor
+    /// sent to peers.
+    pub paused: bool,
+    /// Messages and payload bytes dropped since the last pause.
+    pub skipped_messages: usize,
+    pub skipped_bytes: usize,
+}
+
+/// Descriptive details of a broker, shown in `mqtt_brokers` summaries.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct BrokerInfo {
+    pub host: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub revision: u64,
+}
+
+impl BrokerInfo {
+    pub fn from_config(config: &BrokerConfig) -> Self {
+        Self {
+            host: config.host.clone(),
+            name: config.name.clone(),
+            description: config.description.clone(),
+            tags: config.tags.clone(),
+            revision: config.revision,
+        }
+    }
+}
+
+/// Client of the current connection task and the token that stops it.
+struct Connection {
+    client: rumqttc::AsyncClient,
+    cancel: CancellationToken,
+}
+
+/// A connected broker. Stored messages have their own lock, so publishing to
+/// the broker or checking its connection never waits for a message replay.
+pub struct BrokerEntry {
+    connection: Mutex<Connection>,
+    connected: AtomicBool,
     /// Whether this broker requires authentication to view its messages.
-    pub requires_auth: bool,
+    requires_auth: AtomicBool,
+    /// Options the current connection was made with.
+    config: Mutex<BrokerConfig>,
+    /// Publishes on the current client. Locked before `connection`.
+    deliveries: Mutex<delivery::DeliveryTracker>,
+    state: Mutex<MqttBroker>,
+}
+
+impl BrokerEntry {
+    pub fn new(client: rumqttc::AsyncClient, requires_auth: bool, state: MqttBroker) -> Self {
+        Self {
+            connection: Mutex::new(Connection {
+                client,
+                cancel: CancellationToken::new(),
+            }),
+            connected: AtomicBool::new(false),
+            requires_auth: AtomicBool::new(requires_auth),
+            config: Mutex::new(BrokerConfig::from_host(&state.broker)),
+            deliveries: Mutex::new(delivery::DeliveryTracker::default()),
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn client(&self) -> rumqttc::AsyncClient {
+        self.connection.lock().unwrap().client.clone()
+    }
+
+    /// Install the client of a reconnect, unless the task owning `cancel` has
+    /// been stopped or replaced in the meantime.
+    pub fn set_client(&self, cancel: &CancellationToken, client: rumqttc::AsyncClient) -> bool {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let mut connection = self.connection.lock().unwrap();
+        if cancel.is_cancelled() {
+            return false;
+        }
+        connection.client = client;
+        deliveries.reset();
+        true
+    }
+
+    /// Queue a publish on the current client. `tracked` publishes are
+    /// reported to their requester once acknowledged.
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        tracked: Option<delivery::Tracked>,
+    ) -> Result<(), rumqttc::ClientError> {
+        // Hold the tracker so publishes are queued in the order the client sends them
+        let mut deliveries = self.deliveries.lock().unwrap();
+        self.client().try_publish(topic, qos, retain, payload)?;
+        deliveries.push(tracked);
+        Ok(())
+    }
+
+    pub fn deliveries(&self) -> MutexGuard<'_, delivery::DeliveryTracker> {
+        self.deliveries.lock().unwrap()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        self.requires_auth.load(Ordering::Relaxed)
+    }
+
+    pub fn set_requires_auth(&self, requires_auth: bool) {
+        self.requires_auth.store(requires_auth, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> BrokerInfo {
+        BrokerInfo::from_config(&self.config.lock().unwrap())
+    }
+
+    pub fn config(&self) -> BrokerConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: &BrokerConfig) {
+        *self.config.lock().unwrap() = config.clone();
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MqttBroker> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn cancellation(&self) -> CancellationToken {
+        self.connection.lock().unwrap().cancel.clone()
+    }
+
+    /// Stop the current connection task and switch to `client`. Stored
+    /// messages are kept. Returns the token for the task driving `client`.
+    pub fn restart(&self, client: rumqttc::AsyncClient) -> CancellationToken {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let mut connection = self.connection.lock().unwrap();
+        Self::disconnect(&connection);
+        *connection = Connection {
+            client,
+            cancel: CancellationToken::new(),
+        };
+        deliveries.reset();
+        self.set_connected(false);
+        connection.cancel.clone()
+    }
+
+    /// Disconnect and stop the connection task without waiting for its next event.
+    pub fn stop(&self) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        Self::disconnect(&self.connection.lock().unwrap());
+        deliveries.reset();
+        self.set_connected(false);
+    }
+
+    fn disconnect(connection: &Connection) {
+        if let Err(err) = connection.client.try_disconnect() {
+            println!("Error disconnecting MQTT client: {err:?}");
+        }
+        connection.cancel.cancel();
+    }
 }
 
 fn env_usize_mb(name: &str, default_mb: usize) -> usize {
@@ -113,18 +473,250 @@ pub fn max_incoming_packet_size() -> usize {
     })
 }
 
-pub type BrokerMap = Arc<Mutex<HashMap<String, MqttBroker>>>;
+impl MqttBroker {
+    pub fn new(broker: &str) -> Self {
+        Self {
+            broker: broker.to_string(),
+            topics: TopicStore::default(),
+            total_bytes: 0,
+            total_messages: 0,
+            eviction_order: VecDeque::new(),
+            next_seq: 0,
+            rate_history: Vec::new(),
+            rate_bytes_accumulator: 0,
+            rate_last_sample_ms: chrono::Utc::now().timestamp_millis(),
+            paused: false,
+            skipped_messages: 0,
+            skipped_bytes: 0,
+        }
+    }
+
+    /// Pause or resume capture. Pausing resets the skip counters. Returns
+    /// whether the state changed.
+    pub fn set_paused(&mut self, paused: bool) -> bool {
+        if self.paused == paused {
+            return false;
+        }
+        self.paused = paused;
+        if paused {
+            self.skipped_messages = 0;
+            self.skipped_bytes = 0;
+        }
+        true
+    }
+
+    /// Store a message and queue it for eviction. Returns the number of
+    /// messages now stored for `topic`.
+    pub fn store_message(
+        &mut self,
+        topic: &str,
+        timestamp_ns: i64,
+        payload: bytes::Bytes,
+        original_payload_size: usize,
+        retain: bool,
+    ) -> usize {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let cost = message_cost(payload.len());
+        let message = MqttMessage {
+            timestamp_ns,
+            seq,
+            // Copy so a small payload doesn't keep a large network read buffer alive.
+            payload: bytes::Bytes::copy_from_slice(&payload),
+            original_payload_size,
+            retain,
+        };
+        let (id, created) = self.topics.push(topic, message);
+        if created {
+            self.total_bytes += topic_cost(topic);
+        }
+        self.total_bytes += cost;
+        self.total_messages += 1;
+        self.eviction_order.push_back((id, seq));
+        self.topics
+            .history_by_id(id)
+            .map_or(0, |h| h.messages.len())
+    }
+
+    /// Remove the oldest stored message of a topic. Its eviction queue entry
+    /// is skipped when it comes up.
+    pub fn remove_oldest_by_id(&mut self, id: TopicId) -> Option<MqttMessage> {
+        let (message, dropped_topic) = self.topics.pop_front(id)?;
+        let mut freed = message_cost(message.payload.len());
+        if let Some(name) = dropped_topic {
+            freed += topic_cost(&name);
+        }
+        self.total_bytes = self.total_bytes.saturating_sub(freed);
+        self.total_messages = self.total_messages.saturating_sub(1);
+        self.compact_eviction_order();
+        Some(message)
+    }
+
+    /// Drop queue entries of removed messages once there are more of them
+    /// than stored messages, so the queue stays proportional to the store
+    /// however messages are removed.
+    fn compact_eviction_order(&mut self) {
+        if self.eviction_order.len() <= 2 * self.total_messages {
+            return;
+        }
+        let topics = &self.topics;
+        // Messages only leave a topic from the front, so an entry is live
+        // while its topic's oldest message is not newer.
+        self.eviction_order.retain(|(id, seq)| {
+            topics
+                .history_by_id(*id)
+                .and_then(|history| history.messages.front())
+                .is_some_and(|oldest| oldest.seq <= *seq)
+        });
+    }
+
+    pub fn remove_oldest(&mut self, topic: &str) -> bool {
+        match self.topics.id(topic) {
+            Some(id) => self.remove_oldest_by_id(id).is_some(),
+            None => false,
+        }
+    }
+
+    /// Drop every stored message of the topics selected by `matches`, together
+    /// with their eviction queue entries. Returns the purged topics and how
+    /// many messages each had.
+    pub fn purge_topics(&mut self, matches: impl Fn(&str) -> bool) -> Vec<(String, usize)> {
+        let ids: Vec<TopicId> = self
+            .topics
+            .names()
+            .filter(|name| matches(name))
+            .filter_map(|name| self.topics.id(name))
+            .collect();
+        let mut purged = Vec::with_capacity(ids.len());
+        for id in &ids {
+            let Some(history) = self.topics.remove(*id) else {
+                continue;
+            };
+            let count = history.messages.len();
+            let freed =
+                topic_cost(&history.name) + history.payload_bytes + count * MESSAGE_OVERHEAD_BYTES;
+            self.total_bytes = self.total_bytes.saturating_sub(freed);
+            self.total_messages = self.total_messages.saturating_sub(count);
+            purged.push((history.name.to_string(), count));
+        }
+        if self.topics.histories().next().is_none() {
+            self.eviction_order.clear();
+        } else if !ids.is_empty() {
+            let ids: std::collections::HashSet<TopicId> = ids.into_iter().collect();
+            self.eviction_order.retain(|(id, _)| !ids.contains(id));
+        }
+        purged
+    }
+}
+
+pub type SharedBroker = Arc<BrokerEntry>;
+
+/// Brokers by key. The map lock is only held to look up, add or remove a
+/// broker, so brokers never wait for each other.
+pub type BrokerMap = Arc<RwLock<HashMap<String, SharedBroker>>>;
+
+pub fn get_broker(mqtt_map: &BrokerMap, host: &str) -> Option<SharedBroker> {
+    mqtt_map.read().unwrap().get(host).cloned()
+}
+
+/// Snapshot of all brokers, so callers can lock them one at a time.
+pub fn all_brokers(mqtt_map: &BrokerMap) -> Vec<SharedBroker> {
+    mqtt_map.read().unwrap().values().cloned().collect()
+}
+
+/// Inserts `broker` with a client whose event loop is never driven.
+#[cfg(test)]
+pub fn insert_offline_broker(mqtt_map: &BrokerMap, broker: MqttBroker) -> SharedBroker {
+    let (client, _eventloop) =
+        connect_to_mqtt_host(&BrokerConfig::from_host(&broker.broker)).unwrap();
+    let entry = SharedBroker::new(BrokerEntry::new(client, false, broker));
+    mqtt_map
+        .write()
+        .unwrap()
+        .insert(entry.state().broker.clone(), Arc::clone(&entry));
+    entry
+}
+
+pub fn qos_from_u8(qos: u8) -> Option<QoS> {
+    match qos {
+        0 => Some(QoS::AtMostOnce),
+        1 => Some(QoS::AtLeastOnce),
+        2 => Some(QoS::ExactlyOnce),
+        _ => None,
+    }
+}
+
+/// How a payload given as text is turned into bytes for publishing.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Utf8,
+    Base64,
+    Hex,
+}
+
+impl PayloadEncoding {
+    pub fn is_utf8(&self) -> bool {
+        *self == PayloadEncoding::Utf8
+    }
+
+    pub fn decode(self, payload: &str) -> Result<Vec<u8>, String> {
+        match self {
+            PayloadEncoding::Utf8 => Ok(payload.as_bytes().to_vec()),
+            PayloadEncoding::Base64 => {
+                use base64::Engine;
+                base64::engine::general_purpose::STANDARD
+                    .decode(payload.trim())
+                    .map_err(|err| format!("Invalid base64 payload: {err}"))
+            }
+            PayloadEncoding::Hex => {
+                let digits: Vec<u8> = payload
+                    .bytes()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                if !digits.len().is_multiple_of(2) {
+                    return Err("Invalid hex payload: odd number of digits".to_string());
+                }
+                digits
+                    .chunks(2)
+                    .map(|pair| {
+                        std::str::from_utf8(pair)
+                            .ok()
+                            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                            .ok_or_else(|| "Invalid hex payload".to_string())
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Check whether `topic` matches an MQTT subscription filter (`+` and `#` wildcards).
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
 
-pub fn connect_to_mqtt_host(config: &BrokerConfig) -> (rumqttc::Client, rumqttc::Connection) {
+pub fn connect_to_mqtt_host(
+    config: &BrokerConfig,
+) -> Result<(rumqttc::AsyncClient, rumqttc::EventLoop), String> {
     let id = uuid::Uuid::new_v4();
     let host = &config.host;
+    let (hostname, port) = schema::check_broker_host(host.trim_matches('"'))?;
     println!(
         "Connecting to Mqtt broker at {host} (tls={}) with id {id}",
         config.use_tls
     );
-    let hostname_ip = host.trim_matches('"').split(':').collect::<Vec<&str>>();
-    let hostname = hostname_ip[0];
-    let port = hostname_ip[1].parse::<u16>().unwrap();
     let mut mqttoptions = MqttOptions::new(id, hostname, port);
     mqttoptions.set_keep_alive(std::time::Duration::from_secs(120));
     // Allow very large incoming and outgoing packets so the bridge can
@@ -141,33 +733,59 @@ pub fn connect_to_mqtt_host(config: &BrokerConfig) -> (rumqttc::Client, rumqttc:
         }
     }
 
-    let (mut client, connection) = rumqttc::Client::new(mqttoptions, 1000);
-    client.subscribe("#", QoS::AtMostOnce).unwrap();
+    let (client, eventloop) = rumqttc::AsyncClient::new(mqttoptions, 1000);
+    client
+        .try_subscribe("#", QoS::AtMostOnce)
+        .map_err(|err| format!("Can't subscribe on {host}: {err}"))?;
 
-    (client, connection)
+    Ok((client, eventloop))
 }
 
-pub fn publish_message(host: &str, topic: &str, payload: &str, retain: bool, mqtt_map: &BrokerMap) {
-    let client = {
-        let binding = mqtt_map.lock().unwrap();
-        binding.get(host).map(|broker| broker.client.clone())
+/// Queue a publish, also while the broker is disconnected. If `requester`
+/// is given, it gets a `publish_result` once the publish is acknowledged.
+pub fn publish_message(
+    host: &str,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    requester: Option<delivery::Requester>,
+    mqtt_map: &BrokerMap,
+) -> Result<(), String> {
+    let Some(broker) = get_broker(mqtt_map, host) else {
+        println!("Can't publish. Broker {host} not found");
+        return Err(format!("Broker {host} not found"));
     };
+    let tracked = requester.map(|requester| delivery::Tracked::new(requester, host, topic, qos));
+    broker
+        .publish(topic, payload, qos, retain, tracked)
+        .map_err(|err| {
+            println!(
+                "Error publishing {} bytes to {host} topic {topic}: {err:?}",
+                payload.len()
+            );
+            format!("Error publishing to {host} topic {topic}: {err}")
+        })
+}
 
-    match client {
-        Some(mut client) => {
-            if let Err(err) =
-                client.publish(topic, rumqttc::QoS::AtLeastOnce, retain, payload.as_bytes())
-            {
-                println!(
-                    "Error publishing {} bytes to {host} topic {topic}: {err:?}",
-                    payload.len()
-                );
-            }
-        }
-        None => {
-            println!("Can't publish. Broker {host} not found");
-        }
-    }
+/// Publish without blocking when the broker's request queue is full, e.g. while
+/// it is disconnected. Used when forwarding from another broker's receive loop.
+pub fn try_publish_bytes(
+    host: &str,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    mqtt_map: &BrokerMap,
+) -> Result<(), String> {
+    let broker = match get_broker(mqtt_map, host) {
+        Some(broker) if broker.is_connected() => broker,
+        Some(_) => return Err(format!("Broker {host} is not connected")),
+        None => return Err(format!("Broker {host} not found")),
+    };
+    broker
+        .publish(topic, payload, qos, retain, None)
+        .map_err(|err| format!("Error publishing to {host} topic {topic}: {err}"))
 }
 
 #[cfg(test)]
@@ -175,26 +793,51 @@ mod tests {
     use super::*;
 
     fn make_broker_map() -> BrokerMap {
-        BrokerMap::new(Mutex::new(HashMap::new()))
+        BrokerMap::new(RwLock::new(HashMap::new()))
     }
 
     #[test]
     fn test_publish_message_broker_not_found() {
         let mqtt_map = make_broker_map();
-        // Should not panic, just prints a message
-        publish_message("nonexistent:1883", "topic", "payload", false, &mqtt_map);
+        let result = publish_message(
+            "nonexistent:1883",
+            "topic",
+            b"payload",
+            QoS::AtLeastOnce,
+            false,
+            None,
+            &mqtt_map,
+        );
+        assert_eq!(result, Err("Broker nonexistent:1883 not found".to_string()));
+    }
+
+    #[test]
+    fn test_payload_encoding_decode() {
+        assert_eq!(PayloadEncoding::Utf8.decode("hi"), Ok(b"hi".to_vec()));
+        assert_eq!(
+            PayloadEncoding::Base64.decode("AP8K"),
+            Ok(vec![0x00, 0xff, 0x0a])
+        );
+        assert_eq!(
+            PayloadEncoding::Hex.decode("00 ff 0A"),
+            Ok(vec![0x00, 0xff, 0x0a])
+        );
+        assert!(PayloadEncoding::Base64.decode("not base64!").is_err());
+        assert!(PayloadEncoding::Hex.decode("abc").is_err());
+        assert!(PayloadEncoding::Hex.decode("zz").is_err());
     }
 
     #[test]
     fn test_mqtt_message_serialization() {
         let msg = MqttMessage {
-            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            timestamp_ns: parse_timestamp_ns("2024-01-01T00:00:00Z").unwrap(),
+            seq: 0,
             payload: bytes::Bytes::from(vec![72, 101, 108, 108, 111]),
             original_payload_size: 5,
             retain: false,
         };
         let serialized = serde_json::to_string(&msg).unwrap();
-        assert!(serialized.contains("\"timestamp\":\"2024-01-01T00:00:00Z\""));
+        assert!(serialized.contains("\"timestamp\":\"2024-01-01T00:00:00+00:00\""));
         assert!(serialized.contains("\"payload\":[72,101,108,108,111]"));
     }
 
@@ -223,7 +866,8 @@ mod tests {
     #[test]
     fn test_mqtt_message_empty_payload() {
         let msg = MqttMessage {
-            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            timestamp_ns: 0,
+            seq: 0,
             payload: bytes::Bytes::new(),
             original_payload_size: 0,
             retain: false,
@@ -236,52 +880,36 @@ mod tests {
     fn test_broker_map_insert_and_lookup() {
         let mqtt_map = make_broker_map();
         let cfg = super::super::config::BrokerConfig::from_host("127.0.0.1:18830");
-        let (client, _connection) = connect_to_mqtt_host(&cfg);
-        let broker = MqttBroker {
-            client,
-            broker: "127.0.0.1:18830".to_string(),
-            connected: false,
-            topics: HashMap::new(),
-            total_bytes: 0,
-            total_messages: 0,
-            eviction_order: VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: 0,
-            requires_auth: false,
-        };
-        mqtt_map
-            .lock()
-            .unwrap()
-            .insert("127.0.0.1:18830".to_string(), broker);
-        assert_eq!(mqtt_map.lock().unwrap().len(), 1);
-        assert!(mqtt_map.lock().unwrap().contains_key("127.0.0.1:18830"));
+        let (client, _eventloop) = connect_to_mqtt_host(&cfg).unwrap();
+        let broker = MqttBroker::new("127.0.0.1:18830");
+        mqtt_map.write().unwrap().insert(
+            "127.0.0.1:18830".to_string(),
+            Arc::new(BrokerEntry::new(client, false, broker)),
+        );
+        assert_eq!(mqtt_map.read().unwrap().len(), 1);
+        assert!(mqtt_map.read().unwrap().contains_key("127.0.0.1:18830"));
     }
 
     #[test]
     fn test_broker_map_remove() {
         let mqtt_map = make_broker_map();
         let cfg = super::super::config::BrokerConfig::from_host("127.0.0.1:18831");
-        let (client, _connection) = connect_to_mqtt_host(&cfg);
-        let broker = MqttBroker {
-            client,
-            broker: "127.0.0.1:18831".to_string(),
-            connected: false,
-            topics: HashMap::new(),
-            total_bytes: 0,
-            total_messages: 0,
-            eviction_order: VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: 0,
-            requires_auth: false,
-        };
-        mqtt_map
-            .lock()
-            .unwrap()
-            .insert("127.0.0.1:18831".to_string(), broker);
-        mqtt_map.lock().unwrap().remove("127.0.0.1:18831");
-        assert_eq!(mqtt_map.lock().unwrap().len(), 0);
+        let (client, _eventloop) = connect_to_mqtt_host(&cfg).unwrap();
+        let broker = MqttBroker::new("127.0.0.1:18831");
+        mqtt_map.write().unwrap().insert(
+            "127.0.0.1:18831".to_string(),
+            Arc::new(BrokerEntry::new(client, false, broker)),
+        );
+        mqtt_map.write().unwrap().remove("127.0.0.1:18831");
+        assert_eq!(mqtt_map.read().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_connect_to_mqtt_host_rejects_invalid_host() {
+        for host in ["nohost", "a:", ":1883", "a:0", "a:99999"] {
+            let cfg = super::super::config::BrokerConfig::from_host(host);
+            assert!(connect_to_mqtt_host(&cfg).is_err(), "{host}");
+        }
     }
 
     #[test]
@@ -294,14 +922,23 @@ mod tests {
         let mm1 = Arc::clone(&mqtt_map);
         let h1 = thread::spawn(move || {
             for i in 0..50 {
-                publish_message(&format!("host{}:1883", i), "topic", "payload", false, &mm1);
+                publish_message(
+                    &format!("host{}:1883", i),
+                    "topic",
+                    b"payload",
+                    QoS::AtLeastOnce,
+                    false,
+                    None,
+                    &mm1,
+                )
+                .ok();
             }
         });
 
         let mm2 = Arc::clone(&mqtt_map);
         let h2 = thread::spawn(move || {
             for _ in 0..50 {
-                let _ = mm2.lock().unwrap().len();
+                let _ = mm2.read().unwrap().len();
             }
         });
 
@@ -309,6 +946,183 @@ mod tests {
         h2.join().unwrap();
     }
 
+    #[test]
+    fn test_set_paused_resets_skip_counters() {
+        let mut broker = MqttBroker::new("test");
+        assert!(broker.set_paused(true));
+        assert!(!broker.set_paused(true));
+        broker.skipped_messages = 3;
+        broker.skipped_bytes = 30;
+
+        // Counters stay readable after resuming until the next pause
+        assert!(broker.set_paused(false));
+        assert_eq!(broker.skipped_messages, 3);
+        assert!(broker.set_paused(true));
+        assert_eq!(broker.skipped_messages, 0);
+        assert_eq!(broker.skipped_bytes, 0);
+    }
+
+    #[test]
+    fn test_purge_topics_subtree() {
+        let mut broker = MqttBroker::new("test");
+        let empty_bytes = broker.total_bytes;
+        broker.store_message("a/b", 1, bytes::Bytes::from("1"), 1, false);
+        broker.store_message("a/b/c", 2, bytes::Bytes::from("22"), 2, false);
+        broker.store_message("a/bc", 3, bytes::Bytes::from("333"), 3, false);
+        broker.store_message("a/b", 4, bytes::Bytes::from("4444"), 4, false);
+        let bytes_with_bc = {
+            let mut only_bc = MqttBroker::new("test");
+            only_bc.store_message("a/bc", 3, bytes::Bytes::from("333"), 3, false);
+            only_bc.total_bytes
+        };
+
+        let mut purged = broker.purge_topics(|t| topic_matches("a/b/#", t));
+        purged.sort();
+        assert_eq!(
+            purged,
+            vec![("a/b".to_string(), 2), ("a/b/c".to_string(), 1)]
+        );
+        assert_eq!(broker.total_messages, 1);
+        assert_eq!(broker.total_bytes, bytes_with_bc);
+        let bc = broker.topics.id("a/bc").unwrap();
+        let order: Vec<_> = broker.eviction_order.iter().copied().collect();
+        assert_eq!(order, vec![(bc, 2)]);
+
+        assert_eq!(broker.purge_topics(|_| true).len(), 1);
+        assert_eq!(broker.total_bytes, empty_bytes);
+        assert_eq!(broker.total_messages, 0);
+        assert!(broker.eviction_order.is_empty());
+        assert!(broker.topics.is_empty());
+    }
+
+    #[test]
+    fn test_broker_state_locks_are_independent() {
+        let mqtt_map = make_broker_map();
+        let a = insert_offline_broker(&mqtt_map, MqttBroker::new("127.0.0.1:18832"));
+        insert_offline_broker(&mqtt_map, MqttBroker::new("127.0.0.1:18833"));
+
+        // Hold broker A's state for the whole test, like a long history replay
+        let _a_state = a.state();
+
+        let mm = Arc::clone(&mqtt_map);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let b = get_broker(&mm, "127.0.0.1:18833").unwrap();
+            b.state()
+                .store_message("t", 1, bytes::Bytes::from("x"), 1, false);
+            // Publishing to A only needs its client, not its state
+            publish_message(
+                "127.0.0.1:18832",
+                "t",
+                b"x",
+                QoS::AtLeastOnce,
+                false,
+                None,
+                &mm,
+            )
+            .ok();
+            assert_eq!(all_brokers(&mm).len(), 2);
+            tx.send(()).unwrap();
+        });
+
+        assert!(
+            rx.recv_timeout(std::time::Duration::from_secs(5)).is_ok(),
+            "broker B must not wait for broker A's state lock"
+        );
+    }
+
+    /// Store latency on one broker while another broker's state is held for
+    /// long stretches, compared with funnelling both through one lock as the
+    /// old global map did. Run with
+    /// `cargo test --release bench_store_latency -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_store_latency_with_busy_neighbour() {
+        use std::time::{Duration, Instant};
+
+        const STORES: usize = 2_000;
+        const PACE: Duration = Duration::from_micros(50);
+        const HOLD: Duration = Duration::from_millis(5);
+
+        fn run(shared_lock: bool) -> Vec<Duration> {
+            let mqtt_map = make_broker_map();
+            let a = insert_offline_broker(&mqtt_map, MqttBroker::new("bench-a:1883"));
+            let b = insert_offline_broker(&mqtt_map, MqttBroker::new("bench-b:1883"));
+            let global = Arc::new(Mutex::new(()));
+            let stop = Arc::new(AtomicBool::new(false));
+
+            let busy = {
+                let global = Arc::clone(&global);
+                let stop = Arc::clone(&stop);
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let guard = shared_lock.then(|| global.lock().unwrap());
+                        let state = b.state();
+                        std::thread::sleep(HOLD);
+                        drop(state);
+                        drop(guard);
+                        std::thread::yield_now();
+                    }
+                })
+            };
+
+            let payload = bytes::Bytes::from_static(&[0u8; 64]);
+            let mut latencies = Vec::with_capacity(STORES);
+            for i in 0..STORES {
+                let start = Instant::now();
+                let guard = shared_lock.then(|| global.lock().unwrap());
+                a.state()
+                    .store_message("bench/topic", i as i64, payload.clone(), 64, false);
+                drop(guard);
+                latencies.push(start.elapsed());
+                std::thread::sleep(PACE);
+            }
+            stop.store(true, Ordering::Relaxed);
+            busy.join().unwrap();
+            latencies.sort();
+            latencies
+        }
+
+        fn report(label: &str, latencies: &[Duration]) {
+            let pct = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+            let total: Duration = latencies.iter().sum();
+            println!(
+                "{label:<16} total {total:>10.2?}  p50 {:>10.2?}  p99 {:>10.2?}  max {:>10.2?}",
+                pct(50),
+                pct(99),
+                latencies[latencies.len() - 1]
+            );
+        }
+
+        report("one shared lock", &run(true));
+        report("per-broker lock", &run(false));
+    }
+
+    // --- topic_matches ---
+
+    #[test]
+    fn test_topic_matches_exact() {
+        assert!(topic_matches("a/b/c", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+    }
+
+    #[test]
+    fn test_topic_matches_single_level_wildcard() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("+/+", "a/b"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/+", "a/"));
+    }
+
+    #[test]
+    fn test_topic_matches_multi_level_wildcard() {
+        assert!(topic_matches("#", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(!topic_matches("a/#", "b/c"));
+    }
+
     // --- max_broker_bytes / max_message_size ---
 
     #[test]
@@ -327,7 +1141,8 @@ mod tests {
     fn test_mqtt_message_large_payload() {
         let payload = bytes::Bytes::from(vec![0xFFu8; 1024 * 1024]); // 1MB
         let msg = MqttMessage {
-            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            timestamp_ns: 0,
+            seq: 0,
             payload,
             original_payload_size: 1024 * 1024,
             retain: false,
@@ -339,33 +1154,36 @@ mod tests {
 
     #[test]
     fn test_broker_eviction_order_fifo() {
-        let cfg = super::super::config::BrokerConfig::from_host("127.0.0.1:18832");
-        let (client, _conn) = connect_to_mqtt_host(&cfg);
-        let mut broker = MqttBroker {
-            client,
-            broker: "test".to_string(),
-            connected: true,
-            topics: HashMap::new(),
-            total_bytes: 0,
-            total_messages: 0,
-            eviction_order: VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: 0,
-            requires_auth: false,
-        };
+        let mut broker = MqttBroker::new("test");
 
-        broker.eviction_order.push_back(("t1".to_string(), 10));
-        broker.eviction_order.push_back(("t2".to_string(), 20));
-        broker.eviction_order.push_back(("t3".to_string(), 30));
+        broker.store_message("t1", 1, bytes::Bytes::from("a"), 1, false);
+        broker.store_message("t2", 2, bytes::Bytes::from("b"), 1, false);
+        broker.store_message("t1", 3, bytes::Bytes::from("c"), 1, false);
 
-        let (key, sz) = broker.eviction_order.pop_front().unwrap();
-        assert_eq!(key, "t1");
-        assert_eq!(sz, 10);
+        let t1 = broker.topics.id("t1").unwrap();
+        let t2 = broker.topics.id("t2").unwrap();
+        assert_ne!(t1, t2);
+        let order: Vec<_> = broker.eviction_order.iter().copied().collect();
+        assert_eq!(order, vec![(t1, 0), (t2, 1), (t1, 2)]);
+    }
 
-        let (key, sz) = broker.eviction_order.pop_front().unwrap();
-        assert_eq!(key, "t2");
-        assert_eq!(sz, 20);
+    #[test]
+    fn test_eviction_order_compacted_after_per_topic_removal() {
+        let mut broker = MqttBroker::new("test");
+        broker.store_message("quiet", 0, bytes::Bytes::from("q"), 1, false);
+        for i in 1..1000 {
+            broker.store_message("busy", i, bytes::Bytes::from("x"), 1, false);
+            if broker.topics["busy"].len() > 1 {
+                broker.remove_oldest("busy");
+            }
+        }
+        assert_eq!(broker.total_messages, 2);
+        assert!(broker.eviction_order.len() <= 4);
+
+        while broker.remove_oldest("quiet") {}
+        let busy = broker.topics.id("busy").unwrap();
+        let order: Vec<_> = broker.eviction_order.iter().copied().collect();
+        assert_eq!(order, vec![(busy, 999)]);
     }
 
     // --- Rate history ---
@@ -400,84 +1218,91 @@ mod tests {
 
     #[test]
     fn test_broker_topics_multiple_messages_per_topic() {
-        let cfg = super::super::config::BrokerConfig::from_host("127.0.0.1:18833");
-        let (client, _conn) = connect_to_mqtt_host(&cfg);
-        let mut broker = MqttBroker {
-            client,
-            broker: "test".to_string(),
-            connected: true,
-            topics: HashMap::new(),
-            total_bytes: 0,
-            total_messages: 0,
-            eviction_order: VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: 0,
-            requires_auth: false,
-        };
+        let mut broker = MqttBroker::new("test");
 
-        let mut msgs = VecDeque::new();
         for i in 0..100 {
-            msgs.push_back(MqttMessage {
-                timestamp: format!("ts_{i}"),
-                payload: bytes::Bytes::from(format!("payload_{i}")),
-                original_payload_size: format!("payload_{i}").len(),
-                retain: false,
-            });
+            let payload = format!("payload_{i}");
+            broker.store_message(
+                "stress/topic",
+                i,
+                bytes::Bytes::from(payload.clone()),
+                payload.len(),
+                false,
+            );
         }
-        broker.topics.insert("stress/topic".to_string(), msgs);
 
         assert_eq!(broker.topics["stress/topic"].len(), 100);
+        assert_eq!(broker.topics["stress/topic"].front().unwrap().seq, 0);
         assert_eq!(
-            broker.topics["stress/topic"].front().unwrap().timestamp,
-            "ts_0"
-        );
-        assert_eq!(
-            broker.topics["stress/topic"].back().unwrap().timestamp,
-            "ts_99"
+            broker.topics["stress/topic"].back().unwrap().timestamp_ns,
+            99
         );
+        assert_eq!(broker.topics.len(), 1);
+        assert_eq!(broker.total_messages, 100);
     }
 
     #[test]
     fn test_broker_topics_eviction_removes_oldest() {
-        let cfg = super::super::config::BrokerConfig::from_host("127.0.0.1:18834");
-        let (client, _conn) = connect_to_mqtt_host(&cfg);
-        let mut broker = MqttBroker {
-            client,
-            broker: "test".to_string(),
-            connected: true,
-            topics: HashMap::new(),
-            total_bytes: 0,
-            total_messages: 0,
-            eviction_order: VecDeque::new(),
-            rate_history: Vec::new(),
-            rate_bytes_accumulator: 0,
-            rate_last_sample_ms: 0,
-            requires_auth: false,
-        };
+        let mut broker = MqttBroker::new("test");
 
-        let mut msgs = VecDeque::new();
-        msgs.push_back(MqttMessage {
-            timestamp: "oldest".to_string(),
-            payload: bytes::Bytes::from("a"),
-            original_payload_size: 1,
-            retain: false,
-        });
-        msgs.push_back(MqttMessage {
-            timestamp: "newest".to_string(),
-            payload: bytes::Bytes::from("b"),
-            original_payload_size: 1,
-            retain: false,
-        });
-        broker.topics.insert("t".to_string(), msgs);
-        broker.eviction_order.push_back(("t".to_string(), 1));
-        broker.eviction_order.push_back(("t".to_string(), 1));
+        broker.store_message("t", 1, bytes::Bytes::from("a"), 1, false);
+        broker.store_message("t", 2, bytes::Bytes::from("b"), 1, false);
 
-        // Simulate eviction of the oldest
-        let (topic_key, _) = broker.eviction_order.pop_front().unwrap();
-        broker.topics.get_mut(&topic_key).unwrap().pop_front();
+        assert!(broker.remove_oldest("t"));
 
         assert_eq!(broker.topics["t"].len(), 1);
-        assert_eq!(broker.topics["t"].front().unwrap().timestamp, "newest");
+        assert_eq!(broker.topics["t"].front().unwrap().timestamp_ns, 2);
+        assert_eq!(broker.topics.history("t").unwrap().payload_bytes, 1);
+    }
+
+    #[test]
+    fn test_memory_accounting_covers_messages_and_topics() {
+        let mut broker = MqttBroker::new("test");
+
+        broker.store_message("a/b", 1, bytes::Bytes::from("xyz"), 3, false);
+        assert_eq!(broker.total_bytes, message_cost(3) + topic_cost("a/b"));
+        broker.store_message("a/b", 2, bytes::Bytes::from("xy"), 2, false);
+        assert_eq!(
+            broker.total_bytes,
+            message_cost(3) + message_cost(2) + topic_cost("a/b")
+        );
+
+        assert!(broker.remove_oldest("a/b"));
+        assert!(broker.remove_oldest("a/b"));
+        assert!(!broker.remove_oldest("a/b"));
+        assert_eq!(broker.total_bytes, 0);
+        assert_eq!(broker.total_messages, 0);
+        assert!(broker.topics.is_empty());
+    }
+
+    #[test]
+    fn test_topic_store_reuses_ids_of_dropped_topics() {
+        let mut store = TopicStore::default();
+        let message = |seq| MqttMessage {
+            timestamp_ns: 0,
+            seq,
+            payload: bytes::Bytes::new(),
+            original_payload_size: 0,
+            retain: false,
+        };
+        let (a, created) = store.push("a", message(0));
+        assert!(created);
+        let (b, _) = store.push("b", message(1));
+        assert_eq!(store.push("a", message(2)), (a, false));
+
+        assert!(store.pop_front(b).unwrap().1.is_some());
+        assert!(!store.contains_key("b"));
+        let (c, created) = store.push("c", message(3));
+        assert!(created);
+        assert_eq!(c, b);
+        assert_eq!(store.names().collect::<Vec<_>>(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_timestamp_ns_roundtrip() {
+        let ts = "2026-03-04T05:06:07.123456789+00:00";
+        let ns = parse_timestamp_ns(ts).unwrap();
+        assert_eq!(format_timestamp_ns(ns), ts);
+        assert!(parse_timestamp_ns("not a timestamp").is_none());
     }
 }
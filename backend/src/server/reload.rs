@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::broker_peer_bridge;
+use super::config;
+use super::mqtt;
+use super::services;
+use super::websocket;
+
+use std::collections::BTreeMap;
+
+/// How often the config directory is checked for changes.
+pub const POLL_INTERVAL_SECS: u64 = 2;
+
+/// Contents of the config files that are applied while running, to detect
+/// edits made outside the app, e.g. by config management.
+pub struct ConfigWatcher {
+    config_path: String,
+    brokers: Option<WatchedFile>,
+    commands: BTreeMap<String, WatchedFile>,
+    pipelines: BTreeMap<String, WatchedFile>,
+}
+
+/// What changed since the last poll.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigChanges {
+    pub brokers: bool,
+    pub commands: bool,
+    pub pipelines: bool,
+}
+
+impl ConfigWatcher {
+    pub fn new(config_path: &str) -> Self {
+        let mut watcher = Self {
+            config_path: config_path.to_string(),
+            brokers: None,
+            commands: BTreeMap::new(),
+            pipelines: BTreeMap::new(),
+        };
+        watcher.poll();
+        watcher
+    }
+
+    pub fn poll(&mut self) -> ConfigChanges {
+        let (brokers, brokers_changed) = refresh_file(
+            std::path::Path::new(&brokers_path(&self.config_path)),
+            self.brokers.take(),
+        );
+        let (commands, commands_changed) = refresh_dir(
+            &format!("{}/commands", self.config_path),
+            std::mem::take(&mut self.commands),
+        );
+        let (pipelines, pipelines_changed) = refresh_dir(
+            &format!("{}/pipelines", self.config_path),
+            std::mem::take(&mut self.pipelines),
+        );
+        self.brokers = brokers;
+        self.commands = commands;
+        self.pipelines = pipelines;
+        ConfigChanges {
+            brokers: brokers_changed,
+            commands: commands_changed,
+            pipelines: pipelines_changed,
+        }
+    }
+}
+
+fn brokers_path(config_path: &str) -> String {
+    format!("{config_path}/brokers.json")
+}
+
+/// A watched file with the modification time and length it was read at.
+struct WatchedFile {
+    modified: Option<std::time::SystemTime>,
+    len: u64,
+    content: Vec<u8>,
+}
+
+/// `path` as of now and whether its content differs from `previous`. The
+/// file is only read again if its modification time or length changed.
+fn refresh_file(
+    path: &std::path::Path,
+    previous: Option<WatchedFile>,
+) -> (Option<WatchedFile>, bool) {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return (None, previous.is_some());
+    };
+    let modified = metadata.modified().ok();
+    let len = metadata.len();
+    if previous
+        .as_ref()
+        .is_some_and(|previous| previous.modified == modified && previous.len == len)
+    {
+        return (previous, false);
+    }
+    match std::fs::read(path) {
+        Ok(content) => {
+            let changed = previous.is_none_or(|previous| previous.content != content);
+            let file = WatchedFile {
+                modified,
+                len,
+                content,
+            };
+            (Some(file), changed)
+        }
+        Err(_) => (None, previous.is_some()),
+    }
+}
+
+/// The files below `path`, keyed by their relative path, and whether any of
+/// them was added, removed or changed since `previous`. Hidden files and
+/// directories, such as the revision history, are skipped.
+fn refresh_dir(
+    path: &str,
+    mut previous: BTreeMap<String, WatchedFile>,
+) -> (BTreeMap<String, WatchedFile>, bool) {
+    let mut files = BTreeMap::new();
+    let mut changed = false;
+    let root = std::path::Path::new(path);
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let name = path
+                .strip_prefix(root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            let (file, file_changed) = refresh_file(&path, previous.remove(&name));
+            changed |= file_changed;
+            if let Some(file) = file {
+                files.insert(name, file);
+            }
+        }
+    }
+    // Whatever is left was removed
+    (files, changed || !previous.is_empty())
+}
+
+/// Apply changes to the config directory: connect, reconnect or remove
+/// brokers to match brokers.json, and resend changed commands and pipelines
+/// to all peers.
+pub fn process_changes(
+    watcher: &mut ConfigWatcher,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+    services: &services::Services,
+) {
+    let changes = watcher.poll();
+    if changes.brokers {
+        let path = brokers_path(&watcher.config_path);
+        // Keep the running brokers while the file is missing or half written
+        if let Some(configs) = config::read_broker_configs(&path) {
+            println!("{path} changed, updating brokers");
+            broker_peer_bridge::sync_brokers(
+                &configs,
+                peer_map,
+                mqtt_map,
+                notification_buf,
+                services,
+            );
+        }
+    }
+    if changes.commands {
+        websocket::broadcast_commands(peer_map, &watcher.config_path);
+    }
+    if changes.pipelines {
+        websocket::broadcast_pipelines(peer_map, &watcher.config_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config_dir() -> String {
+        let path = format!("/tmp/mqtt_reload_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(format!("{path}/commands/nested")).unwrap();
+        std::fs::create_dir_all(format!("{path}/pipelines")).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_poll_reports_changed_files() {
+        let path = temp_config_dir();
+        std::fs::write(format!("{path}/brokers.json"), "[]").unwrap();
+        let mut watcher = ConfigWatcher::new(&path);
+        assert_eq!(watcher.poll(), ConfigChanges::default());
+
+        std::fs::write(format!("{path}/commands/nested/on.json"), "{}").unwrap();
+        assert_eq!(
+            watcher.poll(),
+            ConfigChanges {
+                commands: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(watcher.poll(), ConfigChanges::default());
+
+        std::fs::write(format!("{path}/brokers.json"), r#"[{"host":"a:1883"}]"#).unwrap();
+        std::fs::write(format!("{path}/pipelines/p.json"), "{}").unwrap();
+        assert_eq!(
+            watcher.poll(),
+            ConfigChanges {
+                brokers: true,
+                commands: false,
+                pipelines: true,
+            }
+        );
+
+        // Same size and content: rewriting the file is no change
+        std::fs::write(format!("{path}/pipelines/p.json"), "{}").unwrap();
+        assert_eq!(watcher.poll(), ConfigChanges::default());
+        std::fs::write(format!("{path}/pipelines/p.json"), r#"{"name":"p"}"#).unwrap();
+        assert!(watcher.poll().pipelines);
+
+        // The revision history is not watched
+        std::fs::create_dir_all(format!("{path}/commands/.history")).unwrap();
+        std::fs::write(format!("{path}/commands/.history/on.json"), "{}").unwrap();
+        assert_eq!(watcher.poll(), ConfigChanges::default());
+
+        std::fs::remove_file(format!("{path}/commands/nested/on.json")).unwrap();
+        assert!(watcher.poll().commands);
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_invalid_brokers_file_keeps_brokers() {
+        let path = temp_config_dir();
+        std::fs::write(format!("{path}/brokers.json"), "[]").unwrap();
+        let mut watcher = ConfigWatcher::new(&path);
+        let peer_map = websocket::PeerMap::default();
+        let mqtt_map = mqtt::BrokerMap::default();
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("a:1883"));
+
+        std::fs::write(format!("{path}/brokers.json"), r#"[{"host":"#).unwrap();
+        process_changes(
+            &mut watcher,
+            &peer_map,
+            &mqtt_map,
+            &websocket::NotificationBuf::default(),
+            &services::Services::default(),
+        );
+        assert!(mqtt::get_broker(&mqtt_map, "a:1883").is_some());
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
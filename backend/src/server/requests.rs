@@ -0,0 +1,385 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::alerts;
+use super::mqtt;
+use super::waiters;
+
+/// Longest a `request` waits for its reply.
+pub const MAX_TIMEOUT_MS: u64 = 60_000;
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+/// Params of the `request` RPC: publish to `topic` and wait for a reply on
+/// `response_topic`.
+///
+/// Broker connections use MQTT 3.1.1, which has no correlation data, so
+/// replies are matched by the JSON value at `correlation_path`. It must equal
+/// `correlation_value` or, if that is not given, the value at the same path in
+/// the request payload. Without a path, the first message on
+/// `response_topic` is the reply.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct RequestParams {
+    /// Echoed in the result so callers can tell concurrent requests apart.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub broker: String,
+    pub topic: String,
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub encoding: mqtt::PayloadEncoding,
+    pub response_topic: String,
+    #[serde(default)]
+    pub correlation_path: Option<String>,
+    #[serde(default)]
+    pub correlation_value: Option<serde_json::Value>,
+    /// MQTT v5 correlation data. Rejected, see above.
+    #[serde(default)]
+    pub correlation_data: Option<serde_json::Value>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl RequestParams {
+    /// The value a reply must have at `correlation_path`, if any.
+    fn correlation(&self, request_payload: &[u8]) -> Result<Option<serde_json::Value>, String> {
+        let Some(path) = &self.correlation_path else {
+            return Ok(None);
+        };
+        if let Some(value) = &self.correlation_value {
+            return Ok(Some(value.clone()));
+        }
+        let json = serde_json::from_slice::<serde_json::Value>(request_payload)
+            .map_err(|_| format!("Request payload is not JSON, no value at {path}"))?;
+        alerts::json_path_lookup(&json, path)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| format!("Request payload has no value at {path}"))
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct RequestReply {
+    pub topic: String,
+    /// UTF-8 text, or base64 if the payload is binary.
+    pub payload: String,
+    pub encoding: mqtt::PayloadEncoding,
+}
+
+impl RequestReply {
+    fn from_message(message: &waiters::ReceivedMessage) -> Self {
+        let (payload, encoding) = match std::str::from_utf8(&message.payload) {
+            Ok(text) => (text.to_string(), mqtt::PayloadEncoding::Utf8),
+            Err(_) => {
+                use base64::Engine;
+                let encoded = base64::engine::general_purpose::STANDARD.encode(&message.payload);
+                (encoded, mqtt::PayloadEncoding::Base64)
+            }
+        };
+        Self {
+            topic: message.topic.clone(),
+            payload,
+            encoding,
+        }
+    }
+}
+
+/// Result of `request`, sent to the caller.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct RequestResult {
+    pub id: Option<String>,
+    pub broker: String,
+    pub topic: String,
+    pub success: bool,
+    pub reply: Option<RequestReply>,
+    /// Milliseconds from publishing to the broker loop receiving the reply.
+    pub rtt_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl RequestResult {
+    pub fn failed(params: &RequestParams, error: String) -> Self {
+        Self {
+            id: params.id.clone(),
+            broker: params.broker.clone(),
+            topic: params.topic.clone(),
+            success: false,
+            reply: None,
+            rtt_ms: None,
+            error: Some(error),
+        }
+    }
+}
+
+struct ReplyMatcher<'a> {
+    params: &'a RequestParams,
+    payload: &'a [u8],
+    correlation: Option<serde_json::Value>,
+    own_echo_seen: std::sync::atomic::AtomicBool,
+}
+
+impl ReplyMatcher<'_> {
+    fn is_reply(&self, message: &waiters::ReceivedMessage) -> bool {
+        // When replies share the request topic, our own request comes back first
+        if message.topic == self.params.topic
+            && message.payload == self.payload
+            && !self
+                .own_echo_seen
+                .swap(true, std::sync::atomic::Ordering::Relaxed)
+        {
+            return false;
+        }
+        let (Some(path), Some(expected)) = (&self.params.correlation_path, &self.correlation)
+        else {
+            return true;
+        };
+        serde_json::from_slice::<serde_json::Value>(&message.payload)
+            .ok()
+            .is_some_and(|json| alerts::json_path_lookup(&json, path) == Some(expected))
+    }
+}
+
+/// Wait for the first message accepted by `is_reply`.
+async fn wait_for_reply(
+    subscription: &mut waiters::Subscription,
+    is_reply: impl Fn(&waiters::ReceivedMessage) -> bool,
+    timeout: std::time::Duration,
+) -> Option<waiters::ReceivedMessage> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let received = tokio::time::timeout_at(deadline, subscription.recv())
+            .await
+            .ok()??;
+        if is_reply(&received) {
+            return Some(received);
+        }
+    }
+}
+
+/// Publish the request and wait for its reply.
+pub async fn send_request(
+    params: &RequestParams,
+    waiter_map: &waiters::WaiterMap,
+    mqtt_map: &mqtt::BrokerMap,
+) -> RequestResult {
+    if params.correlation_data.is_some() {
+        let error = "MQTT v5 correlation data is not supported, use correlation_path".to_string();
+        return RequestResult::failed(params, error);
+    }
+    if params.timeout_ms == 0 || params.timeout_ms > MAX_TIMEOUT_MS {
+        let error = format!("Timeout must be between 1 and {MAX_TIMEOUT_MS} ms");
+        return RequestResult::failed(params, error);
+    }
+    let Some(qos) = mqtt::qos_from_u8(params.qos) else {
+        return RequestResult::failed(params, format!("Invalid QoS {}", params.qos));
+    };
+    let payload = match params.encoding.decode(&params.payload) {
+        Ok(payload) => payload,
+        Err(err) => return RequestResult::failed(params, err),
+    };
+    let correlation = match params.correlation(&payload) {
+        Ok(correlation) => correlation,
+        Err(err) => return RequestResult::failed(params, err),
+    };
+
+    // Listen before publishing so a fast reply isn't missed
+    let mut subscription = waiters::register(
+        waiter_map,
+        Some(&params.broker),
+        vec![params.response_topic.clone()],
+    );
+    // Not queued while disconnected: the reply couldn't arrive before the
+    // timeout, and the request would still go out after it was reported
+    // as failed
+    let published = mqtt::try_publish_bytes(
+        &params.broker,
+        &params.topic,
+        &payload,
+        qos,
+        false,
+        mqtt_map,
+    );
+    if let Err(err) = published {
+        return RequestResult::failed(params, err);
+    }
+    let published_at = std::time::Instant::now();
+
+    let matcher = ReplyMatcher {
+        params,
+        payload: &payload,
+        correlation,
+        own_echo_seen: std::sync::atomic::AtomicBool::new(false),
+    };
+    let timeout = std::time::Duration::from_millis(params.timeout_ms);
+    match wait_for_reply(&mut subscription, |m| matcher.is_reply(m), timeout).await {
+        Some(message) => RequestResult {
+            id: params.id.clone(),
+            broker: params.broker.clone(),
+            topic: params.topic.clone(),
+            success: true,
+            reply: Some(RequestReply::from_message(&message)),
+            rtt_ms: Some(
+                message
+                    .received_at
+                    .saturating_duration_since(published_at)
+                    .as_secs_f64()
+                    * 1000.0,
+            ),
+            error: None,
+        },
+        None => RequestResult::failed(
+            params,
+            format!(
+                "No reply on {} within {} ms",
+                params.response_topic, params.timeout_ms
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(json: serde_json::Value) -> RequestParams {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn message(topic: &str, payload: &'static [u8]) -> waiters::ReceivedMessage {
+        waiters::ReceivedMessage {
+            broker: "b".to_string(),
+            topic: topic.to_string(),
+            payload: bytes::Bytes::from_static(payload),
+            received_at: std::time::Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_reply_matched_by_json_field() {
+        let request = params(serde_json::json!({
+            "broker": "b",
+            "topic": "rpc",
+            "payload": r#"{"id":7,"method":"reboot"}"#,
+            "response_topic": "rpc",
+            "correlation_path": "$.id",
+        }));
+        let payload = request.payload.as_bytes();
+        let matcher = ReplyMatcher {
+            params: &request,
+            payload,
+            correlation: request.correlation(payload).unwrap(),
+            own_echo_seen: std::sync::atomic::AtomicBool::new(false),
+        };
+        assert_eq!(matcher.correlation, Some(serde_json::json!(7)));
+        // The echo of the request itself is skipped once
+        assert!(!matcher.is_reply(&message("rpc", br#"{"id":7,"method":"reboot"}"#)));
+        assert!(!matcher.is_reply(&message("rpc", br#"{"id":6,"ok":true}"#)));
+        assert!(!matcher.is_reply(&message("rpc", b"not json")));
+        assert!(matcher.is_reply(&message("rpc", br#"{"id":7,"ok":true}"#)));
+
+        let explicit = params(serde_json::json!({
+            "broker": "b",
+            "topic": "rpc/req",
+            "payload": "binary",
+            "response_topic": "rpc/res",
+            "correlation_path": "$.request",
+            "correlation_value": "abc",
+        }));
+        assert_eq!(
+            explicit.correlation(b"binary").unwrap(),
+            Some(serde_json::json!("abc"))
+        );
+        let missing = params(serde_json::json!({
+            "broker": "b",
+            "topic": "rpc",
+            "payload": "{}",
+            "response_topic": "rpc",
+            "correlation_path": "$.id",
+        }));
+        assert_eq!(
+            missing.correlation(b"{}").unwrap_err(),
+            "Request payload has no value at $.id"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_reply_times_out_or_returns_match() {
+        let waiter_map = waiters::WaiterMap::default();
+        let mut subscription = waiters::register(&waiter_map, Some("b"), vec!["res/#".to_string()]);
+        let timeout = std::time::Duration::from_millis(200);
+
+        let payload = bytes::Bytes::from_static(b"done");
+        waiters::dispatch(&waiter_map, "b", "res/1", &payload);
+        waiters::dispatch(&waiter_map, "b", "res/2", &payload);
+        let reply = wait_for_reply(&mut subscription, |m| m.topic == "res/2", timeout).await;
+        assert_eq!(RequestReply::from_message(&reply.unwrap()).payload, "done");
+
+        let short = std::time::Duration::from_millis(20);
+        assert!(wait_for_reply(&mut subscription, |_| true, short)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_request_errors() {
+        let waiter_map = waiters::WaiterMap::default();
+        let mqtt_map = mqtt::BrokerMap::default();
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("b:1883"));
+        let base = serde_json::json!({
+            "id": "r1",
+            "broker": "b:1883",
+            "topic": "rpc",
+            "payload": "{}",
+            "response_topic": "rpc/res",
+        });
+
+        let result = send_request(&params(base.clone()), &waiter_map, &mqtt_map).await;
+        assert_eq!(result.id.as_deref(), Some("r1"));
+        assert_eq!(
+            result.error.as_deref(),
+            Some("Broker b:1883 is not connected")
+        );
+        assert!(waiter_map.lock().unwrap().is_empty());
+
+        let mut v5 = base.clone();
+        v5["correlation_data"] = serde_json::json!("abc");
+        let result = send_request(&params(v5), &waiter_map, &mqtt_map).await;
+        assert_eq!(
+            result.error.as_deref(),
+            Some("MQTT v5 correlation data is not supported, use correlation_path")
+        );
+
+        let mut slow = base;
+        slow["timeout_ms"] = serde_json::json!(MAX_TIMEOUT_MS + 1);
+        let result = send_request(&params(slow), &waiter_map, &mqtt_map).await;
+        assert!(!result.success);
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::mqtt;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// How long a `clear_retained` preview can be confirmed.
+pub const CLEAR_TOKEN_TTL_SECS: i64 = 60;
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct RetainedTopic {
+    pub topic: String,
+    /// Payload size as published, before truncation.
+    pub size: usize,
+    pub timestamp: String,
+    pub age_secs: i64,
+}
+
+/// Topics under `prefix` whose newest stored message is retained and not a
+/// zero-length clear, sorted by name.
+pub fn retained_topics(
+    broker: &mqtt::MqttBroker,
+    prefix: Option<&str>,
+    now_ns: i64,
+) -> Vec<RetainedTopic> {
+    let mut topics: Vec<RetainedTopic> = broker
+        .topics
+        .histories()
+        .filter(|h| prefix.is_none_or(|p| h.name.starts_with(p)))
+        .filter_map(|h| {
+            let latest = h.messages.back()?;
+            if !latest.retain || latest.original_payload_size == 0 {
+                return None;
+            }
+            Some(RetainedTopic {
+                topic: h.name.to_string(),
+                size: latest.original_payload_size,
+                timestamp: mqtt::format_timestamp_ns(latest.timestamp_ns),
+                age_secs: (now_ns - latest.timestamp_ns).max(0) / 1_000_000_000,
+            })
+        })
+        .collect();
+    topics.sort_by(|a, b| a.topic.cmp(&b.topic));
+    topics
+}
+
+/// Topics from a `clear_retained` preview, waiting for confirmation.
+struct PendingClear {
+    broker: String,
+    topics: Vec<String>,
+    expires_ms: i64,
+}
+
+/// Confirmation tokens handed out by `clear_retained` dry runs. A token is
+/// valid once, for one broker, and only for the topics shown in its preview.
+#[derive(Default)]
+pub struct ClearConfirmations {
+    pending: HashMap<String, PendingClear>,
+}
+
+pub type ClearConfirmationMap = Arc<Mutex<ClearConfirmations>>;
+
+impl ClearConfirmations {
+    pub fn issue(&mut self, broker: &str, topics: Vec<String>, now_ms: i64) -> String {
+        self.pending.retain(|_, p| p.expires_ms > now_ms);
+        let token = uuid::Uuid::new_v4().to_string();
+        self.pending.insert(
+            token.clone(),
+            PendingClear {
+                broker: broker.to_string(),
+                topics,
+                expires_ms: now_ms + CLEAR_TOKEN_TTL_SECS * 1000,
+            },
+        );
+        token
+    }
+
+    /// Consume `token` and return the topics it confirms, if it was issued for
+    /// `broker` and has not expired.
+    pub fn take(&mut self, token: &str, broker: &str, now_ms: i64) -> Option<Vec<String>> {
+        let pending = self.pending.remove(token)?;
+        if pending.broker != broker || pending.expires_ms <= now_ms {
+            return None;
+        }
+        Some(pending.topics)
+    }
+}
+
+/// Publish a zero-length retained message to each topic, which makes the broker
+/// drop its retained message. Returns the cleared topics and failures.
+pub fn clear_topics(
+    mqtt_map: &mqtt::BrokerMap,
+    broker: &str,
+    topics: &[String],
+) -> (Vec<String>, Vec<(String, String)>) {
+    let mut cleared = Vec::new();
+    let mut failed = Vec::new();
+    for topic in topics {
+        match mqtt::try_publish_bytes(
+            broker,
+            topic,
+            &[],
+            rumqttc::QoS::AtLeastOnce,
+            true,
+            mqtt_map,
+        ) {
+            Ok(()) => cleared.push(topic.clone()),
+            Err(err) => failed.push((topic.clone(), err)),
+        }
+    }
+    (cleared, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: i64 = 1_000_000_000;
+
+    fn store(broker: &mut mqtt::MqttBroker, topic: &str, secs: i64, payload: &[u8], retain: bool) {
+        broker.store_message(
+            topic,
+            secs * SEC,
+            bytes::Bytes::copy_from_slice(payload),
+            payload.len(),
+            retain,
+        );
+    }
+
+    #[test]
+    fn test_retained_topics_uses_latest_message() {
+        let mut broker = mqtt::MqttBroker::new("127.0.0.1:18850");
+        store(&mut broker, "a/still", 10, b"on", true);
+        store(&mut broker, "a/updated", 10, b"on", true);
+        store(&mut broker, "a/updated", 20, b"off", false);
+        store(&mut broker, "a/cleared", 10, b"on", true);
+        store(&mut broker, "a/cleared", 20, b"", true);
+        store(&mut broker, "b/other", 30, b"12345", true);
+
+        let topics = retained_topics(&broker, None, 40 * SEC);
+        let names: Vec<_> = topics.iter().map(|t| t.topic.as_str()).collect();
+        assert_eq!(names, vec!["a/still", "b/other"]);
+        assert_eq!(topics[1].size, 5);
+        assert_eq!(topics[1].age_secs, 10);
+        assert_eq!(topics[1].timestamp, mqtt::format_timestamp_ns(30 * SEC));
+    }
+
+    #[test]
+    fn test_retained_topics_prefix() {
+        let mut broker = mqtt::MqttBroker::new("127.0.0.1:18851");
+        store(&mut broker, "a/x", 1, b"1", true);
+        store(&mut broker, "ab/y", 1, b"1", true);
+        store(&mut broker, "b/z", 1, b"1", true);
+
+        let names: Vec<_> = retained_topics(&broker, Some("a/"), SEC)
+            .into_iter()
+            .map(|t| t.topic)
+            .collect();
+        assert_eq!(names, vec!["a/x"]);
+    }
+
+    #[test]
+    fn test_confirmation_token_is_single_use() {
+        let mut confirmations = ClearConfirmations::default();
+        let token = confirmations.issue("broker:1883", vec!["a".to_string()], 0);
+        assert_eq!(
+            confirmations.take(&token, "broker:1883", 1000),
+            Some(vec!["a".to_string()])
+        );
+        assert_eq!(confirmations.take(&token, "broker:1883", 1000), None);
+    }
+
+    #[test]
+    fn test_confirmation_token_checks_broker_and_expiry() {
+        let mut confirmations = ClearConfirmations::default();
+        let token = confirmations.issue("broker:1883", vec!["a".to_string()], 0);
+        assert_eq!(confirmations.take(&token, "other:1883", 1000), None);
+
+        let token = confirmations.issue("broker:1883", vec!["a".to_string()], 0);
+        let expired = CLEAR_TOKEN_TTL_SECS * 1000;
+        assert_eq!(confirmations.take(&token, "broker:1883", expired), None);
+    }
+
+    #[test]
+    fn test_clear_topics_reports_failures() {
+        let mqtt_map = mqtt::BrokerMap::new(std::sync::RwLock::new(HashMap::new()));
+        let (cleared, failed) = clear_topics(&mqtt_map, "missing:1883", &["a".to_string()]);
+        assert!(cleared.is_empty());
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "a");
+    }
+}
@@ -0,0 +1,527 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::config;
+use super::mqtt;
+use super::websocket;
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+fn default_filter() -> String {
+    "#".to_string()
+}
+
+fn default_keep_latest() -> bool {
+    true
+}
+
+/// Storage limits for the topics matching `filter`. Every matching rule
+/// applies; where limits overlap the strictest one wins.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct RetentionRule {
+    pub name: String,
+    /// Broker key the rule is limited to. Applies to all brokers if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker: Option<String>,
+    #[serde(default = "default_filter")]
+    pub filter: String,
+    /// Messages kept per matching topic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    /// Messages older than this are removed by the periodic sweep.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+    /// Payload bytes kept across all matching topics together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    /// Don't store matching messages at all. Bridges, alerts and webhooks
+    /// still see them.
+    #[serde(default)]
+    pub never_store: bool,
+    /// Keep the newest message of each topic when applying `max_age_secs`
+    /// and `max_bytes`, like the global byte limit does.
+    #[serde(default = "default_keep_latest")]
+    pub keep_latest: bool,
+}
+
+impl RetentionRule {
+    fn applies_to(&self, broker: &str, topic: &str) -> bool {
+        self.broker.as_deref().is_none_or(|b| b == broker)
+            && mqtt::topic_matches(&self.filter, topic)
+    }
+
+    fn is_swept(&self) -> bool {
+        self.max_age_secs.is_some() || self.max_bytes.is_some()
+    }
+}
+
+/// Limits checked for every incoming message of a topic.
+#[derive(Debug, Default, PartialEq)]
+pub struct TopicLimits {
+    pub never_store: bool,
+    pub max_messages: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+}
+
+pub type RetentionMap = Arc<Mutex<RetentionPolicy>>;
+
+impl RetentionPolicy {
+    pub fn new(rules: Vec<RetentionRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn rules(&self) -> &[RetentionRule] {
+        &self.rules
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<RetentionRule>) {
+        self.rules = rules;
+    }
+
+    pub fn topic_limits(&self, broker: &str, topic: &str) -> TopicLimits {
+        let mut limits = TopicLimits::default();
+        for rule in self.rules.iter().filter(|r| r.applies_to(broker, topic)) {
+            limits.never_store |= rule.never_store;
+            if let Some(max) = rule.max_messages {
+                limits.max_messages = Some(limits.max_messages.map_or(max, |m| m.min(max)));
+            }
+        }
+        limits
+    }
+}
+
+/// Trim `topic` down to `limits.max_messages` after a message was stored.
+/// The newest message is always kept. Returns the number of removed messages.
+pub fn apply_topic_limits(
+    broker: &mut mqtt::MqttBroker,
+    topic: &str,
+    limits: &TopicLimits,
+) -> usize {
+    let Some(max_messages) = limits.max_messages else {
+        return 0;
+    };
+    let max_messages = max_messages.max(1);
+    let mut removed = 0;
+    while broker
+        .topics
+        .get(topic)
+        .is_some_and(|v| v.len() > max_messages)
+    {
+        broker.remove_oldest(topic);
+        removed += 1;
+    }
+    removed
+}
+
+fn message_ms(message: &mqtt::MqttMessage) -> i64 {
+    message.timestamp_ns / 1_000_000
+}
+
+fn oldest_ms(broker: &mqtt::MqttBroker, topic: &str) -> Option<i64> {
+    broker.topics.get(topic)?.front().map(message_ms)
+}
+
+fn can_remove(broker: &mqtt::MqttBroker, topic: &str, keep_latest: bool) -> bool {
+    let min_len = if keep_latest { 2 } else { 1 };
+    broker.topics.get(topic).is_some_and(|v| v.len() >= min_len)
+}
+
+fn sweep_age(
+    rule: &RetentionRule,
+    max_age_secs: u64,
+    broker: &mut mqtt::MqttBroker,
+    topics: &[String],
+    now_ms: i64,
+    removed: &mut HashMap<String, usize>,
+) {
+    let cutoff = now_ms.saturating_sub((max_age_secs as i64).saturating_mul(1000));
+    for topic in topics {
+        while can_remove(broker, topic, rule.keep_latest)
+            && oldest_ms(broker, topic).is_some_and(|ts| ts < cutoff)
+        {
+            broker.remove_oldest(topic);
+            *removed.entry(topic.clone()).or_insert(0) += 1;
+        }
+    }
+}
+
+fn sweep_bytes(
+    rule: &RetentionRule,
+    max_bytes: usize,
+    broker: &mut mqtt::MqttBroker,
+    topics: &[String],
+    removed: &mut HashMap<String, usize>,
+) {
+    let mut total: usize = topics
+        .iter()
+        .filter_map(|t| broker.topics.history(t))
+        .map(|h| h.payload_bytes)
+        .sum();
+    if total <= max_bytes {
+        return;
+    }
+
+    // Remove the oldest message across the whole subtree first.
+    let mut heap: BinaryHeap<Reverse<(i64, &String)>> = topics
+        .iter()
+        .filter_map(|t| oldest_ms(broker, t).map(|ts| Reverse((ts, t))))
+        .collect();
+    while total > max_bytes {
+        let Some(Reverse((_, topic))) = heap.pop() else {
+            break;
+        };
+        if !can_remove(broker, topic, rule.keep_latest) {
+            continue;
+        }
+        let len = broker.topics[topic].front().map_or(0, |m| m.payload.len());
+        broker.remove_oldest(topic);
+        total = total.saturating_sub(len);
+        *removed.entry(topic.clone()).or_insert(0) += 1;
+        if let Some(ts) = oldest_ms(broker, topic) {
+            heap.push(Reverse((ts, topic)));
+        }
+    }
+}
+
+/// Apply the age and subtree byte limits of `rules` to one broker. Returns
+/// `(topic, removed, remaining)` per topic, like the global eviction does.
+pub fn sweep_broker(
+    rules: &[RetentionRule],
+    broker: &mut mqtt::MqttBroker,
+    now_ms: i64,
+) -> Vec<(String, usize, usize)> {
+    let mut removed: HashMap<String, usize> = HashMap::new();
+    for rule in rules.iter().filter(|r| r.is_swept()) {
+        let topics: Vec<String> = broker
+            .topics
+            .names()
+            .filter(|t| rule.applies_to(&broker.broker, t))
+            .map(str::to_string)
+            .collect();
+        if topics.is_empty() {
+            continue;
+        }
+        if let Some(max_age_secs) = rule.max_age_secs {
+            sweep_age(rule, max_age_secs, broker, &topics, now_ms, &mut removed);
+        }
+        if let Some(max_bytes) = rule.max_bytes {
+            sweep_bytes(rule, max_bytes, broker, &topics, &mut removed);
+        }
+    }
+    removed
+        .into_iter()
+        .map(|(topic, count)| {
+            let remaining = broker.topics.get(&topic).map_or(0, |v| v.len());
+            (topic, count, remaining)
+        })
+        .collect()
+}
+
+// ─── Rule persistence ────────────────────────────────────────────────
+
+pub fn get_retention_rules(retention_path: &str) -> Vec<RetentionRule> {
+    config::read_named_files(retention_path, "retention rule")
+}
+
+pub fn parse_retention_rule(params: serde_json::Value) -> Option<RetentionRule> {
+    let rule = match serde_json::from_value::<RetentionRule>(params) {
+        Ok(rule) => rule,
+        Err(_) => {
+            println!("Could not deserialize retention rule.");
+            return None;
+        }
+    };
+    if let Err(err) = config::validate_file_name(&rule.name) {
+        println!("Retention rule {} is invalid: {err}", rule.name);
+        return None;
+    }
+    Some(rule)
+}
+
+pub fn add_to_retention_rules(retention_path: &str, rule: &RetentionRule) -> bool {
+    config::write_named_file(retention_path, "retention rule", &rule.name, rule)
+}
+
+pub fn remove_from_retention_rules(retention_path: &str, name: &str) -> bool {
+    config::remove_named_file(retention_path, "retention rule", name)
+}
+
+pub fn reload_retention_rules(retention_map: &RetentionMap, retention_path: &str) {
+    let rules = get_retention_rules(retention_path);
+    retention_map.lock().unwrap().set_rules(rules);
+}
+
+/// Apply age and subtree byte limits to all brokers, so old messages expire
+/// even when no new messages arrive. Each broker is locked separately.
+pub fn process_sweep(
+    retention_map: &RetentionMap,
+    mqtt_map: &mqtt::BrokerMap,
+    notification_buf: &websocket::NotificationBuf,
+) {
+    let rules: Vec<RetentionRule> = {
+        let policy = retention_map.lock().unwrap();
+        policy
+            .rules()
+            .iter()
+            .filter(|r| r.is_swept())
+            .cloned()
+            .collect()
+    };
+    if rules.is_empty() {
+        return;
+    }
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    for entry in mqtt::all_brokers(mqtt_map) {
+        let (hostname, evictions) = {
+            let mut broker = entry.state();
+            let evictions = sweep_broker(&rules, &mut broker, now_ms);
+            (broker.broker.clone(), evictions)
+        };
+        if !evictions.is_empty() {
+            websocket::buffer_evictions(notification_buf, &hostname, &evictions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_broker() -> mqtt::MqttBroker {
+        mqtt::MqttBroker::new("127.0.0.1:18840")
+    }
+
+    fn store(broker: &mut mqtt::MqttBroker, topic: &str, secs: i64, payload: &'static [u8]) {
+        broker.store_message(
+            topic,
+            secs * 1_000_000_000,
+            bytes::Bytes::from_static(payload),
+            payload.len(),
+            false,
+        );
+    }
+
+    fn rule(json: serde_json::Value) -> RetentionRule {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_rule_defaults() {
+        let r = rule(serde_json::json!({"name": "r"}));
+        assert_eq!(r.filter, "#");
+        assert!(r.keep_latest);
+        assert!(!r.never_store);
+        assert!(!r.is_swept());
+    }
+
+    #[test]
+    fn test_topic_limits_combine_matching_rules() {
+        let policy = RetentionPolicy::new(vec![
+            rule(serde_json::json!({"name": "a", "filter": "sensors/#", "max_messages": 10})),
+            rule(serde_json::json!({"name": "b", "filter": "sensors/+/raw", "max_messages": 3})),
+            rule(serde_json::json!({"name": "c", "filter": "noise/#", "never_store": true})),
+            rule(
+                serde_json::json!({"name": "d", "broker": "other:1883", "filter": "#", "max_messages": 1}),
+            ),
+        ]);
+        assert_eq!(
+            policy.topic_limits("h:1883", "sensors/1/raw"),
+            TopicLimits {
+                never_store: false,
+                max_messages: Some(3)
+            }
+        );
+        assert_eq!(
+            policy.topic_limits("h:1883", "sensors/1/temp").max_messages,
+            Some(10)
+        );
+        assert!(policy.topic_limits("h:1883", "noise/x").never_store);
+        assert_eq!(
+            policy.topic_limits("h:1883", "other"),
+            TopicLimits::default()
+        );
+        assert_eq!(
+            policy.topic_limits("other:1883", "other").max_messages,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_apply_topic_limits_trims_oldest() {
+        let mut broker = make_broker();
+        for (i, payload) in [b"1", b"2", b"3", b"4"].iter().enumerate() {
+            store(&mut broker, "t", i as i64, *payload);
+        }
+        let limits = TopicLimits {
+            never_store: false,
+            max_messages: Some(2),
+        };
+        assert_eq!(apply_topic_limits(&mut broker, "t", &limits), 2);
+        let payloads: Vec<_> = broker.topics["t"]
+            .iter()
+            .map(|m| m.payload.clone())
+            .collect();
+        assert_eq!(payloads, vec![&b"3"[..], &b"4"[..]]);
+        assert_eq!(broker.total_messages, 2);
+        assert_eq!(
+            broker.total_bytes,
+            2 * mqtt::message_cost(1) + mqtt::topic_cost("t")
+        );
+        assert!(broker.eviction_order.len() <= 2 * broker.total_messages);
+        let limits = TopicLimits {
+            max_messages: Some(1),
+            ..limits
+        };
+        assert_eq!(apply_topic_limits(&mut broker, "t", &limits), 1);
+        assert_eq!(broker.eviction_order.len(), 1);
+    }
+
+    #[test]
+    fn test_sweep_age_keeps_latest_by_default() {
+        let mut broker = make_broker();
+        store(&mut broker, "a", 100, b"old");
+        store(&mut broker, "a", 200, b"mid");
+        store(&mut broker, "b", 100, b"only");
+        let rules = vec![rule(serde_json::json!({"name": "age", "max_age_secs": 50}))];
+
+        let evictions = sweep_broker(&rules, &mut broker, 300_000);
+
+        assert_eq!(evictions, vec![("a".to_string(), 1, 1)]);
+        assert_eq!(broker.topics["a"][0].payload, &b"mid"[..]);
+        assert_eq!(broker.topics["b"].len(), 1);
+    }
+
+    #[test]
+    fn test_sweep_age_without_keep_latest_removes_topic() {
+        let mut broker = make_broker();
+        store(&mut broker, "a", 100, b"old");
+        store(&mut broker, "b", 290, b"new");
+        let rules = vec![rule(
+            serde_json::json!({"name": "age", "max_age_secs": 50, "keep_latest": false}),
+        )];
+
+        let evictions = sweep_broker(&rules, &mut broker, 300_000);
+
+        assert_eq!(evictions, vec![("a".to_string(), 1, 0)]);
+        assert!(!broker.topics.contains_key("a"));
+        assert_eq!(broker.total_messages, 1);
+        assert_eq!(
+            broker.total_bytes,
+            mqtt::message_cost(3) + mqtt::topic_cost("b")
+        );
+    }
+
+    #[test]
+    fn test_sweep_bytes_removes_oldest_across_subtree() {
+        let mut broker = make_broker();
+        store(&mut broker, "s/a", 1, b"aaaa");
+        store(&mut broker, "s/b", 2, b"bbbb");
+        store(&mut broker, "s/a", 3, b"AAAA");
+        store(&mut broker, "s/b", 4, b"BBBB");
+        store(&mut broker, "other", 0, b"xxxxxxxx");
+        let rules = vec![rule(
+            serde_json::json!({"name": "cap", "filter": "s/#", "max_bytes": 8}),
+        )];
+
+        let mut evictions = sweep_broker(&rules, &mut broker, 10_000);
+        evictions.sort();
+
+        assert_eq!(
+            evictions,
+            vec![("s/a".to_string(), 1, 1), ("s/b".to_string(), 1, 1)]
+        );
+        assert_eq!(broker.topics["s/a"][0].payload, &b"AAAA"[..]);
+        assert_eq!(broker.topics["s/b"][0].payload, &b"BBBB"[..]);
+        assert_eq!(broker.topics["other"].len(), 1);
+        assert_eq!(broker.topics.history("s/a").unwrap().payload_bytes, 4);
+    }
+
+    #[test]
+    fn test_sweep_ignores_rules_for_other_brokers() {
+        let mut broker = make_broker();
+        store(&mut broker, "a", 1, b"old");
+        store(&mut broker, "a", 2, b"new");
+        let rules = vec![rule(
+            serde_json::json!({"name": "age", "broker": "elsewhere:1883", "max_age_secs": 1}),
+        )];
+        assert!(sweep_broker(&rules, &mut broker, 100_000).is_empty());
+    }
+
+    #[test]
+    fn test_process_sweep_buffers_evictions() {
+        let mqtt_map = mqtt::BrokerMap::new(std::sync::RwLock::new(HashMap::new()));
+        let mut broker = make_broker();
+        store(&mut broker, "a", 1, b"old");
+        store(&mut broker, "a", 2, b"new");
+        mqtt::insert_offline_broker(&mqtt_map, broker);
+        let retention_map = RetentionMap::new(Mutex::new(RetentionPolicy::new(vec![rule(
+            serde_json::json!({"name": "age", "max_age_secs": 1}),
+        )])));
+        let buf =
+            websocket::NotificationBuf::new(Mutex::new(websocket::NotificationBuffer::default()));
+
+        process_sweep(&retention_map, &mqtt_map, &buf);
+
+        let evictions = &buf.lock().unwrap().evictions;
+        assert_eq!(evictions.len(), 1);
+        assert_eq!(evictions[0].source, "127.0.0.1:18840");
+        assert_eq!(evictions[0].topic, "a");
+        assert_eq!(evictions[0].count, 1);
+        assert_eq!(evictions[0].topic_message_count, 1);
+    }
+
+    #[test]
+    fn test_retention_rule_persistence() {
+        let path = format!("/tmp/mqtt_test_retention_{}", uuid::Uuid::new_v4());
+        for params in [
+            serde_json::json!({"name": "b", "filter": "x/#", "max_messages": 5}),
+            serde_json::json!({"name": "a", "never_store": true}),
+        ] {
+            let rule = parse_retention_rule(params).unwrap();
+            assert!(add_to_retention_rules(&path, &rule));
+        }
+        assert!(parse_retention_rule(serde_json::json!({"filter": "x"})).is_none());
+        assert!(parse_retention_rule(serde_json::json!({"name": "../x"})).is_none());
+        assert!(!remove_from_retention_rules(&path, "../b"));
+
+        let rules = get_retention_rules(&path);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "a");
+        assert_eq!(rules[1].max_messages, Some(5));
+
+        let retention_map = RetentionMap::new(Mutex::new(RetentionPolicy::default()));
+        reload_retention_rules(&retention_map, &path);
+        assert_eq!(retention_map.lock().unwrap().rules().len(), 2);
+
+        assert!(remove_from_retention_rules(&path, "a"));
+        assert_eq!(get_retention_rules(&path).len(), 1);
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
@@ -0,0 +1,623 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::alerts;
+use super::commands;
+use super::config;
+use super::mqtt;
+use super::services;
+use super::waiters;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Unmatched messages kept for later `wait_for` steps.
+const MAX_INBOX_MESSAGES: usize = 1000;
+
+/// Payload bytes shown in the result of a `wait_for` step.
+const MAX_DETAIL_PAYLOAD_BYTES: usize = 256;
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+/// A JSON payload value at `path` (e.g. `$.state`), or the whole payload if
+/// there is no path, compared against `value`. Payloads that aren't JSON
+/// compare as strings.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct PayloadCheck {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub op: alerts::CompareOp,
+    pub value: serde_json::Value,
+}
+
+impl PayloadCheck {
+    fn check(&self, payload: &[u8]) -> Result<(), String> {
+        let json = serde_json::from_slice::<serde_json::Value>(payload).ok();
+        let observed = match (&self.path, json) {
+            (Some(path), Some(json)) => alerts::json_path_lookup(&json, path)
+                .cloned()
+                .ok_or_else(|| format!("No value at {path}"))?,
+            (Some(path), None) => return Err(format!("Payload is not JSON, no value at {path}")),
+            (None, Some(json)) => json,
+            (None, None) => serde_json::Value::String(String::from_utf8_lossy(payload).into()),
+        };
+        if alerts::compare_values(&observed, self.op, &self.value) {
+            return Ok(());
+        }
+        let op = serde_json::json!(self.op);
+        Err(format!(
+            "Expected {} {} {}, got {observed}",
+            self.path.as_deref().unwrap_or("payload"),
+            op.as_str().unwrap_or_default(),
+            self.value
+        ))
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Step {
+    /// Execute a saved command, on `broker`, the scenario's broker or the
+    /// broker bound to the command.
+    Publish {
+        command: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        broker: Option<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        variables: BTreeMap<String, String>,
+    },
+    Sleep {
+        ms: u64,
+    },
+    /// Wait for a message on `filter` received since the last publish step
+    /// that passes `predicate`.
+    WaitFor {
+        filter: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        broker: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        predicate: Option<PayloadCheck>,
+        #[serde(default = "default_timeout_ms")]
+        timeout_ms: u64,
+    },
+    /// Check the payload of the message the last `wait_for` step received.
+    Assert(PayloadCheck),
+}
+
+impl Step {
+    fn kind(&self) -> &'static str {
+        match self {
+            Step::Publish { .. } => "publish",
+            Step::Sleep { .. } => "sleep",
+            Step::WaitFor { .. } => "wait_for",
+            Step::Assert(_) => "assert",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct ScenarioConfig {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Default broker for all steps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+impl ScenarioConfig {
+    fn validate(&self) -> Result<(), String> {
+        config::validate_file_name(&self.name)?;
+        if self.steps.is_empty() {
+            return Err("Scenario has no steps".to_string());
+        }
+        let mut waited = false;
+        for (index, step) in self.steps.iter().enumerate() {
+            match step {
+                Step::WaitFor { filter, broker, .. } => {
+                    if filter.is_empty() {
+                        return Err(format!("Step {index} has an empty topic filter"));
+                    }
+                    if broker.is_none() && self.broker.is_none() {
+                        return Err(format!("Step {index} has no broker to wait on"));
+                    }
+                    waited = true;
+                }
+                Step::Assert(_) if !waited => {
+                    return Err(format!("Step {index} asserts before any wait_for step"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn publish_broker(
+        &self,
+        broker: &Option<String>,
+        command: &config::CommandMessage,
+    ) -> Option<String> {
+        broker
+            .clone()
+            .or(self.broker.clone())
+            .or(command.broker.clone())
+    }
+
+    /// Every broker the scenario publishes to or waits on.
+    pub fn brokers(&self, commands_path: &str) -> Result<BTreeSet<String>, String> {
+        let mut brokers = BTreeSet::new();
+        for step in &self.steps {
+            match step {
+                Step::Publish {
+                    command, broker, ..
+                } => {
+                    let saved = config::get_command(commands_path, command)
+                        .ok_or_else(|| format!("Command {command} not found"))?;
+                    let broker = self
+                        .publish_broker(broker, &saved)
+                        .ok_or_else(|| format!("No broker for command {command}"))?;
+                    brokers.insert(broker);
+                }
+                Step::WaitFor { broker, .. } => {
+                    brokers.extend(broker.clone().or(self.broker.clone()));
+                }
+                Step::Sleep { .. } | Step::Assert(_) => {}
+            }
+        }
+        Ok(brokers)
+    }
+}
+
+// ─── Persistence ─────────────────────────────────────────────────────
+
+pub fn get_scenarios(scenarios_path: &str) -> Vec<ScenarioConfig> {
+    config::read_named_files::<ScenarioConfig>(scenarios_path, "scenario")
+        .into_iter()
+        .filter(|scenario| match scenario.validate() {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Invalid scenario {}: {err}", scenario.name);
+                false
+            }
+        })
+        .collect()
+}
+
+pub fn get_scenario(scenarios_path: &str, name: &str) -> Option<ScenarioConfig> {
+    get_scenarios(scenarios_path)
+        .into_iter()
+        .find(|s| s.name == name)
+}
+
+pub fn parse_scenario(params: serde_json::Value) -> Option<ScenarioConfig> {
+    let scenario = match serde_json::from_value::<ScenarioConfig>(params) {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            println!("Could not deserialize scenario: {err}");
+            return None;
+        }
+    };
+    if let Err(err) = scenario.validate() {
+        println!("Scenario {} is invalid: {err}", scenario.name);
+        return None;
+    }
+    Some(scenario)
+}
+
+pub fn add_to_scenarios(scenarios_path: &str, scenario: &ScenarioConfig) -> bool {
+    config::write_named_file(scenarios_path, "scenario", &scenario.name, scenario)
+}
+
+pub fn remove_from_scenarios(scenarios_path: &str, name: &str) -> bool {
+    config::remove_named_file(scenarios_path, "scenario", name)
+}
+
+// ─── Running scenarios ───────────────────────────────────────────────
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct StepResult {
+    pub index: usize,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub success: bool,
+    pub detail: Option<String>,
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+/// Outcome of a run. Steps after the first failing one are not run.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct ScenarioReport {
+    pub run_id: String,
+    pub scenario: String,
+    pub success: bool,
+    pub steps: Vec<StepResult>,
+    pub error: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+impl ScenarioReport {
+    /// A run that could not start.
+    pub fn failed(run_id: &str, scenario: &str, error: String) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            scenario: scenario.to_string(),
+            success: false,
+            steps: Vec::new(),
+            error: Some(error),
+            elapsed_ms: 0,
+        }
+    }
+}
+
+/// Messages received for the scenario's `wait_for` filters.
+struct Inbox {
+    subscription: waiters::Subscription,
+    pending: VecDeque<waiters::ReceivedMessage>,
+}
+
+impl Inbox {
+    fn push(&mut self, message: waiters::ReceivedMessage) {
+        if self.pending.len() >= MAX_INBOX_MESSAGES {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(message);
+    }
+
+    fn drain(&mut self) {
+        while let Some(message) = self.subscription.try_recv() {
+            self.push(message);
+        }
+    }
+
+    /// Forget what arrived so far, e.g. before publishing a new request.
+    fn clear(&mut self) {
+        self.drain();
+        self.pending.clear();
+    }
+
+    /// The first pending or newly arriving message that `matches`. Others
+    /// stay pending for later steps.
+    async fn wait_for(
+        &mut self,
+        matches: impl Fn(&waiters::ReceivedMessage) -> bool,
+        timeout: std::time::Duration,
+    ) -> Option<waiters::ReceivedMessage> {
+        self.drain();
+        if let Some(pos) = self.pending.iter().position(&matches) {
+            return self.pending.remove(pos);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let received = tokio::time::timeout_at(deadline, self.subscription.recv())
+                .await
+                .ok()??;
+            if matches(&received) {
+                return Some(received);
+            }
+            self.push(received);
+        }
+    }
+}
+
+fn describe_message(message: &waiters::ReceivedMessage) -> String {
+    let shown = message.payload.len().min(MAX_DETAIL_PAYLOAD_BYTES);
+    let mut payload = String::from_utf8_lossy(&message.payload[..shown]).into_owned();
+    if shown < message.payload.len() {
+        payload.push('…');
+    }
+    format!("{} {}: {payload}", message.broker, message.topic)
+}
+
+/// Run the steps of `scenario` in order, calling `on_step` after each one.
+pub async fn run(
+    scenario: &ScenarioConfig,
+    run_id: &str,
+    commands_path: &str,
+    services: &services::Services,
+    mqtt_map: &mqtt::BrokerMap,
+    mut on_step: impl FnMut(&StepResult),
+) -> ScenarioReport {
+    let started = std::time::Instant::now();
+    let filters: Vec<String> = scenario
+        .steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::WaitFor { filter, .. } => Some(filter.clone()),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let mut inbox = Inbox {
+        subscription: waiters::register(&services.waiters, None, filters),
+        pending: VecDeque::new(),
+    };
+    let mut last_received: Option<waiters::ReceivedMessage> = None;
+    let mut steps = Vec::new();
+
+    for (index, step) in scenario.steps.iter().enumerate() {
+        let step_started = std::time::Instant::now();
+        let outcome: Result<Option<String>, String> = match step {
+            Step::Publish {
+                command,
+                broker,
+                variables,
+            } => {
+                inbox.clear();
+                match config::get_command(commands_path, command) {
+                    None => Err(format!("Command {command} not found")),
+                    Some(saved) => match scenario.publish_broker(broker, &saved) {
+                        None => Err(format!("No broker for command {command}")),
+                        Some(broker) => {
+                            let result = commands::execute_command(
+                                &saved,
+                                &broker,
+                                variables,
+                                &services.command_counters,
+                                mqtt_map,
+                            );
+                            match result.error {
+                                Some(err) => Err(err),
+                                None => Ok(Some(format!(
+                                    "{broker} {}: {}",
+                                    result.topic.unwrap_or_default(),
+                                    result.payload.unwrap_or_default()
+                                ))),
+                            }
+                        }
+                    },
+                }
+            }
+            Step::Sleep { ms } => {
+                tokio::time::sleep(std::time::Duration::from_millis(*ms)).await;
+                Ok(None)
+            }
+            Step::WaitFor {
+                filter,
+                broker,
+                predicate,
+                timeout_ms,
+            } => {
+                let broker = broker.clone().or(scenario.broker.clone());
+                let matches = |message: &waiters::ReceivedMessage| {
+                    broker.as_deref() == Some(message.broker.as_str())
+                        && mqtt::topic_matches(filter, &message.topic)
+                        && predicate
+                            .as_ref()
+                            .is_none_or(|p| p.check(&message.payload).is_ok())
+                };
+                let timeout = std::time::Duration::from_millis(*timeout_ms);
+                match inbox.wait_for(matches, timeout).await {
+                    Some(message) => {
+                        let detail = describe_message(&message);
+                        last_received = Some(message);
+                        Ok(Some(detail))
+                    }
+                    None => Err(format!(
+                        "No matching message on {filter} within {timeout_ms} ms"
+                    )),
+                }
+            }
+            Step::Assert(check) => match &last_received {
+                Some(message) => check.check(&message.payload).map(|_| None),
+                None => Err("No message received to assert on".to_string()),
+            },
+        };
+        let result = StepResult {
+            index,
+            kind: step.kind(),
+            success: outcome.is_ok(),
+            elapsed_ms: step_started.elapsed().as_millis() as u64,
+            detail: outcome.clone().ok().flatten(),
+            error: outcome.err(),
+        };
+        on_step(&result);
+        let failed = !result.success;
+        steps.push(result);
+        if failed {
+            break;
+        }
+    }
+
+    ScenarioReport {
+        run_id: run_id.to_string(),
+        scenario: scenario.name.clone(),
+        success: steps.len() == scenario.steps.len() && steps.iter().all(|s| s.success),
+        steps,
+        error: None,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario(json: serde_json::Value) -> ScenarioConfig {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn check(json: serde_json::Value) -> PayloadCheck {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_payload_check() {
+        let open = br#"{"state":"open","battery":80}"#;
+        assert!(
+            check(serde_json::json!({"path": "$.state", "op": "eq", "value": "open"}))
+                .check(open)
+                .is_ok()
+        );
+        assert!(
+            check(serde_json::json!({"path": "$.battery", "op": "gt", "value": 50}))
+                .check(open)
+                .is_ok()
+        );
+        let err = check(serde_json::json!({"path": "$.battery", "op": "lt", "value": 20}))
+            .check(open)
+            .unwrap_err();
+        assert_eq!(err, "Expected $.battery lt 20, got 80");
+        assert!(check(serde_json::json!({"op": "eq", "value": "ok"}))
+            .check(b"ok")
+            .is_ok());
+        assert!(
+            check(serde_json::json!({"path": "$.state", "op": "eq", "value": "ok"}))
+                .check(b"ok")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let assert_first = scenario(serde_json::json!({
+            "name": "s",
+            "broker": "b",
+            "steps": [{ "type": "assert", "op": "eq", "value": 1 }],
+        }));
+        assert!(assert_first.validate().is_err());
+        let no_broker = scenario(serde_json::json!({
+            "name": "s",
+            "steps": [{ "type": "wait_for", "filter": "a/#" }],
+        }));
+        assert_eq!(
+            no_broker.validate().unwrap_err(),
+            "Step 0 has no broker to wait on"
+        );
+        let valid = scenario(serde_json::json!({
+            "name": "s",
+            "steps": [
+                { "type": "sleep", "ms": 10 },
+                { "type": "wait_for", "broker": "b", "filter": "a/#" },
+                { "type": "assert", "op": "eq", "value": 1 },
+            ],
+        }));
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.brokers("/nonexistent").unwrap().len(), 1);
+        let escaping = ScenarioConfig {
+            name: "../pipelines/s".to_string(),
+            ..valid
+        };
+        assert!(escaping.validate().is_err());
+        assert!(!remove_from_scenarios("/nonexistent", "../s"));
+    }
+
+    #[tokio::test]
+    async fn test_run_waits_for_matching_reply_and_asserts() {
+        let services = services::Services::default();
+        let mqtt_map = mqtt::BrokerMap::default();
+        let door = scenario(serde_json::json!({
+            "name": "door",
+            "broker": "b",
+            "steps": [
+                {
+                    "type": "wait_for",
+                    "filter": "door/+/state",
+                    "predicate": { "path": "$.state", "op": "eq", "value": "open" },
+                    "timeout_ms": 2000,
+                },
+                { "type": "assert", "path": "$.battery", "op": "gte", "value": 10 },
+                { "type": "assert", "path": "$.battery", "op": "gte", "value": 90 },
+                { "type": "sleep", "ms": 1 },
+            ],
+        }));
+
+        let waiters = services.waiters.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            let closed = bytes::Bytes::from_static(br#"{"state":"closed"}"#);
+            let open = bytes::Bytes::from_static(br#"{"state":"open","battery":80}"#);
+            waiters::dispatch(&waiters, "other", "door/1/state", &open);
+            waiters::dispatch(&waiters, "b", "door/1/state", &closed);
+            waiters::dispatch(&waiters, "b", "door/1/state", &open);
+        });
+
+        let mut notified = Vec::new();
+        let report = run(&door, "run", "/nonexistent", &services, &mqtt_map, |step| {
+            notified.push(step.clone())
+        })
+        .await;
+
+        assert!(!report.success);
+        assert_eq!(report.steps, notified);
+        assert_eq!(
+            report.steps[0].detail.as_deref(),
+            Some(r#"b door/1/state: {"state":"open","battery":80}"#)
+        );
+        assert!(report.steps[1].success);
+        assert_eq!(
+            report.steps[2].error.as_deref(),
+            Some("Expected $.battery gte 90, got 80")
+        );
+        // Stops at the first failure
+        assert_eq!(report.steps.len(), 3);
+        assert!(services.waiters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_timeout_and_missing_command() {
+        let services = services::Services::default();
+        let mqtt_map = mqtt::BrokerMap::default();
+        let timeout = scenario(serde_json::json!({
+            "name": "timeout",
+            "broker": "b",
+            "steps": [{ "type": "wait_for", "filter": "reply", "timeout_ms": 20 }],
+        }));
+        let report = run(
+            &timeout,
+            "run",
+            "/nonexistent",
+            &services,
+            &mqtt_map,
+            |_| {},
+        )
+        .await;
+        assert!(!report.success);
+        assert_eq!(
+            report.steps[0].error.as_deref(),
+            Some("No matching message on reply within 20 ms")
+        );
+
+        let missing = scenario(serde_json::json!({
+            "name": "missing",
+            "broker": "b",
+            "steps": [{ "type": "publish", "command": "nope" }],
+        }));
+        let report = run(
+            &missing,
+            "run",
+            "/nonexistent",
+            &services,
+            &mqtt_map,
+            |_| {},
+        )
+        .await;
+        assert_eq!(
+            report.steps[0].error.as_deref(),
+            Some("Command nope not found")
+        );
+        assert!(missing.brokers("/nonexistent").is_err());
+    }
+}
@@ -0,0 +1,724 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::commands;
+use super::config;
+use super::cron;
+use super::mqtt;
+use super::services;
+use super::websocket;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+/// How often due jobs are looked for.
+pub const TICK_MS: u64 = 200;
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// What a job does when it runs.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobAction {
+    /// Execute a saved command, on `broker` or the broker bound to it.
+    Command {
+        command: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        broker: Option<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        variables: BTreeMap<String, String>,
+    },
+    /// Publish a message. Topic and payload can use command placeholders.
+    Publish {
+        broker: String,
+        topic: String,
+        #[serde(default)]
+        payload: String,
+        #[serde(default = "default_qos")]
+        qos: u8,
+        #[serde(default)]
+        retain: bool,
+        #[serde(default, skip_serializing_if = "mqtt::PayloadEncoding::is_utf8")]
+        encoding: mqtt::PayloadEncoding,
+    },
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Schedule {
+    /// Every `every_secs`, starting one interval after the job is loaded.
+    Interval { every_secs: u64 },
+    /// Five-field cron expression, in UTC.
+    Cron { expression: String },
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct JobConfig {
+    pub name: String,
+    pub action: JobAction,
+    pub schedule: Schedule,
+    /// RFC 3339 time after which the job no longer runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<String>,
+    /// Number of runs after which the job stops.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<u64>,
+    /// Whether the job is running. Toggled by `start_schedule`/`stop_schedule`.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+impl JobConfig {
+    fn validate(&self) -> Result<(), String> {
+        config::validate_file_name(&self.name)?;
+        match &self.schedule {
+            Schedule::Interval { every_secs } if *every_secs == 0 => {
+                return Err("Interval must be at least one second".to_string());
+            }
+            Schedule::Interval { .. } => {}
+            Schedule::Cron { expression } => {
+                cron::CronSchedule::parse(expression)?;
+            }
+        }
+        if let JobAction::Publish { qos, .. } = &self.action {
+            if mqtt::qos_from_u8(*qos).is_none() {
+                return Err(format!("Invalid QoS {qos}"));
+            }
+        }
+        if let Some(end_time) = &self.end_time {
+            if mqtt::parse_timestamp_ns(end_time).is_none() {
+                return Err(format!("Invalid end time {end_time}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn end_ms(&self) -> Option<i64> {
+        let end_time = self.end_time.as_ref()?;
+        mqtt::parse_timestamp_ns(end_time).map(|ns| ns / 1_000_000)
+    }
+
+    /// The first run after `after_ms`, or `None` if the job is done.
+    fn next_run_after(&self, after_ms: i64, run_count: u64) -> Option<i64> {
+        if !self.enabled || self.max_count.is_some_and(|max| run_count >= max) {
+            return None;
+        }
+        let next_ms = match &self.schedule {
+            Schedule::Interval { every_secs } => after_ms + *every_secs as i64 * 1000,
+            Schedule::Cron { expression } => {
+                let schedule = cron::CronSchedule::parse(expression).ok()?;
+                let after = chrono::DateTime::from_timestamp_millis(after_ms)?;
+                schedule.next_after(after)?.timestamp_millis()
+            }
+        };
+        if self.end_ms().is_some_and(|end_ms| next_ms > end_ms) {
+            return None;
+        }
+        Some(next_ms)
+    }
+
+    /// Whether `other` runs at the same times as this job, so runs counted
+    /// for one count for the other.
+    fn same_plan(&self, other: &JobConfig) -> bool {
+        self.schedule == other.schedule
+            && self.end_time == other.end_time
+            && self.max_count == other.max_count
+    }
+
+    /// Broker the job publishes to. For saved commands without a broker of
+    /// their own, the one bound to the command.
+    pub fn target_broker(&self, commands_path: &str) -> Option<String> {
+        match &self.action {
+            JobAction::Command {
+                broker: Some(broker),
+                ..
+            }
+            | JobAction::Publish { broker, .. } => Some(broker.clone()),
+            JobAction::Command { command, .. } => {
+                config::get_command(commands_path, command)?.broker
+            }
+        }
+    }
+}
+
+struct Job {
+    config: JobConfig,
+    run_count: u64,
+    failed: u64,
+    last_run_ms: Option<i64>,
+    next_run_ms: Option<i64>,
+    last_error: Option<String>,
+}
+
+impl Job {
+    fn new(config: JobConfig, run_count: u64, now_ms: i64) -> Self {
+        let next_run_ms = config.next_run_after(now_ms, run_count);
+        Self {
+            config,
+            run_count,
+            failed: 0,
+            last_run_ms: None,
+            next_run_ms,
+            last_error: None,
+        }
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let format_ms = |ms: i64| mqtt::format_timestamp_ns(ms * 1_000_000);
+        serde_json::json!({
+            "name": self.config.name,
+            "enabled": self.config.enabled,
+            "run_count": self.run_count,
+            "failed": self.failed,
+            "last_run": self.last_run_ms.map(format_ms),
+            "next_run": self.next_run_ms.map(format_ms),
+            "last_error": self.last_error,
+            "finished": self.config.enabled && self.next_run_ms.is_none(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+pub type SchedulerMap = Arc<Mutex<Scheduler>>;
+
+impl Scheduler {
+    /// Replace the job set. Jobs whose config is unchanged keep their counts
+    /// and next run; changed jobs start over, but keep their run count while
+    /// their schedule and limits are the same. New jobs continue from the
+    /// stored `run_counts`.
+    pub fn set_jobs(
+        &mut self,
+        configs: Vec<JobConfig>,
+        run_counts: &HashMap<String, u64>,
+        now_ms: i64,
+    ) {
+        let mut previous: HashMap<String, Job> = self
+            .jobs
+            .drain(..)
+            .map(|j| (j.config.name.clone(), j))
+            .collect();
+        self.jobs = configs
+            .into_iter()
+            .map(|config| match previous.remove(&config.name) {
+                Some(existing) if existing.config == config => existing,
+                Some(existing) if existing.config.same_plan(&config) => {
+                    Job::new(config, existing.run_count, now_ms)
+                }
+                _ => {
+                    let run_count = run_counts.get(&config.name).copied().unwrap_or(0);
+                    Job::new(config, run_count, now_ms)
+                }
+            })
+            .collect();
+    }
+
+    /// Jobs due at `now_ms` with their run count. Their run is counted and
+    /// the next one planned right away, so a slow publish never runs a job
+    /// twice.
+    pub fn take_due(&mut self, now_ms: i64) -> Vec<(JobConfig, u64)> {
+        let mut due = Vec::new();
+        for job in &mut self.jobs {
+            let Some(next_run_ms) = job.next_run_ms else {
+                continue;
+            };
+            if next_run_ms > now_ms {
+                continue;
+            }
+            job.run_count += 1;
+            job.last_run_ms = Some(now_ms);
+            // Interval jobs keep their phase, unless they fell a whole
+            // interval behind
+            let after_ms = match job.config.schedule {
+                Schedule::Interval { every_secs }
+                    if next_run_ms + every_secs as i64 * 1000 > now_ms =>
+                {
+                    next_run_ms
+                }
+                _ => now_ms,
+            };
+            job.next_run_ms = job.config.next_run_after(after_ms, job.run_count);
+            due.push((job.config.clone(), job.run_count));
+        }
+        due
+    }
+
+    /// Record the outcome of a run and return the job's status.
+    pub fn record_result(
+        &mut self,
+        name: &str,
+        error: Option<String>,
+    ) -> Option<serde_json::Value> {
+        let job = self.jobs.iter_mut().find(|j| j.config.name == name)?;
+        if error.is_some() {
+            job.failed += 1;
+        }
+        job.last_error = error;
+        Some(job.status())
+    }
+
+    pub fn summaries(&self) -> serde_json::Value {
+        let jobs: Vec<serde_json::Value> = self
+            .jobs
+            .iter()
+            .map(|j| {
+                let mut summary = j.status();
+                summary["config"] = serde_json::json!(j.config);
+                summary
+            })
+            .collect();
+        serde_json::json!(jobs)
+    }
+}
+
+// ─── Job persistence ─────────────────────────────────────────────────
+
+pub fn get_jobs(schedules_path: &str) -> Vec<JobConfig> {
+    config::read_named_files::<JobConfig>(schedules_path, "schedule")
+        .into_iter()
+        .filter(|job| match job.validate() {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Invalid schedule {}: {err}", job.name);
+                false
+            }
+        })
+        .collect()
+}
+
+/// Runs of a job so far, stored in `<schedules>/.runs` so `max_count` holds
+/// across restarts. Only valid for the schedule and limits it was counted for.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct JobRuns {
+    name: String,
+    schedule: Schedule,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_count: Option<u64>,
+    run_count: u64,
+}
+
+fn runs_path(schedules_path: &str) -> String {
+    format!("{schedules_path}/.runs")
+}
+
+/// Stored run counts of `jobs`, skipping those counted for another schedule.
+pub fn get_run_counts(schedules_path: &str, jobs: &[JobConfig]) -> HashMap<String, u64> {
+    config::read_named_files::<JobRuns>(&runs_path(schedules_path), "schedule runs")
+        .into_iter()
+        .filter(|runs| {
+            jobs.iter().any(|job| {
+                job.name == runs.name
+                    && job.schedule == runs.schedule
+                    && job.end_time == runs.end_time
+                    && job.max_count == runs.max_count
+            })
+        })
+        .map(|runs| (runs.name, runs.run_count))
+        .collect()
+}
+
+fn write_run_count(schedules_path: &str, job: &JobConfig, run_count: u64) -> bool {
+    let runs = JobRuns {
+        name: job.name.clone(),
+        schedule: job.schedule.clone(),
+        end_time: job.end_time.clone(),
+        max_count: job.max_count,
+        run_count,
+    };
+    config::write_named_file(
+        &runs_path(schedules_path),
+        "schedule runs",
+        &job.name,
+        &runs,
+    )
+}
+
+fn write_job(schedules_path: &str, job: &JobConfig) -> bool {
+    config::write_named_file(schedules_path, "schedule", &job.name, job)
+}
+
+pub fn parse_job(params: serde_json::Value) -> Option<JobConfig> {
+    let job = match serde_json::from_value::<JobConfig>(params) {
+        Ok(job) => job,
+        Err(err) => {
+            println!("Could not deserialize schedule: {err}");
+            return None;
+        }
+    };
+    if let Err(err) = job.validate() {
+        println!("Schedule {} is invalid: {err}", job.name);
+        return None;
+    }
+    Some(job)
+}
+
+pub fn add_to_jobs(schedules_path: &str, job: &JobConfig) -> bool {
+    write_job(schedules_path, job)
+}
+
+pub fn remove_from_jobs(schedules_path: &str, name: &str) -> bool {
+    if !config::remove_named_file(schedules_path, "schedule", name) {
+        return false;
+    }
+    // A new schedule with the same name starts counting from zero
+    std::fs::remove_file(format!("{}/{name}.json", runs_path(schedules_path))).ok();
+    true
+}
+
+pub fn set_job_enabled(schedules_path: &str, name: &str, enabled: bool) -> bool {
+    let Some(mut job) = get_jobs(schedules_path)
+        .into_iter()
+        .find(|j| j.name == name)
+    else {
+        println!("Schedule {name} not found in {schedules_path}");
+        return false;
+    };
+    job.enabled = enabled;
+    write_job(schedules_path, &job)
+}
+
+/// Reload the jobs from disk, e.g. after one was saved, removed or toggled.
+pub fn reload_jobs(scheduler_map: &SchedulerMap, schedules_path: &str) {
+    let jobs = get_jobs(schedules_path);
+    let run_counts = get_run_counts(schedules_path, &jobs);
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    scheduler_map
+        .lock()
+        .unwrap()
+        .set_jobs(jobs, &run_counts, now_ms);
+}
+
+// ─── Running jobs ────────────────────────────────────────────────────
+
+fn run_job(
+    job: &JobConfig,
+    commands_path: &str,
+    services: &services::Services,
+    mqtt_map: &mqtt::BrokerMap,
+) -> commands::CommandResult {
+    let (command, broker, variables) = match &job.action {
+        JobAction::Command {
+            command,
+            broker,
+            variables,
+        } => {
+            let Some(saved) = config::get_command(commands_path, command) else {
+                let error = format!("Command {command} not found");
+                return commands::CommandResult::failed(command, "", error);
+            };
+            let Some(broker) = broker.clone().or(saved.broker.clone()) else {
+                let error = format!("No broker for command {command}");
+                return commands::CommandResult::failed(command, "", error);
+            };
+            (saved, broker, variables.clone())
+        }
+        JobAction::Publish {
+            broker,
+            topic,
+            payload,
+            qos,
+            retain,
+            encoding,
+        } => {
+            let inline = config::CommandMessage {
+                name: job.name.clone(),
+                topic: topic.clone(),
+                payload: payload.clone(),
+                template: true,
+                variables: BTreeMap::new(),
+                qos: *qos,
+                retain: *retain,
+                broker: Some(broker.clone()),
+                encoding: *encoding,
+                revision: 0,
+            };
+            (inline, broker.clone(), BTreeMap::new())
+        }
+    };
+    commands::execute_command(
+        &command,
+        &broker,
+        &variables,
+        &services.command_counters,
+        mqtt_map,
+    )
+}
+
+/// Run the jobs that are due and notify peers of their status.
+pub fn process_tick(
+    config_path: &str,
+    services: &services::Services,
+    peer_map: &websocket::PeerMap,
+    mqtt_map: &mqtt::BrokerMap,
+) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let due = services.scheduler.lock().unwrap().take_due(now_ms);
+    let commands_path = format!("{config_path}/commands");
+    let schedules_path = format!("{config_path}/schedules");
+    for (job, run_count) in due {
+        // Only limited jobs need their runs counted across restarts
+        if job.max_count.is_some() {
+            write_run_count(&schedules_path, &job, run_count);
+        }
+        let result = run_job(&job, &commands_path, services, mqtt_map);
+        if let Some(error) = &result.error {
+            println!("Schedule {} failed: {error}", job.name);
+        }
+        let status = services
+            .scheduler
+            .lock()
+            .unwrap()
+            .record_result(&job.name, result.error);
+        if let Some(status) = status {
+            websocket::broadcast_schedule_status(peer_map, status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(json: serde_json::Value) -> JobConfig {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn heartbeat(every_secs: u64) -> JobConfig {
+        job(serde_json::json!({
+            "name": "heartbeat",
+            "action": { "type": "publish", "broker": "b:1883", "topic": "hb", "payload": "{{counter}}" },
+            "schedule": { "type": "interval", "every_secs": every_secs },
+        }))
+    }
+
+    fn due_names(scheduler: &mut Scheduler, now_ms: i64) -> Vec<String> {
+        scheduler
+            .take_due(now_ms)
+            .into_iter()
+            .map(|(j, _)| j.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_interval_keeps_phase_and_catches_up() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_jobs(vec![heartbeat(10)], &HashMap::new(), 0);
+        assert!(due_names(&mut scheduler, 9_999).is_empty());
+        assert_eq!(due_names(&mut scheduler, 10_150), vec!["heartbeat"]);
+        assert!(due_names(&mut scheduler, 19_999).is_empty());
+        assert_eq!(due_names(&mut scheduler, 20_000), vec!["heartbeat"]);
+        // Far behind: run once, then continue from now
+        assert_eq!(due_names(&mut scheduler, 95_000), vec!["heartbeat"]);
+        assert!(due_names(&mut scheduler, 100_000).is_empty());
+        assert_eq!(due_names(&mut scheduler, 105_000), vec!["heartbeat"]);
+    }
+
+    #[test]
+    fn test_max_count_and_end_time() {
+        let mut limited = heartbeat(1);
+        limited.max_count = Some(2);
+        let mut ending = heartbeat(1);
+        ending.name = "ending".to_string();
+        ending.end_time = Some(mqtt::format_timestamp_ns(2_500 * 1_000_000));
+        let mut scheduler = Scheduler::default();
+        scheduler.set_jobs(vec![limited, ending], &HashMap::new(), 0);
+
+        assert_eq!(
+            due_names(&mut scheduler, 1_000),
+            vec!["heartbeat", "ending"]
+        );
+        assert_eq!(
+            due_names(&mut scheduler, 2_000),
+            vec!["heartbeat", "ending"]
+        );
+        assert!(due_names(&mut scheduler, 3_000).is_empty());
+        let status = scheduler.record_result("heartbeat", None).unwrap();
+        assert_eq!(status["run_count"], 2);
+        assert_eq!(status["finished"], true);
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        let nightly = job(serde_json::json!({
+            "name": "nightly",
+            "action": { "type": "command", "command": "lights_off" },
+            "schedule": { "type": "cron", "expression": "0 3 * * *" },
+        }));
+        let start = mqtt::parse_timestamp_ns("2026-03-01T12:00:00Z").unwrap() / 1_000_000;
+        let mut scheduler = Scheduler::default();
+        scheduler.set_jobs(vec![nightly], &HashMap::new(), start);
+        let first = start + 15 * 3_600_000;
+        assert!(due_names(&mut scheduler, first - 1).is_empty());
+        assert_eq!(due_names(&mut scheduler, first), vec!["nightly"]);
+        let status = scheduler.record_result("nightly", None).unwrap();
+        assert_eq!(status["next_run"], "2026-03-03T03:00:00+00:00");
+    }
+
+    #[test]
+    fn test_set_jobs_keeps_state_of_unchanged_jobs() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_jobs(vec![heartbeat(10)], &HashMap::new(), 0);
+        scheduler.take_due(10_000);
+        scheduler.set_jobs(vec![heartbeat(10)], &HashMap::new(), 12_000);
+        assert_eq!(
+            scheduler.record_result("heartbeat", None).unwrap()["run_count"],
+            1
+        );
+
+        scheduler.set_jobs(vec![heartbeat(5)], &HashMap::new(), 12_000);
+        assert_eq!(
+            scheduler.record_result("heartbeat", None).unwrap()["run_count"],
+            0
+        );
+        assert!(due_names(&mut scheduler, 16_999).is_empty());
+        assert_eq!(due_names(&mut scheduler, 17_000), vec!["heartbeat"]);
+    }
+
+    #[test]
+    fn test_run_count_survives_restart_and_stop() {
+        let path = format!("/tmp/mqtt_schedules_test_{}", uuid::Uuid::new_v4());
+        let mut limited = heartbeat(1);
+        limited.max_count = Some(2);
+        assert!(add_to_jobs(&path, &limited));
+        let scheduler_map = SchedulerMap::default();
+        reload_jobs(&scheduler_map, &path);
+        let run_due = |scheduler_map: &SchedulerMap| {
+            let later_ms = chrono::Utc::now().timestamp_millis() + 1_000;
+            let due = scheduler_map.lock().unwrap().take_due(later_ms);
+            for (job, run_count) in due {
+                write_run_count(&path, &job, run_count);
+            }
+        };
+        let run_count = |scheduler_map: &SchedulerMap| {
+            scheduler_map.lock().unwrap().summaries()[0]["run_count"].clone()
+        };
+        run_due(&scheduler_map);
+
+        // Stopping and starting again keeps the count
+        assert!(set_job_enabled(&path, "heartbeat", false));
+        reload_jobs(&scheduler_map, &path);
+        assert!(set_job_enabled(&path, "heartbeat", true));
+        reload_jobs(&scheduler_map, &path);
+        assert_eq!(run_count(&scheduler_map), 1);
+
+        // So does a restart, which finds the job done after its second run
+        run_due(&scheduler_map);
+        let restarted = SchedulerMap::default();
+        reload_jobs(&restarted, &path);
+        assert_eq!(run_count(&restarted), 2);
+        assert_eq!(restarted.lock().unwrap().summaries()[0]["finished"], true);
+
+        // Changing the limit starts counting over, as does removing the job
+        limited.max_count = Some(3);
+        assert!(add_to_jobs(&path, &limited));
+        let changed = SchedulerMap::default();
+        reload_jobs(&changed, &path);
+        assert_eq!(run_count(&changed), 0);
+        limited.max_count = Some(2);
+        assert!(remove_from_jobs(&path, "heartbeat"));
+        assert!(add_to_jobs(&path, &limited));
+        reload_jobs(&changed, &path);
+        assert_eq!(run_count(&changed), 0);
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_parse_job_validation() {
+        assert!(parse_job(serde_json::json!(heartbeat(1))).is_some());
+        assert!(parse_job(serde_json::json!(heartbeat(0))).is_none());
+        let mut cron = serde_json::json!(heartbeat(1));
+        cron["schedule"] = serde_json::json!({ "type": "cron", "expression": "every day" });
+        assert!(parse_job(cron).is_none());
+        let mut qos = serde_json::json!(heartbeat(1));
+        qos["action"]["qos"] = serde_json::json!(3);
+        assert!(parse_job(qos).is_none());
+        let mut end = serde_json::json!(heartbeat(1));
+        end["end_time"] = serde_json::json!("tomorrow");
+        assert!(parse_job(end).is_none());
+        let mut name = serde_json::json!(heartbeat(1));
+        name["name"] = serde_json::json!("../commands/heartbeat");
+        assert!(parse_job(name).is_none());
+    }
+
+    #[test]
+    fn test_job_persistence() {
+        let path = format!("/tmp/mqtt_schedules_test_{}", uuid::Uuid::new_v4());
+        assert!(add_to_jobs(&path, &heartbeat(1)));
+        assert_eq!(get_jobs(&path), vec![heartbeat(1)]);
+        assert!(set_job_enabled(&path, "heartbeat", false));
+        assert!(!get_jobs(&path)[0].enabled);
+        assert!(!remove_from_jobs(&path, "../heartbeat"));
+        assert!(remove_from_jobs(&path, "heartbeat"));
+        assert!(get_jobs(&path).is_empty());
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_process_tick_reports_status() {
+        let services = services::Services::default();
+        let peer_map = websocket::PeerMap::default();
+        let (tx, mut rx) = futures_channel::mpsc::channel(16);
+        let addr = "127.0.0.1:9001".parse().unwrap();
+        peer_map
+            .lock()
+            .unwrap()
+            .insert(addr, websocket::PeerConnection::new(tx));
+        let mqtt_map = mqtt::BrokerMap::default();
+        mqtt::insert_offline_broker(&mqtt_map, mqtt::MqttBroker::new("b:1883"));
+        services
+            .scheduler
+            .lock()
+            .unwrap()
+            .set_jobs(vec![heartbeat(1)], &HashMap::new(), 0);
+
+        process_tick(
+            &format!("/tmp/mqtt_schedules_test_{}", uuid::Uuid::new_v4()),
+            &services,
+            &peer_map,
+            &mqtt_map,
+        );
+        let message = rx.try_recv().unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(parsed["method"], "schedule_status");
+        assert_eq!(parsed["params"]["name"], "heartbeat");
+        assert_eq!(parsed["params"]["run_count"], 1);
+        assert_eq!(parsed["params"]["failed"], 1);
+        assert_eq!(
+            parsed["params"]["last_error"],
+            "Error publishing to b:1883 topic hb: Failed to send mqtt requests to eventloop"
+        );
+    }
+}
@@ -0,0 +1,398 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::config::{self, BrokerConfig, CommandMessage, PipelineMessage};
+
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer};
+
+/// Version of the config directory layout written by this build. Version 0 is
+/// the unversioned layout, where brokers.json holds a bare list of brokers.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// A problem found in a config file. `line` and `column` point at it when the
+/// file was parsed up to there.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    /// Path relative to the config directory.
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(file: &str, message: impl Into<String>) -> Self {
+        Self {
+            file: file.to_string(),
+            line: None,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    fn json(file: &str, err: &serde_json::Error) -> Self {
+        // serde_json appends the position to the message, it is reported apart
+        let message = err.to_string();
+        let position = format!(" at line {} column {}", err.line(), err.column());
+        Self {
+            file: file.to_string(),
+            line: Some(err.line()),
+            column: Some(err.column()),
+            message: message
+                .strip_suffix(&position)
+                .unwrap_or(&message)
+                .to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(f, "{}:{line}:{column}: {}", self.file, self.message)
+            }
+            _ => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// brokers.json from version 1 on.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+struct BrokersFile {
+    #[serde(deserialize_with = "supported_version")]
+    version: u32,
+    brokers: Vec<BrokerConfig>,
+}
+
+fn supported_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let version = u32::deserialize(deserializer)?;
+    if version == 0 || version > CONFIG_VERSION {
+        return Err(de::Error::custom(format!(
+            "unsupported config version {version}, expected 1 to {CONFIG_VERSION}"
+        )));
+    }
+    Ok(version)
+}
+
+/// Split a broker's `host:port` into its parts. The port must be non-zero.
+pub fn check_broker_host(host: &str) -> Result<(&str, u16), String> {
+    host.rsplit_once(':')
+        .and_then(|(name, port)| {
+            let port = port.parse::<u16>().ok().filter(|port| *port > 0)?;
+            (!name.is_empty()).then_some((name, port))
+        })
+        .ok_or_else(|| format!("invalid host {host:?}, expected host:port"))
+}
+
+/// `host:port` of a broker, checked while parsing.
+pub fn broker_host<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let host = String::deserialize(deserializer)?;
+    check_broker_host(&host).map_err(de::Error::custom)?;
+    Ok(host)
+}
+
+/// Parse brokers.json in the current or the unversioned layout. Returns the
+/// brokers and the version the file has. `file` names it in errors.
+pub fn parse_brokers(file: &str, content: &str) -> Result<(Vec<BrokerConfig>, u32), ConfigError> {
+    let parsed = if content.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<BrokerConfig>>(content)
+            .map(|brokers| (dedupe_legacy_brokers(brokers), 0))
+    } else {
+        serde_json::from_str::<BrokersFile>(content).map(|file| (file.brokers, file.version))
+    };
+    let (brokers, version) = parsed.map_err(|err| ConfigError::json(file, &err))?;
+    for (index, broker) in brokers.iter().enumerate() {
+        if let Some(first) = brokers[..index]
+            .iter()
+            .position(|b| b.key() == broker.key())
+        {
+            return Err(ConfigError::new(
+                file,
+                format!(
+                    "duplicate broker {} in entries {} and {}",
+                    broker.key(),
+                    first + 1,
+                    index + 1
+                ),
+            ));
+        }
+    }
+    Ok((brokers, version))
+}
+
+/// Saving a broker in the unversioned layout appended it, also if the list
+/// had one with the same key already. The last entry of each key wins.
+fn dedupe_legacy_brokers(brokers: Vec<BrokerConfig>) -> Vec<BrokerConfig> {
+    let mut deduped: Vec<BrokerConfig> = Vec::with_capacity(brokers.len());
+    for broker in brokers.into_iter().rev() {
+        if !deduped.iter().any(|b| b.key() == broker.key()) {
+            deduped.push(broker);
+        }
+    }
+    deduped.reverse();
+    deduped
+}
+
+/// brokers.json content for `configs` in the current layout.
+pub fn brokers_json(configs: &[BrokerConfig]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&BrokersFile {
+        version: CONFIG_VERSION,
+        brokers: configs.to_vec(),
+    })
+}
+
+/// Bring the config at `config_path` to `CONFIG_VERSION`. The old file is
+/// copied to `brokers.json.v<version>-<time>.bak` first. Files that don't
+/// parse are left alone; `validate_config_dir` reports them. Returns the
+/// path of the backup if anything was migrated.
+pub fn migrate(config_path: &str) -> Result<Option<String>, String> {
+    let path = format!("{config_path}/brokers.json");
+    let _lock = config::lock_config();
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return Ok(None);
+    };
+    let Ok((brokers, version)) = parse_brokers("brokers.json", &content) else {
+        return Ok(None);
+    };
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+    let backup = format!(
+        "{path}.v{version}-{}.bak",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S")
+    );
+    std::fs::write(&backup, &content).map_err(|err| format!("Can't write {backup}: {err}"))?;
+    let migrated = brokers_json(&brokers).map_err(|err| err.to_string())?;
+    config::write_atomic(std::path::Path::new(&path), &migrated)
+        .map_err(|err| format!("Can't write {path}: {err}"))?;
+    Ok(Some(backup))
+}
+
+/// Check brokers.json and every command and pipeline below `config_path`
+/// against the schema, including fields it doesn't know.
+pub fn validate_config_dir(config_path: &str) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    match std::fs::read_to_string(format!("{config_path}/brokers.json")) {
+        Ok(content) => {
+            if let Err(err) = parse_brokers("brokers.json", &content) {
+                errors.push(err);
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => errors.push(ConfigError::new("brokers.json", err.to_string())),
+    }
+    validate_items::<CommandMessage>(
+        config_path,
+        "commands",
+        CommandMessage::validate,
+        &mut errors,
+    );
+    validate_items::<PipelineMessage>(config_path, "pipelines", |_| Ok(()), &mut errors);
+    errors
+}
+
+fn validate_items<T: DeserializeOwned>(
+    config_path: &str,
+    kind: &str,
+    check: impl Fn(&T) -> Result<(), String>,
+    errors: &mut Vec<ConfigError>,
+) {
+    for (name, path) in config::item_files(&format!("{config_path}/{kind}")) {
+        let file = format!("{kind}/{name}.json");
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) => {
+                errors.push(ConfigError::new(&file, err.to_string()));
+                continue;
+            }
+        };
+        match serde_json::from_str::<T>(&content) {
+            Ok(item) => {
+                if let Err(err) = check(&item) {
+                    errors.push(ConfigError::new(&file, err));
+                }
+            }
+            Err(err) => errors.push(ConfigError::json(&file, &err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config_dir() -> String {
+        let path = format!("/tmp/mqtt_schema_test_{}", uuid::Uuid::new_v4());
+        std::fs::create_dir_all(format!("{path}/commands/lab")).unwrap();
+        std::fs::create_dir_all(format!("{path}/pipelines")).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parse_brokers_in_both_layouts() {
+        let (brokers, version) =
+            parse_brokers("brokers.json", r#"[{"host": "localhost:1883"}]"#).unwrap();
+        assert_eq!((brokers[0].host.as_str(), version), ("localhost:1883", 0));
+
+        let current = brokers_json(&brokers).unwrap();
+        assert!(current.contains(r#""version": 1"#));
+        assert_eq!(
+            parse_brokers("brokers.json", &current).unwrap(),
+            (brokers, 1)
+        );
+    }
+
+    #[test]
+    fn test_parse_brokers_reports_position() {
+        let typo = "{\n  \"version\": 1,\n  \"brokers\": [\n    {\"host\": \"a:1883\", \"use_tsl\": true}\n  ]\n}";
+        let err = parse_brokers("brokers.json", typo).unwrap_err();
+        assert_eq!((err.line, err.column), (Some(4), Some(32)));
+        assert!(
+            err.message.starts_with("unknown field `use_tsl`"),
+            "{}",
+            err.message
+        );
+        assert!(err
+            .to_string()
+            .starts_with("brokers.json:4:32: unknown field"));
+
+        let cases = [
+            (
+                "[{\"host\": \"a\"}]",
+                1,
+                "invalid host \"a\", expected host:port",
+            ),
+            (
+                "{\"version\": 2, \"brokers\": []}",
+                1,
+                "unsupported config version 2, expected 1 to 1",
+            ),
+            ("[{\"host\": \"a:1883\"}", 1, "EOF while parsing a list"),
+        ];
+        for (content, line, message) in cases {
+            let err = parse_brokers("brokers.json", content).unwrap_err();
+            assert_eq!(err.line, Some(line), "{content}");
+            assert_eq!(err.message, message);
+        }
+
+        let duplicate = r#"{"version": 1, "brokers": [{"host": "a:1883"}, {"id": "b", "host": "a:1883"}, {"host": "a:1883"}]}"#;
+        let err = parse_brokers("brokers.json", duplicate).unwrap_err();
+        assert_eq!(err.line, None);
+        assert_eq!(
+            err.to_string(),
+            "brokers.json: duplicate broker a:1883 in entries 1 and 3"
+        );
+    }
+
+    #[test]
+    fn test_migrate_unversioned_config_with_backup() {
+        let path = temp_config_dir();
+        let brokers_path = format!("{path}/brokers.json");
+        let legacy = r#"[{"host": "localhost:1883"}, {"id": "b", "host": "h:1883"}]"#;
+        std::fs::write(&brokers_path, legacy).unwrap();
+
+        let backup = migrate(&path).unwrap().unwrap();
+        assert!(backup.starts_with(&format!("{brokers_path}.v0-")));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), legacy);
+        let migrated = std::fs::read_to_string(&brokers_path).unwrap();
+        let (brokers, version) = parse_brokers("brokers.json", &migrated).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(brokers.len(), 2);
+        assert_eq!(brokers[1].key(), "b");
+
+        assert_eq!(migrate(&path), Ok(None));
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_migrate_keeps_last_of_duplicate_legacy_brokers() {
+        let path = temp_config_dir();
+        let brokers_path = format!("{path}/brokers.json");
+        // Older versions appended a broker again on every save
+        let legacy = r#"[
+            {"host": "a:1883"},
+            {"host": "b:1883"},
+            {"host": "a:1883", "username": "new"},
+            {"id": "c", "host": "a:1883"}
+        ]"#;
+        std::fs::write(&brokers_path, legacy).unwrap();
+
+        let backup = migrate(&path).unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), legacy);
+        let migrated = std::fs::read_to_string(&brokers_path).unwrap();
+        let (brokers, version) = parse_brokers("brokers.json", &migrated).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        let keys: Vec<_> = brokers.iter().map(|b| b.key()).collect();
+        assert_eq!(keys, ["b:1883", "a:1883", "c"]);
+        assert_eq!(brokers[1].username.as_deref(), Some("new"));
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_migrate_leaves_invalid_file_alone() {
+        let path = temp_config_dir();
+        let brokers_path = format!("{path}/brokers.json");
+        std::fs::write(&brokers_path, "[{\"host\": }]").unwrap();
+        assert_eq!(migrate(&path), Ok(None));
+        assert_eq!(
+            std::fs::read_to_string(&brokers_path).unwrap(),
+            "[{\"host\": }]"
+        );
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 3);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_validate_config_dir() {
+        let path = temp_config_dir();
+        assert!(validate_config_dir(&path).is_empty());
+
+        std::fs::write(format!("{path}/brokers.json"), "[{\"host\": \"a:1883\"}]").unwrap();
+        let files = [
+            (
+                "commands/ok.json",
+                r#"{"name": "ok", "topic": "t", "payload": "p"}"#,
+            ),
+            (
+                "commands/lab/qos.json",
+                r#"{"name": "qos", "topic": "t", "payload": "p", "qos": 3}"#,
+            ),
+            (
+                "pipelines/typo.json",
+                "{\"name\": \"typo\",\n \"pipline\": []}",
+            ),
+        ];
+        for (file, content) in files {
+            std::fs::write(format!("{path}/{file}"), content).unwrap();
+        }
+        let errors = validate_config_dir(&path);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].file, "commands/lab/qos.json");
+        assert_eq!(errors[0].line, None);
+        assert_eq!(errors[0].message, "Invalid QoS 3 for command qos");
+        assert_eq!(errors[1].file, "pipelines/typo.json");
+        assert_eq!(errors[1].line, Some(2));
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::alerts;
+use super::bridges;
+use super::commands;
+use super::loadgen;
+use super::retained;
+use super::retention;
+use super::scheduler;
+use super::waiters;
+use super::webhooks;
+
+use std::sync::Mutex;
+
+/// Subsystems that observe broker traffic or are driven over JSON-RPC, bundled
+/// so broker loops and the RPC dispatcher don't need one parameter each.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Default))]
+pub struct Services {
+    pub alerts: alerts::AlertMap,
+    pub webhooks: webhooks::WebhookMap,
+    pub bridges: bridges::BridgeMap,
+    pub retention: retention::RetentionMap,
+    pub retained_clears: retained::ClearConfirmationMap,
+    pub command_counters: commands::CounterMap,
+    pub scheduler: scheduler::SchedulerMap,
+    pub waiters: waiters::WaiterMap,
+    pub load_tests: loadgen::LoadMap,
+}
+
+impl Services {
+    pub fn new(config_path: &str) -> Self {
+        let rules = alerts::get_alert_rules(&format!("{config_path}/alerts"));
+        let mut dispatcher =
+            webhooks::WebhookDispatcher::new(&format!("{config_path}/dead_letters"));
+        dispatcher.set_webhooks(webhooks::get_webhooks(&format!("{config_path}/webhooks")));
+        let mut bridge_manager = bridges::BridgeManager::default();
+        bridge_manager.set_bridges(bridges::get_bridges(&format!("{config_path}/bridges")));
+        let retention_rules = retention::get_retention_rules(&format!("{config_path}/retention"));
+        let schedules_path = format!("{config_path}/schedules");
+        let jobs = scheduler::get_jobs(&schedules_path);
+        let run_counts = scheduler::get_run_counts(&schedules_path, &jobs);
+        let mut job_scheduler = scheduler::Scheduler::default();
+        job_scheduler.set_jobs(jobs, &run_counts, chrono::Utc::now().timestamp_millis());
+        Self {
+            alerts: alerts::AlertMap::new(Mutex::new(alerts::AlertEngine::new(rules))),
+            webhooks: webhooks::WebhookMap::new(Mutex::new(dispatcher)),
+            bridges: bridges::BridgeMap::new(Mutex::new(bridge_manager)),
+            retention: retention::RetentionMap::new(Mutex::new(retention::RetentionPolicy::new(
+                retention_rules,
+            ))),
+            retained_clears: retained::ClearConfirmationMap::default(),
+            command_counters: commands::CounterMap::default(),
+            scheduler: scheduler::SchedulerMap::new(Mutex::new(job_scheduler)),
+            waiters: waiters::WaiterMap::default(),
+            load_tests: loadgen::LoadMap::default(),
+        }
+    }
+}
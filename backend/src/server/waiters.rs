@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::mqtt;
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+/// A message seen by a broker loop, handed to a waiter.
+#[derive(Clone, Debug)]
+pub struct ReceivedMessage {
+    pub broker: String,
+    pub topic: String,
+    pub payload: bytes::Bytes,
+    /// When the broker loop saw the message.
+    pub received_at: std::time::Instant,
+}
+
+struct Waiter {
+    id: u64,
+    broker: Option<String>,
+    filters: Vec<String>,
+    tx: mpsc::UnboundedSender<ReceivedMessage>,
+}
+
+/// Listeners for incoming messages on topic filters, registered by tasks that
+/// wait for a reply (scenarios, requests) instead of polling stored history.
+#[derive(Default)]
+pub struct MessageWaiters {
+    next_id: u64,
+    waiters: Vec<Waiter>,
+}
+
+pub type WaiterMap = Arc<Mutex<MessageWaiters>>;
+
+impl MessageWaiters {
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+/// Receives matching messages until dropped, which unregisters the waiter.
+pub struct Subscription {
+    id: u64,
+    waiters: WaiterMap,
+    rx: mpsc::UnboundedReceiver<ReceivedMessage>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Option<ReceivedMessage> {
+        self.rx.recv().await
+    }
+
+    /// A message that already arrived, without waiting.
+    pub fn try_recv(&mut self) -> Option<ReceivedMessage> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.waiters.lock().unwrap().waiters.retain(|w| w.id != id);
+    }
+}
+
+/// Start receiving messages on any of `filters`, from `broker` or, if `None`,
+/// from every broker.
+pub fn register(waiters: &WaiterMap, broker: Option<&str>, filters: Vec<String>) -> Subscription {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut lock = waiters.lock().unwrap();
+    lock.next_id += 1;
+    let id = lock.next_id;
+    lock.waiters.push(Waiter {
+        id,
+        broker: broker.map(str::to_string),
+        filters,
+        tx,
+    });
+    Subscription {
+        id,
+        waiters: waiters.clone(),
+        rx,
+    }
+}
+
+/// Hand an incoming message to every waiter whose broker and filters match.
+pub fn dispatch(waiters: &WaiterMap, broker: &str, topic: &str, payload: &bytes::Bytes) {
+    let lock = waiters.lock().unwrap();
+    if lock.is_empty() {
+        return;
+    }
+    let received_at = std::time::Instant::now();
+    for waiter in &lock.waiters {
+        if waiter.broker.as_deref().is_some_and(|b| b != broker) {
+            continue;
+        }
+        if !waiter.filters.iter().any(|f| mqtt::topic_matches(f, topic)) {
+            continue;
+        }
+        let _ = waiter.tx.send(ReceivedMessage {
+            broker: broker.to_string(),
+            topic: topic.to_string(),
+            payload: payload.clone(),
+            received_at,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispatch_matches_broker_and_filter_until_dropped() {
+        let waiters = WaiterMap::default();
+        let mut any = register(&waiters, None, vec!["door/+/state".to_string()]);
+        let mut only_b = register(&waiters, Some("b"), vec!["#".to_string()]);
+
+        let payload = bytes::Bytes::from_static(b"open");
+        dispatch(&waiters, "a", "door/1/state", &payload);
+        dispatch(&waiters, "b", "door/1/cmd", &payload);
+
+        let received = any.try_recv().unwrap();
+        assert_eq!(
+            (received.broker.as_str(), received.topic.as_str()),
+            ("a", "door/1/state")
+        );
+        assert!(any.try_recv().is_none());
+        assert_eq!(only_b.try_recv().unwrap().topic, "door/1/cmd");
+        assert!(only_b.try_recv().is_none());
+
+        drop(any);
+        drop(only_b);
+        assert!(waiters.lock().unwrap().is_empty());
+    }
+}
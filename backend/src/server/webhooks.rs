@@ -0,0 +1,839 @@
+/*
+ * Copyright (c) 2026 Kai Lawrence
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use super::alerts;
+use super::config;
+use super::mqtt;
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Events queued per webhook before new ones are dropped. Keeps a stalled
+/// endpoint from growing memory without bound.
+const WEBHOOK_QUEUE_CAPACITY: usize = 10_000;
+const WEBHOOK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_MAX_BACKOFF_MS: u64 = 60_000;
+
+fn default_filter() -> String {
+    "#".to_string()
+}
+
+fn default_batch_size() -> usize {
+    1
+}
+
+fn default_batch_interval_ms() -> u64 {
+    1000
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct WebhookConfig {
+    pub name: String,
+    pub url: String,
+    /// MQTT topic filter selecting the messages to forward.
+    #[serde(default = "default_filter")]
+    pub filter: String,
+    /// Restrict forwarding to one broker. Applies to all brokers when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broker: Option<String>,
+    /// Forward alert state changes in addition to messages.
+    #[serde(default)]
+    pub alerts: bool,
+    /// Body template for messages. Supports `{{broker}}`, `{{topic}}`,
+    /// `{{timestamp}}`, `{{retain}}`, `{{payload}}` and `{{payload_json}}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Maximum number of events sent in one request.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// How long to wait for a batch to fill up before sending it anyway.
+    #[serde(default = "default_batch_interval_ms")]
+    pub batch_interval_ms: u64,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry. Doubles with every further attempt.
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+}
+
+impl WebhookConfig {
+    fn matches_message(&self, broker: &str, topic: &str) -> bool {
+        self.broker.as_deref().is_none_or(|b| b == broker)
+            && mqtt::topic_matches(&self.filter, topic)
+    }
+
+    fn matches_alert(&self, broker: &str) -> bool {
+        self.alerts && self.broker.as_deref().is_none_or(|b| b == broker)
+    }
+}
+
+#[derive(Clone)]
+pub enum WebhookEvent {
+    Message {
+        broker: String,
+        topic: String,
+        timestamp: String,
+        payload: bytes::Bytes,
+        retain: bool,
+    },
+    Alert {
+        event: &'static str,
+        alert: alerts::Alert,
+    },
+}
+
+impl WebhookEvent {
+    fn render(&self, config: &WebhookConfig) -> String {
+        match self {
+            WebhookEvent::Message {
+                broker,
+                topic,
+                timestamp,
+                payload,
+                retain,
+            } => {
+                let payload = String::from_utf8_lossy(payload);
+                match &config.template {
+                    Some(template) => {
+                        let payload_json = serde_json::Value::String(payload.to_string());
+                        render_template(
+                            template,
+                            &[
+                                ("broker", broker),
+                                ("topic", topic),
+                                ("timestamp", timestamp),
+                                ("retain", &retain.to_string()),
+                                ("payload_json", &payload_json.to_string()),
+                                ("payload", &payload),
+                            ],
+                        )
+                    }
+                    None => serde_json::json!({
+                        "event": "mqtt_message",
+                        "broker": broker,
+                        "topic": topic,
+                        "timestamp": timestamp,
+                        "retain": retain,
+                        "payload": payload,
+                    })
+                    .to_string(),
+                }
+            }
+            WebhookEvent::Alert { event, alert } => serde_json::json!({
+                "event": event,
+                "alert": alert,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Replace the `{{name}}` placeholders in `template` in one pass, so values
+/// containing placeholders are not replaced again. Unknown ones are kept.
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let (_, value) = values.iter().find(|(name, _)| *name == &after[..end])?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                rendered.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Join rendered events into one request body. Untemplated batches become a
+/// JSON array, templated ones are newline-delimited.
+fn batch_body(config: &WebhookConfig, items: &[String]) -> String {
+    if config.template.is_some() {
+        items.join("\n")
+    } else if config.batch_size <= 1 && items.len() == 1 {
+        items[0].clone()
+    } else {
+        format!("[{}]", items.join(","))
+    }
+}
+
+#[derive(Default)]
+pub struct WebhookStats {
+    delivered: AtomicUsize,
+    failed: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+struct WebhookHandle {
+    config: WebhookConfig,
+    tx: tokio::sync::mpsc::Sender<WebhookEvent>,
+    stats: Arc<WebhookStats>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl WebhookHandle {
+    fn enqueue(&self, event: WebhookEvent) {
+        if self.tx.try_send(event).is_err()
+            && self.stats.dropped.fetch_add(1, Ordering::Relaxed) == 0
+        {
+            println!(
+                "Webhook {} queue is full. Dropping events until it drains.",
+                self.config.name
+            );
+        }
+    }
+}
+
+/// Owns one delivery task per configured webhook. Broker loops only enqueue,
+/// so a slow endpoint never blocks MQTT ingestion.
+pub struct WebhookDispatcher {
+    webhooks: Vec<WebhookHandle>,
+    dead_letter_path: String,
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+pub type WebhookMap = Arc<Mutex<WebhookDispatcher>>;
+
+impl Default for WebhookDispatcher {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl WebhookDispatcher {
+    pub fn new(dead_letter_path: &str) -> Self {
+        Self {
+            webhooks: Vec::new(),
+            dead_letter_path: dead_letter_path.to_string(),
+            runtime: tokio::runtime::Handle::try_current().ok(),
+        }
+    }
+
+    /// Replace all webhooks. Unchanged webhooks keep their delivery task,
+    /// queue and counters; the tasks of removed or changed webhooks are
+    /// stopped and their pending events discarded.
+    pub fn set_webhooks(&mut self, configs: Vec<WebhookConfig>) {
+        let mut previous: Vec<WebhookHandle> = self.webhooks.drain(..).collect();
+        for config in configs {
+            if let Some(index) = previous.iter().position(|h| h.config == config) {
+                self.webhooks.push(previous.swap_remove(index));
+                continue;
+            }
+            let Some(runtime) = self.runtime.clone() else {
+                eprintln!(
+                    "No async runtime available. Webhook {} is disabled.",
+                    config.name
+                );
+                continue;
+            };
+            let (tx, rx) = tokio::sync::mpsc::channel(WEBHOOK_QUEUE_CAPACITY);
+            let stats = Arc::new(WebhookStats::default());
+            let task = runtime.spawn(run_webhook(
+                config.clone(),
+                rx,
+                stats.clone(),
+                self.dead_letter_path.clone(),
+            ));
+            self.webhooks.push(WebhookHandle {
+                config,
+                tx,
+                stats,
+                task,
+            });
+        }
+        for handle in previous {
+            handle.task.abort();
+        }
+    }
+
+    /// Configured webhooks with delivery counters. Header values are masked
+    /// because they commonly carry credentials.
+    pub fn summaries(&self) -> Vec<serde_json::Value> {
+        self.webhooks
+            .iter()
+            .map(|handle| {
+                let mut config = handle.config.clone();
+                config
+                    .headers
+                    .values_mut()
+                    .for_each(|v| *v = "********".to_string());
+                serde_json::json!({
+                    "config": config,
+                    "delivered": handle.stats.delivered.load(Ordering::Relaxed),
+                    "failed": handle.stats.failed.load(Ordering::Relaxed),
+                    "dropped": handle.stats.dropped.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+}
+
+type HttpsClient =
+    hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>, hyper::Body>;
+
+fn build_http_client() -> HttpsClient {
+    // Load the platform roots ourselves: minimal images ship without CA
+    // certificates, which should only break https webhooks, not plain http.
+    let mut roots = rustls::RootCertStore::empty();
+    if let Ok(certs) = rustls_native_certs::load_native_certs() {
+        for cert in certs {
+            let _ = roots.add(&rustls::Certificate(cert.0));
+        }
+    }
+    let tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    hyper::Client::builder().build(connector)
+}
+
+async fn post(client: &HttpsClient, config: &WebhookConfig, body: &str) -> Result<(), String> {
+    let mut request = hyper::Request::post(&config.url);
+    if !config
+        .headers
+        .keys()
+        .any(|k| k.eq_ignore_ascii_case("content-type"))
+    {
+        let content_type = if config.template.is_some() {
+            "text/plain"
+        } else {
+            "application/json"
+        };
+        request = request.header("content-type", content_type);
+    }
+    for (key, value) in &config.headers {
+        request = request.header(key.as_str(), value.as_str());
+    }
+    let request = request
+        .body(hyper::Body::from(body.to_string()))
+        .map_err(|err| err.to_string())?;
+
+    match tokio::time::timeout(WEBHOOK_REQUEST_TIMEOUT, client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => Ok(()),
+        Ok(Ok(response)) => Err(format!("HTTP {}", response.status())),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("request timed out".to_string()),
+    }
+}
+
+fn write_dead_letter(dead_letter_path: &str, config: &WebhookConfig, body: &str, error: &str) {
+    if dead_letter_path.is_empty() {
+        return;
+    }
+    if let Err(err) = config::validate_file_name(&config.name) {
+        eprintln!("Not writing dead letter of webhook {}: {err}", config.name);
+        return;
+    }
+    if let Err(err) = std::fs::create_dir_all(dead_letter_path) {
+        eprintln!("Failed to create dead letter directory {dead_letter_path}: {err}");
+        return;
+    }
+    let file_path = format!("{dead_letter_path}/{}.jsonl", config.name);
+    let line = serde_json::json!({
+        "webhook": config.name,
+        "url": config.url,
+        "failed_at": chrono::Utc::now().to_rfc3339(),
+        "error": error,
+        "body": body,
+    });
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&file_path)
+        .and_then(|mut file| writeln!(file, "{line}"));
+    if written.is_err() {
+        eprintln!("Failed to write dead letter to {file_path}");
+    }
+}
+
+async fn deliver(
+    client: &HttpsClient,
+    config: &WebhookConfig,
+    body: &str,
+    stats: &WebhookStats,
+    dead_letter_path: &str,
+) {
+    let mut attempt = 0;
+    loop {
+        match post(client, config, body).await {
+            Ok(()) => {
+                stats.delivered.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(err) if attempt >= config.max_retries => {
+                println!(
+                    "Webhook {} failed after {} attempts: {err}. Writing dead letter.",
+                    config.name,
+                    attempt + 1
+                );
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                let (path, config, body) = (
+                    dead_letter_path.to_string(),
+                    config.clone(),
+                    body.to_string(),
+                );
+                let written = tokio::task::spawn_blocking(move || {
+                    write_dead_letter(&path, &config, &body, &err)
+                });
+                written.await.ok();
+                return;
+            }
+            Err(err) => {
+                let backoff = config
+                    .retry_backoff_ms
+                    .saturating_mul(1 << attempt.min(16))
+                    .min(WEBHOOK_MAX_BACKOFF_MS);
+                println!(
+                    "Webhook {} delivery failed: {err}. Retrying in {backoff} ms.",
+                    config.name
+                );
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn run_webhook(
+    config: WebhookConfig,
+    mut rx: tokio::sync::mpsc::Receiver<WebhookEvent>,
+    stats: Arc<WebhookStats>,
+    dead_letter_path: String,
+) {
+    let client = build_http_client();
+    let batch_size = config.batch_size.max(1);
+    let batch_interval = Duration::from_millis(config.batch_interval_ms);
+    let mut batch = Vec::with_capacity(batch_size);
+
+    while let Some(first) = rx.recv().await {
+        batch.push(first.render(&config));
+        let deadline = tokio::time::Instant::now() + batch_interval;
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(event)) => batch.push(event.render(&config)),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let body = batch_body(&config, &batch);
+        batch.clear();
+        deliver(&client, &config, &body, &stats, &dead_letter_path).await;
+    }
+}
+
+// ─── Webhook persistence ─────────────────────────────────────────────
+
+pub fn get_webhooks(webhooks_path: &str) -> Vec<WebhookConfig> {
+    config::read_named_files(webhooks_path, "webhook")
+}
+
+pub fn parse_webhook(params: serde_json::Value) -> Option<WebhookConfig> {
+    let Ok(webhook) = serde_json::from_value::<WebhookConfig>(params) else {
+        println!("Could not deserialize webhook.");
+        return None;
+    };
+    if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+        println!(
+            "Webhook {} has an unsupported url {}",
+            webhook.name, webhook.url
+        );
+        return None;
+    }
+    if let Err(err) = config::validate_file_name(&webhook.name) {
+        println!("Webhook {} is invalid: {err}", webhook.name);
+        return None;
+    }
+    Some(webhook)
+}
+
+pub fn add_to_webhooks(webhooks_path: &str, webhook: &WebhookConfig) -> bool {
+    config::write_named_file(webhooks_path, "webhook", &webhook.name, webhook)
+}
+
+pub fn remove_from_webhooks(webhooks_path: &str, name: &str) -> bool {
+    config::remove_named_file(webhooks_path, "webhook", name)
+}
+
+// ─── Entry points for broker loops and alerts ────────────────────────
+
+pub fn dispatch_message(
+    webhook_map: &WebhookMap,
+    broker: &str,
+    topic: &str,
+    timestamp: &str,
+    payload: &bytes::Bytes,
+    retain: bool,
+) {
+    let dispatcher = webhook_map.lock().unwrap();
+    let mut event = None;
+    for handle in &dispatcher.webhooks {
+        if handle.config.matches_message(broker, topic) {
+            let event = event.get_or_insert_with(|| WebhookEvent::Message {
+                broker: broker.to_string(),
+                topic: topic.to_string(),
+                timestamp: timestamp.to_string(),
+                payload: payload.clone(),
+                retain,
+            });
+            handle.enqueue(event.clone());
+        }
+    }
+}
+
+pub fn dispatch_alert(webhook_map: &WebhookMap, event: &'static str, alert: &alerts::Alert) {
+    let dispatcher = webhook_map.lock().unwrap();
+    for handle in &dispatcher.webhooks {
+        if handle.config.matches_alert(&alert.broker) {
+            handle.enqueue(WebhookEvent::Alert {
+                event,
+                alert: alert.clone(),
+            });
+        }
+    }
+}
+
+/// Reload the webhooks from disk, e.g. after one was saved or removed.
+pub fn reload_webhooks(webhook_map: &WebhookMap, webhooks_path: &str) {
+    let webhooks = get_webhooks(webhooks_path);
+    webhook_map.lock().unwrap().set_webhooks(webhooks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    fn webhook(name: &str, url: &str) -> WebhookConfig {
+        serde_json::from_value(serde_json::json!({ "name": name, "url": url })).unwrap()
+    }
+
+    fn message(topic: &str, payload: &'static str) -> WebhookEvent {
+        WebhookEvent::Message {
+            broker: "b:1883".to_string(),
+            topic: topic.to_string(),
+            timestamp: "2026-01-01T00:00:00Z".to_string(),
+            payload: bytes::Bytes::from_static(payload.as_bytes()),
+            retain: false,
+        }
+    }
+
+    /// Start a local HTTP server that records request bodies. The first
+    /// `failures` requests are answered with 500.
+    async fn start_receiver(failures: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let store = received.clone();
+        let route = warp::post()
+            .and(warp::body::bytes())
+            .map(move |body: bytes::Bytes| {
+                if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                    return warp::http::StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                store
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&body).to_string());
+                warp::http::StatusCode::OK
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{addr}/hook"), received)
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[test]
+    fn test_webhook_config_defaults() {
+        let config = webhook("w", "http://localhost/");
+        assert_eq!(config.filter, "#");
+        assert_eq!(config.batch_size, 1);
+        assert_eq!(config.max_retries, 5);
+        assert!(!config.alerts);
+        assert!(config.matches_message("any:1883", "a/b"));
+        assert!(!config.matches_alert("any:1883"));
+    }
+
+    #[test]
+    fn test_render_default_and_template() {
+        let mut config = webhook("w", "http://localhost/");
+        let rendered: serde_json::Value =
+            serde_json::from_str(&message("t/1", "hi").render(&config)).unwrap();
+        assert_eq!(rendered["topic"], "t/1");
+        assert_eq!(rendered["payload"], "hi");
+
+        config.template = Some(r#"{"t":"{{topic}}","p":{{payload_json}}}"#.to_string());
+        let rendered = message("t/1", "say \"hi\"").render(&config);
+        let parsed: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(parsed["p"], "say \"hi\"");
+
+        // Placeholders in values are not replaced again
+        config.template = Some("{{topic}}|{{payload}}|{{other}}".to_string());
+        let rendered = message("t/{{payload}}", "p{{topic}}").render(&config);
+        assert_eq!(rendered, "t/{{payload}}|p{{topic}}|{{other}}");
+    }
+
+    #[test]
+    fn test_batch_body() {
+        let mut config = webhook("w", "http://localhost/");
+        let items = vec!["{\"a\":1}".to_string()];
+        assert_eq!(batch_body(&config, &items), "{\"a\":1}");
+        config.batch_size = 10;
+        assert_eq!(batch_body(&config, &items), "[{\"a\":1}]");
+        config.template = Some("{{payload}}".to_string());
+        let items = vec!["x".to_string(), "y".to_string()];
+        assert_eq!(batch_body(&config, &items), "x\ny");
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_delivers_matching_messages_in_batches() {
+        let (url, received) = start_receiver(0).await;
+        let mut config = webhook("batched", &url);
+        config.filter = "sensors/#".to_string();
+        config.batch_size = 3;
+        config.batch_interval_ms = 5000;
+
+        let webhook_map = WebhookMap::new(Mutex::new(WebhookDispatcher::new("")));
+        webhook_map.lock().unwrap().set_webhooks(vec![config]);
+
+        for topic in ["sensors/1", "other", "sensors/2", "sensors/3"] {
+            dispatch_message(
+                &webhook_map,
+                "b:1883",
+                topic,
+                "2026-01-01T00:00:00Z",
+                &bytes::Bytes::from_static(b"1"),
+                false,
+            );
+        }
+
+        assert!(wait_for(|| received.lock().unwrap().len() == 1).await);
+        let body: serde_json::Value = serde_json::from_str(&received.lock().unwrap()[0]).unwrap();
+        let topics: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["topic"].as_str().unwrap())
+            .collect();
+        assert_eq!(topics, ["sensors/1", "sensors/2", "sensors/3"]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_then_success() {
+        let (url, received) = start_receiver(2).await;
+        let mut config = webhook("retry", &url);
+        config.retry_backoff_ms = 1;
+
+        let stats = Arc::new(WebhookStats::default());
+        let client = build_http_client();
+        deliver(&client, &config, "{}", &stats, "").await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(stats.delivered.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.failed.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_retries_write_dead_letter() {
+        let (url, received) = start_receiver(usize::MAX).await;
+        let mut config = webhook("dead", &url);
+        config.retry_backoff_ms = 1;
+        config.max_retries = 2;
+        let dead_letter_path = format!("/tmp/mqtt_test_{}", uuid::Uuid::new_v4());
+
+        let stats = Arc::new(WebhookStats::default());
+        let client = build_http_client();
+        deliver(&client, &config, "{\"x\":1}", &stats, &dead_letter_path).await;
+
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(stats.failed.load(Ordering::Relaxed), 1);
+        let dead_letters =
+            std::fs::read_to_string(format!("{dead_letter_path}/dead.jsonl")).unwrap();
+        let line: serde_json::Value = serde_json::from_str(dead_letters.trim()).unwrap();
+        assert_eq!(line["body"], "{\"x\":1}");
+        assert_eq!(line["error"], "HTTP 500 Internal Server Error");
+
+        // A name that would leave the dead letter directory is not used
+        let dead_letter_path = format!("{dead_letter_path}/nested");
+        config.name = "../escaped".to_string();
+        deliver(&client, &config, "{}", &stats, &dead_letter_path).await;
+        assert_eq!(stats.failed.load(Ordering::Relaxed), 2);
+        let escaped = format!("{dead_letter_path}/../escaped.jsonl");
+        assert!(!std::path::Path::new(&escaped).exists());
+
+        std::fs::remove_dir_all(std::path::Path::new(&dead_letter_path).parent().unwrap()).ok();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_alert_only_to_opted_in_webhooks() {
+        let (url, received) = start_receiver(0).await;
+        let mut with_alerts = webhook("with_alerts", &url);
+        with_alerts.alerts = true;
+        with_alerts.filter = "nothing/matches".to_string();
+        let mut without_alerts = webhook("without_alerts", &url);
+        without_alerts.filter = "nothing/matches".to_string();
+
+        let webhook_map = WebhookMap::new(Mutex::new(WebhookDispatcher::new("")));
+        webhook_map
+            .lock()
+            .unwrap()
+            .set_webhooks(vec![with_alerts, without_alerts]);
+
+        let mut engine = alerts::AlertEngine::new(vec![serde_json::from_value(
+            serde_json::json!({"name": "any", "condition": {"type": "topic_match", "filter": "#"}}),
+        )
+        .unwrap()]);
+        let alert = match engine.on_message("b:1883", "t", b"", 0).pop() {
+            Some(alerts::AlertEvent::Fired(alert)) => alert,
+            _ => panic!("expected a fired alert"),
+        };
+        dispatch_alert(&webhook_map, "alert_fired", &alert);
+
+        assert!(wait_for(|| received.lock().unwrap().len() == 1).await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(received.lock().unwrap().len(), 1);
+        let body: serde_json::Value = serde_json::from_str(&received.lock().unwrap()[0]).unwrap();
+        assert_eq!(body["event"], "alert_fired");
+        assert_eq!(body["alert"]["rule"], "any");
+    }
+
+    #[test]
+    fn test_webhook_persistence() {
+        let webhooks_path = format!("/tmp/mqtt_test_{}/webhooks", uuid::Uuid::new_v4());
+        let webhook =
+            parse_webhook(serde_json::json!({"name": "w", "url": "http://localhost:9/hook"}));
+        assert!(add_to_webhooks(&webhooks_path, &webhook.unwrap()));
+        assert!(
+            parse_webhook(serde_json::json!({"name": "bad", "url": "ftp://localhost/"})).is_none()
+        );
+        assert_eq!(get_webhooks(&webhooks_path).len(), 1);
+        assert!(parse_webhook(
+            serde_json::json!({"name": "../../commands/x", "url": "http://localhost:9/hook"})
+        )
+        .is_none());
+        assert!(!remove_from_webhooks(&webhooks_path, "../webhooks/w"));
+
+        assert!(remove_from_webhooks(&webhooks_path, "w"));
+        assert!(get_webhooks(&webhooks_path).is_empty());
+
+        std::fs::remove_dir_all(std::path::Path::new(&webhooks_path).parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn test_summaries_mask_headers() {
+        let mut dispatcher = WebhookDispatcher::default();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        dispatcher.runtime = Some(runtime.handle().clone());
+        let mut config = webhook("w", "http://localhost:9/hook");
+        config
+            .headers
+            .insert("Authorization".to_string(), "Bearer secret".to_string());
+        dispatcher.set_webhooks(vec![config]);
+
+        let summaries = dispatcher.summaries();
+        assert_eq!(
+            summaries[0]["config"]["headers"]["Authorization"],
+            "********"
+        );
+        assert_eq!(summaries[0]["delivered"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_set_webhooks_keeps_unchanged_webhooks_running() {
+        let (url, received) = start_receiver(0).await;
+        let mut kept = webhook("kept", &url);
+        // Holds the message in its batch until long after the reload
+        kept.batch_size = 2;
+        kept.batch_interval_ms = 300;
+        let changed = webhook("changed", "http://127.0.0.1:9/hook");
+        let webhook_map = WebhookMap::new(Mutex::new(WebhookDispatcher::new("")));
+        webhook_map
+            .lock()
+            .unwrap()
+            .set_webhooks(vec![kept.clone(), changed.clone()]);
+        dispatch_message(
+            &webhook_map,
+            "b:1883",
+            "t",
+            "2026-01-01T00:00:00Z",
+            &bytes::Bytes::from_static(b"queued"),
+            false,
+        );
+        let mut changed = changed;
+        changed.filter = "other/#".to_string();
+        webhook_map
+            .lock()
+            .unwrap()
+            .set_webhooks(vec![kept, changed.clone()]);
+
+        assert!(wait_for(|| received.lock().unwrap().len() == 1).await);
+        assert!(received.lock().unwrap()[0].contains("queued"));
+        let dispatcher = webhook_map.lock().unwrap();
+        assert_eq!(dispatcher.webhooks.len(), 2);
+        assert_eq!(
+            dispatcher.webhooks[0]
+                .stats
+                .delivered
+                .load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(dispatcher.webhooks[1].config, changed);
+    }
+}
@@ -20,9 +20,22 @@
  * THE SOFTWARE.
  */
 
-use super::config::{CommandMessage, PipelineMessage};
+use super::alerts;
+use super::bridges;
+use super::bundle;
+use super::commands;
+use super::config;
+use super::delivery;
+use super::history;
 use super::jsonrpc;
 use super::mqtt;
+use super::requests;
+use super::retained;
+use super::retention;
+use super::scenarios;
+use super::scheduler;
+use super::schema;
+use super::webhooks;
 
 use std::{
     collections::{HashMap, HashSet},
@@ -123,9 +136,9 @@ where
 /// Auto-authenticate a peer for all brokers that do not require a password.
 /// Called when a new peer connects so it immediately receives data from open brokers.
 pub fn auto_authenticate_peer(peer: &mut PeerConnection, mqtt_map: &mqtt::BrokerMap) {
-    let ml = mqtt_map.lock().unwrap();
+    let ml = mqtt_map.read().unwrap();
     for (name, broker) in ml.iter() {
-        if !broker.requires_auth {
+        if !broker.requires_auth() {
             peer.authenticated_brokers.insert(name.clone());
         }
     }
@@ -140,6 +153,17 @@ pub fn auto_authenticate_all_peers_for_broker(peer_map: &PeerMap, broker_name: &
     }
 }
 
+/// Drop `broker_name` from every peer's authenticated brokers except `keep`,
+/// e.g. after its password changed.
+pub fn revoke_broker_access(peer_map: &PeerMap, broker_name: &str, keep: Option<SocketAddr>) {
+    let mut peers = peer_map.lock().unwrap();
+    for (addr, peer) in peers.iter_mut() {
+        if Some(*addr) != keep {
+            peer.authenticated_brokers.remove(broker_name);
+        }
+    }
+}
+
 /// Drain the notification buffer and send batched messages to all connected
 /// peers. Each peer only receives items for brokers in its `authenticated_brokers` set.
 pub fn flush_notification_buffer(buf: &NotificationBuf, peer_map: &PeerMap) {
@@ -235,7 +259,7 @@ pub struct PeerConnection {
     /// Topics this peer is watching live (split editor groups → multiple at once),
     /// scoped to `selected_broker`.
     pub subscribed_topics: std::collections::HashSet<String>,
-    /// Brokers this peer has authenticated for (by hostname key).
+    /// Brokers this peer has authenticated for (by broker id).
     pub authenticated_brokers: std::collections::HashSet<String>,
 }
 
@@ -437,26 +461,21 @@ pub fn handle_subscribe_topic(
     topic: &str,
     since_timestamp: Option<&str>,
 ) {
-    let since_dt = since_timestamp.and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok());
+    let since_ns = since_timestamp.and_then(mqtt::parse_timestamp_ns);
 
     // Phase 1: Collect this topic's messages (newest first), delta-filtered.
-    let messages: Vec<(String, bytes::Bytes, usize, bool)> = {
-        let mqtt_lock = mqtt_map.lock().unwrap();
-        if let Some(topic_msgs) = mqtt_lock.get(broker).and_then(|b| b.topics.get(topic)) {
+    let messages: Vec<(i64, bytes::Bytes, usize, bool)> = {
+        let entry = mqtt::get_broker(mqtt_map, broker);
+        let state = entry.as_ref().map(|b| b.state());
+        if let Some(topic_msgs) = state.as_ref().and_then(|b| b.topics.get(topic)) {
             topic_msgs
                 .iter()
                 .rev()
-                .filter(|msg| match since_dt {
-                    // Keep only messages strictly newer than the cached one. If a
-                    // timestamp can't be parsed, keep it to avoid data loss.
-                    Some(since) => chrono::DateTime::parse_from_rfc3339(&msg.timestamp)
-                        .map(|ts| ts > since)
-                        .unwrap_or(true),
-                    None => true,
-                })
+                // Keep only messages strictly newer than the cached one.
+                .filter(|msg| since_ns.is_none_or(|since| msg.timestamp_ns > since))
                 .map(|msg| {
                     (
-                        msg.timestamp.clone(),
+                        msg.timestamp_ns,
                         msg.payload.clone(),
                         msg.original_payload_size,
                         msg.retain,
@@ -492,11 +511,11 @@ pub fn handle_subscribe_topic(
     // Phase 3: Stream existing messages (only when authenticated). No clear step —
     // the frontend keeps its per-topic cache and the delta contains no duplicates.
     if is_authenticated {
-        for (timestamp, payload, original_payload_size, retain) in &messages {
+        for (timestamp_ns, payload, original_payload_size, retain) in &messages {
             if let Some(frame) = build_binary_mqtt_frame(
                 broker,
                 topic,
-                timestamp,
+                &mqtt::format_timestamp_ns(*timestamp_ns),
                 payload,
                 *original_payload_size,
                 None,
@@ -590,6 +609,467 @@ pub fn send_broker_status_to_peers(peer_map: &PeerMap, source: &str, status: boo
     send_serialized_to_peers(peer_map, &serialized, "mqtt_connection_status");
 }
 
+/// Notify peers authenticated for the alert's broker about a state change.
+pub fn send_alert_to_peers(peer_map: &PeerMap, method: &str, alert: &alerts::Alert) {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method,
+        params: serde_json::json!(alert),
+    };
+
+    let serialized = match serde_json::to_string(&message) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    send_serialized_to_authenticated_peers(peer_map, &serialized, &alert.broker);
+}
+
+/// Progress of a load test, for peers authenticated for its broker.
+pub fn broadcast_load_progress(peer_map: &PeerMap, broker: &str, progress: serde_json::Value) {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "load_progress",
+        params: progress,
+    };
+    if let Ok(serialized) = serde_json::to_string(&message) {
+        send_serialized_to_authenticated_peers(peer_map, &serialized, broker);
+    }
+}
+
+/// Send the alert rules plus firing and recently resolved alerts to a specific
+/// peer. Alerts of brokers the peer is not authenticated for are left out.
+pub fn send_alerts(peer_map: &PeerMap, alert_map: &alerts::AlertMap, addr: SocketAddr) {
+    let authenticated_brokers = match peer_map.lock().unwrap().get(&addr) {
+        Some(peer) => peer.authenticated_brokers.clone(),
+        None => return,
+    };
+    let params = {
+        let engine = alert_map.lock().unwrap();
+        serde_json::json!({
+            "rules": engine.rules(),
+            "alerts": engine.alerts(|broker| authenticated_brokers.contains(broker)),
+        })
+    };
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "alerts",
+        params,
+    };
+    if let Ok(serialized) = serde_json::to_string(&message) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn broadcast_alerts(peer_map: &PeerMap, alert_map: &alerts::AlertMap) {
+    let addrs: Vec<SocketAddr> = peer_map.lock().unwrap().keys().copied().collect();
+    for addr in addrs {
+        send_alerts(peer_map, alert_map, addr);
+    }
+}
+
+fn serialize_webhooks(webhook_map: &webhooks::WebhookMap) -> Option<String> {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "webhooks",
+        params: serde_json::json!(webhook_map.lock().unwrap().summaries()),
+    };
+    serde_json::to_string(&message).ok()
+}
+
+pub fn send_webhooks(peer_map: &PeerMap, webhook_map: &webhooks::WebhookMap, addr: SocketAddr) {
+    if let Some(serialized) = serialize_webhooks(webhook_map) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn broadcast_webhooks(peer_map: &PeerMap, webhook_map: &webhooks::WebhookMap) {
+    if let Some(serialized) = serialize_webhooks(webhook_map) {
+        send_serialized_to_peers(peer_map, &serialized, "webhooks");
+    }
+}
+
+fn serialize_bridges(bridge_map: &bridges::BridgeMap) -> Option<String> {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "bridges",
+        params: bridge_map.lock().unwrap().summaries(),
+    };
+    serde_json::to_string(&message).ok()
+}
+
+pub fn send_bridges(peer_map: &PeerMap, bridge_map: &bridges::BridgeMap, addr: SocketAddr) {
+    if let Some(serialized) = serialize_bridges(bridge_map) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn broadcast_bridges(peer_map: &PeerMap, bridge_map: &bridges::BridgeMap) {
+    if let Some(serialized) = serialize_bridges(bridge_map) {
+        send_serialized_to_peers(peer_map, &serialized, "bridges");
+    }
+}
+
+fn serialize_schedules(scheduler_map: &scheduler::SchedulerMap) -> Option<String> {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "schedules",
+        params: scheduler_map.lock().unwrap().summaries(),
+    };
+    serde_json::to_string(&message).ok()
+}
+
+pub fn send_schedules(
+    peer_map: &PeerMap,
+    scheduler_map: &scheduler::SchedulerMap,
+    addr: SocketAddr,
+) {
+    if let Some(serialized) = serialize_schedules(scheduler_map) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn broadcast_schedules(peer_map: &PeerMap, scheduler_map: &scheduler::SchedulerMap) {
+    if let Some(serialized) = serialize_schedules(scheduler_map) {
+        send_serialized_to_peers(peer_map, &serialized, "schedules");
+    }
+}
+
+/// Status of one job after it ran.
+pub fn broadcast_schedule_status(peer_map: &PeerMap, status: serde_json::Value) {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "schedule_status",
+        params: status,
+    };
+    if let Ok(serialized) = serde_json::to_string(&message) {
+        send_serialized_to_peers(peer_map, &serialized, "schedule_status");
+    }
+}
+
+fn serialize_scenarios(config_path: &str) -> Option<String> {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "scenarios",
+        params: serde_json::json!(scenarios::get_scenarios(&format!(
+            "{config_path}/scenarios"
+        ))),
+    };
+    serde_json::to_string(&message).ok()
+}
+
+pub fn send_scenarios(peer_map: &PeerMap, config_path: &str, addr: SocketAddr) {
+    if let Some(serialized) = serialize_scenarios(config_path) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn broadcast_scenarios(peer_map: &PeerMap, config_path: &str) {
+    if let Some(serialized) = serialize_scenarios(config_path) {
+        send_serialized_to_peers(peer_map, &serialized, "scenarios");
+    }
+}
+
+/// Result of one step of a running scenario.
+pub fn send_scenario_step(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    run_id: &str,
+    scenario: &str,
+    step: &scenarios::StepResult,
+) {
+    let mut params = serde_json::json!(step);
+    params["run_id"] = serde_json::json!(run_id);
+    params["scenario"] = serde_json::json!(scenario);
+    send_notification_to_peer(peer_map, addr, "scenario_step", params);
+}
+
+pub fn send_scenario_result(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    report: &scenarios::ScenarioReport,
+) {
+    send_notification_to_peer(peer_map, addr, "scenario_result", serde_json::json!(report));
+}
+
+fn serialize_retention_rules(retention_map: &retention::RetentionMap) -> Option<String> {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method: "retention_rules",
+        params: serde_json::json!(retention_map.lock().unwrap().rules()),
+    };
+    serde_json::to_string(&message).ok()
+}
+
+pub fn send_retention_rules(
+    peer_map: &PeerMap,
+    retention_map: &retention::RetentionMap,
+    addr: SocketAddr,
+) {
+    if let Some(serialized) = serialize_retention_rules(retention_map) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn broadcast_retention_rules(peer_map: &PeerMap, retention_map: &retention::RetentionMap) {
+    if let Some(serialized) = serialize_retention_rules(retention_map) {
+        send_serialized_to_peers(peer_map, &serialized, "retention_rules");
+    }
+}
+
+fn send_notification_to_peer(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    method: &str,
+    params: serde_json::Value,
+) {
+    let message = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method,
+        params,
+    };
+    if let Ok(serialized) = serde_json::to_string(&message) {
+        send_to_specific_peer(peer_map, addr, &serialized);
+    }
+}
+
+pub fn send_retained_topics(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    broker: &str,
+    topics: &[retained::RetainedTopic],
+) {
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "retained_topics",
+        serde_json::json!({ "broker": broker, "topics": topics }),
+    );
+}
+
+/// Reply to a `clear_retained` dry run. Nothing is cleared until the peer
+/// sends the token back.
+pub fn send_retained_clear_preview(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    broker: &str,
+    topics: &[String],
+    token: &str,
+) {
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "retained_clear_preview",
+        serde_json::json!({
+            "broker": broker,
+            "topics": topics,
+            "token": token,
+            "expires_in_secs": retained::CLEAR_TOKEN_TTL_SECS,
+        }),
+    );
+}
+
+pub fn send_retained_clear_result(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    broker: &str,
+    cleared: &[String],
+    failed: &[(String, String)],
+    error: Option<&str>,
+) {
+    let failed: Vec<_> = failed
+        .iter()
+        .map(|(topic, err)| serde_json::json!({ "topic": topic, "error": err }))
+        .collect();
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "retained_clear_result",
+        serde_json::json!({
+            "broker": broker,
+            "success": error.is_none() && failed.is_empty(),
+            "cleared": cleared,
+            "failed": failed,
+            "error": error,
+        }),
+    );
+}
+
+pub fn send_command_result(peer_map: &PeerMap, addr: SocketAddr, result: &commands::CommandResult) {
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "command_executed",
+        serde_json::json!(result),
+    );
+}
+
+pub fn send_publish_result(peer_map: &PeerMap, addr: SocketAddr, result: &delivery::PublishResult) {
+    send_notification_to_peer(peer_map, addr, "publish_result", serde_json::json!(result));
+}
+
+pub fn send_request_result(peer_map: &PeerMap, addr: SocketAddr, result: &requests::RequestResult) {
+    send_notification_to_peer(peer_map, addr, "request_result", serde_json::json!(result));
+}
+
+/// Tell `addr` whether its save or removal of the `kind` item `name` went
+/// through. `revision` is the stored revision after a save and 0 after a
+/// removal; on a conflict `current_revision` is the one to reload.
+pub fn send_save_result(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    kind: &str,
+    name: &str,
+    result: &Result<u64, config::SaveError>,
+) {
+    let params = match result {
+        Ok(revision) => serde_json::json!({
+            "kind": kind,
+            "name": name,
+            "success": true,
+            "revision": revision,
+            "conflict": false,
+            "current_revision": revision,
+            "error": null,
+        }),
+        Err(err) => serde_json::json!({
+            "kind": kind,
+            "name": name,
+            "success": false,
+            "revision": null,
+            "conflict": matches!(err, config::SaveError::Conflict { .. }),
+            "current_revision": match err {
+                config::SaveError::Conflict { current } => Some(*current),
+                config::SaveError::Failed(_) => None,
+            },
+            "error": err.to_string(),
+        }),
+    };
+    send_notification_to_peer(peer_map, addr, "save_result", params);
+}
+
+pub fn send_revisions(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    kind: &str,
+    name: &str,
+    revisions: &[history::RevisionSummary],
+) {
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "revisions",
+        serde_json::json!({ "kind": kind, "name": name, "revisions": revisions }),
+    );
+}
+
+pub fn send_removed_items(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    kind: &str,
+    items: &[history::RemovedItem],
+) {
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "removed_items",
+        serde_json::json!({ "kind": kind, "items": items }),
+    );
+}
+
+pub fn send_revision_diff(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    kind: &str,
+    name: &str,
+    diff: &Result<Vec<history::Change>, String>,
+) {
+    let params = match diff {
+        Ok(changes) => {
+            serde_json::json!({ "kind": kind, "name": name, "changes": changes, "error": null })
+        }
+        Err(err) => serde_json::json!({ "kind": kind, "name": name, "changes": [], "error": err }),
+    };
+    send_notification_to_peer(peer_map, addr, "revision_diff", params);
+}
+
+pub fn send_config_validation(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    errors: &[schema::ConfigError],
+) {
+    send_notification_to_peer(
+        peer_map,
+        addr,
+        "config_validation",
+        serde_json::json!({ "version": schema::CONFIG_VERSION, "errors": errors }),
+    );
+}
+
+pub fn send_config_bundle(peer_map: &PeerMap, addr: SocketAddr, bundle: &bundle::ConfigBundle) {
+    send_notification_to_peer(peer_map, addr, "config_bundle", serde_json::json!(bundle));
+}
+
+pub fn send_config_import_preview(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    preview: &Result<Vec<bundle::ImportItem>, String>,
+) {
+    let params = match preview {
+        Ok(items) => serde_json::json!({ "items": items, "error": null }),
+        Err(err) => serde_json::json!({ "items": [], "error": err }),
+    };
+    send_notification_to_peer(peer_map, addr, "config_import_preview", params);
+}
+
+pub fn send_config_import_result(
+    peer_map: &PeerMap,
+    addr: SocketAddr,
+    result: &Result<bundle::ImportReport, String>,
+) {
+    let params = match result {
+        Ok(report) => {
+            serde_json::json!({ "success": report.failed.is_empty(), "report": report, "error": null })
+        }
+        Err(err) => serde_json::json!({ "success": false, "report": null, "error": err }),
+    };
+    send_notification_to_peer(peer_map, addr, "config_import_result", params);
+}
+
+/// Topic names with message count and latest timestamp. Only raw values are
+/// copied while the broker is locked; the JSON is built afterwards.
+fn topic_summary(entry: &mqtt::BrokerEntry) -> serde_json::Value {
+    let (source, topics) = {
+        let broker = entry.state();
+        let topics: Vec<_> = broker
+            .topics
+            .histories()
+            .map(|h| {
+                (
+                    h.name.clone(),
+                    h.messages.len(),
+                    h.messages.back().map(|m| m.timestamp_ns),
+                )
+            })
+            .collect();
+        (broker.broker.clone(), topics)
+    };
+    let mut topics_map = serde_json::Map::new();
+    for (topic, count, latest_ns) in topics {
+        topics_map.insert(
+            topic.to_string(),
+            serde_json::json!({
+                "count": count,
+                "latest_timestamp": latest_ns.map(mqtt::format_timestamp_ns).unwrap_or_default(),
+            }),
+        );
+    }
+    serde_json::json!({
+        "source": source,
+        "topics": topics_map,
+    })
+}
+
 /// Send topic summaries for a single broker to a specific peer.
 /// Called after a peer successfully authenticates for a broker.
 pub fn send_broker_topic_summaries(
@@ -598,28 +1078,7 @@ pub fn send_broker_topic_summaries(
     addr: SocketAddr,
     broker_name: &str,
 ) {
-    let summary = {
-        let ml = mqtt_map.lock().unwrap();
-        if let Some(broker) = ml.get(broker_name) {
-            let mut topics_map = serde_json::Map::new();
-            for (topic, messages) in &broker.topics {
-                let latest_ts = messages.back().map(|m| m.timestamp.as_str()).unwrap_or("");
-                topics_map.insert(
-                    topic.clone(),
-                    serde_json::json!({
-                        "count": messages.len(),
-                        "latest_timestamp": latest_ts,
-                    }),
-                );
-            }
-            Some(serde_json::json!({
-                "source": broker.broker,
-                "topics": topics_map,
-            }))
-        } else {
-            None
-        }
-    };
+    let summary = mqtt::get_broker(mqtt_map, broker_name).map(|broker| topic_summary(&broker));
 
     if let Some(summary) = summary {
         let msg = jsonrpc::JsonRpcNotification {
@@ -638,19 +1097,23 @@ pub fn send_configs(sender: &mut Sender<warp::filters::ws::Message>, config_path
     send_pipelines(sender, &format!("{config_path}/pipelines"));
 }
 
-pub fn send_commands(sender: &mut Sender<warp::filters::ws::Message>, commands_path: &str) {
-    if let Ok(commands) = std::fs::read_dir(commands_path) {
-        let commands: Vec<CommandMessage> = commands
-            .filter_map(|dir_entry| {
-                if let Ok(file) = dir_entry {
-                    if let Ok(file_content) = std::fs::read_to_string(file.path()) {
-                        return serde_json::from_str(&file_content).ok();
-                    }
-                }
-                None
-            })
-            .collect();
+/// Folders below `dir` as a `method` notification, including empty ones.
+fn send_folders(sender: &mut Sender<warp::filters::ws::Message>, method: &str, dir: &str) {
+    let jsonrpc = jsonrpc::JsonRpcNotification {
+        jsonrpc: "2.0",
+        method,
+        params: serde_json::json!(config::get_folders(dir)),
+    };
+    if let Ok(serialized) = serde_json::to_string(&jsonrpc) {
+        if let Err(err) = sender.try_send(warp::filters::ws::Message::text(serialized)) {
+            println!("Error sending message: {err:?}");
+        }
+    }
+}
 
+pub fn send_commands(sender: &mut Sender<warp::filters::ws::Message>, commands_path: &str) {
+    send_folders(sender, "command_folders", commands_path);
+    if let Some(commands) = config::get_commands(commands_path) {
         let jsonrpc = jsonrpc::JsonRpcNotification {
             jsonrpc: "2.0",
             method: "commands",
@@ -671,18 +1134,8 @@ pub fn send_commands(sender: &mut Sender<warp::filters::ws::Message>, commands_p
 }
 
 pub fn send_pipelines(sender: &mut Sender<warp::filters::ws::Message>, pipelines_path: &str) {
-    if let Ok(pipelines) = std::fs::read_dir(pipelines_path) {
-        let pipelines: Vec<PipelineMessage> = pipelines
-            .filter_map(|dir_entry| {
-                if let Ok(file) = dir_entry {
-                    if let Ok(file_content) = std::fs::read_to_string(file.path()) {
-                        return serde_json::from_str(&file_content).ok();
-                    }
-                }
-                None
-            })
-            .collect();
-
+    send_folders(sender, "pipeline_folders", pipelines_path);
+    if let Some(pipelines) = config::get_pipelines(pipelines_path) {
         let jsonrpc = jsonrpc::JsonRpcNotification {
             jsonrpc: "2.0",
             method: "pipelines",
@@ -700,49 +1153,65 @@ pub fn send_pipelines(sender: &mut Sender<warp::filters::ws::Message>, pipelines
     }
 }
 
+/// `mqtt_brokers` entry for one broker. Stored totals, throughput and skip
+/// counters are left out unless the peer is `authenticated` for the broker.
+fn broker_summary(entry: &mqtt::BrokerEntry, authenticated: bool) -> serde_json::Value {
+    let info = entry.info();
+    let broker = entry.state();
+    let mut summary = serde_json::json!({
+        "broker": broker.broker,
+        "id": broker.broker,
+        "host": info.host,
+        "name": info.name,
+        "description": info.description,
+        "tags": info.tags,
+        "revision": info.revision,
+        "connected": entry.is_connected(),
+        "topics": {},
+        "total_bytes": 0,
+        "total_messages": 0,
+        "rate_history": [],
+        "requires_auth": entry.requires_auth(),
+        "paused": broker.paused,
+        "skipped_messages": 0,
+        "skipped_bytes": 0,
+    });
+    if authenticated {
+        summary["total_bytes"] = serde_json::json!(broker.total_bytes);
+        summary["total_messages"] = serde_json::json!(broker.total_messages);
+        summary["rate_history"] = serde_json::json!(broker.rate_history);
+        summary["skipped_messages"] = serde_json::json!(broker.skipped_messages);
+        summary["skipped_bytes"] = serde_json::json!(broker.skipped_bytes);
+    }
+    summary
+}
+
 pub fn broadcast_brokers(peer_map: &PeerMap, mqtt_map: &mqtt::BrokerMap) {
-    // Build broker summaries, then send per-peer with throughput data
-    // filtered by authentication.
-    let brokers: Vec<(
-        String,
-        bool,
-        usize,
-        usize,
-        Vec<mqtt::RateHistoryEntry>,
-        bool,
-    )> = {
-        let binding = mqtt_map.lock().unwrap();
-        binding
-            .values()
-            .map(|b| {
-                (
-                    b.broker.clone(),
-                    b.connected,
-                    b.total_bytes,
-                    b.total_messages,
-                    b.rate_history.clone(),
-                    b.requires_auth,
-                )
-            })
-            .collect()
-    };
+    // Build both variants of each broker summary once, then send per-peer
+    // with throughput data filtered by authentication.
+    let brokers: Vec<(String, serde_json::Value, serde_json::Value)> = mqtt::all_brokers(mqtt_map)
+        .iter()
+        .map(|entry| {
+            let name = entry.state().broker.clone();
+            (
+                name,
+                broker_summary(entry, true),
+                broker_summary(entry, false),
+            )
+        })
+        .collect();
 
     let mut to_remove = Vec::new();
     let mut peers = peer_map.lock().unwrap();
     for (addr, peer) in peers.iter_mut() {
-        let summaries: Vec<serde_json::Value> = brokers
+        let summaries: Vec<&serde_json::Value> = brokers
             .iter()
-            .map(|(broker, connected, total_bytes, total_messages, rate_history, requires_auth)| {
-                let authed = peer.authenticated_brokers.contains(broker.as_str());
-                serde_json::json!({
-                    "broker": broker,
-                    "connected": connected,
-                    "topics": {},
-                    "total_bytes": if authed { *total_bytes } else { 0 },
-                    "total_messages": if authed { *total_messages } else { 0 },
-                    "rate_history": if authed { serde_json::json!(rate_history) } else { serde_json::json!([]) },
-                    "requires_auth": requires_auth,
-                })
+            .map(|(broker, full, redacted)| {
+                if peer.authenticated_brokers.contains(broker.as_str()) {
+                    full
+                } else {
+                    redacted
+                }
             })
             .collect();
         let message = jsonrpc::JsonRpcNotification {
@@ -773,53 +1242,22 @@ pub fn send_brokers(tx: &mut Sender<warp::filters::ws::Message>, mqtt_map: &mqtt
     // First, send settings so the frontend knows the configured limits
     send_settings(tx);
 
-    // Clone data out of the lock so we don't hold mqtt_map during serialization.
-    // Under heavy load, holding the lock here blocks the MQTT receive loop long
-    // enough to trip the broker's keep-alive timeout.
-    let (broker_summaries, topic_summaries) = {
-        let binding = mqtt_map.lock().unwrap();
-
-        let summaries: Vec<serde_json::Value> = binding
-            .values()
-            .map(|broker| {
-                serde_json::json!({
-                    "broker": broker.broker,
-                    "connected": broker.connected,
-                    "topics": {},
-                    "total_bytes": broker.total_bytes,
-                    "total_messages": broker.total_messages,
-                    "rate_history": broker.rate_history,
-                    "requires_auth": broker.requires_auth,
-                })
-            })
-            .collect();
-
-        // Collect topic summaries only for non-auth brokers.
-        // Protected brokers get their summaries after authenticate_broker.
-        let mut topic_sums: Vec<serde_json::Value> = Vec::new();
-        for broker in binding.values() {
-            if broker.requires_auth {
-                continue;
-            }
-            let mut topics_map = serde_json::Map::new();
-            for (topic, messages) in &broker.topics {
-                let latest_ts = messages.back().map(|m| m.timestamp.as_str()).unwrap_or("");
-                topics_map.insert(
-                    topic.clone(),
-                    serde_json::json!({
-                        "count": messages.len(),
-                        "latest_timestamp": latest_ts,
-                    }),
-                );
-            }
-            topic_sums.push(serde_json::json!({
-                "source": broker.broker,
-                "topics": topics_map,
-            }));
-        }
+    // Each broker is locked on its own and only while its data is copied out.
+    // Under heavy load, holding a broker's lock here blocks its MQTT receive
+    // loop long enough to trip the broker's keep-alive timeout.
+    let brokers = mqtt::all_brokers(mqtt_map);
+    let broker_summaries: Vec<serde_json::Value> = brokers
+        .iter()
+        .map(|entry| broker_summary(entry, true))
+        .collect();
 
-        (summaries, topic_sums)
-    }; // mqtt_map lock released here
+    // Collect topic summaries only for non-auth brokers.
+    // Protected brokers get their summaries after authenticate_broker.
+    let topic_summaries: Vec<serde_json::Value> = brokers
+        .iter()
+        .filter(|entry| !entry.requires_auth())
+        .map(|entry| topic_summary(entry))
+        .collect();
 
     // Phase 1: Send broker metadata (without full topic data) so UI renders immediately
     let meta_msg = jsonrpc::JsonRpcNotification {
@@ -896,7 +1334,7 @@ mod tests {
     }
 
     fn make_mqtt_map() -> mqtt::BrokerMap {
-        mqtt::BrokerMap::new(Mutex::new(HashMap::new()))
+        mqtt::BrokerMap::new(std::sync::RwLock::new(HashMap::new()))
     }
 
     fn insert_peer(
@@ -1176,18 +1614,21 @@ mod tests {
         // Use the test config with real command/pipeline files
         send_configs(&mut tx, "../test/config_source");
 
-        // Should receive at least 2 messages (commands + pipelines)
-        let msg1 = rx.try_recv().unwrap();
-        let msg2 = rx.try_recv().unwrap();
-        let parsed1: serde_json::Value = serde_json::from_str(msg1.to_str().unwrap()).unwrap();
-        let parsed2: serde_json::Value = serde_json::from_str(msg2.to_str().unwrap()).unwrap();
-
-        let methods: Vec<&str> = vec![
-            parsed1["method"].as_str().unwrap(),
-            parsed2["method"].as_str().unwrap(),
-        ];
-        assert!(methods.contains(&"commands"));
-        assert!(methods.contains(&"pipelines"));
+        // Commands and pipelines, each with their folders
+        let mut methods = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            let parsed: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+            methods.push(parsed["method"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            methods,
+            [
+                "command_folders",
+                "commands",
+                "pipeline_folders",
+                "pipelines"
+            ]
+        );
     }
 
     // --- Concurrency tests: verify no deadlock ---
@@ -1599,42 +2040,20 @@ mod tests {
     fn insert_broker_with_messages(
         mqtt_map: &mqtt::BrokerMap,
         broker: &str,
-        port: &str,
         topic: &str,
         messages: &[(&str, &str)],
     ) {
-        let mut map = mqtt_map.lock().unwrap();
-        let now_ms = chrono::Utc::now().timestamp_millis();
-        let mut topics = std::collections::HashMap::new();
-        let mut msgs = std::collections::VecDeque::new();
+        let mut mqtt_broker = mqtt::MqttBroker::new(broker);
         for (ts, payload) in messages {
-            msgs.push_back(mqtt::MqttMessage {
-                timestamp: ts.to_string(),
-                payload: bytes::Bytes::from(payload.to_string()),
-                original_payload_size: payload.len(),
-                retain: false,
-            });
+            mqtt_broker.store_message(
+                topic,
+                mqtt::parse_timestamp_ns(ts).unwrap(),
+                bytes::Bytes::from(payload.to_string()),
+                payload.len(),
+                false,
+            );
         }
-        topics.insert(topic.to_string(), msgs);
-        map.insert(
-            broker.to_string(),
-            mqtt::MqttBroker {
-                client: mqtt::connect_to_mqtt_host(
-                    &crate::server::config::BrokerConfig::from_host(port),
-                )
-                .0,
-                broker: broker.to_string(),
-                connected: true,
-                topics,
-                total_bytes: 12,
-                total_messages: messages.len(),
-                eviction_order: std::collections::VecDeque::new(),
-                rate_history: Vec::new(),
-                rate_bytes_accumulator: 0,
-                rate_last_sample_ms: now_ms,
-                requires_auth: false,
-            },
-        );
+        mqtt::insert_offline_broker(mqtt_map, mqtt_broker);
     }
 
     fn authenticate(peer_map: &PeerMap, addr: SocketAddr, broker: &str) {
@@ -1679,7 +2098,6 @@ mod tests {
         insert_broker_with_messages(
             &mqtt_map,
             "broker:1883",
-            "127.0.0.1:19995",
             "test/topic",
             &[
                 ("2024-01-01T00:00:01Z", "msg1"),
@@ -1724,7 +2142,6 @@ mod tests {
         insert_broker_with_messages(
             &mqtt_map,
             "broker:1883",
-            "127.0.0.1:19993",
             "test/topic",
             &[
                 ("2024-01-01T00:00:01Z", "msg1"),
@@ -1827,7 +2244,7 @@ mod tests {
         let mqtt_map = make_mqtt_map();
         let (addr, mut rx) = insert_peer(&peer_map, 9001);
 
-        insert_broker_with_messages(&mqtt_map, "broker:1883", "127.0.0.1:19994", "other", &[]);
+        insert_broker_with_messages(&mqtt_map, "broker:1883", "other", &[]);
         authenticate(&peer_map, addr, "broker:1883");
 
         handle_subscribe_topic(
@@ -1962,36 +2379,15 @@ mod tests {
         let (mut tx, mut rx) = channel(PEER_CHANNEL_CAPACITY);
 
         {
-            let mut map = mqtt_map.lock().unwrap();
-            let now_ms = chrono::Utc::now().timestamp_millis();
-            let mut topics = std::collections::HashMap::new();
-            let mut msgs = std::collections::VecDeque::new();
-            msgs.push_back(mqtt::MqttMessage {
-                timestamp: "2024-01-01T00:00:00Z".to_string(),
-                payload: bytes::Bytes::from("data"),
-                original_payload_size: 4,
-                retain: false,
-            });
-            topics.insert("test/topic".to_string(), msgs);
-            map.insert(
-                "broker:1883".to_string(),
-                mqtt::MqttBroker {
-                    client: mqtt::connect_to_mqtt_host(
-                        &crate::server::config::BrokerConfig::from_host("127.0.0.1:19993"),
-                    )
-                    .0,
-                    broker: "broker:1883".to_string(),
-                    connected: true,
-                    topics,
-                    total_bytes: 4,
-                    total_messages: 2,
-                    eviction_order: std::collections::VecDeque::new(),
-                    rate_history: Vec::new(),
-                    rate_bytes_accumulator: 0,
-                    rate_last_sample_ms: now_ms,
-                    requires_auth: false,
-                },
+            let mut broker = mqtt::MqttBroker::new("broker:1883");
+            broker.store_message(
+                "test/topic",
+                mqtt::parse_timestamp_ns("2024-01-01T00:00:00Z").unwrap(),
+                bytes::Bytes::from("data"),
+                4,
+                false,
             );
+            mqtt::insert_offline_broker(&mqtt_map, broker);
         }
 
         send_brokers(&mut tx, &mqtt_map);
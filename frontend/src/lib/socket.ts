@@ -134,3 +134,21 @@ export function requestBrokerAuthentication(hostname: string, password: string,
 
 	socket.send(message);
 }
+
+export type LoadConfig = {
+	broker: string;
+	topic_prefix: string;
+	topics: number;
+	rate: number;
+	payload_size: number;
+	qos: number;
+	duration_secs: number;
+};
+
+export function requestStartLoad(config: LoadConfig, socket: WebSocket) {
+	socket.send(JSON.stringify({ jsonrpc: '2.0', method: 'start_load', params: config }));
+}
+
+export function requestStopLoad(runId: string, socket: WebSocket) {
+	socket.send(JSON.stringify({ jsonrpc: '2.0', method: 'stop_load', params: { run_id: runId } }));
+}
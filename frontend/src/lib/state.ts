@@ -27,6 +27,8 @@ export class AppState {
 	pipelines: SavedPipeline[] = [];
 	commands: Command[] = [];
 	maxBrokerBytes: number = 64 * 1024 * 1024;
+	/** Latest `load_progress` report of each load test run, by run id. */
+	loadRuns: { [runId: string]: LoadProgress } = {};
 }
 
 const decoder = new TextDecoder();
@@ -130,6 +132,21 @@ export type Command = {
 	payload: string;
 };
 
+export type LoadProgress = {
+	runId: string;
+	broker: string;
+	state: 'running' | 'finished' | 'stopped';
+	sent: number;
+	dropped: number;
+	failed: number;
+	bytes: number;
+	elapsedSecs: number;
+	durationSecs: number;
+	targetRate: number;
+	averageRate: number;
+	currentRate: number;
+};
+
 export type RateHistoryEntry = {
 	timestamp: number;
 	bytesPerSecond: number;
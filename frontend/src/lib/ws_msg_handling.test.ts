@@ -22,7 +22,9 @@ import {
 	processMQTTMessageMetaBatch,
 	processMessagesEvictedBatch,
 	processPipelines,
-	type PipelineParam
+	type PipelineParam,
+	processLoadProgress,
+	type LoadProgressParam
 } from './ws_msg_handling';
 import { AppState, Message, type BrokerRepository } from './state';
 
@@ -376,6 +378,32 @@ test('processSettings updates maxBrokerBytes on AppState', () => {
 	expect(result.maxBrokerBytes).toBe(256 * 1024 * 1024);
 });
 
+test('processLoadProgress keeps the latest report per run', () => {
+	const app = new AppState();
+	const report: LoadProgressParam = {
+		run_id: 'r1',
+		broker: 'b:1883',
+		state: 'running',
+		sent: 100,
+		dropped: 5,
+		failed: 0,
+		bytes: 6400,
+		elapsed_secs: 1,
+		duration_secs: 10,
+		target_rate: 105,
+		average_rate: 100,
+		current_rate: 100
+	};
+	processLoadProgress(report, app);
+	const result = processLoadProgress({ ...report, state: 'stopped', sent: 150 }, app);
+
+	expect(Object.keys(result.loadRuns)).toEqual(['r1']);
+	expect(result.loadRuns['r1'].state).toBe('stopped');
+	expect(result.loadRuns['r1'].sent).toBe(150);
+	expect(result.loadRuns['r1'].dropped).toBe(5);
+	expect(result.loadRuns['r1'].targetRate).toBe(105);
+});
+
 test('processRateHistorySample appends entry and updates bytesPerSecond', () => {
 	const app = new AppState();
 	app.brokerRepository = {
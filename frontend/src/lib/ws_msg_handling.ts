@@ -593,6 +593,39 @@ export function processSettings(params: SettingsParam, app: AppState) {
 	return app;
 }
 
+export type LoadProgressParam = {
+	run_id: string;
+	broker: string;
+	state: 'running' | 'finished' | 'stopped';
+	sent: number;
+	dropped: number;
+	failed: number;
+	bytes: number;
+	elapsed_secs: number;
+	duration_secs: number;
+	target_rate: number;
+	average_rate: number;
+	current_rate: number;
+};
+
+export function processLoadProgress(params: LoadProgressParam, app: AppState) {
+	app.loadRuns[params.run_id] = {
+		runId: params.run_id,
+		broker: params.broker,
+		state: params.state,
+		sent: params.sent,
+		dropped: params.dropped,
+		failed: params.failed,
+		bytes: params.bytes,
+		elapsedSecs: params.elapsed_secs,
+		durationSecs: params.duration_secs,
+		targetRate: params.target_rate,
+		averageRate: params.average_rate,
+		currentRate: params.current_rate
+	};
+	return app;
+}
+
 export type RateHistorySampleParam = {
 	source: string;
 	sample: { timestamp: number; bytes_per_second: number; total_bytes: number };